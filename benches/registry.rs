@@ -0,0 +1,87 @@
+//! Registration and dispatch throughput with a large number of fds.
+//!
+//! Run with `cargo bench --bench registry [connections]`, the default is
+//! 100k. The soft RLIMIT_NOFILE is raised to the hard limit and the count
+//! is capped to what the process is allowed to open.
+
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::time::{Duration, Instant};
+
+use copoll::{Epoll, Events, Interest, Mode, Token};
+
+fn raise_fd_limit() -> usize {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+
+    unsafe {
+        libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit);
+        limit.rlim_cur = limit.rlim_max;
+        libc::setrlimit(libc::RLIMIT_NOFILE, &limit);
+        libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit);
+    }
+
+    match limit.rlim_cur {
+        libc::RLIM_INFINITY => usize::MAX,
+        soft => soft as usize,
+    }
+}
+
+fn report(name: &str, count: usize, elapsed: Duration) {
+    println!(
+        "{:<12} {:>8} fds in {:>10.3?} ({:>7.1} ns/fd)",
+        name,
+        count,
+        elapsed,
+        elapsed.as_nanos() as f64 / count as f64
+    );
+}
+
+fn main() {
+    let requested = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse::<usize>().ok())
+        .unwrap_or(100_000);
+
+    // Leave some room for stdio, the epoll fd and whatever the harness holds
+    let count = requested.min(raise_fd_limit().saturating_sub(64));
+    if count < requested {
+        println!("fd limit only allows {} of {} connections", count, requested);
+    }
+
+    let fds: Vec<_> = (0..count)
+        .map(|_| unsafe { libc::eventfd(1, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) })
+        .map(|fd| {
+            assert!(fd >= 0, "eventfd: {}", std::io::Error::last_os_error());
+            unsafe { OwnedFd::from_raw_fd(fd) }
+        })
+        .collect();
+
+    let mut epoll = Epoll::create().unwrap();
+
+    let start = Instant::now();
+    for (i, fd) in fds.iter().enumerate() {
+        epoll.register(fd, Token(i), Interest::READABLE, Mode::OneShot).unwrap();
+    }
+    report("register", count, start.elapsed());
+
+    // Every eventfd is readable, one-shot makes each one fire exactly once
+    let start = Instant::now();
+    let mut events = Events::new();
+    let mut delivered = 0;
+    while delivered < count {
+        epoll.poll_into(&mut events, Some(Duration::ZERO)).unwrap();
+        delivered += events.len();
+    }
+    report("dispatch", count, start.elapsed());
+
+    let start = Instant::now();
+    for (i, fd) in fds.iter().enumerate() {
+        epoll.reregister(fd, Token(i), Interest::READABLE, Mode::OneShot).unwrap();
+    }
+    report("reregister", count, start.elapsed());
+
+    let start = Instant::now();
+    for fd in fds.iter() {
+        epoll.unregister(fd).unwrap();
+    }
+    report("unregister", count, start.elapsed());
+}
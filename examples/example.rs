@@ -1,23 +1,23 @@
-use copoll::{Interest, Mode, Token, Epoll, Event, Events};
-use std::os::unix::{net::UnixListener, io::AsRawFd};
+use copoll::sources::UnixListenerSource;
+use copoll::{Token, Epoll};
 use std::time::Duration;
 
 const LISTENER: Token = Token(0);
 
 fn main() {
     let mut epoll = Epoll::create().unwrap();
-    let mut listener = UnixListener::bind("test.sock").unwrap();
-    epoll.register(listener.as_raw_fd(), LISTENER, Interest::Both, Mode::Edge).unwrap();
+    let listener = UnixListenerSource::bind("test.sock").unwrap();
+    listener.register(&mut epoll, LISTENER).unwrap();
 
     loop {
-        let mut events = epoll.poll(Some(Duration::from_millis(2000))).unwrap();
+        let events = epoll.poll(Some(Duration::from_millis(2000))).unwrap();
 
-        for event in events.iter() {
+        for (token, _) in events.iter() {
             // Handle the event, read from the socket
             // respond to it etc
-            // Here you could also use the utility functions provided in copoll::event;
-            // example just breaks on first event
-            break;
+            if token == LISTENER {
+                listener.accept_all(|_stream, _addr| {}).unwrap();
+            }
         }
     }
 }
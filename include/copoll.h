@@ -0,0 +1,74 @@
+#ifndef COPOLL_H
+#define COPOLL_H
+
+/* Generated by cbindgen from src/ffi.rs, do not edit */
+
+#include <stdint.h>
+#include <stddef.h>
+
+#define COPOLL_READABLE 1
+
+#define COPOLL_WRITABLE 2
+
+#define COPOLL_PRIORITY 4
+
+/**
+ * Combine with COPOLL_READABLE to be told about the peer closing its write side
+ */
+#define COPOLL_READ_CLOSED 8
+
+#define COPOLL_LEVEL 0
+
+#define COPOLL_EDGE 1
+
+#define COPOLL_ONESHOT 2
+
+/**
+ * Opaque handle to an epoll instance
+ */
+typedef struct CopollEpoll CopollEpoll;
+
+/**
+ * Called once for every event with the user pointer handed to copoll_poll,
+ * the token of the registration and the raw epoll event bits
+ */
+typedef void (*CopollCallback)(void *user, uintptr_t token, uint32_t events);
+
+/**
+ * Create an epoll instance, NULL on failure
+ */
+CopollEpoll *copoll_create(void);
+
+/**
+ * Close an epoll instance created by copoll_create, NULL is ignored
+ */
+void copoll_destroy(CopollEpoll *handle);
+
+/**
+ * The epoll file descriptor, to nest the instance in another loop
+ */
+int copoll_fd(const CopollEpoll *handle);
+
+/**
+ * Register `fd` with COPOLL_READABLE, COPOLL_WRITABLE, COPOLL_PRIORITY and
+ * COPOLL_READ_CLOSED or-ed together and a mode
+ */
+int copoll_register(CopollEpoll *handle, int fd, uintptr_t token, int interest, int mode);
+
+/**
+ * Change the token, interest or mode of a registered fd
+ */
+int copoll_reregister(CopollEpoll *handle, int fd, uintptr_t token, int interest, int mode);
+
+/**
+ * Stop polling `fd`
+ */
+int copoll_unregister(CopollEpoll *handle, int fd);
+
+/**
+ * Wait up to `timeout_ms` milliseconds, -1 blocks, and dispatch every event
+ * to `callback`. Returns the number of events dispatched
+ */
+int copoll_poll(CopollEpoll *handle, int timeout_ms, CopollCallback callback, void *user);
+
+#endif /* COPOLL_H */
@@ -0,0 +1,136 @@
+//! Queueing registration changes and applying them together.
+//!
+//! ```ignore
+//! epoll.batch()
+//!     .add(&client, Token(1), Interest::READABLE, Mode::Edge)
+//!     .modify(&listener, Token(0), Interest::READABLE, Mode::Level)
+//!     .remove(&stale)
+//!     .commit()?;
+//! ```
+//!
+//! Any [`Poller`] can be batched with [`Batch::new`]. epoll has no batched
+//! epoll_ctl, every change is still its own syscall. Through
+//! [`IoUring`](crate::uring::IoUring) the changes are queued requests that
+//! are submitted in one syscall with the next poll.
+use std::io;
+use std::os::fd::{AsFd, BorrowedFd};
+
+use crate::{Epoll, Interest, Mode, Poller, Token};
+
+#[derive(Debug, Copy, Clone)]
+enum Op {
+    Add(Token, Interest, Mode),
+    Modify(Token, Interest, Mode),
+    Remove,
+}
+
+/// Registration changes applied in order by [`Batch::commit`]
+#[derive(Debug)]
+pub struct Batch<'p, 'f, P: Poller + ?Sized> {
+    poller: &'p mut P,
+    ops: Vec<(BorrowedFd<'f>, Op)>,
+}
+
+impl<'p, 'f, P: Poller + ?Sized> Batch<'p, 'f, P> {
+    pub fn new(poller: &'p mut P) -> Batch<'p, 'f, P> {
+        Batch { poller, ops: Vec::new() }
+    }
+
+    /// Queue a registration of a new source
+    pub fn add<S: AsFd + ?Sized>(mut self, source: &'f S, token: Token, interest: Interest, mode: Mode) -> Self {
+        self.ops.push((source.as_fd(), Op::Add(token, interest, mode)));
+        self
+    }
+
+    /// Queue a change of the token, interest or mode of a registered source
+    pub fn modify<S: AsFd + ?Sized>(mut self, source: &'f S, token: Token, interest: Interest, mode: Mode) -> Self {
+        self.ops.push((source.as_fd(), Op::Modify(token, interest, mode)));
+        self
+    }
+
+    /// Queue an unregistration
+    pub fn remove<S: AsFd + ?Sized>(mut self, source: &'f S) -> Self {
+        self.ops.push((source.as_fd(), Op::Remove));
+        self
+    }
+
+    /// Number of queued changes
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Apply the changes in order. Stops at the first one that fails and
+    /// returns its error, which names the fd; the changes before it stay
+    /// applied
+    pub fn commit(self) -> io::Result<()> {
+        for (fd, op) in self.ops {
+            match op {
+                Op::Add(token, interest, mode) => self.poller.register(fd, token, interest, mode)?,
+                Op::Modify(token, interest, mode) => self.poller.reregister(fd, token, interest, mode)?,
+                Op::Remove => self.poller.unregister(fd)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Epoll {
+    /// Start a [`Batch`] of registration changes
+    pub fn batch<'f>(&mut self) -> Batch<'_, 'f, Epoll> {
+        Batch::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn changes_are_applied_in_order() {
+        let mut epoll = Epoll::create().unwrap();
+        let (first, first_peer) = UnixStream::pair().unwrap();
+        let (second, second_peer) = UnixStream::pair().unwrap();
+        (&first_peer).write_all(b"x").unwrap();
+        (&second_peer).write_all(b"x").unwrap();
+
+        let batch = epoll
+            .batch()
+            .add(&first, Token(1), Interest::READABLE, Mode::Level)
+            .add(&second, Token(2), Interest::READABLE, Mode::Level)
+            .modify(&first, Token(3), Interest::READABLE, Mode::Level)
+            .remove(&second);
+        assert_eq!(batch.len(), 4);
+        batch.commit().unwrap();
+
+        let events = epoll.poll(Duration::ZERO).unwrap();
+        assert_eq!(events.iter().map(|(token, _)| token).collect::<Vec<_>>(), [Token(3)]);
+    }
+
+    #[test]
+    fn commit_stops_at_the_first_failure() {
+        let mut epoll = Epoll::create().unwrap();
+        let (first, _first_peer) = UnixStream::pair().unwrap();
+        let (second, _second_peer) = UnixStream::pair().unwrap();
+        let (third, _third_peer) = UnixStream::pair().unwrap();
+
+        let result = epoll
+            .batch()
+            .add(&first, Token(1), Interest::READABLE, Mode::Level)
+            .remove(&second)
+            .add(&third, Token(3), Interest::READABLE, Mode::Level)
+            .commit();
+        assert!(result.is_err());
+
+        assert!(epoll.is_registered(&first));
+        assert!(!epoll.is_registered(&third));
+    }
+}
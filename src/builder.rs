@@ -0,0 +1,181 @@
+//! Creation options of an [`Epoll`] instance.
+//!
+//! [`Epoll::create`] is the same as `Epoll::builder().build()`, every option
+//! starts out the way create sets it up. The runtime settings can still be
+//! changed afterwards with the `set_*` methods of the instance.
+//!
+//! ```ignore
+//! let mut epoll = Epoll::builder()
+//!     .cloexec(true)
+//!     .events_capacity(1024)
+//!     .retry_interrupted(true)
+//!     .build()?;
+//! ```
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::Arc;
+
+use crate::leak::Leaked;
+use crate::namespace::Namespaces;
+use crate::profile::WakeupProfile;
+use crate::registry::Registry;
+use crate::scoped::Released;
+use crate::stats::PollStats;
+use crate::{sys, Epoll, Interest, Mode, Slab};
+
+/// Configures and creates an [`Epoll`], see [`Epoll::builder`]
+#[derive(Debug, Clone)]
+pub struct EpollBuilder {
+    cloexec: bool,
+    events_capacity: usize,
+    default_interest: Interest,
+    default_mode: Mode,
+    retry_interrupted: bool,
+    edge_nonblocking: bool,
+    coalesce: bool,
+    fast_path: bool,
+    strict: bool,
+    detect_duplicates: bool,
+    fallible_alloc: bool,
+    wakeup_profiling: bool,
+    stats: bool,
+}
+
+impl Default for EpollBuilder {
+    fn default() -> EpollBuilder {
+        EpollBuilder {
+            cloexec: false,
+            events_capacity: 0,
+            default_interest: Interest::READABLE,
+            default_mode: Mode::Level,
+            retry_interrupted: false,
+            edge_nonblocking: false,
+            coalesce: false,
+            fast_path: false,
+            strict: false,
+            detect_duplicates: false,
+            fallible_alloc: false,
+            wakeup_profiling: false,
+            stats: false,
+        }
+    }
+}
+
+impl EpollBuilder {
+    pub fn new() -> EpollBuilder {
+        EpollBuilder::default()
+    }
+
+    /// Create the epoll fd with EPOLL_CLOEXEC, so it is closed in programs
+    /// started with exec. Off by default
+    pub fn cloexec(mut self, enabled: bool) -> EpollBuilder {
+        self.cloexec = enabled;
+        self
+    }
+
+    /// Room for `capacity` events in the buffers [`Epoll::poll`] returns
+    pub fn events_capacity(mut self, capacity: usize) -> EpollBuilder {
+        self.events_capacity = capacity;
+        self
+    }
+
+    /// Interest of [`Epoll::register_default`], readable by default
+    pub fn default_interest(mut self, interest: Interest) -> EpollBuilder {
+        self.default_interest = interest;
+        self
+    }
+
+    /// Mode of [`Epoll::register_default`], level triggered by default
+    pub fn default_mode(mut self, mode: Mode) -> EpollBuilder {
+        self.default_mode = mode;
+        self
+    }
+
+    /// See [`Epoll::set_retry_interrupted`]
+    pub fn retry_interrupted(mut self, enabled: bool) -> EpollBuilder {
+        self.retry_interrupted = enabled;
+        self
+    }
+
+    /// See [`Epoll::set_edge_nonblocking`]
+    pub fn edge_nonblocking(mut self, enabled: bool) -> EpollBuilder {
+        self.edge_nonblocking = enabled;
+        self
+    }
+
+    /// See [`Epoll::set_coalesce`]
+    pub fn coalesce(mut self, enabled: bool) -> EpollBuilder {
+        self.coalesce = enabled;
+        self
+    }
+
+    /// See [`Epoll::set_fast_path`]
+    pub fn fast_path(mut self, enabled: bool) -> EpollBuilder {
+        self.fast_path = enabled;
+        self
+    }
+
+    /// See [`Epoll::set_strict`]
+    pub fn strict(mut self, enabled: bool) -> EpollBuilder {
+        self.strict = enabled;
+        self
+    }
+
+    /// See [`Epoll::set_detect_duplicates`]
+    pub fn detect_duplicates(mut self, enabled: bool) -> EpollBuilder {
+        self.detect_duplicates = enabled;
+        self
+    }
+
+    /// See [`Epoll::set_fallible_alloc`]
+    pub fn fallible_alloc(mut self, enabled: bool) -> EpollBuilder {
+        self.fallible_alloc = enabled;
+        self
+    }
+
+    /// See [`Epoll::set_wakeup_profiling`]
+    pub fn wakeup_profiling(mut self, enabled: bool) -> EpollBuilder {
+        self.wakeup_profiling = enabled;
+        self
+    }
+
+    /// See [`Epoll::set_stats`]
+    pub fn stats(mut self, enabled: bool) -> EpollBuilder {
+        self.stats = enabled;
+        self
+    }
+
+    /// Create the epoll instance
+    pub fn build(&self) -> io::Result<Epoll> {
+        let flags = if self.cloexec { libc::EPOLL_CLOEXEC } else { 0 };
+
+        let mut registry = Registry::default();
+        registry.set_index_files(self.detect_duplicates);
+
+        Ok(Epoll {
+            epoll_fd: Arc::new(sys::epoll_create(flags)?),
+            registry,
+            fast_path: self.fast_path,
+            strict: self.strict,
+            profile: self.wakeup_profiling.then(WakeupProfile::new),
+            fallible_alloc: self.fallible_alloc,
+            error_policies: HashMap::new(),
+            disabled: HashSet::new(),
+            wakers: HashMap::new(),
+            retry_interrupted: self.retry_interrupted,
+            edge_nonblocking: self.edge_nonblocking,
+            stats: self.stats.then(PollStats::default),
+            coalesce: self.coalesce,
+            user_data: Slab::new(),
+            events_capacity: self.events_capacity,
+            default_interest: self.default_interest,
+            default_mode: self.default_mode,
+            dups: HashMap::new(),
+            released: Released::default(),
+            leaked: Leaked::default(),
+            leak_hook: None,
+            namespaces: Namespaces::default(),
+            cloned: Arc::default(),
+        })
+    }
+}
@@ -0,0 +1,383 @@
+//! Packet capture sources.
+//!
+//! [`PacketSocket`] is a raw AF_PACKET socket receiving every frame of one
+//! or all interfaces, read one frame per syscall. [`PacketRing`] maps a
+//! TPACKET_V3 receive ring instead: the kernel fills whole blocks of frames
+//! and the socket turns readable when a block is handed to userspace, so a
+//! wakeup delivers a batch without any copies. Both need CAP_NET_RAW.
+//!
+//! AF_XDP sockets are created with their UMEM by libxdp or similar, their
+//! fds can be registered readable as they are, readiness means descriptors
+//! are waiting in the RX ring. With the need_wakeup flag use
+//! [`xdp_wakeup_rx`] and [`xdp_wakeup_tx`] to kick the driver.
+use std::io;
+use std::mem;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::slice;
+use std::time::Duration;
+
+use libc::{c_int, c_void};
+
+const SOL_PACKET: c_int = 263;
+const PACKET_RX_RING: c_int = 5;
+const PACKET_STATISTICS: c_int = 6;
+const PACKET_VERSION: c_int = 10;
+const TPACKET_V3: c_int = 2;
+const TP_STATUS_KERNEL: u32 = 0;
+const TP_STATUS_USER: u32 = 1;
+
+#[repr(C)]
+struct TpacketReq3 {
+    tp_block_size: u32,
+    tp_block_nr: u32,
+    tp_frame_size: u32,
+    tp_frame_nr: u32,
+    tp_retire_blk_tov: u32,
+    tp_sizeof_priv: u32,
+    tp_feature_req_word: u32,
+}
+
+#[repr(C)]
+struct TpacketStats {
+    tp_packets: u32,
+    tp_drops: u32,
+    tp_freeze_q_cnt: u32,
+}
+
+/// Start of tpacket_block_desc with its tpacket_hdr_v1
+#[repr(C)]
+struct BlockDesc {
+    version: u32,
+    offset_to_priv: u32,
+    block_status: u32,
+    num_pkts: u32,
+    offset_to_first_pkt: u32,
+}
+
+/// Start of tpacket3_hdr
+#[repr(C)]
+struct FrameHeader {
+    tp_next_offset: u32,
+    tp_sec: u32,
+    tp_nsec: u32,
+    tp_snaplen: u32,
+    tp_len: u32,
+    tp_status: u32,
+    tp_mac: u16,
+    tp_net: u16,
+}
+
+/// Kernel counters of a capture socket since the previous call
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct PacketStats {
+    pub packets: u32,
+    pub drops: u32,
+}
+
+/// A raw AF_PACKET socket, register it readable
+#[derive(Debug)]
+pub struct PacketSocket {
+    fd: OwnedFd,
+}
+
+impl PacketSocket {
+    /// Capture every protocol on `interface`, or on all interfaces with None
+    pub fn open(interface: Option<&str>) -> io::Result<PacketSocket> {
+        let protocol = (libc::ETH_P_ALL as u16).to_be();
+
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_PACKET,
+                libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                protocol as c_int
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let socket = PacketSocket { fd: unsafe { OwnedFd::from_raw_fd(fd) } };
+
+        if let Some(interface) = interface {
+            let index = if_nametoindex(interface)?;
+
+            let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+            addr.sll_family = libc::AF_PACKET as u16;
+            addr.sll_protocol = protocol;
+            addr.sll_ifindex = index as c_int;
+
+            let result = unsafe {
+                libc::bind(
+                    fd,
+                    &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t
+                )
+            };
+            if result < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(socket)
+    }
+
+    /// Receive one frame into `buf`, None if nothing is queued.
+    /// Frames longer than `buf` are truncated, the full length is returned
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        let n = unsafe { libc::recv(self.fd.as_raw_fd(), buf.as_mut_ptr() as *mut c_void, buf.len(), libc::MSG_TRUNC) };
+
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            return match err.kind() {
+                io::ErrorKind::WouldBlock => Ok(None),
+                _ => Err(err),
+            };
+        }
+
+        Ok(Some(n as usize))
+    }
+
+    /// Receive up to `max` frames and hand each one to `f`, returns how many
+    /// were received. The frames are truncated to the size of `buf`
+    pub fn recv_batch<F: FnMut(&[u8])>(&self, buf: &mut [u8], max: usize, mut f: F) -> io::Result<usize> {
+        let mut received = 0;
+
+        while received < max {
+            match self.recv(buf)? {
+                Some(n) => f(&buf[..n.min(buf.len())]),
+                None => break,
+            }
+
+            received += 1;
+        }
+
+        Ok(received)
+    }
+
+    /// Frames received and dropped by the kernel since the previous call
+    pub fn stats(&self) -> io::Result<PacketStats> {
+        let mut stats: TpacketStats = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<TpacketStats>() as libc::socklen_t;
+
+        let result = unsafe {
+            libc::getsockopt(self.fd.as_raw_fd(), SOL_PACKET, PACKET_STATISTICS, &mut stats as *mut TpacketStats as *mut c_void, &mut len)
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(PacketStats { packets: stats.tp_packets, drops: stats.tp_drops })
+    }
+
+    fn set_option<T>(&self, option: c_int, value: &T) -> io::Result<()> {
+        let result = unsafe {
+            libc::setsockopt(
+                self.fd.as_raw_fd(),
+                SOL_PACKET,
+                option,
+                value as *const T as *const c_void,
+                mem::size_of::<T>() as libc::socklen_t
+            )
+        };
+
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+impl AsRawFd for PacketSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for PacketSocket {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+/// Geometry of a [`PacketRing`]
+#[derive(Debug, Clone)]
+pub struct RingConfig {
+    /// Size of a block, a multiple of the page size
+    pub block_size: u32,
+    pub block_count: u32,
+    /// Largest frame expected, a multiple of 16
+    pub frame_size: u32,
+    /// A block that is not full is handed out after this long
+    pub retire_timeout: Duration,
+}
+
+impl Default for RingConfig {
+    /// 64 blocks of 256 KiB, retired after 10ms
+    fn default() -> RingConfig {
+        RingConfig {
+            block_size: 1 << 18,
+            block_count: 64,
+            frame_size: 2048,
+            retire_timeout: Duration::from_millis(10),
+        }
+    }
+}
+
+/// A frame in a [`PacketRing`] block
+#[derive(Debug)]
+pub struct Frame<'a> {
+    /// The captured bytes, starting at the link layer header
+    pub data: &'a [u8],
+    /// The length of the frame on the wire, data may be shorter
+    pub len: usize,
+    /// Receive time, CLOCK_REALTIME
+    pub timestamp: Duration,
+}
+
+/// An AF_PACKET socket with a memory mapped TPACKET_V3 receive ring,
+/// register it readable and call [`PacketRing::read_frames`]
+#[derive(Debug)]
+pub struct PacketRing {
+    socket: PacketSocket,
+    map: *mut u8,
+    block_size: usize,
+    block_count: usize,
+    next: usize,
+}
+
+impl PacketRing {
+    pub fn open(interface: Option<&str>, config: &RingConfig) -> io::Result<PacketRing> {
+        let socket = PacketSocket::open(interface)?;
+
+        socket.set_option(PACKET_VERSION, &TPACKET_V3)?;
+
+        let request = TpacketReq3 {
+            tp_block_size: config.block_size,
+            tp_block_nr: config.block_count,
+            tp_frame_size: config.frame_size,
+            tp_frame_nr: (config.block_size / config.frame_size.max(1)) * config.block_count,
+            tp_retire_blk_tov: config.retire_timeout.as_millis().min(u32::MAX as u128) as u32,
+            tp_sizeof_priv: 0,
+            tp_feature_req_word: 0,
+        };
+        socket.set_option(PACKET_RX_RING, &request)?;
+
+        let block_size = config.block_size as usize;
+        let block_count = config.block_count as usize;
+
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                block_size * block_count,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                socket.fd.as_raw_fd(),
+                0
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(PacketRing { socket, map: map as *mut u8, block_size, block_count, next: 0 })
+    }
+
+    /// Hand every frame of the blocks the kernel retired to `f`, oldest first,
+    /// and give the blocks back. Returns the number of frames
+    pub fn read_frames<F: FnMut(Frame<'_>)>(&mut self, mut f: F) -> usize {
+        let mut frames = 0;
+
+        for _ in 0..self.block_count {
+            let block = unsafe { self.map.add(self.next * self.block_size) };
+            let desc = block as *mut BlockDesc;
+
+            let status = unsafe { ptr::read_volatile(ptr::addr_of!((*desc).block_status)) };
+            if status & TP_STATUS_USER == 0 {
+                break;
+            }
+
+            let count = unsafe { (*desc).num_pkts };
+            let mut offset = unsafe { (*desc).offset_to_first_pkt } as usize;
+
+            for _ in 0..count {
+                let header = unsafe { &*(block.add(offset) as *const FrameHeader) };
+                let data = unsafe {
+                    slice::from_raw_parts(block.add(offset + header.tp_mac as usize), header.tp_snaplen as usize)
+                };
+
+                f(Frame {
+                    data,
+                    len: header.tp_len as usize,
+                    timestamp: Duration::new(header.tp_sec as u64, header.tp_nsec),
+                });
+
+                frames += 1;
+                offset += header.tp_next_offset as usize;
+            }
+
+            unsafe { ptr::write_volatile(ptr::addr_of_mut!((*desc).block_status), TP_STATUS_KERNEL) };
+            self.next = (self.next + 1) % self.block_count;
+        }
+
+        frames
+    }
+
+    /// Frames received and dropped by the kernel since the previous call
+    pub fn stats(&self) -> io::Result<PacketStats> {
+        self.socket.stats()
+    }
+}
+
+impl AsRawFd for PacketRing {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+impl AsFd for PacketRing {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.socket.as_fd()
+    }
+}
+
+impl Drop for PacketRing {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.map as *mut c_void, self.block_size * self.block_count) };
+    }
+}
+
+/// Ask the driver of an AF_XDP socket to process its fill ring
+pub fn xdp_wakeup_rx(fd: BorrowedFd<'_>) -> io::Result<()> {
+    let result = unsafe { libc::recvfrom(fd.as_raw_fd(), ptr::null_mut(), 0, libc::MSG_DONTWAIT, ptr::null_mut(), ptr::null_mut()) };
+    wakeup_result(result)
+}
+
+/// Ask the driver of an AF_XDP socket to send what is in its TX ring
+pub fn xdp_wakeup_tx(fd: BorrowedFd<'_>) -> io::Result<()> {
+    let result = unsafe { libc::sendto(fd.as_raw_fd(), ptr::null(), 0, libc::MSG_DONTWAIT, ptr::null(), 0) };
+    wakeup_result(result)
+}
+
+fn wakeup_result(result: isize) -> io::Result<()> {
+    if result >= 0 {
+        return Ok(());
+    }
+
+    // The driver is busy or the ring is empty, it will pick the work up anyway
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EAGAIN) | Some(libc::EBUSY) | Some(libc::ENOBUFS) | Some(libc::ENETDOWN) => Ok(()),
+        _ => Err(err),
+    }
+}
+
+fn if_nametoindex(name: &str) -> io::Result<libc::c_uint> {
+    let name = std::ffi::CString::new(name).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(io::Error::last_os_error()),
+        index => Ok(index),
+    }
+}
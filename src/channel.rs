@@ -0,0 +1,178 @@
+//! Feeding messages from other threads into the poll loop.
+//!
+//! [`channel`] pairs a std mpsc channel with a [`ping`](crate::ping), every
+//! send makes the [`Channel`] readable. Register the channel, or insert it
+//! with [`EventLoop::insert_channel`](crate::EventLoop::insert_channel), and
+//! take the messages with [`Channel::recv`] until it returns None.
+use std::io;
+use std::mem::ManuallyDrop;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::sync::mpsc::{self, SendError, TryRecvError};
+
+use crate::leak::LeakGuard;
+use crate::ping::{self, Ping, PingSource};
+
+/// What [`Channel::recv`] took out of the channel
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChannelEvent<T> {
+    Msg(T),
+    /// Every sender is gone, reported once after the last message
+    Closed,
+}
+
+/// Sending half, clone it for every thread
+#[derive(Debug)]
+pub struct Sender<T> {
+    /// Dropped by hand, before the ping announcing the disconnect
+    sender: ManuallyDrop<mpsc::Sender<T>>,
+    ping: Ping,
+}
+
+impl<T> Sender<T> {
+    /// Queue `msg` and wake the loop. Fails once the channel is dropped
+    pub fn send(&self, msg: T) -> Result<(), SendError<T>> {
+        self.sender.send(msg)?;
+        self.ping.ping();
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        Sender { sender: ManuallyDrop::new((*self.sender).clone()), ping: self.ping.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        // Disconnect first, a loop woken before that would see the channel
+        // empty, use up the ping and never learn it was closed
+        unsafe { ManuallyDrop::drop(&mut self.sender) };
+
+        // Let the channel see the disconnect if this was the last sender
+        self.ping.ping();
+    }
+}
+
+/// Receiving half, the source to register
+#[derive(Debug)]
+pub struct Channel<T> {
+    /// Declared first to drop while the fd is still open
+    pub(crate) leaks: LeakGuard,
+    receiver: mpsc::Receiver<T>,
+    source: PingSource,
+    closed: bool,
+}
+
+impl<T> Channel<T> {
+    /// Take the next message, None once the channel is empty. Call it until
+    /// None after the channel was reported, that resets its readiness
+    pub fn recv(&mut self) -> Option<ChannelEvent<T>> {
+        match self.receiver.try_recv() {
+            Ok(msg) => return Some(ChannelEvent::Msg(msg)),
+            Err(TryRecvError::Disconnected) => return self.close(),
+            Err(TryRecvError::Empty) => {}
+        }
+
+        // Reset before looking again, a message sent in between pings
+        // after it was queued and is either seen now or wakes the loop again
+        let _ = self.source.drain();
+
+        match self.receiver.try_recv() {
+            Ok(msg) => Some(ChannelEvent::Msg(msg)),
+            Err(TryRecvError::Disconnected) => self.close(),
+            Err(TryRecvError::Empty) => None,
+        }
+    }
+
+    fn close(&mut self) -> Option<ChannelEvent<T>> {
+        let _ = self.source.drain();
+
+        if self.closed {
+            return None;
+        }
+
+        self.closed = true;
+        Some(ChannelEvent::Closed)
+    }
+}
+
+impl<T> AsRawFd for Channel<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.source.as_raw_fd()
+    }
+}
+
+impl<T> AsFd for Channel<T> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.source.as_fd()
+    }
+}
+
+/// Create a connected [`Sender`] and [`Channel`]
+pub fn channel<T>() -> io::Result<(Sender<T>, Channel<T>)> {
+    let (sender, receiver) = mpsc::channel();
+    let (ping, source) = ping::ping()?;
+
+    Ok((Sender { sender: ManuallyDrop::new(sender), ping }, Channel { leaks: LeakGuard::default(), receiver, source, closed: false }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{Epoll, Interest, Mode, Token};
+
+    /// Wait for the channel and take everything it has, None on a timeout
+    fn recv_all(epoll: &mut Epoll, channel: &mut Channel<u32>) -> Option<Vec<ChannelEvent<u32>>> {
+        if epoll.poll(Some(Duration::from_secs(5))).unwrap().is_empty() {
+            return None;
+        }
+
+        Some(std::iter::from_fn(|| channel.recv()).collect())
+    }
+
+    #[test]
+    fn messages_then_closed_across_threads() {
+        let (sender, mut channel) = channel::<u32>().unwrap();
+        let mut epoll = Epoll::create().unwrap();
+        epoll.register(&channel, Token(0), Interest::READABLE, Mode::Level).unwrap();
+
+        let second = sender.clone();
+        thread::spawn(move || {
+            sender.send(1).unwrap();
+            second.send(2).unwrap();
+        })
+        .join()
+        .unwrap();
+
+        let mut events = Vec::new();
+        while !events.contains(&ChannelEvent::Closed) {
+            events.extend(recv_all(&mut epoll, &mut channel).expect("closed was never reported"));
+        }
+
+        assert_eq!(events, [ChannelEvent::Msg(1), ChannelEvent::Msg(2), ChannelEvent::Closed]);
+        assert_eq!(channel.recv(), None);
+    }
+
+    #[test]
+    fn last_sender_dropped_while_loop_waits() {
+        for _ in 0..100 {
+            let (sender, mut channel) = channel::<u32>().unwrap();
+            let mut epoll = Epoll::create().unwrap();
+            epoll.register(&channel, Token(0), Interest::READABLE, Mode::Level).unwrap();
+
+            let dropper = thread::spawn(move || drop(sender));
+
+            let mut closed = false;
+            while !closed {
+                let events = recv_all(&mut epoll, &mut channel).expect("closed was never reported");
+                closed = events.contains(&ChannelEvent::Closed);
+            }
+
+            dropper.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,75 @@
+//! Counting completions of work done outside the loop.
+//!
+//! [`completion`] returns a [`Completer`], cloned and sent to worker
+//! threads, and the [`Completion`] source to register. Every finished job
+//! adds to the counter of an eventfd, the loop takes the accumulated count
+//! and resets it in one read. The fd of the source can also be handed to
+//! the kernel, e.g. as the resfd of Linux AIO requests submitted with
+//! IOCB_FLAG_RESFD, which bumps it once per completed request.
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::sync::Arc;
+
+use crate::sys;
+
+/// Reports completed jobs to the [`Completion`] it was created with
+#[derive(Debug, Clone)]
+pub struct Completer {
+    fd: Arc<OwnedFd>,
+}
+
+impl Completer {
+    /// Report one completed job
+    pub fn complete(&self) -> io::Result<()> {
+        self.complete_many(1)
+    }
+
+    /// Report `count` completed jobs. Fails with WouldBlock if the counter
+    /// would overflow, which takes 2^64 - 1 completions nobody took
+    pub fn complete_many(&self, count: u64) -> io::Result<()> {
+        if count == 0 {
+            return Ok(());
+        }
+
+        sys::write_counter(self.fd.as_raw_fd(), count)
+    }
+}
+
+/// The registrable end of a [`completion`] pair, readable while completions
+/// are waiting to be taken
+#[derive(Debug)]
+pub struct Completion {
+    fd: Arc<OwnedFd>,
+}
+
+impl Completion {
+    /// Take the number of completions since the last call and reset the
+    /// counter, 0 if there were none
+    pub fn take(&mut self) -> io::Result<u64> {
+        sys::read_counter(self.fd.as_raw_fd())
+    }
+
+    /// Another handle reporting to this source
+    pub fn completer(&self) -> Completer {
+        Completer { fd: self.fd.clone() }
+    }
+}
+
+impl AsRawFd for Completion {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for Completion {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+/// Create a connected [`Completer`] and [`Completion`]
+pub fn completion() -> io::Result<(Completer, Completion)> {
+    let fd = Arc::new(sys::eventfd(0)?);
+
+    Ok((Completer { fd: fd.clone() }, Completion { fd }))
+}
@@ -0,0 +1,203 @@
+//! Building an epoll instance and its sources from a declarative description.
+//!
+//! With the `serde` feature every type here can be deserialized, so the
+//! sockets and timers of a daemon can live in its TOML or YAML config:
+//!
+//! ```toml
+//! [[sources]]
+//! name = "http"
+//! token = 0
+//! kind = "tcp"
+//! addr = "0.0.0.0:8080"
+//!
+//! [[sources]]
+//! name = "stats"
+//! token = 1
+//! kind = "timer"
+//! interval_ms = 1000
+//!
+//! [[sources]]
+//! name = "shutdown"
+//! token = 2
+//! kind = "signal"
+//! signals = ["SIGTERM", "SIGINT"]
+//! ```
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::signal::{Signal, Signals};
+use crate::timer::Timer;
+use crate::{prefork, Epoll, Interest, Mode, Token};
+
+/// Every source of a loop
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct LoopConfig {
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub sources: Vec<SourceConfig>,
+}
+
+/// One source, registered with its name as the label
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SourceConfig {
+    pub name: String,
+    pub token: Token,
+    /// Defaults to Interest::READABLE, written like "readable|priority"
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub interest: Option<Interest>,
+    /// Defaults to Mode::Level
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub mode: Option<Mode>,
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    pub kind: SourceKind,
+}
+
+/// What a source is made of
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "kind", rename_all = "snake_case"))]
+pub enum SourceKind {
+    /// Non-blocking TCP listener
+    Tcp { addr: SocketAddr },
+    /// Non-blocking Unix socket listener
+    Unix { path: PathBuf },
+    /// Repeating [`Timer`] firing every `interval_ms`, the first time after
+    /// `initial_ms` if given
+    Timer {
+        interval_ms: u64,
+        #[cfg_attr(feature = "serde", serde(default))]
+        initial_ms: Option<u64>,
+    },
+    /// [`Signals`] for the named signals, e.g. "SIGTERM". The signals are
+    /// blocked in the calling thread while the source is alive, so they are
+    /// only delivered to the fd
+    Signal { signals: Vec<String> },
+}
+
+/// A source created from its [`SourceKind`]
+#[derive(Debug)]
+pub enum Source {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+    Timer(Timer),
+    Signal(Box<Signals>),
+}
+
+impl AsRawFd for Source {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Source::Tcp(listener) => listener.as_raw_fd(),
+            Source::Unix(listener) => listener.as_raw_fd(),
+            Source::Timer(timer) => timer.as_raw_fd(),
+            Source::Signal(signals) => signals.as_raw_fd(),
+        }
+    }
+}
+
+impl AsFd for Source {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            Source::Tcp(listener) => listener.as_fd(),
+            Source::Unix(listener) => listener.as_fd(),
+            Source::Timer(timer) => timer.as_fd(),
+            Source::Signal(signals) => signals.as_fd(),
+        }
+    }
+}
+
+/// The epoll instance built from a [`LoopConfig`] and the sources registered in it
+#[derive(Debug)]
+pub struct ConfiguredLoop {
+    pub epoll: Epoll,
+    sources: Vec<(String, Token, Source)>,
+}
+
+impl ConfiguredLoop {
+    /// The source with the given name
+    pub fn source(&self, name: &str) -> Option<&Source> {
+        self.sources.iter().find(|(other, _, _)| other == name).map(|(_, _, source)| source)
+    }
+
+    /// Mutable access, e.g. to read a signal from a signalfd
+    pub fn source_mut(&mut self, name: &str) -> Option<&mut Source> {
+        self.sources.iter_mut().find(|(other, _, _)| other == name).map(|(_, _, source)| source)
+    }
+
+    /// The source registered with the given token
+    pub fn by_token(&self, token: Token) -> Option<(&str, &Source)> {
+        self.sources.iter().find(|(_, other, _)| *other == token).map(|(name, _, source)| (name.as_str(), source))
+    }
+
+    /// Mutable access to the source registered with the given token
+    pub fn by_token_mut(&mut self, token: Token) -> Option<(&str, &mut Source)> {
+        self.sources.iter_mut().find(|(_, other, _)| *other == token).map(|(name, _, source)| (name.as_str(), source))
+    }
+
+    /// Iterate over the names, tokens and sources in config order
+    pub fn sources(&self) -> impl Iterator<Item = (&str, Token, &Source)> {
+        self.sources.iter().map(|(name, token, source)| (name.as_str(), *token, source))
+    }
+}
+
+impl LoopConfig {
+    /// Create every source and register it in a new epoll instance.
+    /// Stops at the first source that fails, the error names it
+    pub fn build(&self) -> io::Result<ConfiguredLoop> {
+        let mut configured = ConfiguredLoop { epoll: Epoll::create()?, sources: Vec::new() };
+
+        for config in &self.sources {
+            let source = config.kind.create().map_err(|err| {
+                io::Error::new(err.kind(), format!("source {}: {}", config.name, err))
+            })?;
+
+            configured.epoll.register_named(
+                &source,
+                config.token,
+                config.name.clone(),
+                config.interest.unwrap_or(Interest::READABLE),
+                config.mode.unwrap_or(Mode::Level)
+            )?;
+
+            configured.sources.push((config.name.clone(), config.token, source));
+        }
+
+        Ok(configured)
+    }
+}
+
+impl SourceKind {
+    fn create(&self) -> io::Result<Source> {
+        match self {
+            SourceKind::Tcp { addr } => Ok(Source::Tcp(prefork::bind_tcp(*addr)?)),
+            SourceKind::Unix { path } => Ok(Source::Unix(prefork::bind_unix(path)?)),
+            SourceKind::Timer { interval_ms, initial_ms } => {
+                if *interval_ms == 0 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "timer interval must not be zero"));
+                }
+
+                let interval = Duration::from_millis(*interval_ms);
+                let initial = initial_ms.map_or(interval, Duration::from_millis);
+
+                let mut timer = Timer::new()?;
+                timer.set(initial, Some(interval))?;
+                Ok(Source::Timer(timer))
+            }
+            SourceKind::Signal { signals } => {
+                let signals = signals.iter()
+                    .map(|name| Signal::from_str(name))
+                    .collect::<io::Result<Vec<Signal>>>()?;
+
+                Ok(Source::Signal(Box::new(Signals::new(&signals)?)))
+            }
+        }
+    }
+}
@@ -0,0 +1,29 @@
+use std::os::unix::io::RawFd;
+
+use crate::sys;
+
+/// Device and inode of the file behind an fd
+pub(crate) type FileId = (libc::dev_t, libc::ino_t);
+
+const KCMP_FILE: libc::c_long = 0;
+
+pub(crate) fn file_id(fd: RawFd) -> Option<FileId> {
+    sys::fstat(fd).ok().map(|stat| (stat.st_dev, stat.st_ino))
+}
+
+/// Returns true if both fds share one open file description, which is
+/// the case for fds created by dup, fork or SCM_RIGHTS
+pub(crate) fn same_description(a: RawFd, b: RawFd) -> bool {
+    let pid = std::process::id() as libc::c_long;
+    let ret = unsafe {
+        libc::syscall(libc::SYS_kcmp, pid, pid, KCMP_FILE, a as libc::c_long, b as libc::c_long)
+    };
+
+    match ret {
+        0 => true,
+        1..=3 => false,
+        // kcmp is missing or blocked, callers already know the dev/ino match.
+        // Separate opens of a regular file are told apart by their offset
+        _ => sys::lseek(a, 0, libc::SEEK_CUR).ok() == sys::lseek(b, 0, libc::SEEK_CUR).ok(),
+    }
+}
@@ -0,0 +1,158 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::os::unix::io::RawFd;
+
+use crate::{Interest, Mode, Token};
+
+/// The epoll_ctl operation that was attempted
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CtlOp {
+    Add,
+    Modify,
+    Delete,
+}
+
+/// What went wrong in a ctl operation, decoded from the errno
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CtlErrorKind {
+    /// EEXIST, the fd is already registered in this instance
+    AlreadyRegistered,
+    /// ENOENT, the fd is not registered in this instance
+    NotRegistered,
+    /// EBADF, the fd (or the epoll fd) is not open
+    InvalidFd,
+    /// EPERM, the fd does not support polling, e.g. a regular file
+    Unsupported,
+    /// ELOOP, registering an epoll fd would create a cycle or nest too deep
+    NestedEpollCycle,
+    /// ENOSPC, the user hit /proc/sys/fs/epoll/max_user_watches
+    WatchLimitReached,
+    /// The fd was closed without being unregistered and now refers to another
+    /// file, the stale registration was dropped. See [`CtlError::stale_token`]
+    FdReused,
+    /// Anything else, inspect the source error
+    Other,
+}
+
+impl CtlErrorKind {
+    fn from_error(err: &io::Error) -> CtlErrorKind {
+        match err.raw_os_error() {
+            Some(libc::EEXIST) => CtlErrorKind::AlreadyRegistered,
+            Some(libc::ENOENT) => CtlErrorKind::NotRegistered,
+            Some(libc::EBADF) => CtlErrorKind::InvalidFd,
+            Some(libc::EPERM) => CtlErrorKind::Unsupported,
+            Some(libc::ELOOP) => CtlErrorKind::NestedEpollCycle,
+            Some(libc::ENOSPC) => CtlErrorKind::WatchLimitReached,
+            Some(_) => CtlErrorKind::Other,
+            // Rejected before reaching the kernel
+            None => match err.kind() {
+                io::ErrorKind::AlreadyExists => CtlErrorKind::AlreadyRegistered,
+                io::ErrorKind::NotFound => CtlErrorKind::NotRegistered,
+                _ => CtlErrorKind::Other,
+            },
+        }
+    }
+}
+
+/// Context of a failed registration, reregistration or unregistration.
+/// It travels inside the returned io::Error, which keeps the kind of the
+/// underlying error, use [`CtlError::of`] to get it back
+#[derive(Debug)]
+pub struct CtlError {
+    pub op: CtlOp,
+    pub fd: RawFd,
+    pub token: Option<Token>,
+    pub interest: Option<Interest>,
+    pub mode: Option<Mode>,
+    pub label: Option<String>,
+    /// Token of the stale registration dropped for a reused fd
+    pub stale_token: Option<Token>,
+    kind: CtlErrorKind,
+    source: io::Error,
+}
+
+impl CtlError {
+    pub(crate) fn new(op: CtlOp, fd: RawFd, source: io::Error) -> CtlError {
+        CtlError {
+            op,
+            fd,
+            token: None,
+            interest: None,
+            mode: None,
+            label: None,
+            stale_token: None,
+            kind: CtlErrorKind::from_error(&source),
+            source,
+        }
+    }
+
+    pub(crate) fn with_kind(mut self, kind: CtlErrorKind) -> CtlError {
+        self.kind = kind;
+        self
+    }
+
+    /// What went wrong, match on this instead of comparing errnos
+    pub fn kind(&self) -> CtlErrorKind {
+        self.kind
+    }
+
+    /// Get the context out of an error returned by a ctl operation
+    pub fn of(err: &io::Error) -> Option<&CtlError> {
+        err.get_ref().and_then(|inner| inner.downcast_ref::<CtlError>())
+    }
+
+    /// The errno reported by the kernel, None if the operation was
+    /// rejected before reaching it, e.g. in strict mode
+    pub fn errno(&self) -> Option<i32> {
+        self.source.raw_os_error()
+    }
+
+    /// The errno of an error returned by a ctl operation. Its
+    /// io::Error::raw_os_error is None, the io::Error carries the context
+    pub fn raw_os_error(err: &io::Error) -> Option<i32> {
+        err.raw_os_error().or_else(|| CtlError::of(err).and_then(CtlError::errno))
+    }
+}
+
+impl fmt::Display for CtlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match self.op {
+            CtlOp::Add => "register",
+            CtlOp::Modify => "reregister",
+            CtlOp::Delete => "unregister",
+        };
+
+        write!(f, "{} fd {}", op, self.fd)?;
+
+        if let Some(label) = &self.label {
+            write!(f, " ({})", label)?;
+        }
+        if let Some(token) = self.token {
+            write!(f, " token {}", token.0)?;
+        }
+        if let Some(interest) = self.interest {
+            write!(f, " interest {:?}", interest)?;
+        }
+        if let Some(mode) = self.mode {
+            write!(f, " mode {:?}", mode)?;
+        }
+
+        write!(f, ": {}", self.source)
+    }
+}
+
+impl Error for CtlError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Keeps the kind of the source, the errno is left to
+/// [`CtlError::raw_os_error`]
+impl From<CtlError> for io::Error {
+    fn from(err: CtlError) -> io::Error {
+        io::Error::new(err.source.kind(), err)
+    }
+}
@@ -0,0 +1,280 @@
+//! Things that can be registered in an [`Epoll`] instance.
+//!
+//! [`Epoll::register`], [`Epoll::reregister`] and [`Epoll::unregister`] take
+//! any [`Source`] and leave the attaching to it, so a type wrapping several
+//! fds or needing extra setup can hide that from its users. Sources that are
+//! a single fd forward to the impl for BorrowedFd, any other [`AsFd`] type
+//! can be registered by its `as_fd()`:
+//!
+//! ```ignore
+//! impl Source for Connection {
+//!     fn register(&self, epoll: &mut Epoll, token: Token, interest: Interest, mode: Mode) -> io::Result<()> {
+//!         self.socket.as_fd().register(epoll, token, interest, mode)
+//!     }
+//!     ...
+//! }
+//! ```
+use std::fs::File;
+use std::io;
+use std::net::{TcpListener, TcpStream, UdpSocket};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd};
+use std::os::unix::net::{UnixDatagram, UnixListener, UnixStream};
+
+use crate::{Epoll, Interest, Mode, Token};
+
+/// A source of events that knows how to attach itself to an epoll instance.
+/// Keep it alive for as long as it is registered, epoll drops an fd once it
+/// is closed but the registration in Epoll stays around until unregistered.
+/// Timers, signalfds and channels clean up after themselves and are reported
+/// to [`Epoll::set_leak_hook`].
+/// Epoll itself is not a Source, its register would be shadowed by this one
+/// wherever the trait is in scope, nest an instance in a
+/// [`SubPoller`](crate::nested::SubPoller) instead
+pub trait Source {
+    fn register(
+        &self,
+        epoll: &mut Epoll,
+        token: Token,
+        interest: Interest,
+        mode: Mode
+    ) -> io::Result<()>;
+
+    fn reregister(
+        &self,
+        epoll: &mut Epoll,
+        token: Token,
+        interest: Interest,
+        mode: Mode
+    ) -> io::Result<()>;
+
+    fn unregister(&self, epoll: &mut Epoll) -> io::Result<()>;
+}
+
+/// A borrowed file descriptor, every other source ends up here.
+/// The borrow ends with the call, keeping the fd open while it is
+/// registered is still up to the owner
+impl Source for BorrowedFd<'_> {
+    fn register(
+        &self,
+        epoll: &mut Epoll,
+        token: Token,
+        interest: Interest,
+        mode: Mode
+    ) -> io::Result<()> {
+        epoll.register_fd(self.as_raw_fd(), token, interest, mode)
+    }
+
+    fn reregister(
+        &self,
+        epoll: &mut Epoll,
+        token: Token,
+        interest: Interest,
+        mode: Mode
+    ) -> io::Result<()> {
+        epoll.reregister_fd(self.as_raw_fd(), token, interest, mode)
+    }
+
+    fn unregister(&self, epoll: &mut Epoll) -> io::Result<()> {
+        epoll.unregister_fd(self.as_raw_fd())
+    }
+}
+
+macro_rules! fd_source {
+    ($fd:path: $($ty:ty),* $(,)?) => {
+        $(
+            impl Source for $ty {
+                fn register(
+                    &self,
+                    epoll: &mut Epoll,
+                    token: Token,
+                    interest: Interest,
+                    mode: Mode
+                ) -> io::Result<()> {
+                    $fd(self).register(epoll, token, interest, mode)
+                }
+
+                fn reregister(
+                    &self,
+                    epoll: &mut Epoll,
+                    token: Token,
+                    interest: Interest,
+                    mode: Mode
+                ) -> io::Result<()> {
+                    $fd(self).reregister(epoll, token, interest, mode)
+                }
+
+                fn unregister(&self, epoll: &mut Epoll) -> io::Result<()> {
+                    $fd(self).unregister(epoll)
+                }
+            }
+        )*
+    };
+}
+
+fd_source!(AsFd::as_fd:
+    OwnedFd,
+    TcpListener,
+    TcpStream,
+    UdpSocket,
+    UnixListener,
+    UnixStream,
+    UnixDatagram,
+    File,
+    crate::capture::PacketSocket,
+    crate::capture::PacketRing,
+    crate::completion::Completion,
+    crate::config::Source,
+    crate::frame::FrameTimer,
+    crate::gpio::GpioLine,
+    crate::gpio::SysfsGpio,
+    crate::inotify::Inotify,
+    crate::introspect::Introspection,
+    crate::nested::SubPoller,
+    crate::netlink::Netlink,
+    crate::ping::PingSource,
+    crate::prefork::ShutdownSignal,
+    crate::pressure::Pressure,
+    crate::process::ChildOutput,
+    crate::process::ChildWatcher,
+    crate::process::PidFd,
+    crate::serial::SerialPort,
+    crate::sources::TcpListenerSource,
+    crate::sources::TcpStreamSource,
+    crate::sources::UdpSocketSource,
+    crate::sources::UnixListenerSource,
+    crate::systemd::Activated,
+    crate::systemd::Watchdog,
+    crate::timer::TimerQueue,
+    crate::waker::Waker,
+);
+
+/// Sources owning their fd, they remember where they are registered to
+/// report a drop without unregistering, see [`Epoll::set_leak_hook`]
+macro_rules! owned_source {
+    ($(impl$(<$generic:ident>)? for $ty:ty),* $(,)?) => {
+        $(
+            impl$(<$generic>)? Source for $ty {
+                fn register(
+                    &self,
+                    epoll: &mut Epoll,
+                    token: Token,
+                    interest: Interest,
+                    mode: Mode
+                ) -> io::Result<()> {
+                    self.as_fd().register(epoll, token, interest, mode)?;
+                    self.leaks.register(epoll, self.as_raw_fd(), token);
+                    Ok(())
+                }
+
+                fn reregister(
+                    &self,
+                    epoll: &mut Epoll,
+                    token: Token,
+                    interest: Interest,
+                    mode: Mode
+                ) -> io::Result<()> {
+                    self.as_fd().reregister(epoll, token, interest, mode)?;
+                    self.leaks.register(epoll, self.as_raw_fd(), token);
+                    Ok(())
+                }
+
+                fn unregister(&self, epoll: &mut Epoll) -> io::Result<()> {
+                    self.leaks.unregister(epoll);
+                    self.as_fd().unregister(epoll)
+                }
+            }
+        )*
+    };
+}
+
+owned_source!(
+    impl for crate::signal::Signals,
+    impl for crate::timer::Timer,
+    impl<T> for crate::channel::Channel<T>,
+);
+
+impl<C: AsFd> Source for crate::pool::PoolWatcher<C> {
+    fn register(
+        &self,
+        epoll: &mut Epoll,
+        token: Token,
+        interest: Interest,
+        mode: Mode
+    ) -> io::Result<()> {
+        self.as_fd().register(epoll, token, interest, mode)
+    }
+
+    fn reregister(
+        &self,
+        epoll: &mut Epoll,
+        token: Token,
+        interest: Interest,
+        mode: Mode
+    ) -> io::Result<()> {
+        self.as_fd().reregister(epoll, token, interest, mode)
+    }
+
+    fn unregister(&self, epoll: &mut Epoll) -> io::Result<()> {
+        self.as_fd().unregister(epoll)
+    }
+}
+
+#[cfg(feature = "mio-compat")]
+impl Source for crate::mio_compat::MioBridge {
+    fn register(
+        &self,
+        epoll: &mut Epoll,
+        token: Token,
+        interest: Interest,
+        mode: Mode
+    ) -> io::Result<()> {
+        self.as_fd().register(epoll, token, interest, mode)
+    }
+
+    fn reregister(
+        &self,
+        epoll: &mut Epoll,
+        token: Token,
+        interest: Interest,
+        mode: Mode
+    ) -> io::Result<()> {
+        self.as_fd().reregister(epoll, token, interest, mode)
+    }
+
+    fn unregister(&self, epoll: &mut Epoll) -> io::Result<()> {
+        self.as_fd().unregister(epoll)
+    }
+}
+
+#[cfg(feature = "wayland-client")]
+impl<D> Source for crate::wayland::WaylandSource<D> {
+    fn register(
+        &self,
+        epoll: &mut Epoll,
+        token: Token,
+        interest: Interest,
+        mode: Mode
+    ) -> io::Result<()> {
+        self.as_fd().register(epoll, token, interest, mode)
+    }
+
+    fn reregister(
+        &self,
+        epoll: &mut Epoll,
+        token: Token,
+        interest: Interest,
+        mode: Mode
+    ) -> io::Result<()> {
+        self.as_fd().reregister(epoll, token, interest, mode)
+    }
+
+    fn unregister(&self, epoll: &mut Epoll) -> io::Result<()> {
+        self.as_fd().unregister(epoll)
+    }
+}
+
+/// The fd of a type that only implements AsRawFd, borrowed for as long as
+/// the owner is
+pub(crate) fn borrow_raw<T: AsRawFd + ?Sized>(owner: &T) -> BorrowedFd<'_> {
+    unsafe { BorrowedFd::borrow_raw(owner.as_raw_fd()) }
+}
@@ -0,0 +1,1158 @@
+//! A callback based reactor on top of [`Epoll`].
+//!
+//! Every source inserted into an [`EventLoop`] comes with a closure, the loop
+//! owns both and hands out the tokens itself. [`EventLoop::dispatch`] polls
+//! once and calls the closure of every source that became ready with its
+//! readiness, the source and the state shared by all closures.
+//!
+//! One-shot sources are disarmed by every event. With
+//! [`EventLoop::set_auto_rearm`] the loop arms them again after their
+//! callback returns Continue, a callback returning Disable leaves them
+//! disarmed until [`EventLoop::rearm`].
+//!
+//! Sources have a priority, [`EventLoop::set_priority`]. The callbacks of
+//! the sources ready at the same time run highest priority first, sources of
+//! the same priority in round robin order.
+//!
+//! A budget ([`EventLoop::set_max_events`], [`EventLoop::set_max_time`])
+//! caps how many callbacks one dispatch runs. The events left over are
+//! dispatched first by the next call, which does not wait while any are
+//! left, so a burst on some sources can not keep the others waiting.
+//!
+//! Idle callbacks ([`EventLoop::insert_idle`]) run at the end of every
+//! dispatch that leaves no events pending, before the next one blocks. That
+//! is the place for work deferred while handling events, like flushing
+//! buffered output once all input was read.
+//!
+//! [`EventLoop::run`] dispatches until a [`LoopSignal`] asks it to stop.
+//! Signals are cloned and sent wherever the loop is stopped or woken from:
+//! other threads, callbacks, or a signal handler that finds one in a static.
+//!
+//! Daemons use [`EventLoop::run_until_shutdown`] instead. With
+//! [`EventLoop::handle_termination`] SIGINT and SIGTERM stop it through a
+//! signalfd, then the hook of [`EventLoop::set_shutdown_hook`] runs and every
+//! source is removed before it returns:
+//!
+//! ```ignore
+//! event_loop.handle_termination()?;
+//! event_loop.set_shutdown_hook(|signal, server: &mut Server| server.flush());
+//! event_loop.run_until_shutdown(&mut server)?;
+//! ```
+//!
+//! A foreign loop like the GLib main loop can drive an event loop: it polls
+//! the fd of the loop ([`AsFd`]) with [`EventLoop::next_timeout`] as its
+//! timeout, calls [`EventLoop::collect_ready`] once the fd is readable and
+//! [`EventLoop::dispatch_pending`] when it dispatches.
+//!
+//! ```ignore
+//! let mut event_loop = EventLoop::new()?;
+//! event_loop.insert_source(listener, Interest::READABLE, Mode::Level, |_, listener, clients: &mut Vec<_>| {
+//!     if let Ok((stream, _)) = listener.accept() {
+//!         clients.push(stream);
+//!     }
+//!     PostAction::Continue
+//! })?;
+//!
+//! let mut clients = Vec::new();
+//! loop {
+//!     event_loop.dispatch(None, &mut clients)?;
+//! }
+//! ```
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::process::{Child, ExitStatus};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::channel::{Channel, ChannelEvent};
+use crate::completion::Completion;
+use crate::netlink::{Message, Netlink};
+use crate::ping::PingSource;
+use crate::process::{ChildOutput, ChildWatcher, Pipe};
+use crate::signal::{Signal, Signals};
+use crate::stats::HandlerStats;
+use crate::timer::Timer;
+use crate::{Epoll, Events, Interest, Mode, Readiness, Slab, Source, Timeout, Token, Waker};
+
+/// Token of the waker behind the loop signals, never handed out by the slab
+const SIGNAL: Token = Token(usize::MAX);
+
+/// Up to this many sources are kept in a flat list, see [`Sources`]
+const SMALL: usize = 16;
+
+/// What the loop does with a source after its callback returned
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PostAction {
+    /// Keep the source registered
+    Continue,
+    /// Keep the source registered without arming an auto-rearmed one-shot
+    /// source again
+    Disable,
+    /// Unregister and drop the source and its callback
+    Remove,
+}
+
+/// A source together with its callback, type erased over both
+trait Dispatch<S> {
+    fn dispatch(&mut self, readiness: Readiness, state: &mut S) -> PostAction;
+
+    fn reregister(&self, epoll: &mut Epoll, token: Token, interest: Interest, mode: Mode) -> io::Result<()>;
+
+    fn unregister(&self, epoll: &mut Epoll) -> io::Result<()>;
+}
+
+struct Dispatcher<T, F> {
+    source: T,
+    callback: F,
+}
+
+impl<S, T, F> Dispatch<S> for Dispatcher<T, F>
+where
+    T: Source,
+    F: FnMut(Readiness, &mut T, &mut S) -> PostAction,
+{
+    fn dispatch(&mut self, readiness: Readiness, state: &mut S) -> PostAction {
+        (self.callback)(readiness, &mut self.source, state)
+    }
+
+    fn reregister(&self, epoll: &mut Epoll, token: Token, interest: Interest, mode: Mode) -> io::Result<()> {
+        self.source.reregister(epoll, token, interest, mode)
+    }
+
+    fn unregister(&self, epoll: &mut Epoll) -> io::Result<()> {
+        self.source.unregister(epoll)
+    }
+}
+
+/// Stops or wakes an [`EventLoop`] from anywhere, see
+/// [`EventLoop::signal`]. Both set an atomic flag at most and write to an
+/// eventfd through a [`SignalSafeWaker`](crate::SignalSafeWaker), so they
+/// are safe to call from a signal handler. Cloning and dropping the handle
+/// are not, store it where the handler finds it before installing the
+/// handler, e.g. in a OnceLock
+#[derive(Debug, Clone)]
+pub struct LoopSignal {
+    stop: Arc<AtomicBool>,
+    /// Keeps the eventfd of the SignalSafeWaker open
+    waker: Waker,
+}
+
+impl LoopSignal {
+    /// Make [`EventLoop::run`] return once the running dispatch is done
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+        self.wakeup();
+    }
+
+    /// Make a blocked dispatch return, its idle callbacks run
+    pub fn wakeup(&self) {
+        self.waker.signal_safe().wake();
+    }
+}
+
+type Idle<'l, S> = Box<dyn FnMut(&mut S) -> PostAction + 'l>;
+
+type ShutdownHook<'l, S> = Box<dyn FnMut(Option<Signal>, &mut S) + 'l>;
+
+type Classify<'l> = Box<dyn Fn(Token) -> &'static str + 'l>;
+
+/// A dispatcher and what it was registered with
+struct Entry<'l, S> {
+    dispatcher: Box<dyn Dispatch<S> + 'l>,
+    interest: Interest,
+    mode: Mode,
+    auto_rearm: bool,
+    priority: i32,
+}
+
+/// The sources of a loop by token. Small control loops keep theirs in a
+/// list searched linearly, which stays in a cache line or two, and move
+/// them to the slab once there are more than [`SMALL`]. They move back below
+/// half of that. The slab hands out the tokens either way, holding None for
+/// the sources in the list, so tokens stay valid when the sources move
+struct Sources<T> {
+    slab: Slab<Option<T>>,
+    small: Vec<(Token, T)>,
+    /// Whether the sources are in the list
+    is_small: bool,
+}
+
+impl<T> Sources<T> {
+    fn new() -> Sources<T> {
+        Sources { slab: Slab::new(), small: Vec::new(), is_small: true }
+    }
+
+    fn len(&self) -> usize {
+        self.slab.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.slab.is_empty()
+    }
+
+    fn vacant_token(&self) -> Token {
+        self.slab.vacant_token()
+    }
+
+    fn insert(&mut self, value: T) -> Token {
+        if !self.is_small {
+            return self.slab.insert(Some(value));
+        }
+
+        let token = self.slab.insert(None);
+        self.small.push((token, value));
+
+        if self.small.len() > SMALL {
+            for (token, value) in self.small.drain(..) {
+                self.slab[token] = Some(value);
+            }
+            self.is_small = false;
+        }
+
+        token
+    }
+
+    fn remove(&mut self, token: Token) -> Option<T> {
+        let value = if self.is_small {
+            let index = self.small.iter().position(|(small, _)| *small == token)?;
+            self.slab.remove(token);
+            Some(self.small.swap_remove(index).1)
+        } else {
+            self.slab.remove(token)?
+        };
+
+        if !self.is_small && self.slab.len() < SMALL / 2 {
+            self.small.extend(self.slab.iter_mut().filter_map(|(token, value)| Some((token, value.take()?))));
+            self.is_small = true;
+        }
+
+        value
+    }
+
+    fn contains(&self, token: Token) -> bool {
+        self.get(token).is_some()
+    }
+
+    fn get(&self, token: Token) -> Option<&T> {
+        if self.is_small {
+            self.small.iter().find(|(small, _)| *small == token).map(|(_, value)| value)
+        } else {
+            self.slab.get(token)?.as_ref()
+        }
+    }
+
+    fn get_mut(&mut self, token: Token) -> Option<&mut T> {
+        if self.is_small {
+            self.small.iter_mut().find(|(small, _)| *small == token).map(|(_, value)| value)
+        } else {
+            self.slab.get_mut(token)?.as_mut()
+        }
+    }
+
+    fn tokens(&self) -> Vec<Token> {
+        if self.is_small {
+            self.small.iter().map(|(token, _)| *token).collect()
+        } else {
+            self.slab.iter().map(|(token, _)| token).collect()
+        }
+    }
+}
+
+/// Epoll instance routing every event to the callback of its source
+pub struct EventLoop<'l, S> {
+    epoll: Epoll,
+    sources: Sources<Entry<'l, S>>,
+    events: Events,
+    /// Events not dispatched yet because the budget ran out, in the order
+    /// they are dispatched
+    pending: VecDeque<(Token, Readiness)>,
+    max_events: Option<usize>,
+    max_time: Option<Duration>,
+    /// Token of the last source dispatched, where the round robin continues
+    last: Token,
+    idle: Vec<Idle<'l, S>>,
+    signal: Option<LoopSignal>,
+    /// Token of the signalfd inserted by handle_termination
+    termination: Option<Token>,
+    /// The first termination signal received, set by its callback
+    terminated: Rc<Cell<Option<Signal>>>,
+    shutdown_hook: Option<ShutdownHook<'l, S>>,
+    /// Callback durations and the class of every token
+    handler_stats: Option<(Classify<'l>, HandlerStats)>,
+}
+
+impl<'l, S> EventLoop<'l, S> {
+    pub fn new() -> io::Result<EventLoop<'l, S>> {
+        Ok(EventLoop {
+            epoll: Epoll::create()?,
+            sources: Sources::new(),
+            events: Events::new(),
+            pending: VecDeque::new(),
+            max_events: None,
+            max_time: None,
+            last: Token(0),
+            idle: Vec::new(),
+            signal: None,
+            termination: None,
+            terminated: Rc::new(Cell::new(None)),
+            shutdown_hook: None,
+            handler_stats: None,
+        })
+    }
+
+    /// Take ownership of `source` and call `callback` whenever it is ready.
+    /// Returns the token the source was registered with
+    pub fn insert_source<T, F>(
+        &mut self,
+        source: T,
+        interest: Interest,
+        mode: Mode,
+        callback: F
+    ) -> io::Result<Token>
+    where
+        T: Source + 'l,
+        F: FnMut(Readiness, &mut T, &mut S) -> PostAction + 'l,
+    {
+        let dispatcher = Dispatcher { source, callback };
+        let token = self.sources.vacant_token();
+
+        self.epoll.register(&dispatcher.source, token, interest, mode)?;
+
+        let entry = Entry { dispatcher: Box::new(dispatcher), interest, mode, auto_rearm: false, priority: 0 };
+        Ok(self.sources.insert(entry))
+    }
+
+    /// Arm the one-shot source registered with `token` again whenever its
+    /// callback returns Continue
+    pub fn set_auto_rearm(&mut self, token: Token, enabled: bool) -> io::Result<()> {
+        let entry = match self.sources.get_mut(token) {
+            Some(entry) => entry,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "no source with this token")),
+        };
+
+        if !matches!(entry.mode, Mode::OneShot) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "only one-shot sources are rearmed"));
+        }
+
+        entry.auto_rearm = enabled;
+        Ok(())
+    }
+
+    /// Run the callback of the source registered with `token` before those
+    /// of lower priority ready at the same time. Sources start at 0
+    pub fn set_priority(&mut self, token: Token, priority: i32) -> io::Result<()> {
+        match self.sources.get_mut(token) {
+            Some(entry) => {
+                entry.priority = priority;
+                Ok(())
+            }
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "no source with this token")),
+        }
+    }
+
+    /// Arm the source registered with `token` again with the interest and
+    /// mode it was inserted with
+    pub fn rearm(&mut self, token: Token) -> io::Result<()> {
+        match self.sources.get(token) {
+            Some(entry) => entry.dispatcher.reregister(&mut self.epoll, token, entry.interest, entry.mode),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "no source with this token")),
+        }
+    }
+
+    /// Take ownership of `timer` and call `callback` with the number of
+    /// expirations whenever it fires. The timer is drained before the
+    /// callback runs, which may set or cancel it
+    pub fn insert_timer<F>(&mut self, timer: Timer, mut callback: F) -> io::Result<Token>
+    where
+        F: FnMut(u64, &mut Timer, &mut S) -> PostAction + 'l,
+    {
+        self.insert_source(timer, Interest::READABLE, Mode::Level, move |_, timer, state| {
+            // Reading a timerfd can only fail for a spurious wakeup
+            match timer.expirations() {
+                Ok(0) | Err(_) => PostAction::Continue,
+                Ok(expirations) => callback(expirations, timer, state),
+            }
+        })
+    }
+
+    /// Take ownership of `source` and call `callback` once per batch of
+    /// pings. The source is drained before the callback runs
+    pub fn insert_ping<F>(&mut self, source: PingSource, mut callback: F) -> io::Result<Token>
+    where
+        F: FnMut(&mut S) -> PostAction + 'l,
+    {
+        self.insert_source(source, Interest::READABLE, Mode::Level, move |_, source, state| {
+            match source.drain() {
+                Ok(true) => callback(state),
+                Ok(false) | Err(_) => PostAction::Continue,
+            }
+        })
+    }
+
+    /// Take ownership of `completion` and call `callback` with the number of
+    /// completions reported since the last call. The counter is reset before
+    /// the callback runs
+    pub fn insert_completion<F>(&mut self, completion: Completion, mut callback: F) -> io::Result<Token>
+    where
+        F: FnMut(u64, &mut S) -> PostAction + 'l,
+    {
+        self.insert_source(completion, Interest::READABLE, Mode::Level, move |_, completion, state| {
+            match completion.take() {
+                Ok(0) | Err(_) => PostAction::Continue,
+                Ok(count) => callback(count, state),
+            }
+        })
+    }
+
+    /// Take ownership of `channel` and call `callback` with every message,
+    /// and with Closed once the last sender is gone. Returning Remove stops
+    /// at that message, the rest of the queue goes with the channel
+    pub fn insert_channel<T, F>(&mut self, channel: Channel<T>, mut callback: F) -> io::Result<Token>
+    where
+        T: 'l,
+        F: FnMut(ChannelEvent<T>, &mut S) -> PostAction + 'l,
+    {
+        self.insert_source(channel, Interest::READABLE, Mode::Level, move |_, channel, state| {
+            while let Some(event) = channel.recv() {
+                if callback(event, state) == PostAction::Remove {
+                    return PostAction::Remove;
+                }
+            }
+
+            PostAction::Continue
+        })
+    }
+
+    /// Take ownership of `netlink` and call `callback` with every message it
+    /// receives. Returning Remove stops at that message. Notifications lost
+    /// to a full receive queue are skipped silently
+    pub fn insert_netlink<F>(&mut self, netlink: Netlink, mut callback: F) -> io::Result<Token>
+    where
+        F: FnMut(Message<'_>, &mut S) -> PostAction + 'l,
+    {
+        self.insert_source(netlink, Interest::READABLE, Mode::Level, move |_, netlink, state| {
+            while let Ok(Some(messages)) = netlink.recv() {
+                for message in messages {
+                    if callback(message, state) == PostAction::Remove {
+                        return PostAction::Remove;
+                    }
+                }
+            }
+
+            PostAction::Continue
+        })
+    }
+
+    /// Watch `child` and call `callback` with its exit status once it exits,
+    /// the child is reaped and removed from the loop afterwards. It is removed
+    /// without calling `callback` if waiting for it fails, e.g. because it
+    /// was reaped elsewhere and its pidfd would stay readable forever
+    pub fn insert_child<F>(&mut self, child: Child, mut callback: F) -> io::Result<Token>
+    where
+        F: FnMut(ExitStatus, &mut S) + 'l,
+    {
+        let watcher = ChildWatcher::new(child)?;
+
+        self.insert_source(watcher, Interest::READABLE, Mode::Level, move |_, watcher, state| {
+            match watcher.try_wait() {
+                Ok(Some(status)) => {
+                    callback(status, state);
+                    PostAction::Remove
+                }
+                Ok(None) => PostAction::Continue,
+                Err(_) => PostAction::Remove,
+            }
+        })
+    }
+
+    /// Call `callback` with every line `output` reads, see
+    /// [`ChildOutput::read_lines`]. The source is removed once the pipe is
+    /// closed or reading it fails
+    pub fn insert_child_output<F>(&mut self, output: ChildOutput, mut callback: F) -> io::Result<Token>
+    where
+        F: FnMut(Pipe, &[u8], &mut S) + 'l,
+    {
+        self.insert_source(output, Interest::READABLE, Mode::Level, move |_, output, state| {
+            let pipe = output.pipe();
+
+            match output.read_lines(|line| callback(pipe, line, state)) {
+                Ok(false) => PostAction::Continue,
+                Ok(true) | Err(_) => PostAction::Remove,
+            }
+        })
+    }
+
+    /// Supervise `child`: `on_output` is called with every line of its piped
+    /// stdout and stderr, `on_exit` with its exit status once it exits. The
+    /// pipes are read until they are closed, output still buffered when the
+    /// child exits may be reported after on_exit. Returns the token of the
+    /// exit notification
+    pub fn insert_process<F, E>(&mut self, mut child: Child, on_output: F, on_exit: E) -> io::Result<Token>
+    where
+        F: FnMut(Pipe, &[u8], &mut S) + 'l,
+        E: FnMut(ExitStatus, &mut S) + 'l,
+    {
+        let (stdout, stderr) = ChildOutput::take(&mut child)?;
+        let on_output = Rc::new(RefCell::new(on_output));
+
+        for output in [stdout, stderr].into_iter().flatten() {
+            let on_output = on_output.clone();
+            self.insert_child_output(output, move |pipe, line, state| {
+                (on_output.borrow_mut())(pipe, line, state)
+            })?;
+        }
+
+        self.insert_child(child, on_exit)
+    }
+
+    /// Unregister and drop the source registered with `token`
+    pub fn remove_source(&mut self, token: Token) -> io::Result<()> {
+        let entry = match self.sources.get(token) {
+            Some(entry) => entry,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "no source with this token")),
+        };
+
+        // Dropping the source closes it either way, the entry goes too
+        let result = entry.dispatcher.unregister(&mut self.epoll);
+        self.sources.remove(token);
+        result
+    }
+
+    /// Call `callback` at the end of every dispatch that leaves no events
+    /// pending, in the order the idle callbacks were inserted. Returning
+    /// Remove drops it, Continue and Disable keep it for the next time
+    pub fn insert_idle<F>(&mut self, callback: F)
+    where
+        F: FnMut(&mut S) -> PostAction + 'l,
+    {
+        self.idle.push(Box::new(callback));
+    }
+
+    /// A handle stopping [`run`](Self::run) and waking the loop, all the
+    /// handles of a loop are clones of the first one
+    pub fn signal(&mut self) -> io::Result<LoopSignal> {
+        if let Some(signal) = &self.signal {
+            return Ok(signal.clone());
+        }
+
+        let waker = self.epoll.waker(SIGNAL)?;
+        let signal = LoopSignal { stop: Arc::new(AtomicBool::new(false)), waker };
+        self.signal = Some(signal.clone());
+
+        Ok(signal)
+    }
+
+    /// Dispatch with `timeout` until a [`LoopSignal`] stops the loop. A stop
+    /// is used up by the run it ends, a later run goes on until the next one
+    pub fn run<T: Into<Timeout>>(&mut self, timeout: T, state: &mut S) -> io::Result<()> {
+        let timeout = timeout.into();
+        let stop = self.signal()?.stop;
+
+        while !stop.swap(false, Ordering::SeqCst) {
+            self.dispatch(timeout, state)?;
+        }
+
+        Ok(())
+    }
+
+    /// Stop [`run`](Self::run) and [`run_until_shutdown`](Self::run_until_shutdown)
+    /// on SIGINT or SIGTERM. The signals are blocked in the calling thread
+    /// and read from a signalfd until the source is removed, see [`Signals`].
+    /// Returns the token of the source
+    pub fn handle_termination(&mut self) -> io::Result<Token> {
+        if let Some(token) = self.termination.filter(|token| self.sources.contains(*token)) {
+            return Ok(token);
+        }
+
+        let signals = Signals::new(&[Signal::SIGINT, Signal::SIGTERM])?;
+        let stop = self.signal()?;
+        let terminated = self.terminated.clone();
+
+        let token = self.insert_source(signals, Interest::READABLE, Mode::Level, move |_, signals, _| {
+            while let Ok(Some(info)) = signals.read() {
+                terminated.set(terminated.get().or(Some(info.signal)));
+                stop.stop();
+            }
+
+            PostAction::Continue
+        })?;
+
+        self.termination = Some(token);
+        Ok(token)
+    }
+
+    /// Call `hook` once [`run_until_shutdown`](Self::run_until_shutdown)
+    /// stopped, before the sources are removed. It gets the termination
+    /// signal, None if a [`LoopSignal`] stopped the loop
+    pub fn set_shutdown_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(Option<Signal>, &mut S) + 'l,
+    {
+        self.shutdown_hook = Some(Box::new(hook));
+    }
+
+    /// Dispatch until a [`LoopSignal`] or a termination signal stops the
+    /// loop, then call the shutdown hook and remove every source. The
+    /// teardown happens after an error too, the first error is returned.
+    /// Returns the termination signal, None if a LoopSignal stopped it
+    pub fn run_until_shutdown(&mut self, state: &mut S) -> io::Result<Option<Signal>> {
+        let result = self.run(None, state);
+        let signal = self.terminated.take();
+
+        if let Some(hook) = &mut self.shutdown_hook {
+            hook(signal, state);
+        }
+
+        result.and(self.clear()).map(|()| signal)
+    }
+
+    /// Remove every source, idle callback and left over event. All sources
+    /// go even if unregistering one of them fails, the first error is returned
+    pub fn clear(&mut self) -> io::Result<()> {
+        let tokens = self.sources.tokens();
+
+        let mut result = Ok(());
+        for token in tokens {
+            if let Err(err) = self.remove_source(token) {
+                result = result.and(Err(err));
+            }
+        }
+
+        self.pending.clear();
+        self.idle.clear();
+        self.termination = None;
+        result
+    }
+
+    /// Run at most `max` callbacks per dispatch, None for no limit
+    pub fn set_max_events(&mut self, max: Option<usize>) {
+        self.max_events = max;
+    }
+
+    /// Stop starting callbacks once a dispatch ran for `max`, None for no
+    /// limit. At least one callback runs per dispatch
+    pub fn set_max_time(&mut self, max: Option<Duration>) {
+        self.max_time = max;
+    }
+
+    /// Number of events left over from the last dispatch
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Number of sources in the loop
+    pub fn len(&self) -> usize {
+        self.sources.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    /// Time every source callback and count it in the histogram, with
+    /// buckets up to `buckets`, of the class `classify` puts its token in.
+    /// Replaces the stats collected so far, see [`HandlerStats`]
+    pub fn set_handler_stats<F>(&mut self, buckets: &[Duration], classify: F)
+    where
+        F: Fn(Token) -> &'static str + 'l,
+    {
+        self.handler_stats = Some((Box::new(classify), HandlerStats::new(buckets)));
+    }
+
+    /// Stop timing the callbacks and drop the stats
+    pub fn clear_handler_stats(&mut self) {
+        self.handler_stats = None;
+    }
+
+    /// The callback durations, if they are timed
+    pub fn handler_stats(&self) -> Option<&HandlerStats> {
+        self.handler_stats.as_ref().map(|(_, stats)| stats)
+    }
+
+    /// The callback durations, if they are timed, e.g. to reset them
+    pub fn handler_stats_mut(&mut self) -> Option<&mut HandlerStats> {
+        self.handler_stats.as_mut().map(|(_, stats)| stats)
+    }
+
+    /// The underlying instance, e.g. for its wakeup profile
+    pub fn epoll(&self) -> &Epoll {
+        &self.epoll
+    }
+
+    /// Wait up to `timeout` and call the callback of every ready source,
+    /// within the budget. Events left over from the last call are dispatched
+    /// first and without waiting. A signal interrupting the wait counts as
+    /// no events. The idle callbacks run last if no events are left over.
+    /// Returns the number of source callbacks called
+    pub fn dispatch<T: Into<Timeout>>(&mut self, timeout: T, state: &mut S) -> io::Result<usize> {
+        let timeout = if self.pending.is_empty() { timeout.into() } else { Timeout::Immediate };
+
+        self.collect(timeout)?;
+        self.dispatch_pending(state)
+    }
+
+    /// How long a foreign loop embedding this one may block before calling
+    /// [`dispatch_pending`](Self::dispatch_pending), while waiting for the
+    /// fd of the loop to become readable. Zero while events are left over,
+    /// None otherwise: timers are fds and wake the foreign loop themselves
+    pub fn next_timeout(&self) -> Option<Duration> {
+        if self.pending.is_empty() { None } else { Some(Duration::ZERO) }
+    }
+
+    /// Collect the ready events without waiting, for a foreign loop that saw
+    /// the fd of this one readable. Returns the number of events waiting for
+    /// [`dispatch_pending`](Self::dispatch_pending)
+    pub fn collect_ready(&mut self) -> io::Result<usize> {
+        self.collect(Timeout::Immediate)?;
+        Ok(self.pending.len())
+    }
+
+    fn collect(&mut self, timeout: Timeout) -> io::Result<()> {
+        match self.epoll.poll_into(&mut self.events, timeout) {
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => self.events.clear(),
+            Err(err) => return Err(err),
+        }
+
+        // A source still waiting keeps its place, new readiness joins it.
+        // Every source is called once per batch, with all of its readiness
+        let mut ready: Vec<(Token, Readiness)> = Vec::new();
+        for (token, readiness) in &self.events {
+            if token == SIGNAL {
+                continue;
+            }
+
+            match self.pending.iter_mut().chain(ready.iter_mut()).find(|(pending, _)| *pending == token) {
+                Some((_, pending)) => *pending = pending.merge(readiness),
+                None => ready.push((token, readiness)),
+            }
+        }
+
+        // The others queue up round robin, starting after the last source
+        // that was dispatched
+        let last = self.last;
+        ready.sort_by_key(|(token, _)| (*token <= last, *token));
+        self.pending.extend(ready);
+
+        // Stable, so the round robin order holds within a priority
+        let sources = &self.sources;
+        self.pending.make_contiguous().sort_by_key(|(token, _)| {
+            std::cmp::Reverse(sources.get(*token).map_or(0, |entry| entry.priority))
+        });
+
+        Ok(())
+    }
+
+    /// Call the callbacks of the collected events within the budget, then
+    /// the idle callbacks if no events are left over. [`dispatch`](Self::dispatch)
+    /// without the poll. Returns the number of source callbacks called
+    pub fn dispatch_pending(&mut self, state: &mut S) -> io::Result<usize> {
+        let start = Instant::now();
+        let mut dispatched = 0;
+        let mut removed = Vec::new();
+        let mut result = Ok(());
+
+        // Removing waits for the end of the batch, so no token of this batch
+        // can be handed out again to a source inserted in the meantime
+        while let Some((token, readiness)) = self.pending.pop_front() {
+            let exhausted = self.max_events.is_some_and(|max| dispatched >= max)
+                || self.max_time.is_some_and(|max| dispatched > 0 && start.elapsed() >= max);
+
+            if exhausted {
+                self.pending.push_front((token, readiness));
+                break;
+            }
+
+            let entry = match self.sources.get_mut(token) {
+                Some(entry) => entry,
+                None => continue,
+            };
+
+            dispatched += 1;
+            self.last = token;
+
+            let started = self.handler_stats.is_some().then(Instant::now);
+            let action = entry.dispatcher.dispatch(readiness, state);
+            if let (Some(started), Some((classify, stats))) = (started, &mut self.handler_stats) {
+                stats.record(classify(token), started.elapsed());
+            }
+
+            match action {
+                PostAction::Continue if entry.auto_rearm => {
+                    if let Err(err) = entry.dispatcher.reregister(&mut self.epoll, token, entry.interest, entry.mode) {
+                        result = result.and(Err(err));
+                    }
+                }
+                PostAction::Continue | PostAction::Disable => {}
+                PostAction::Remove => removed.push(token),
+            }
+        }
+
+        // Every removed source goes even if unregistering one of them fails
+        let mut result = result.map(|()| dispatched);
+        for token in removed {
+            if let Err(err) = self.remove_source(token) {
+                result = result.and(Err(err));
+            }
+        }
+
+        if self.pending.is_empty() {
+            self.idle.retain_mut(|callback| callback(state) != PostAction::Remove);
+        }
+
+        result
+    }
+}
+
+impl<S> fmt::Debug for EventLoop<'_, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventLoop")
+            .field("epoll", &self.epoll)
+            .field("sources", &self.sources.len())
+            .field("idle", &self.idle.len())
+            .finish()
+    }
+}
+
+impl<S> AsRawFd for EventLoop<'_, S> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll.as_raw_fd()
+    }
+}
+
+impl<S> AsFd for EventLoop<'_, S> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.epoll.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    use super::*;
+
+    /// A stream with a byte to read and the peer that wrote it
+    fn ready_stream() -> (UnixStream, UnixStream) {
+        let (stream, peer) = UnixStream::pair().unwrap();
+        (&peer).write_all(b"x").unwrap();
+        (stream, peer)
+    }
+
+    /// Insert a level triggered stream whose callback reads it and records `id`
+    fn insert_reader(event_loop: &mut EventLoop<'_, Vec<usize>>, stream: UnixStream, id: usize) -> Token {
+        event_loop
+            .insert_source(stream, Interest::READABLE, Mode::Level, move |_, stream, calls: &mut Vec<usize>| {
+                let _ = stream.read(&mut [0; 16]);
+                calls.push(id);
+                PostAction::Continue
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn ready_sources_get_their_callback_called() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let (ready, _ready_peer) = ready_stream();
+        let (idle, _idle_peer) = UnixStream::pair().unwrap();
+
+        insert_reader(&mut event_loop, ready, 1);
+        insert_reader(&mut event_loop, idle, 2);
+
+        let mut calls = Vec::new();
+        assert_eq!(event_loop.dispatch(Some(Duration::from_secs(1)), &mut calls).unwrap(), 1);
+        assert_eq!(calls, [1]);
+        assert_eq!(event_loop.len(), 2);
+    }
+
+    #[test]
+    fn timer_callbacks_get_the_expirations() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let timer = Timer::repeating(Duration::from_millis(5)).unwrap();
+
+        event_loop
+            .insert_timer(timer, |expirations, _, total: &mut Vec<usize>| {
+                total.push(expirations as usize);
+                if total.iter().sum::<usize>() >= 3 { PostAction::Remove } else { PostAction::Continue }
+            })
+            .unwrap();
+
+        let mut total = Vec::new();
+        while !event_loop.is_empty() {
+            event_loop.dispatch(Some(Duration::from_secs(1)), &mut total).unwrap();
+        }
+
+        assert!(total.iter().all(|&expirations| expirations > 0));
+        assert!(total.iter().sum::<usize>() >= 3);
+    }
+
+    #[test]
+    fn sources_keep_their_tokens_when_they_move_to_the_slab_and_back() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let mut peers = Vec::new();
+        let mut tokens = Vec::new();
+
+        for id in 0..=SMALL {
+            let (stream, peer) = ready_stream();
+            tokens.push(insert_reader(&mut event_loop, stream, id));
+            peers.push(peer);
+            assert_eq!(event_loop.sources.is_small, id < SMALL);
+        }
+
+        let mut calls = Vec::new();
+        event_loop.dispatch(Some(Duration::from_secs(1)), &mut calls).unwrap();
+        calls.sort();
+        assert_eq!(calls, (0..=SMALL).collect::<Vec<_>>());
+
+        for token in tokens.drain(..SMALL - 4) {
+            event_loop.remove_source(token).unwrap();
+        }
+        assert!(event_loop.sources.is_small);
+        assert_eq!(event_loop.len(), 5);
+
+        for mut peer in &peers[SMALL - 4..] {
+            peer.write_all(b"x").unwrap();
+        }
+
+        let mut calls = Vec::new();
+        event_loop.dispatch(Some(Duration::from_secs(1)), &mut calls).unwrap();
+        calls.sort();
+        assert_eq!(calls, (SMALL - 4..=SMALL).collect::<Vec<_>>());
+
+        for token in tokens {
+            event_loop.remove_source(token).unwrap();
+        }
+        assert!(event_loop.is_empty());
+    }
+
+    #[test]
+    fn handler_stats_time_callbacks_by_class() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let (fast, _fast_peer) = ready_stream();
+        let (slow, _slow_peer) = ready_stream();
+
+        insert_reader(&mut event_loop, fast, 1);
+        let slow = event_loop
+            .insert_source(slow, Interest::READABLE, Mode::Level, |_, stream, _: &mut Vec<usize>| {
+                let _ = stream.read(&mut [0; 16]);
+                thread::sleep(Duration::from_millis(5));
+                PostAction::Continue
+            })
+            .unwrap();
+
+        event_loop.set_handler_stats(&[Duration::from_millis(1)], move |token| if token == slow { "slow" } else { "fast" });
+        event_loop.dispatch(Some(Duration::from_secs(1)), &mut Vec::new()).unwrap();
+
+        let stats = event_loop.handler_stats().unwrap();
+        assert_eq!(stats.classes().iter().map(|(class, _)| *class).collect::<Vec<_>>(), ["fast", "slow"]);
+
+        let slow = stats.class("slow").unwrap();
+        assert_eq!(slow.buckets(), [(Some(Duration::from_millis(1)), 0), (None, 1)]);
+        assert!(slow.sum() >= Duration::from_millis(5));
+        assert_eq!(stats.class("fast").unwrap().count(), 1);
+
+        event_loop.clear_handler_stats();
+        assert!(event_loop.handler_stats().is_none());
+    }
+
+    #[test]
+    fn sources_returning_remove_are_dropped() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let (stream, _peer) = ready_stream();
+
+        event_loop
+            .insert_source(stream, Interest::READABLE, Mode::Level, |_, _, calls: &mut Vec<usize>| {
+                calls.push(1);
+                PostAction::Remove
+            })
+            .unwrap();
+
+        let mut calls = Vec::new();
+        event_loop.dispatch(Some(Duration::from_secs(1)), &mut calls).unwrap();
+        event_loop.dispatch(Some(Duration::ZERO), &mut calls).unwrap();
+
+        assert_eq!(calls, [1]);
+        assert!(event_loop.is_empty());
+    }
+
+    #[test]
+    fn budget_leaves_events_for_the_next_dispatch() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let mut peers = Vec::new();
+
+        for id in 0..3 {
+            let (stream, peer) = ready_stream();
+            insert_reader(&mut event_loop, stream, id);
+            peers.push(peer);
+        }
+
+        event_loop.set_max_events(Some(1));
+
+        let mut calls = Vec::new();
+        for pending in [2, 1, 0] {
+            assert_eq!(event_loop.dispatch(Some(Duration::from_secs(1)), &mut calls).unwrap(), 1);
+            assert_eq!(event_loop.pending(), pending);
+        }
+
+        calls.sort();
+        assert_eq!(calls, [0, 1, 2]);
+    }
+
+    #[test]
+    fn idle_callbacks_wait_until_nothing_is_left_over() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let mut peers = Vec::new();
+
+        for id in 0..2 {
+            let (stream, peer) = ready_stream();
+            insert_reader(&mut event_loop, stream, id);
+            peers.push(peer);
+        }
+
+        event_loop.insert_idle(|calls: &mut Vec<usize>| {
+            calls.push(99);
+            PostAction::Remove
+        });
+        event_loop.set_max_events(Some(1));
+
+        let mut calls = Vec::new();
+        event_loop.dispatch(Some(Duration::from_secs(1)), &mut calls).unwrap();
+        assert!(!calls.contains(&99));
+
+        event_loop.dispatch(Some(Duration::from_secs(1)), &mut calls).unwrap();
+        assert_eq!(calls.last(), Some(&99));
+
+        // Removed by returning Remove
+        event_loop.dispatch(Some(Duration::ZERO), &mut calls).unwrap();
+        assert_eq!(calls.iter().filter(|call| **call == 99).count(), 1);
+    }
+
+    #[test]
+    fn higher_priorities_are_dispatched_first() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let mut peers = Vec::new();
+        let mut tokens = Vec::new();
+
+        for id in 0..3 {
+            let (stream, peer) = ready_stream();
+            tokens.push(insert_reader(&mut event_loop, stream, id));
+            peers.push(peer);
+        }
+
+        event_loop.set_priority(tokens[2], 10).unwrap();
+        event_loop.set_priority(tokens[1], -10).unwrap();
+
+        let mut calls = Vec::new();
+        event_loop.dispatch(Some(Duration::from_secs(1)), &mut calls).unwrap();
+        assert_eq!(calls, [2, 0, 1]);
+    }
+
+    #[test]
+    fn oneshot_sources_are_rearmed_on_request() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let (stream, _peer) = ready_stream();
+
+        // Not read, so the stream stays ready and every arm reports it
+        let token = event_loop
+            .insert_source(stream, Interest::READABLE, Mode::OneShot, |_, _, calls: &mut Vec<usize>| {
+                calls.push(1);
+                PostAction::Continue
+            })
+            .unwrap();
+
+        let mut calls = Vec::new();
+        event_loop.dispatch(Some(Duration::from_secs(1)), &mut calls).unwrap();
+        event_loop.dispatch(Some(Duration::ZERO), &mut calls).unwrap();
+        assert_eq!(calls.len(), 1);
+
+        event_loop.rearm(token).unwrap();
+        event_loop.set_auto_rearm(token, true).unwrap();
+        event_loop.dispatch(Some(Duration::ZERO), &mut calls).unwrap();
+        event_loop.dispatch(Some(Duration::ZERO), &mut calls).unwrap();
+        assert_eq!(calls.len(), 3);
+
+        let (level, _level_peer) = UnixStream::pair().unwrap();
+        let level = insert_reader(&mut event_loop, level, 2);
+        let err = event_loop.set_auto_rearm(level, true).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn child_reaped_elsewhere_is_removed() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let child = std::process::Command::new("true").spawn().unwrap();
+        let pid = child.id() as libc::pid_t;
+
+        event_loop.insert_child(child, |_, calls: &mut Vec<usize>| calls.push(1)).unwrap();
+        assert_eq!(unsafe { libc::waitpid(pid, std::ptr::null_mut(), 0) }, pid);
+
+        let mut calls = Vec::new();
+        event_loop.dispatch(Some(Duration::from_secs(5)), &mut calls).unwrap();
+
+        assert!(calls.is_empty());
+        assert!(event_loop.is_empty());
+    }
+
+    #[test]
+    fn loop_signal_stops_run_until_shutdown() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        insert_reader(&mut event_loop, stream, 1);
+
+        let hook_calls = Rc::new(Cell::new(0));
+        let hook = hook_calls.clone();
+        event_loop.set_shutdown_hook(move |signal, _| {
+            assert_eq!(signal, None);
+            hook.set(hook.get() + 1);
+        });
+
+        let signal = event_loop.signal().unwrap();
+        let stopper = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            signal.stop();
+        });
+
+        assert_eq!(event_loop.run_until_shutdown(&mut Vec::new()).unwrap(), None);
+        stopper.join().unwrap();
+
+        assert_eq!(hook_calls.get(), 1);
+        assert!(event_loop.is_empty());
+    }
+
+    #[test]
+    fn loop_signal_stops_run_from_a_signal_handler() {
+        static SIGNAL: std::sync::OnceLock<LoopSignal> = std::sync::OnceLock::new();
+
+        extern "C" fn stop(_: libc::c_int) {
+            if let Some(signal) = SIGNAL.get() {
+                signal.stop();
+            }
+        }
+
+        let mut event_loop: EventLoop<'_, ()> = EventLoop::new().unwrap();
+        SIGNAL.set(event_loop.signal().unwrap()).unwrap();
+
+        // Not used by the other tests, its handler is process wide
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = stop as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            assert_eq!(libc::sigaction(libc::SIGVTALRM, &action, std::ptr::null_mut()), 0);
+        }
+
+        let thread = unsafe { libc::pthread_self() };
+        let raiser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            assert_eq!(unsafe { libc::pthread_kill(thread, libc::SIGVTALRM) }, 0);
+        });
+
+        event_loop.run(None, &mut ()).unwrap();
+        raiser.join().unwrap();
+    }
+}
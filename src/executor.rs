@@ -0,0 +1,138 @@
+//! Running a future to completion on the current thread.
+//!
+//! [`block_on`] is enough to await a few [`Async`](crate::reactor::Async)
+//! operations in an otherwise synchronous program. While the future is
+//! pending the thread waits on the reactor of the thread, so the future has
+//! to register its fds with [`Reactor::current`]. A timer is awaited like
+//! any other fd, and a future woken from another thread makes the wait
+//! return through the reactor's notifier.
+//!
+//! ```ignore
+//! let reactor = Reactor::current()?;
+//! let stream = Async::new(TcpStream::connect(addr)?, &reactor)?;
+//!
+//! let mut buf = [0; 1024];
+//! let n = copoll::block_on(stream.read_with(|mut stream| stream.read(&mut buf)))??;
+//! ```
+use std::future::Future;
+use std::io;
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+
+use crate::reactor::Reactor;
+
+/// Wakes the thread running block_on, only the first wake after a poll of
+/// the future writes to the eventfd
+struct Notify {
+    woken: AtomicBool,
+    notifier: crate::Waker,
+}
+
+impl Wake for Notify {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if !self.woken.swap(true, Ordering::AcqRel) {
+            let _ = self.notifier.wake();
+        }
+    }
+}
+
+/// Poll `future` until it completes, waiting on the reactor of the current
+/// thread while it is pending. Fails if the reactor does
+pub fn block_on<F: Future>(future: F) -> io::Result<F::Output> {
+    let reactor = Reactor::current()?;
+
+    let notify = Arc::new(Notify { woken: AtomicBool::new(true), notifier: reactor.notifier()? });
+    let waker = Waker::from(notify.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    let mut future = pin!(future);
+
+    loop {
+        if notify.woken.swap(false, Ordering::AcqRel) {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return Ok(output);
+            }
+
+            continue;
+        }
+
+        reactor.poll(None)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::reactor::Async;
+
+    #[test]
+    fn ready_futures_complete_without_waiting() {
+        assert_eq!(block_on(async { 42 }).unwrap(), 42);
+    }
+
+    #[test]
+    fn waits_on_the_reactor_of_the_thread() {
+        let (stream, peer) = UnixStream::pair().unwrap();
+        let stream = Async::new(stream, &Reactor::current().unwrap()).unwrap();
+
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            (&peer).write_all(b"hello").unwrap();
+            peer
+        });
+
+        let mut buf = [0; 16];
+        let n = block_on(stream.read_with(|mut stream| stream.read(&mut buf))).unwrap().unwrap();
+        assert_eq!(&buf[..n], b"hello");
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn futures_woken_from_another_thread_complete() {
+        /// Pending until another thread sets it, the waker is stored for it
+        #[derive(Default)]
+        struct Shared {
+            done: bool,
+            waker: Option<Waker>,
+        }
+
+        let shared = Arc::new(Mutex::new(Shared::default()));
+
+        let setter = {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                let mut shared = shared.lock().unwrap();
+                shared.done = true;
+                if let Some(waker) = shared.waker.take() {
+                    waker.wake();
+                }
+            })
+        };
+
+        let future = std::future::poll_fn(|cx| {
+            let mut shared = shared.lock().unwrap();
+            if shared.done {
+                Poll::Ready(())
+            } else {
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        });
+
+        block_on(future).unwrap();
+        setter.join().unwrap();
+    }
+}
@@ -0,0 +1,233 @@
+//! A poll(2) backend for where epoll is not available.
+//!
+//! [`PollFallback`] has the register and poll surface of
+//! [`Epoll`](crate::Epoll), for sandboxes that filter epoll_create or
+//! kernels built without it. [`poller::default`](crate::poller::default)
+//! picks it when creating an epoll instance fails.
+//! Every poll passes the whole interest list to the kernel, so it is meant
+//! for tens of fds, not thousands.
+//!
+//! poll(2) has no edge triggering: edge registrations are reported like
+//! level ones, a superset of the edges that loops reading until WouldBlock
+//! handle unchanged. One-shot registrations are disarmed after reporting
+//! until they are reregistered.
+use std::collections::HashMap;
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, RawFd};
+use std::ptr;
+
+use crate::{make_flags, sys, Events, Interest, Mode, Readiness, Timeout, Token};
+
+#[derive(Debug)]
+struct Entry {
+    fd: RawFd,
+    token: Token,
+    interest: Interest,
+    mode: Mode,
+    /// Cleared once a one-shot registration reported
+    armed: bool,
+}
+
+/// Interest list polled with ppoll(2)
+#[derive(Debug, Default)]
+pub struct PollFallback {
+    entries: Vec<Entry>,
+    /// Index into entries by fd
+    index: HashMap<RawFd, usize>,
+    /// The pollfd array, rebuilt for every poll
+    fds: Vec<libc::pollfd>,
+}
+
+impl PollFallback {
+    pub fn create() -> io::Result<PollFallback> {
+        Ok(PollFallback::default())
+    }
+
+    /// Register a new source
+    pub fn register<S: AsFd + ?Sized>(
+        &mut self,
+        source: &S,
+        token: Token,
+        interest: Interest,
+        mode: Mode
+    ) -> io::Result<()> {
+        let fd = source.as_fd().as_raw_fd();
+        if self.index.contains_key(&fd) {
+            return Err(io::Error::from_raw_os_error(libc::EEXIST));
+        }
+
+        self.index.insert(fd, self.entries.len());
+        self.entries.push(Entry { fd, token, interest, mode, armed: true });
+        Ok(())
+    }
+
+    /// Change the token, interest or mode of a registered source, a one-shot
+    /// registration is armed again
+    pub fn reregister<S: AsFd + ?Sized>(
+        &mut self,
+        source: &S,
+        token: Token,
+        interest: Interest,
+        mode: Mode
+    ) -> io::Result<()> {
+        let fd = source.as_fd().as_raw_fd();
+        let entry = match self.index.get(&fd) {
+            Some(index) => &mut self.entries[*index],
+            None => return Err(io::Error::from_raw_os_error(libc::ENOENT)),
+        };
+
+        *entry = Entry { fd, token, interest, mode, armed: true };
+        Ok(())
+    }
+
+    /// Stop polling a source
+    pub fn unregister<S: AsFd + ?Sized>(&mut self, source: &S) -> io::Result<()> {
+        let fd = source.as_fd().as_raw_fd();
+        let index = match self.index.remove(&fd) {
+            Some(index) => index,
+            None => return Err(io::Error::from_raw_os_error(libc::ENOENT)),
+        };
+
+        self.entries.swap_remove(index);
+        if let Some(moved) = self.entries.get(index) {
+            self.index.insert(moved.fd, index);
+        }
+
+        Ok(())
+    }
+
+    /// Poll for new events
+    pub fn poll<T: Into<Timeout>>(&mut self, timeout: T) -> io::Result<Events> {
+        let mut events = Events::new();
+        self.poll_into(&mut events, timeout)?;
+        Ok(events)
+    }
+
+    /// Wait up to `timeout` for events and store them in `events`, which is
+    /// cleared first. Returns the number of events
+    pub fn poll_into<T: Into<Timeout>>(&mut self, events: &mut Events, timeout: T) -> io::Result<usize> {
+        let timeout = timeout.into().remaining();
+        events.clear();
+
+        self.fds.clear();
+        self.fds.extend(self.entries.iter().map(|entry| libc::pollfd {
+            // poll skips negative fds, that is how disarmed entries are kept
+            fd: if entry.armed { entry.fd } else { -1 },
+            // The epoll bits of the interests are the poll bits
+            events: make_flags(entry.interest, Mode::Level) as libc::c_short,
+            revents: 0,
+        }));
+
+        let timeout = timeout.map(sys::timespec);
+        let ready = unsafe {
+            libc::ppoll(
+                self.fds.as_mut_ptr(),
+                self.fds.len() as libc::nfds_t,
+                timeout.as_ref().map_or(ptr::null(), |timeout| timeout as *const libc::timespec),
+                ptr::null()
+            )
+        };
+
+        if ready < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        for (entry, pollfd) in self.entries.iter_mut().zip(&self.fds) {
+            if pollfd.revents == 0 {
+                continue;
+            }
+
+            events.push(entry.token, revents_to_readiness(pollfd.revents));
+
+            if matches!(entry.mode, Mode::OneShot) {
+                entry.armed = false;
+            }
+        }
+
+        Ok(events.len())
+    }
+}
+
+/// poll reports a closed fd as POLLNVAL, which epoll has no bit for
+fn revents_to_readiness(revents: libc::c_short) -> Readiness {
+    let mut bits = (revents & !libc::POLLNVAL) as u16 as u32;
+
+    if revents & libc::POLLNVAL != 0 {
+        bits |= libc::EPOLLERR as u32;
+    }
+
+    Readiness::from_bits(bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn tokens(events: &Events) -> Vec<Token> {
+        events.iter().map(|(token, _)| token).collect()
+    }
+
+    #[test]
+    fn ready_sources_are_reported_with_their_token() {
+        let mut poller = PollFallback::create().unwrap();
+        let (ready, ready_peer) = UnixStream::pair().unwrap();
+        let (idle, _idle_peer) = UnixStream::pair().unwrap();
+
+        poller.register(&idle, Token(1), Interest::READABLE, Mode::Level).unwrap();
+        poller.register(&ready, Token(2), Interest::READABLE, Mode::Level).unwrap();
+        (&ready_peer).write_all(b"x").unwrap();
+
+        let events = poller.poll(Duration::from_secs(1)).unwrap();
+        assert_eq!(tokens(&events), [Token(2)]);
+        assert!(events.iter().all(|(_, readiness)| readiness.readable));
+
+        let err = poller.register(&ready, Token(3), Interest::READABLE, Mode::Level).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EEXIST));
+    }
+
+    #[test]
+    fn oneshot_registrations_wait_for_a_reregister() {
+        let mut poller = PollFallback::create().unwrap();
+        let (stream, peer) = UnixStream::pair().unwrap();
+        (&peer).write_all(b"x").unwrap();
+
+        poller.register(&stream, Token(1), Interest::READABLE, Mode::OneShot).unwrap();
+        assert_eq!(tokens(&poller.poll(Timeout::Immediate).unwrap()), [Token(1)]);
+        assert!(poller.poll(Timeout::Immediate).unwrap().is_empty());
+
+        poller.reregister(&stream, Token(2), Interest::READABLE, Mode::OneShot).unwrap();
+        assert_eq!(tokens(&poller.poll(Timeout::Immediate).unwrap()), [Token(2)]);
+    }
+
+    #[test]
+    fn unregister_keeps_the_other_entries_indexed() {
+        let mut poller = PollFallback::create().unwrap();
+        let pairs: Vec<_> = (0..3).map(|_| UnixStream::pair().unwrap()).collect();
+
+        for (i, (stream, peer)) in pairs.iter().enumerate() {
+            poller.register(stream, Token(i), Interest::READABLE, Mode::Level).unwrap();
+            (&*peer).write_all(b"x").unwrap();
+        }
+
+        // The last entry moves into the slot of the first
+        poller.unregister(&pairs[0].0).unwrap();
+        poller.reregister(&pairs[2].0, Token(5), Interest::READABLE, Mode::Level).unwrap();
+
+        let mut tokens = tokens(&poller.poll(Timeout::Immediate).unwrap());
+        tokens.sort();
+        assert_eq!(tokens, [Token(1), Token(5)]);
+
+        let err = poller.unregister(&pairs[0].0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+    }
+
+    #[test]
+    fn closed_fds_are_reported_as_errors() {
+        assert!(revents_to_readiness(libc::POLLNVAL).error);
+        assert!(revents_to_readiness(libc::POLLIN).readable);
+    }
+}
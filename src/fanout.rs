@@ -0,0 +1,155 @@
+//! Waking a group of loops without a stampede.
+//!
+//! Loops sharing one job or accept queue each have their own [`Waker`],
+//! collected in a [`WakerGroup`]. Waking all of them for every new job makes
+//! all but one find the queue empty again. The [`FanOut`] of the group
+//! decides how many are woken per [`WakerGroup::wake`]:
+//!
+//! ```ignore
+//! let mut group = WakerGroup::new(FanOut::RoundRobin);
+//! for worker in &mut workers {
+//!     group.push(worker.epoll.waker(JOBS)?);
+//! }
+//!
+//! jobs.push(job);
+//! group.wake()?;
+//! ```
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::Waker;
+
+/// How many wakers of a [`WakerGroup`] one wake reaches
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FanOut {
+    /// Every waker, for notifications every loop has to see
+    All,
+    /// The next waker in turn, one loop per wake
+    RoundRobin,
+    /// The next `n` wakers in turn
+    Batch(usize),
+}
+
+/// Wakers of several loops woken according to a [`FanOut`], see the
+/// [module docs](self). Clones share the rotation
+#[derive(Debug, Clone)]
+pub struct WakerGroup {
+    wakers: Vec<Waker>,
+    fan_out: FanOut,
+    /// Where the rotation continues
+    next: Arc<AtomicUsize>,
+}
+
+impl WakerGroup {
+    pub fn new(fan_out: FanOut) -> WakerGroup {
+        WakerGroup { wakers: Vec::new(), fan_out, next: Arc::new(AtomicUsize::new(0)) }
+    }
+
+    pub fn push(&mut self, waker: Waker) {
+        self.wakers.push(waker);
+    }
+
+    pub fn len(&self) -> usize {
+        self.wakers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.wakers.is_empty()
+    }
+
+    pub fn fan_out(&self) -> FanOut {
+        self.fan_out
+    }
+
+    pub fn set_fan_out(&mut self, fan_out: FanOut) {
+        self.fan_out = fan_out;
+    }
+
+    /// Wake the wakers the fan out picks. Every picked waker is woken even
+    /// if one of them fails, the first error is returned
+    pub fn wake(&self) -> io::Result<()> {
+        let count = match self.fan_out {
+            FanOut::All => return self.wake_all(),
+            FanOut::RoundRobin => 1,
+            FanOut::Batch(n) => n.clamp(1, self.wakers.len().max(1)),
+        };
+
+        if self.wakers.is_empty() {
+            return Ok(());
+        }
+
+        let start = self.next.fetch_add(count, Ordering::Relaxed);
+
+        let mut result = Ok(());
+        for i in start..start + count {
+            if let Err(err) = self.wakers[i % self.wakers.len()].wake() {
+                result = result.and(Err(err));
+            }
+        }
+
+        result
+    }
+
+    /// Wake every waker whatever the fan out, e.g. for a shutdown
+    pub fn wake_all(&self) -> io::Result<()> {
+        let mut result = Ok(());
+
+        for waker in &self.wakers {
+            if let Err(err) = waker.wake() {
+                result = result.and(Err(err));
+            }
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{Epoll, Token};
+
+    /// Which of the instances a wake reached, each one is drained by the poll
+    fn woken(loops: &mut [Epoll]) -> Vec<usize> {
+        (0..loops.len())
+            .filter(|i| !loops[*i].poll(Some(Duration::ZERO)).unwrap().is_empty())
+            .collect()
+    }
+
+    fn group(fan_out: FanOut, loops: &mut [Epoll]) -> WakerGroup {
+        let mut group = WakerGroup::new(fan_out);
+        for epoll in loops.iter_mut() {
+            group.push(epoll.waker(Token(0)).unwrap());
+        }
+        group
+    }
+
+    #[test]
+    fn round_robin_wakes_one_loop_in_turn() {
+        let mut loops: Vec<_> = (0..3).map(|_| Epoll::create().unwrap()).collect();
+        let group = group(FanOut::RoundRobin, &mut loops);
+
+        for expected in [0, 1, 2, 0] {
+            group.wake().unwrap();
+            assert_eq!(woken(&mut loops), [expected]);
+        }
+    }
+
+    #[test]
+    fn batch_and_all() {
+        let mut loops: Vec<_> = (0..3).map(|_| Epoll::create().unwrap()).collect();
+        let mut group = group(FanOut::Batch(2), &mut loops);
+
+        group.wake().unwrap();
+        assert_eq!(woken(&mut loops), [0, 1]);
+        group.wake().unwrap();
+        assert_eq!(woken(&mut loops), [0, 2]);
+
+        group.set_fan_out(FanOut::All);
+        group.wake().unwrap();
+        assert_eq!(woken(&mut loops), [0, 1, 2]);
+    }
+}
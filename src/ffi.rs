@@ -0,0 +1,227 @@
+//! C interface, enabled with the `ffi` feature.
+//!
+//! The declarations are in `include/copoll.h`, regenerate it with
+//! `cbindgen --config cbindgen.toml --output include/copoll.h`.
+//! Build a library for C with e.g.
+//! `cargo rustc --release --features ffi --crate-type staticlib`.
+//!
+//! Functions returning an int return 0 (or a count) on success and -1 on
+//! failure with errno set, like the system calls they wrap.
+use std::io;
+use std::mem;
+use std::os::raw::{c_int, c_void};
+use std::os::fd::{BorrowedFd, RawFd};
+use std::ptr;
+use std::time::Duration;
+
+
+use crate::{CtlError, Epoll, Events, Interest, Mode, Token};
+
+pub const COPOLL_READABLE: c_int = 1;
+pub const COPOLL_WRITABLE: c_int = 2;
+pub const COPOLL_PRIORITY: c_int = 4;
+/// Combine with COPOLL_READABLE to be told about the peer closing its write side
+pub const COPOLL_READ_CLOSED: c_int = 8;
+
+pub const COPOLL_LEVEL: c_int = 0;
+pub const COPOLL_EDGE: c_int = 1;
+pub const COPOLL_ONESHOT: c_int = 2;
+
+/// Opaque handle to an epoll instance
+pub struct CopollEpoll {
+    epoll: Epoll,
+    events: Events,
+}
+
+/// Called once for every event with the user pointer handed to copoll_poll,
+/// the token of the registration and the raw epoll event bits
+pub type CopollCallback = Option<unsafe extern "C" fn(user: *mut c_void, token: usize, events: u32)>;
+
+fn set_errno(err: &io::Error) {
+    let errno = match CtlError::raw_os_error(err) {
+        Some(errno) => errno,
+        None => match err.kind() {
+            io::ErrorKind::NotFound => libc::ENOENT,
+            io::ErrorKind::AlreadyExists => libc::EEXIST,
+            io::ErrorKind::OutOfMemory => libc::ENOMEM,
+            _ => libc::EINVAL,
+        },
+    };
+
+    unsafe { *libc::__errno_location() = errno };
+}
+
+fn result(result: io::Result<()>) -> c_int {
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            set_errno(&err);
+            -1
+        }
+    }
+}
+
+/// The COPOLL_* interests share their bits with Interest
+fn interest(interest: c_int) -> Option<Interest> {
+    match u8::try_from(interest) {
+        Ok(0) | Err(_) => None,
+        Ok(bits) => Interest::from_bits(bits),
+    }
+}
+
+fn mode(mode: c_int) -> Option<Mode> {
+    match mode {
+        COPOLL_LEVEL => Some(Mode::Level),
+        COPOLL_EDGE => Some(Mode::Edge),
+        COPOLL_ONESHOT => Some(Mode::OneShot),
+        _ => None,
+    }
+}
+
+/// Decode the arguments shared by register and reregister
+unsafe fn registration<'a>(
+    handle: *mut CopollEpoll,
+    interest_flags: c_int,
+    mode_flags: c_int
+) -> Option<(&'a mut Epoll, Interest, Mode)> {
+    match (handle.as_mut(), interest(interest_flags), mode(mode_flags)) {
+        (Some(handle), Some(interest), Some(mode)) => Some((&mut handle.epoll, interest, mode)),
+        _ => {
+            *libc::__errno_location() = libc::EINVAL;
+            None
+        }
+    }
+}
+
+/// Create an epoll instance, NULL on failure
+#[no_mangle]
+pub extern "C" fn copoll_create() -> *mut CopollEpoll {
+    match Epoll::create() {
+        Ok(epoll) => Box::into_raw(Box::new(CopollEpoll { epoll, events: Events::new() })),
+        Err(err) => {
+            set_errno(&err);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Close an epoll instance created by copoll_create, NULL is ignored
+///
+/// # Safety
+/// `handle` must come from copoll_create and not be used afterwards
+#[no_mangle]
+pub unsafe extern "C" fn copoll_destroy(handle: *mut CopollEpoll) {
+    if !handle.is_null() {
+        drop(Box::from_raw(handle));
+    }
+}
+
+/// The epoll file descriptor, to nest the instance in another loop
+///
+/// # Safety
+/// `handle` must come from copoll_create
+#[no_mangle]
+pub unsafe extern "C" fn copoll_fd(handle: *const CopollEpoll) -> c_int {
+    match handle.as_ref() {
+        Some(handle) => std::os::unix::io::AsRawFd::as_raw_fd(&handle.epoll),
+        None => {
+            *libc::__errno_location() = libc::EINVAL;
+            -1
+        }
+    }
+}
+
+/// Register `fd` with COPOLL_READABLE, COPOLL_WRITABLE, COPOLL_PRIORITY and
+/// COPOLL_READ_CLOSED or-ed together and a mode
+///
+/// # Safety
+/// `handle` must come from copoll_create and `fd` must be open
+#[no_mangle]
+pub unsafe extern "C" fn copoll_register(
+    handle: *mut CopollEpoll,
+    fd: RawFd,
+    token: usize,
+    interest: c_int,
+    mode: c_int
+) -> c_int {
+    match registration(handle, interest, mode) {
+        Some((epoll, interest, mode)) => result(epoll.register(&BorrowedFd::borrow_raw(fd), Token(token), interest, mode)),
+        None => -1,
+    }
+}
+
+/// Change the token, interest or mode of a registered fd
+///
+/// # Safety
+/// `handle` must come from copoll_create and `fd` must be open
+#[no_mangle]
+pub unsafe extern "C" fn copoll_reregister(
+    handle: *mut CopollEpoll,
+    fd: RawFd,
+    token: usize,
+    interest: c_int,
+    mode: c_int
+) -> c_int {
+    match registration(handle, interest, mode) {
+        Some((epoll, interest, mode)) => result(epoll.reregister(&BorrowedFd::borrow_raw(fd), Token(token), interest, mode)),
+        None => -1,
+    }
+}
+
+/// Stop polling `fd`
+///
+/// # Safety
+/// `handle` must come from copoll_create and `fd` must be open
+#[no_mangle]
+pub unsafe extern "C" fn copoll_unregister(handle: *mut CopollEpoll, fd: RawFd) -> c_int {
+    match handle.as_mut() {
+        Some(handle) => result(handle.epoll.unregister(&BorrowedFd::borrow_raw(fd))),
+        None => {
+            *libc::__errno_location() = libc::EINVAL;
+            -1
+        }
+    }
+}
+
+/// Wait up to `timeout_ms` milliseconds, -1 blocks, and dispatch every event
+/// to `callback`. Returns the number of events dispatched
+///
+/// # Safety
+/// `handle` must come from copoll_create. `callback` is called with `user`,
+/// it may register, reregister and unregister fds but not poll or destroy
+/// the same instance
+#[no_mangle]
+pub unsafe extern "C" fn copoll_poll(
+    handle: *mut CopollEpoll,
+    timeout_ms: c_int,
+    callback: CopollCallback,
+    user: *mut c_void
+) -> c_int {
+    let callback = match (handle.is_null(), callback) {
+        (false, Some(callback)) => callback,
+        _ => {
+            *libc::__errno_location() = libc::EINVAL;
+            return -1;
+        }
+    };
+
+    let timeout = u64::try_from(timeout_ms).ok().map(Duration::from_millis);
+
+    // Move the buffer out so the callbacks can use the handle
+    let mut events = mem::take(&mut (*handle).events);
+
+    if let Err(err) = (*handle).epoll.poll_into(&mut events, timeout) {
+        (*handle).events = events;
+        set_errno(&err);
+        return -1;
+    }
+
+    for (token, readiness) in &events {
+        callback(user, token.0, readiness.raw_bits());
+    }
+
+    let dispatched = events.len() as c_int;
+    (*handle).events = events;
+
+    dispatched
+}
@@ -0,0 +1,101 @@
+//! Frame scheduling against absolute CLOCK_MONOTONIC deadlines.
+//!
+//! A [`FrameTimer`] is a timerfd armed with TFD_TIMER_ABSTIME, so a deadline
+//! derived from a vblank timestamp does not drift by the time spent between
+//! computing it and arming the timer. Timestamps are durations since the
+//! CLOCK_MONOTONIC epoch, the clock DRM and Wayland presentation feedback use.
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::time::Duration;
+
+use crate::sys::{self, TimerFd};
+
+/// The current CLOCK_MONOTONIC time
+pub fn now() -> io::Result<Duration> {
+    sys::clock_gettime(libc::CLOCK_MONOTONIC)
+}
+
+/// A deadline that passed, as returned by [`FrameTimer::fire`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Fired {
+    /// The deadline the timer was armed with
+    pub requested: Duration,
+    /// When the expiration was read, after the loop woke up
+    pub actual: Duration,
+}
+
+impl Fired {
+    /// How late the wakeup was handled
+    pub fn lateness(&self) -> Duration {
+        self.actual.saturating_sub(self.requested)
+    }
+}
+
+/// One-shot timer firing at absolute monotonic timestamps.
+/// Register it readable, call [`FrameTimer::fire`] when it is reported
+/// and arm it again for the next frame
+#[derive(Debug)]
+pub struct FrameTimer {
+    timer: TimerFd,
+    deadline: Option<Duration>,
+}
+
+impl FrameTimer {
+    pub fn new() -> io::Result<FrameTimer> {
+        Ok(FrameTimer { timer: TimerFd::new(libc::CLOCK_MONOTONIC)?, deadline: None })
+    }
+
+    /// Fire at `deadline`, replacing any pending one. A deadline in the past
+    /// fires right away
+    pub fn schedule_at(&mut self, deadline: Duration) -> io::Result<()> {
+        // An all zero expiration would disarm the timer instead
+        let deadline = deadline.max(Duration::from_nanos(1));
+
+        self.timer.set(deadline, Duration::ZERO, libc::TFD_TIMER_ABSTIME)?;
+
+        self.deadline = Some(deadline);
+        Ok(())
+    }
+
+    /// Fire `delay` from now
+    pub fn schedule_after(&mut self, delay: Duration) -> io::Result<()> {
+        self.schedule_at(now()? + delay)
+    }
+
+    /// Disarm the timer, a deadline that already passed but was not
+    /// collected with fire is dropped too
+    pub fn cancel(&mut self) -> io::Result<()> {
+        self.timer.unset()?;
+        self.deadline = None;
+        Ok(())
+    }
+
+    /// The pending deadline
+    pub fn deadline(&self) -> Option<Duration> {
+        self.deadline
+    }
+
+    /// Collect the expiration after the timer was reported readable.
+    /// None if the deadline did not pass yet, e.g. after a spurious wakeup
+    pub fn fire(&mut self) -> io::Result<Option<Fired>> {
+        if self.timer.read()? == 0 {
+            return Ok(None);
+        }
+
+        let actual = now()?;
+
+        Ok(self.deadline.take().map(|requested| Fired { requested, actual }))
+    }
+}
+
+impl AsRawFd for FrameTimer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.timer.as_raw_fd()
+    }
+}
+
+impl AsFd for FrameTimer {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.timer.as_fd()
+    }
+}
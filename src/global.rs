@@ -0,0 +1,190 @@
+//! One poll loop for the whole process.
+//!
+//! Libraries that each need a few fds watched can share [`global()`] instead
+//! of every one creating its own epoll instance and thread. The first call
+//! starts a background thread named `copoll-global` that waits on a
+//! [`shared::Poll`](crate::shared::Poll) and calls the callback of every
+//! ready source on that thread. Registering works from any thread, a source
+//! registered while the loop is blocked is picked up by that wait.
+//!
+//! Callbacks run one at a time and should not block. A callback may register
+//! or unregister sources, including its own. A panicking callback ends the
+//! loop for everyone.
+use std::io;
+use std::os::fd::AsFd;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::thread;
+
+use crate::shared::{Poll, Registry};
+use crate::{Events, Interest, Mode, Readiness, Slab, Token};
+
+type Callback = Arc<Mutex<dyn FnMut(Readiness) + Send>>;
+
+static GLOBAL: OnceLock<Global> = OnceLock::new();
+
+/// Serializes starting the loop, so a failed start can be retried
+static START: Mutex<()> = Mutex::new(());
+
+/// Handle to the process-wide loop, see [`global()`]
+pub struct Global {
+    registry: Registry,
+    callbacks: Arc<Mutex<Slab<Callback>>>,
+}
+
+/// The process-wide loop, started on first use
+pub fn global() -> io::Result<&'static Global> {
+    if let Some(global) = GLOBAL.get() {
+        return Ok(global);
+    }
+
+    let _start = START.lock().unwrap_or_else(|err| err.into_inner());
+    if let Some(global) = GLOBAL.get() {
+        return Ok(global);
+    }
+
+    let global = Global::start()?;
+    Ok(GLOBAL.get_or_init(|| global))
+}
+
+impl Global {
+    fn start() -> io::Result<Global> {
+        let poll = Poll::create()?;
+        let registry = poll.registry().clone();
+        let callbacks = Arc::new(Mutex::new(Slab::new()));
+
+        {
+            let callbacks = callbacks.clone();
+            thread::Builder::new()
+                .name("copoll-global".into())
+                .spawn(move || run(poll, &callbacks))?;
+        }
+
+        Ok(Global { registry, callbacks })
+    }
+
+    /// Register `source` and call `callback` on the loop thread whenever it
+    /// is ready. Returns the token of the registration
+    pub fn register<S, F>(
+        &self,
+        source: &S,
+        interest: Interest,
+        mode: Mode,
+        callback: F
+    ) -> io::Result<Token>
+    where
+        S: AsFd + ?Sized,
+        F: FnMut(Readiness) + Send + 'static,
+    {
+        let mut callbacks = self.callbacks();
+        self.registry.register(source, callbacks.vacant_token(), interest, mode)?;
+        Ok(callbacks.insert(Arc::new(Mutex::new(callback))))
+    }
+
+    /// Change the interest or mode of the registration with `token`
+    pub fn reregister<S: AsFd + ?Sized>(
+        &self,
+        source: &S,
+        token: Token,
+        interest: Interest,
+        mode: Mode
+    ) -> io::Result<()> {
+        self.registry.reregister(source, token, interest, mode)
+    }
+
+    /// Unregister `source` and drop its callback. A call of the callback that
+    /// already started on the loop thread may still be running
+    pub fn unregister<S: AsFd + ?Sized>(&self, source: &S, token: Token) -> io::Result<()> {
+        let mut callbacks = self.callbacks();
+        self.registry.unregister(source)?;
+        callbacks.remove(token);
+        Ok(())
+    }
+
+    /// Number of registered sources
+    pub fn len(&self) -> usize {
+        self.callbacks().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.callbacks().is_empty()
+    }
+
+    fn callbacks(&self) -> MutexGuard<'_, Slab<Callback>> {
+        self.callbacks.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl std::fmt::Debug for Global {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Global")
+            .field("registry", &self.registry)
+            .field("sources", &self.len())
+            .finish()
+    }
+}
+
+/// The loop thread, runs for the rest of the process
+fn run(mut poll: Poll, callbacks: &Mutex<Slab<Callback>>) {
+    let mut events = Events::new();
+
+    loop {
+        match poll.poll(&mut events, None) {
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            // Only a broken epoll fd fails, nothing would ever be delivered
+            Err(_) => return,
+        }
+
+        for (token, readiness) in &events {
+            // The slab is not locked while the callback runs, it may register
+            let callback = match callbacks.lock().unwrap_or_else(|err| err.into_inner()).get(token) {
+                Some(callback) => callback.clone(),
+                None => continue,
+            };
+
+            let mut callback = callback.lock().unwrap_or_else(|err| err.into_inner());
+            (*callback)(readiness);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn callbacks_run_on_the_global_thread_until_unregistered() {
+        let global = global().unwrap();
+        let (stream, peer) = UnixStream::pair().unwrap();
+        let (names, names_rx) = mpsc::channel();
+
+        let token = global
+            .register(&stream, Interest::READABLE, Mode::OneShot, move |_| {
+                let _ = names.send(thread::current().name().map(String::from));
+            })
+            .unwrap();
+
+        (&peer).write_all(b"x").unwrap();
+        let name = names_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(name.as_deref(), Some("copoll-global"));
+
+        // The byte is still unread, rearming reports it again
+        global.reregister(&stream, token, Interest::READABLE, Mode::OneShot).unwrap();
+        names_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        let len = global.len();
+        global.unregister(&stream, token).unwrap();
+        assert_eq!(global.len(), len - 1);
+        assert!(global.unregister(&stream, token).is_err());
+    }
+
+    #[test]
+    fn every_call_returns_the_same_loop() {
+        assert!(std::ptr::eq(global().unwrap(), global().unwrap()));
+    }
+}
@@ -0,0 +1,246 @@
+//! GPIO edge interrupts as poll sources.
+//!
+//! [`GpioLine`] requests a line event fd from a gpiochip character device,
+//! register it with Interest::READABLE. [`SysfsGpio`] uses the legacy
+//! /sys/class/gpio interface, whose value file signals edges with EPOLLPRI,
+//! register it with Interest::PRIORITY. Both drop edges that follow the
+//! previous one within the debounce interval.
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::{frame, sys};
+
+const GPIOHANDLE_REQUEST_INPUT: u32 = 1 << 0;
+const GPIOEVENT_REQUEST_RISING_EDGE: u32 = 1 << 0;
+const GPIOEVENT_REQUEST_FALLING_EDGE: u32 = 1 << 1;
+const GPIOEVENT_EVENT_RISING_EDGE: u32 = 0x01;
+
+#[repr(C)]
+struct GpioEventRequest {
+    lineoffset: u32,
+    handleflags: u32,
+    eventflags: u32,
+    consumer_label: [libc::c_char; 32],
+    fd: libc::c_int,
+}
+
+#[repr(C)]
+struct GpioEventData {
+    timestamp: u64,
+    id: u32,
+}
+
+/// GPIO_GET_LINEEVENT_IOCTL, _IOWR(0xB4, 0x04, struct gpioevent_request)
+const GPIO_GET_LINEEVENT_IOCTL: libc::c_ulong =
+    (3 << 30) | ((mem::size_of::<GpioEventRequest>() as libc::c_ulong) << 16) | (0xB4 << 8) | 0x04;
+
+/// Which edges to report
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Trigger {
+    Rising,
+    Falling,
+    Both,
+}
+
+/// Direction of an edge
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Edge {
+    Rising,
+    Falling,
+}
+
+/// One debounced edge
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct EdgeEvent {
+    pub edge: Edge,
+    /// For a [`GpioLine`] the kernel timestamp of the interrupt, CLOCK_MONOTONIC
+    /// since Linux 5.7 and CLOCK_REALTIME before. For a [`SysfsGpio`] the
+    /// CLOCK_MONOTONIC time the value was read
+    pub timestamp: Duration,
+}
+
+#[derive(Debug, Default)]
+struct Debounce {
+    interval: Duration,
+    last: Option<Duration>,
+}
+
+impl Debounce {
+    fn accept(&mut self, timestamp: Duration) -> bool {
+        if let Some(last) = self.last {
+            if timestamp.saturating_sub(last) < self.interval {
+                return false;
+            }
+        }
+
+        self.last = Some(timestamp);
+        true
+    }
+}
+
+/// Edge events of one line of a gpiochip character device
+#[derive(Debug)]
+pub struct GpioLine {
+    fd: OwnedFd,
+    debounce: Debounce,
+}
+
+impl GpioLine {
+    /// Request edge events of line `offset` of a chip like /dev/gpiochip0.
+    /// `consumer` shows up in the kernel's line info, it is cut to 31 bytes
+    pub fn request<P: AsRef<Path>>(chip: P, offset: u32, trigger: Trigger, consumer: &str) -> io::Result<GpioLine> {
+        let eventflags = match trigger {
+            Trigger::Rising => GPIOEVENT_REQUEST_RISING_EDGE,
+            Trigger::Falling => GPIOEVENT_REQUEST_FALLING_EDGE,
+            Trigger::Both => GPIOEVENT_REQUEST_RISING_EDGE | GPIOEVENT_REQUEST_FALLING_EDGE,
+        };
+
+        let mut request = GpioEventRequest {
+            lineoffset: offset,
+            handleflags: GPIOHANDLE_REQUEST_INPUT,
+            eventflags,
+            consumer_label: [0; 32],
+            fd: -1,
+        };
+
+        for (dst, src) in request.consumer_label.iter_mut().zip(consumer.bytes().take(31)) {
+            *dst = src as libc::c_char;
+        }
+
+        let chip = sys::open(chip.as_ref(), libc::O_RDONLY)?;
+        sys::cvt(unsafe { libc::ioctl(chip.as_raw_fd(), GPIO_GET_LINEEVENT_IOCTL, &mut request) })?;
+
+        let line = GpioLine { fd: unsafe { OwnedFd::from_raw_fd(request.fd) }, debounce: Debounce::default() };
+        sys::set_nonblocking(line.fd.as_raw_fd())?;
+
+        Ok(line)
+    }
+
+    /// Drop edges closer than `interval` to the previous one, zero disables it
+    pub fn set_debounce(&mut self, interval: Duration) {
+        self.debounce.interval = interval;
+    }
+
+    /// Read every queued edge after the fd was reported readable
+    pub fn read_events(&mut self) -> io::Result<Vec<EdgeEvent>> {
+        let mut events = Vec::new();
+        let mut data = [0u8; mem::size_of::<GpioEventData>()];
+
+        loop {
+            match sys::read(self.fd.as_raw_fd(), &mut data) {
+                Ok(n) if n == data.len() => {}
+                Ok(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "short gpio event read")),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(events),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+
+            let event: GpioEventData = unsafe { std::ptr::read_unaligned(data.as_ptr() as *const GpioEventData) };
+            let timestamp = Duration::from_nanos(event.timestamp);
+
+            if self.debounce.accept(timestamp) {
+                let edge = if event.id == GPIOEVENT_EVENT_RISING_EDGE { Edge::Rising } else { Edge::Falling };
+                events.push(EdgeEvent { edge, timestamp });
+            }
+        }
+    }
+}
+
+impl AsRawFd for GpioLine {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for GpioLine {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+/// Edges of an exported GPIO through /sys/class/gpio/gpioN/value
+#[derive(Debug)]
+pub struct SysfsGpio {
+    fd: OwnedFd,
+    value: bool,
+    debounce: Debounce,
+}
+
+impl SysfsGpio {
+    /// Open the value file of an exported GPIO and configure which edges
+    /// it reports. The GPIO has to be exported and configured as an input
+    pub fn open(gpio: u32, trigger: Trigger) -> io::Result<SysfsGpio> {
+        let dir = format!("/sys/class/gpio/gpio{}", gpio);
+        let edge = match trigger {
+            Trigger::Rising => "rising",
+            Trigger::Falling => "falling",
+            Trigger::Both => "both",
+        };
+
+        fs::write(format!("{}/edge", dir), edge)?;
+
+        let path = format!("{}/value", dir);
+        let fd = sys::open(Path::new(&path), libc::O_RDONLY | libc::O_NONBLOCK)?;
+
+        let mut gpio = SysfsGpio { fd, value: false, debounce: Debounce::default() };
+        // The value has to be read once before EPOLLPRI reports changes
+        gpio.value = gpio.read_value()?;
+
+        Ok(gpio)
+    }
+
+    /// Drop edges closer than `interval` to the previous one, zero disables it
+    pub fn set_debounce(&mut self, interval: Duration) {
+        self.debounce.interval = interval;
+    }
+
+    /// The value as of the last read
+    pub fn value(&self) -> bool {
+        self.value
+    }
+
+    /// Read the new value after EPOLLPRI was reported. None if the value did
+    /// not change or the edge was debounced
+    pub fn read_event(&mut self) -> io::Result<Option<EdgeEvent>> {
+        let value = self.read_value()?;
+        let timestamp = frame::now()?;
+
+        if value == self.value || !self.debounce.accept(timestamp) {
+            return Ok(None);
+        }
+
+        self.value = value;
+        let edge = if value { Edge::Rising } else { Edge::Falling };
+
+        Ok(Some(EdgeEvent { edge, timestamp }))
+    }
+
+    fn read_value(&self) -> io::Result<bool> {
+        let mut buffer = [0u8; 2];
+
+        sys::lseek(self.fd.as_raw_fd(), 0, libc::SEEK_SET)?;
+        let n = sys::read(self.fd.as_raw_fd(), &mut buffer)?;
+
+        match buffer.get(..n).and_then(|value| value.first()) {
+            Some(b'0') => Ok(false),
+            Some(b'1') => Ok(true),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected gpio value")),
+        }
+    }
+}
+
+impl AsRawFd for SysfsGpio {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for SysfsGpio {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
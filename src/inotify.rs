@@ -0,0 +1,273 @@
+//! Filesystem watches through the poll loop.
+//!
+//! [`Inotify`] is a non-blocking inotify instance, register it readable and
+//! call [`Inotify::read_events`] when it is reported. Watching the directory
+//! of a config file with IN_CLOSE_WRITE | IN_MOVED_TO catches editors that
+//! replace the file instead of writing it in place.
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr, OsString};
+use std::io;
+use std::mem;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::sys;
+
+/// What a watch reports, combine them with `|`. The same bits are set in
+/// [`InotifyEvent::mask`] together with IN_IGNORED, IN_ISDIR and friends
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AddWatchFlags(u32);
+
+impl AddWatchFlags {
+    pub const IN_ACCESS: AddWatchFlags = AddWatchFlags(libc::IN_ACCESS);
+    pub const IN_MODIFY: AddWatchFlags = AddWatchFlags(libc::IN_MODIFY);
+    pub const IN_ATTRIB: AddWatchFlags = AddWatchFlags(libc::IN_ATTRIB);
+    pub const IN_CLOSE_WRITE: AddWatchFlags = AddWatchFlags(libc::IN_CLOSE_WRITE);
+    pub const IN_CLOSE_NOWRITE: AddWatchFlags = AddWatchFlags(libc::IN_CLOSE_NOWRITE);
+    pub const IN_OPEN: AddWatchFlags = AddWatchFlags(libc::IN_OPEN);
+    pub const IN_MOVED_FROM: AddWatchFlags = AddWatchFlags(libc::IN_MOVED_FROM);
+    pub const IN_MOVED_TO: AddWatchFlags = AddWatchFlags(libc::IN_MOVED_TO);
+    pub const IN_CREATE: AddWatchFlags = AddWatchFlags(libc::IN_CREATE);
+    pub const IN_DELETE: AddWatchFlags = AddWatchFlags(libc::IN_DELETE);
+    pub const IN_DELETE_SELF: AddWatchFlags = AddWatchFlags(libc::IN_DELETE_SELF);
+    pub const IN_MOVE_SELF: AddWatchFlags = AddWatchFlags(libc::IN_MOVE_SELF);
+    pub const IN_UNMOUNT: AddWatchFlags = AddWatchFlags(libc::IN_UNMOUNT);
+    pub const IN_Q_OVERFLOW: AddWatchFlags = AddWatchFlags(libc::IN_Q_OVERFLOW);
+    pub const IN_IGNORED: AddWatchFlags = AddWatchFlags(libc::IN_IGNORED);
+    pub const IN_CLOSE: AddWatchFlags = AddWatchFlags(libc::IN_CLOSE);
+    pub const IN_MOVE: AddWatchFlags = AddWatchFlags(libc::IN_MOVE);
+    pub const IN_ONLYDIR: AddWatchFlags = AddWatchFlags(libc::IN_ONLYDIR);
+    pub const IN_DONT_FOLLOW: AddWatchFlags = AddWatchFlags(libc::IN_DONT_FOLLOW);
+    pub const IN_EXCL_UNLINK: AddWatchFlags = AddWatchFlags(libc::IN_EXCL_UNLINK);
+    pub const IN_MASK_ADD: AddWatchFlags = AddWatchFlags(libc::IN_MASK_ADD);
+    pub const IN_ISDIR: AddWatchFlags = AddWatchFlags(libc::IN_ISDIR);
+    pub const IN_ONESHOT: AddWatchFlags = AddWatchFlags(libc::IN_ONESHOT);
+    pub const IN_ALL_EVENTS: AddWatchFlags = AddWatchFlags(libc::IN_ALL_EVENTS);
+
+    pub const fn empty() -> AddWatchFlags {
+        AddWatchFlags(0)
+    }
+
+    pub const fn from_bits(bits: u32) -> AddWatchFlags {
+        AddWatchFlags(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Returns true if every flag of `other` is set in `self`
+    pub const fn contains(self, other: AddWatchFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns true if any flag of `other` is set in `self`
+    pub const fn intersects(self, other: AddWatchFlags) -> bool {
+        self.0 & other.0 != 0
+    }
+}
+
+impl std::ops::BitOr for AddWatchFlags {
+    type Output = AddWatchFlags;
+
+    fn bitor(self, other: AddWatchFlags) -> AddWatchFlags {
+        AddWatchFlags(self.0 | other.0)
+    }
+}
+
+impl std::ops::BitOrAssign for AddWatchFlags {
+    fn bitor_assign(&mut self, other: AddWatchFlags) {
+        self.0 |= other.0;
+    }
+}
+
+/// A watch, as returned by [`Inotify::add_watch`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct WatchDescriptor(libc::c_int);
+
+/// One event read from an [`Inotify`]
+#[derive(Debug, Clone)]
+pub struct InotifyEvent {
+    pub wd: WatchDescriptor,
+    pub mask: AddWatchFlags,
+    /// Ties the IN_MOVED_FROM and IN_MOVED_TO events of one rename together
+    pub cookie: u32,
+    /// The name of the file inside a watched directory
+    pub name: Option<OsString>,
+}
+
+/// An inotify instance and the paths of its watches
+#[derive(Debug)]
+pub struct Inotify {
+    fd: OwnedFd,
+    paths: HashMap<WatchDescriptor, PathBuf>,
+}
+
+impl Inotify {
+    pub fn new() -> io::Result<Inotify> {
+        let fd = sys::owned(unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) })?;
+
+        Ok(Inotify { fd, paths: HashMap::new() })
+    }
+
+    /// Watch `path` for the events in `mask`. Watching a path again
+    /// returns the same descriptor and replaces its mask
+    pub fn add_watch<P: AsRef<Path>>(&mut self, path: P, mask: AddWatchFlags) -> io::Result<WatchDescriptor> {
+        let path = path.as_ref();
+        let raw = CString::new(path.as_os_str().as_bytes())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let wd = WatchDescriptor(sys::cvt(unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), raw.as_ptr(), mask.0) })?);
+
+        self.paths.insert(wd, path.to_path_buf());
+        Ok(wd)
+    }
+
+    /// Stop watching, an IN_IGNORED event for `wd` follows
+    pub fn remove_watch(&mut self, wd: WatchDescriptor) -> io::Result<()> {
+        sys::cvt(unsafe { libc::inotify_rm_watch(self.fd.as_raw_fd(), wd.0) }).map(drop)
+    }
+
+    /// The path `wd` was added for. Event names are relative to it when
+    /// it is a directory
+    pub fn path(&self, wd: WatchDescriptor) -> Option<&Path> {
+        self.paths.get(&wd).map(PathBuf::as_path)
+    }
+
+    /// Read the pending events, empty once they are drained. Read until
+    /// empty when the instance is reported, the kernel queue may hold more
+    /// than one read returns. Watches the kernel dropped, e.g. because the
+    /// file was deleted, are forgotten after their IN_IGNORED event
+    pub fn read_events(&mut self) -> io::Result<Vec<InotifyEvent>> {
+        // Room for at least one event with the longest name
+        let mut buffer = [0u8; 4096];
+
+        let n = match sys::read(self.fd.as_raw_fd(), &mut buffer) {
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+
+        let header = mem::size_of::<libc::inotify_event>();
+        let mut events = Vec::new();
+        let mut offset = 0;
+
+        while offset + header <= n {
+            let event: libc::inotify_event = unsafe {
+                std::ptr::read_unaligned(buffer[offset..].as_ptr() as *const libc::inotify_event)
+            };
+
+            let name = &buffer[offset + header..(offset + header + event.len as usize).min(n)];
+            // The name is padded with nul bytes
+            let name = match name.iter().position(|byte| *byte == 0).map_or(name, |end| &name[..end]) {
+                [] => None,
+                name => Some(OsStr::from_bytes(name).to_os_string()),
+            };
+
+            events.push(InotifyEvent {
+                wd: WatchDescriptor(event.wd),
+                mask: AddWatchFlags(event.mask),
+                cookie: event.cookie,
+                name,
+            });
+
+            offset += header + event.len as usize;
+        }
+
+        for event in &events {
+            if event.mask.contains(AddWatchFlags::IN_IGNORED) {
+                self.paths.remove(&event.wd);
+            }
+        }
+
+        Ok(events)
+    }
+}
+
+impl AsRawFd for Inotify {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for Inotify {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::Interest;
+
+    /// An empty directory for one test, removed again on drop
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path = std::env::temp_dir().join(format!("copoll-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn written_files_are_reported_by_name() {
+        let dir = TempDir::new("inotify-write");
+        let mut inotify = Inotify::new().unwrap();
+        let wd = inotify.add_watch(&dir.0, AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_MOVED_TO).unwrap();
+        assert_eq!(inotify.path(wd), Some(dir.0.as_path()));
+        assert!(inotify.read_events().unwrap().is_empty());
+
+        fs::write(dir.0.join("config"), b"x").unwrap();
+        assert!(crate::wait_for(&inotify, Interest::READABLE, Some(Duration::from_secs(1))).unwrap().is_some());
+
+        let events = inotify.read_events().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].wd, wd);
+        assert!(events[0].mask.contains(AddWatchFlags::IN_CLOSE_WRITE));
+        assert_eq!(events[0].name.as_deref(), Some(OsStr::new("config")));
+    }
+
+    #[test]
+    fn renames_share_a_cookie() {
+        let dir = TempDir::new("inotify-rename");
+        fs::write(dir.0.join("old"), b"x").unwrap();
+
+        let mut inotify = Inotify::new().unwrap();
+        inotify.add_watch(&dir.0, AddWatchFlags::IN_MOVE).unwrap();
+        fs::rename(dir.0.join("old"), dir.0.join("new")).unwrap();
+
+        let events = inotify.read_events().unwrap();
+        assert_eq!(events.len(), 2);
+        assert!(events[0].mask.contains(AddWatchFlags::IN_MOVED_FROM));
+        assert!(events[1].mask.contains(AddWatchFlags::IN_MOVED_TO));
+        assert_eq!(events[0].cookie, events[1].cookie);
+        assert_eq!(events[1].name.as_deref(), Some(OsStr::new("new")));
+    }
+
+    #[test]
+    fn removed_watches_are_forgotten_after_in_ignored() {
+        let dir = TempDir::new("inotify-remove");
+        let mut inotify = Inotify::new().unwrap();
+        let wd = inotify.add_watch(&dir.0, AddWatchFlags::IN_CREATE).unwrap();
+
+        inotify.remove_watch(wd).unwrap();
+        assert!(inotify.path(wd).is_some());
+
+        let events = inotify.read_events().unwrap();
+        assert!(events.iter().any(|event| event.wd == wd && event.mask.contains(AddWatchFlags::IN_IGNORED)));
+        assert_eq!(inotify.path(wd), None);
+    }
+}
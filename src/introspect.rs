@@ -0,0 +1,240 @@
+//! A `/debug` style endpoint for daemons built on copoll.
+//!
+//! [`Introspection`] listens on a unix socket. Register it readable like any
+//! other fd and call [`Introspection::respond`] when it fires, every pending
+//! client is answered with a JSON snapshot of the epoll instance and then
+//! disconnected, so `socat - UNIX-CONNECT:/run/daemon.debug` is all a client
+//! needs.
+//!
+//! The snapshot lists the registrations, and the poll counters and events per
+//! token when [`Epoll::set_stats`] is enabled, `null` otherwise. Timer queues
+//! are not known to the instance, [`Introspection::respond_with_timers`] adds
+//! the depth of the ones passed to it.
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write as _};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::stats::PollStats;
+use crate::timer::TimerQueue;
+use crate::{Epoll, Mode};
+
+/// How long a slow client may stall the loop while its snapshot is written
+const WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Control socket answering every connection with a JSON dump of the poller
+#[derive(Debug)]
+pub struct Introspection {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl Introspection {
+    /// Bind the control socket at `path`. The socket file is removed on drop
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<Introspection> {
+        let path = path.as_ref().to_path_buf();
+        let listener = UnixListener::bind(&path)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(Introspection { listener, path })
+    }
+
+    /// Answer every pending client with a snapshot of `epoll`.
+    /// Returns how many clients were served
+    pub fn respond(&self, epoll: &Epoll) -> io::Result<usize> {
+        self.respond_with_timers(epoll, &[])
+    }
+
+    /// Like [`respond`](Self::respond), the snapshot also has the number of
+    /// deadlines in every named timer queue
+    pub fn respond_with_timers(&self, epoll: &Epoll, timers: &[(&str, &TimerQueue)]) -> io::Result<usize> {
+        let snapshot = snapshot_with_timers(epoll, timers);
+        let mut served = 0;
+
+        loop {
+            let mut stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(served),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+
+            // A client going away early is its own problem, keep serving the rest
+            let _ = stream
+                .set_write_timeout(Some(WRITE_TIMEOUT))
+                .and_then(|_| stream.write_all(snapshot.as_bytes()));
+
+            served += 1;
+        }
+    }
+}
+
+impl AsRawFd for Introspection {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl AsFd for Introspection {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.listener.as_fd()
+    }
+}
+
+impl Drop for Introspection {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Render the state of `epoll` as a single line of JSON
+pub fn snapshot(epoll: &Epoll) -> String {
+    snapshot_with_timers(epoll, &[])
+}
+
+/// Like [`snapshot`], with the depth of every named timer queue
+pub fn snapshot_with_timers(epoll: &Epoll, timers: &[(&str, &TimerQueue)]) -> String {
+    let mut json = String::new();
+
+    let _ = write!(
+        json,
+        "{{\"epoll_fd\":{},\"fast_path\":{},\"registrations\":[",
+        epoll.epoll_fd.as_raw_fd(), epoll.fast_path
+    );
+
+    for (i, (fd, registration)) in epoll.registry.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+
+        let _ = write!(
+            json,
+            "{{\"fd\":{},\"token\":{},\"interest\":\"{}\",\"mode\":\"{}\",\"label\":",
+            fd,
+            registration.token.0,
+            registration.interest,
+            mode_name(registration.mode)
+        );
+
+        match &registration.label {
+            Some(label) => push_json_string(&mut json, label),
+            None => json.push_str("null"),
+        }
+
+        json.push('}');
+    }
+
+    json.push_str("],\"stats\":");
+    match epoll.stats() {
+        Some(stats) => push_stats(&mut json, stats),
+        None => json.push_str("null"),
+    }
+
+    json.push_str(",\"timer_queues\":[");
+    for (i, (name, queue)) in timers.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+
+        json.push_str("{\"name\":");
+        push_json_string(&mut json, name);
+        let _ = write!(json, ",\"depth\":{}}}", queue.len());
+    }
+
+    json.push_str("]}\n");
+    json
+}
+
+fn push_stats(json: &mut String, stats: &PollStats) {
+    let _ = write!(
+        json,
+        "{{\"polls\":{},\"events\":{},\"events_per_poll\":{},\"spurious\":{},\"interrupts\":{},\
+         \"blocked_us\":{},\"dispatching_us\":{},\"tokens\":[",
+        stats.polls(),
+        stats.events(),
+        stats.events_per_poll(),
+        stats.spurious(),
+        stats.interrupts(),
+        stats.blocked().as_micros(),
+        stats.dispatching().as_micros()
+    );
+
+    for (i, (token, events)) in stats.by_token().into_iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+
+        let _ = write!(json, "{{\"token\":{},\"events\":{}}}", token.0, events);
+    }
+
+    json.push_str("]}");
+}
+
+fn mode_name(mode: Mode) -> &'static str {
+    match mode {
+        Mode::Level => "level",
+        Mode::Edge => "edge",
+        Mode::OneShot => "oneshot",
+    }
+}
+
+fn push_json_string(json: &mut String, value: &str) {
+    json.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+
+    json.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+
+    use super::*;
+    use crate::{Interest, Token};
+
+    #[test]
+    fn snapshot_without_stats() {
+        let mut epoll = Epoll::create().unwrap();
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        epoll.register(&stream, Token(4), Interest::READABLE, Mode::Edge).unwrap();
+
+        let json = snapshot(&epoll);
+        assert!(json.contains(&format!("{{\"fd\":{},\"token\":4,\"interest\":\"readable\",\"mode\":\"edge\",\"label\":null}}", stream.as_raw_fd())));
+        assert!(json.ends_with(",\"stats\":null,\"timer_queues\":[]}\n"));
+    }
+
+    #[test]
+    fn snapshot_with_stats_and_timers() {
+        let mut epoll = Epoll::builder().stats(true).build().unwrap();
+        let (stream, peer) = UnixStream::pair().unwrap();
+        epoll.register(&stream, Token(4), Interest::READABLE, Mode::Level).unwrap();
+
+        (&peer).write_all(b"x").unwrap();
+        epoll.poll(Some(Duration::from_secs(1))).unwrap();
+
+        let mut queue = TimerQueue::new().unwrap();
+        queue.insert_after(Token(1), Duration::from_secs(60)).unwrap();
+        queue.insert_after(Token(2), Duration::from_secs(60)).unwrap();
+
+        let json = snapshot_with_timers(&epoll, &[("sessions", &queue)]);
+        assert!(json.contains("\"stats\":{\"polls\":1,\"events\":1,"));
+        assert!(json.contains("\"tokens\":[{\"token\":4,\"events\":1}]"));
+        assert!(json.contains("\"timer_queues\":[{\"name\":\"sessions\",\"depth\":2}]"));
+    }
+}
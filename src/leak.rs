@@ -0,0 +1,142 @@
+//! Owned sources dropped while they are registered.
+//!
+//! [`Timer`](crate::timer::Timer), [`Signals`](crate::signal::Signals) and
+//! [`Channel`](crate::channel::Channel) own their fd and remember the
+//! instances they are registered with. Dropping one before it was
+//! unregistered removes the fd from the interest list right away, where a
+//! duplicate of the fd would otherwise keep reporting it, and tells the
+//! instance. The instance forgets the registration with its next
+//! registration or poll and reports it to the hook of
+//! [`Epoll::set_leak_hook`](crate::Epoll::set_leak_hook).
+use std::fmt;
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::sync::{Arc, Mutex};
+
+use crate::{sys, Epoll, RegistrationInfo, Token};
+
+/// Owned sources dropped since the instance last looked
+pub(crate) type Leaked = Arc<Mutex<Vec<(RawFd, Token)>>>;
+
+/// Called with every registration whose owned source was dropped
+pub(crate) struct LeakHook(pub Box<dyn FnMut(RegistrationInfo<'_>) + Send>);
+
+impl fmt::Debug for LeakHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("LeakHook")
+    }
+}
+
+/// A registration of an owned source
+#[derive(Debug)]
+struct Registered {
+    epoll_fd: Arc<OwnedFd>,
+    leaked: Leaked,
+    fd: RawFd,
+    token: Token,
+}
+
+/// The instances an owned source is registered with, see the
+/// [module docs](self)
+#[derive(Debug, Default)]
+pub(crate) struct LeakGuard {
+    registered: Mutex<Vec<Registered>>,
+}
+
+impl LeakGuard {
+    /// Remember that `fd` is registered with `epoll` under `token`
+    pub fn register(&self, epoll: &Epoll, fd: RawFd, token: Token) {
+        let mut registered = self.registered.lock().unwrap_or_else(|err| err.into_inner());
+        let (epoll_fd, leaked) = epoll.leak_list();
+
+        match registered.iter_mut().find(|other| Arc::ptr_eq(&other.leaked, leaked)) {
+            Some(other) => other.token = token,
+            None => registered.push(Registered { epoll_fd: epoll_fd.clone(), leaked: leaked.clone(), fd, token }),
+        }
+    }
+
+    pub fn unregister(&self, epoll: &Epoll) {
+        let mut registered = self.registered.lock().unwrap_or_else(|err| err.into_inner());
+        let (_, leaked) = epoll.leak_list();
+
+        registered.retain(|other| !Arc::ptr_eq(&other.leaked, leaked));
+    }
+}
+
+impl Drop for LeakGuard {
+    fn drop(&mut self) {
+        let registered = self.registered.get_mut().unwrap_or_else(|err| err.into_inner());
+
+        for registered in registered.drain(..) {
+            // Fails if the instance is gone or was never told about the fd
+            let _ = sys::epoll_ctl(registered.epoll_fd.as_raw_fd(), libc::EPOLL_CTL_DEL, registered.fd, 0, 0);
+
+            let mut leaked = registered.leaked.lock().unwrap_or_else(|err| err.into_inner());
+            leaked.push((registered.fd, registered.token));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::timer::Timer;
+    use crate::{channel, Interest, Mode};
+
+    /// An instance recording the tokens its leak hook is called with
+    fn epoll() -> (Epoll, Arc<Mutex<Vec<Token>>>) {
+        let mut epoll = Epoll::create().unwrap();
+        let leaks = Arc::new(Mutex::new(Vec::new()));
+
+        let hook = leaks.clone();
+        epoll.set_leak_hook(move |leak| hook.lock().unwrap().push(leak.token));
+
+        (epoll, leaks)
+    }
+
+    #[test]
+    fn dropped_sources_are_reported_and_forgotten() {
+        let (mut epoll, leaks) = epoll();
+        let timer = Timer::new().unwrap();
+        let (_sender, channel) = channel::channel::<()>().unwrap();
+
+        epoll.register(&timer, Token(1), Interest::READABLE, Mode::Level).unwrap();
+        epoll.register(&channel, Token(2), Interest::READABLE, Mode::Level).unwrap();
+        epoll.reregister(&channel, Token(3), Interest::READABLE, Mode::Level).unwrap();
+        drop(timer);
+        drop(channel);
+        assert_eq!(epoll.registrations().count(), 2);
+
+        assert!(epoll.poll(Duration::ZERO).unwrap().is_empty());
+        assert_eq!(*leaks.lock().unwrap(), [Token(1), Token(3)]);
+        assert_eq!(epoll.registrations().count(), 0);
+    }
+
+    #[test]
+    fn unregistered_sources_are_not_reported() {
+        let (mut epoll, leaks) = epoll();
+        let timer = Timer::new().unwrap();
+
+        epoll.register(&timer, Token(1), Interest::READABLE, Mode::Level).unwrap();
+        epoll.unregister(&timer).unwrap();
+        drop(timer);
+
+        assert!(epoll.poll(Duration::ZERO).unwrap().is_empty());
+        assert!(leaks.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn duplicates_of_a_dropped_source_are_not_reported() {
+        let (mut epoll, leaks) = epoll();
+        let timer = Timer::oneshot(Duration::from_millis(1)).unwrap();
+        epoll.register(&timer, Token(1), Interest::READABLE, Mode::Level).unwrap();
+
+        // Keeps the timer open, epoll would go on reporting it
+        let _duplicate = sys::dup(timer.as_raw_fd()).unwrap();
+        drop(timer);
+
+        assert!(epoll.poll(Duration::from_millis(30)).unwrap().is_empty());
+        assert_eq!(*leaks.lock().unwrap(), [Token(1)]);
+    }
+}
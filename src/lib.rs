@@ -1,18 +1,261 @@
 use std::{io, os::unix::io::RawFd};
-use std::time::{Duration};
+use std::any::Any;
+use std::collections::{HashMap, HashSet, TryReserveError};
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::os::unix::io::AsRawFd;
+use std::os::fd::{AsFd, BorrowedFd, OwnedFd};
 
-use nix::sys::epoll;
 
+/// A tracing event, compiled out without the `tracing` feature
+macro_rules! trace {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "tracing")]
+        tracing::trace!($($arg)*);
+    }};
+}
 
-/// Describe what you are interested in polling
-/// Readable means you are interested in the readable events
-/// Writable means you are itnerested in the writable event
-#[derive(Debug, Copy, Clone)]
-pub enum Interest {
-    Readable,
-    Writable,
-    Both
+/// A tracing warning, compiled out without the `tracing` feature
+macro_rules! warn {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "tracing")]
+        tracing::warn!($($arg)*);
+    }};
+}
+
+pub mod batch;
+pub mod builder;
+pub mod capture;
+pub mod channel;
+pub mod completion;
+pub mod config;
+mod duplicate;
+mod error;
+pub mod event;
+pub mod event_loop;
+pub mod executor;
+pub mod fallback;
+pub mod fanout;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod frame;
+pub mod global;
+pub mod gpio;
+pub mod inotify;
+pub mod introspect;
+mod leak;
+#[cfg(feature = "mio-compat")]
+pub mod mio_compat;
+pub mod mio_shim;
+pub mod mock;
+pub mod namespace;
+pub mod nested;
+pub mod net;
+pub mod netlink;
+pub mod ping;
+pub mod poller;
+pub mod pool;
+pub mod prefork;
+pub mod pressure;
+pub mod process;
+pub mod profile;
+#[cfg(feature = "raw")]
+pub mod raw;
+pub mod reactor;
+pub mod record;
+mod registry;
+pub mod scoped;
+pub mod serial;
+pub mod shared;
+pub mod signal;
+pub mod slab;
+pub mod sources;
+pub mod stats;
+#[cfg(feature = "futures-core")]
+pub mod stream;
+mod sys;
+pub mod systemd;
+pub mod tick;
+pub mod tiered;
+pub mod timer;
+#[cfg(feature = "io-uring")]
+pub mod uring;
+pub mod waker;
+#[cfg(feature = "wayland-client")]
+pub mod wayland;
+pub mod workers;
+pub mod zerocopy;
+
+pub use builder::EpollBuilder;
+pub use channel::channel;
+pub use error::{CtlError, CtlErrorKind, CtlOp};
+pub use event::Source;
+pub use event_loop::{EventLoop, LoopSignal, PostAction};
+pub use executor::block_on;
+pub use global::global;
+pub use namespace::TokenNamespace;
+pub use poller::Poller;
+pub use scoped::ScopedRegistration;
+pub use slab::Slab;
+pub use waker::{SignalSafeWaker, Waker};
+
+use profile::WakeupProfile;
+use stats::PollStats;
+use duplicate::FileId;
+use namespace::Namespaces;
+use leak::{LeakHook, Leaked};
+use registry::{Registration, Registry};
+use scoped::Released;
+use signal::SigSet;
+
+/// How many events a single epoll_wait can return
+const MAX_EVENTS: usize = 32;
+
+/// Describe what you are interested in polling, combine them with `|`
+/// READABLE means you are interested in the readable events
+/// WRITABLE means you are itnerested in the writable event
+/// PRIORITY means you are interested in exceptional conditions (EPOLLPRI),
+/// like out of band data, a sysfs attribute changing or a PSI trigger
+/// READ_CLOSED asks for EPOLLRDHUP, the peer shutting down its write side is
+/// reported as read_closed instead of a readable read(0). Combine it with READABLE
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Interest(u8);
+
+const READABLE: u8 = 1 << 0;
+const WRITABLE: u8 = 1 << 1;
+const PRIORITY: u8 = 1 << 2;
+const READ_CLOSED: u8 = 1 << 3;
+
+/// Every interest with its name, in the order they are printed
+const INTEREST_NAMES: [(u8, &str); 4] = [
+    (READABLE, "readable"),
+    (WRITABLE, "writable"),
+    (PRIORITY, "priority"),
+    (READ_CLOSED, "read_closed"),
+];
+
+impl Interest {
+    pub const READABLE: Interest = Interest(READABLE);
+    pub const WRITABLE: Interest = Interest(WRITABLE);
+    pub const PRIORITY: Interest = Interest(PRIORITY);
+    pub const READ_CLOSED: Interest = Interest(READ_CLOSED);
+    /// READABLE | WRITABLE
+    pub const BOTH: Interest = Interest(READABLE | WRITABLE);
+
+    /// No interest at all, the kernel still reports errors and hangups
+    pub const fn empty() -> Interest {
+        Interest(0)
+    }
+
+    /// Both interests of `self` and `other`
+    pub const fn add(self, other: Interest) -> Interest {
+        Interest(self.0 | other.0)
+    }
+
+    /// `self` without `other`
+    pub const fn remove(self, other: Interest) -> Interest {
+        Interest(self.0 & !other.0)
+    }
+
+    /// Returns true if every interest of `other` is part of `self`
+    pub const fn contains(self, other: Interest) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// The interests as used by the C interface, see include/copoll.h
+    #[cfg(feature = "ffi")]
+    pub(crate) const fn from_bits(bits: u8) -> Option<Interest> {
+        match bits & !(READABLE | WRITABLE | PRIORITY | READ_CLOSED) {
+            0 => Some(Interest(bits)),
+            _ => None,
+        }
+    }
+
+    fn names(self) -> impl Iterator<Item = &'static str> {
+        INTEREST_NAMES.iter().filter(move |(bit, _)| self.0 & bit != 0).map(|(_, name)| *name)
+    }
+}
+
+impl std::ops::BitOr for Interest {
+    type Output = Interest;
+
+    fn bitor(self, other: Interest) -> Interest {
+        self.add(other)
+    }
+}
+
+impl std::ops::BitOrAssign for Interest {
+    fn bitor_assign(&mut self, other: Interest) {
+        *self = self.add(other);
+    }
+}
+
+impl std::fmt::Debug for Interest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return write!(f, "(empty)");
+        }
+
+        for (i, name) in self.names().enumerate() {
+            let separator = if i == 0 { "" } else { " | " };
+            write!(f, "{}{}", separator, name.to_uppercase())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The names joined by `|`, like "readable|priority", or "none"
+impl std::fmt::Display for Interest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return write!(f, "none");
+        }
+
+        for (i, name) in self.names().enumerate() {
+            let separator = if i == 0 { "" } else { "|" };
+            write!(f, "{}{}", separator, name)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Serialized in its Display form. "both" is accepted for readable|writable
+#[cfg(feature = "serde")]
+impl serde::Serialize for Interest {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Interest {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Interest, D::Error> {
+        let names = String::deserialize(deserializer)?;
+        let mut interest = Interest::empty();
+
+        for name in names.split('|').map(str::trim) {
+            interest |= match name {
+                "both" => Interest::BOTH,
+                "none" => Interest::empty(),
+                name => match INTEREST_NAMES.iter().find(|(_, known)| *known == name) {
+                    Some((bit, _)) => Interest(*bit),
+                    None => return Err(serde::de::Error::custom(format!("unknown interest {:?}", name))),
+                },
+            };
+        }
+
+        Ok(interest)
+    }
 }
 
 
@@ -21,158 +264,2277 @@ pub enum Interest {
 /// Edge is for edge-triggered notifications on the fd
 /// OneShot is for one-shot notifications on the fd
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum Mode {
    Level,
    Edge,
    OneShot
 }
 
+/// What to do with a registration once it reports an error or a hangup,
+/// see Epoll::set_on_error
+/// Keep leaves it alone, level triggered registrations keep reporting the error on every poll
+/// Disable stops delivering its events until it is reregistered
+/// Remove unregisters it
+/// RemoveOnHangup unregisters it once it reports a hangup without being
+/// readable, so the data still buffered is read first, and keeps it on errors.
+/// Meant for pipes and ttys, a socket stays readable at end of file
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OnError {
+    Keep,
+    Disable,
+    Remove,
+    RemoveOnHangup
+}
+
 /// Readiness
 /// readable marks the event as readable
 /// writable marks the event as writable
 /// error means that your event is an error
+/// hangup means the kernel reported EPOLLHUP, both directions are closed
+/// read_closed means the peer will not send anything more (EPOLLRDHUP,
+/// only reported with Interest::READ_CLOSED, or a hangup)
+/// write_closed means nothing more can be sent, a hangup or an error on a
+/// writable fd
+/// other means the kernel reported flags not covered by the fields above,
+/// like EPOLLPRI, inspect them with to_flags
 #[derive(Debug, Copy, Clone)]
 pub struct Readiness {
     pub readable: bool,
     pub writable: bool,
-    pub error: bool
+    pub error: bool,
+    pub hangup: bool,
+    pub read_closed: bool,
+    pub write_closed: bool,
+    pub other: bool,
+    bits: u32,
+}
+
+impl Readiness {
+    /// Decode the epoll event bits as reported by the kernel
+    pub(crate) fn from_bits(bits: u32) -> Readiness {
+        let known = (libc::EPOLLIN | libc::EPOLLOUT | libc::EPOLLERR | libc::EPOLLHUP | libc::EPOLLRDHUP) as u32;
+        let has = |flag: libc::c_int| bits & flag as u32 != 0;
+
+        let readable = has(libc::EPOLLIN);
+        let writable = has(libc::EPOLLOUT);
+        let error = has(libc::EPOLLERR);
+        let hangup = has(libc::EPOLLHUP);
+
+        Readiness {
+            readable,
+            writable,
+            error,
+            hangup,
+            read_closed: hangup || has(libc::EPOLLRDHUP),
+            write_closed: hangup || (writable && error),
+            other: bits & !known != 0,
+            bits,
+        }
+    }
+
+    /// All the epoll event bits of the event, including the ones
+    /// Readiness does not have a field for
+    pub fn raw_bits(&self) -> u32 {
+        self.bits
+    }
+
+    /// Readiness with the flags of both
+    pub(crate) fn merge(self, other: Readiness) -> Readiness {
+        Readiness::from_bits(self.bits | other.bits)
+    }
+
+    /// Readiness from epoll event bits, e.g. `EPOLLIN` for readable, to
+    /// build events by hand like [`MockPoller`](crate::mock::MockPoller) does
+    pub fn from_raw_bits(bits: u32) -> Readiness {
+        Readiness::from_bits(bits)
+    }
+
+    /// All the flags of the event, see [`EventFlags`]
+    pub fn to_flags(self) -> EventFlags {
+        EventFlags(self.bits)
+    }
+
+    pub fn from_flags(flags: EventFlags) -> Readiness {
+        Readiness::from_bits(flags.0)
+    }
+}
+
+/// The epoll event flags the kernel reported.
+/// Unknown bits are kept and printed in hex
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct EventFlags(u32);
+
+/// Every flag with its name, in the order they are printed
+const EVENT_FLAG_NAMES: [(EventFlags, &str); 10] = [
+    (EventFlags::IN, "IN"),
+    (EventFlags::PRI, "PRI"),
+    (EventFlags::OUT, "OUT"),
+    (EventFlags::ERR, "ERR"),
+    (EventFlags::HUP, "HUP"),
+    (EventFlags::RDNORM, "RDNORM"),
+    (EventFlags::RDBAND, "RDBAND"),
+    (EventFlags::WRNORM, "WRNORM"),
+    (EventFlags::WRBAND, "WRBAND"),
+    (EventFlags::RDHUP, "RDHUP"),
+];
+
+impl EventFlags {
+    pub const IN: EventFlags = EventFlags(libc::EPOLLIN as u32);
+    /// Urgent data, like TCP out of band data or a changed sysfs attribute
+    pub const PRI: EventFlags = EventFlags(libc::EPOLLPRI as u32);
+    pub const OUT: EventFlags = EventFlags(libc::EPOLLOUT as u32);
+    pub const ERR: EventFlags = EventFlags(libc::EPOLLERR as u32);
+    pub const HUP: EventFlags = EventFlags(libc::EPOLLHUP as u32);
+    pub const RDNORM: EventFlags = EventFlags(libc::EPOLLRDNORM as u32);
+    pub const RDBAND: EventFlags = EventFlags(libc::EPOLLRDBAND as u32);
+    pub const WRNORM: EventFlags = EventFlags(libc::EPOLLWRNORM as u32);
+    pub const WRBAND: EventFlags = EventFlags(libc::EPOLLWRBAND as u32);
+    pub const RDHUP: EventFlags = EventFlags(libc::EPOLLRDHUP as u32);
+
+    pub const fn empty() -> EventFlags {
+        EventFlags(0)
+    }
+
+    pub const fn from_bits(bits: u32) -> EventFlags {
+        EventFlags(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Returns true if every flag of `other` is set in `self`
+    pub const fn contains(self, other: EventFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns true if any flag of `other` is set in `self`
+    pub const fn intersects(self, other: EventFlags) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl std::ops::BitOr for EventFlags {
+    type Output = EventFlags;
+
+    fn bitor(self, other: EventFlags) -> EventFlags {
+        EventFlags(self.0 | other.0)
+    }
+}
+
+impl std::ops::BitOrAssign for EventFlags {
+    fn bitor_assign(&mut self, other: EventFlags) {
+        self.0 |= other.0;
+    }
+}
+
+impl std::fmt::Debug for EventFlags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return write!(f, "(empty)");
+        }
+
+        let mut rest = self.0;
+        let mut separator = "";
+        for (flag, name) in EVENT_FLAG_NAMES.iter().filter(|(flag, _)| self.contains(*flag)) {
+            write!(f, "{}{}", separator, name)?;
+            rest &= !flag.0;
+            separator = " | ";
+        }
+
+        if rest != 0 {
+            write!(f, "{}{:#x}", separator, rest)?;
+        }
+
+        Ok(())
+    }
 }
 
 /// A unique token indentifying a file descripting in the
 /// Epoll instance
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Token(pub usize);
 
+/// How long a poll waits for events
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Timeout {
+    /// Until an event arrives
+    Infinite,
+    /// Not at all, only collect what is ready now
+    Immediate,
+    After(Duration),
+    /// Until the deadline, one that passed is Immediate
+    At(Instant),
+}
+
+impl Timeout {
+    /// The time left to wait, None for Infinite
+    pub fn remaining(self) -> Option<Duration> {
+        match self {
+            Timeout::Infinite => None,
+            Timeout::Immediate => Some(Duration::ZERO),
+            Timeout::After(timeout) => Some(timeout),
+            Timeout::At(deadline) => Some(deadline.saturating_duration_since(Instant::now())),
+        }
+    }
+}
+
+/// None waits forever, like the Option taken before Timeout existed
+impl From<Option<Duration>> for Timeout {
+    fn from(timeout: Option<Duration>) -> Timeout {
+        timeout.map_or(Timeout::Infinite, Timeout::After)
+    }
+}
+
+impl From<Duration> for Timeout {
+    fn from(timeout: Duration) -> Timeout {
+        Timeout::After(timeout)
+    }
+}
+
+impl From<Instant> for Timeout {
+    fn from(deadline: Instant) -> Timeout {
+        Timeout::At(deadline)
+    }
+}
+
 /// Epoll structure
 #[derive(Debug)]
 pub struct Epoll {
-    epoll_fd: RawFd
+    epoll_fd: Arc<OwnedFd>,
+    registry: Registry,
+    fast_path: bool,
+    strict: bool,
+    profile: Option<WakeupProfile>,
+    fallible_alloc: bool,
+    /// Registrations with an OnError policy other than Keep, by token
+    error_policies: HashMap<Token, RawFd>,
+    /// Tokens of registrations disabled by their OnError policy
+    disabled: HashSet<Token>,
+    /// Wakers created by waker(), drained when their token is reported
+    wakers: HashMap<Token, Waker>,
+    retry_interrupted: bool,
+    edge_nonblocking: bool,
+    stats: Option<PollStats>,
+    coalesce: bool,
+    user_data: Slab<UserData>,
+    /// Capacity of the buffers poll returns
+    events_capacity: usize,
+    default_interest: Interest,
+    default_mode: Mode,
+    /// Duplicates made by register_dup, closed once they are unregistered
+    dups: HashMap<RawFd, OwnedFd>,
+    /// Scoped registrations that were dropped, their bookkeeping still has to go
+    released: Released,
+    /// Owned sources that were dropped while registered, likewise
+    leaked: Leaked,
+    leak_hook: Option<LeakHook>,
+    /// The last namespace token_namespace handed out
+    namespaces: Namespaces,
+    /// Set once try_clone was called on this instance or one of its clones,
+    /// the kernel then holds registrations the registry does not know about
+    cloned: Arc<AtomicBool>,
+}
+
+/// Data attached by [`Epoll::register_with_data`] and the fd it belongs to
+#[derive(Debug)]
+struct UserData {
+    fd: RawFd,
+    data: Box<dyn Any + Send>,
 }
 
 /// Get the readiness and token of the event
 #[derive(Debug)]
-pub struct Event {
+pub(crate) struct Event {
     pub readiness: Readiness,
     pub token: Token,
 }
 
-/// Shorthand for Vec<<epoll::EpollEvent>>
-pub type Events = Vec<Event>;
+/// Reusable buffer the poll functions fill with events
+#[derive(Debug, Default)]
+pub struct Events {
+    inner: Vec<Event>,
+}
+
+impl Events {
+    pub fn new() -> Events {
+        Events::default()
+    }
+
+    /// Room for `capacity` events before the buffer has to grow
+    pub fn with_capacity(capacity: usize) -> Events {
+        Events { inner: Vec::with_capacity(capacity) }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.inner.capacity()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// Remove every event, keeping the allocation
+    pub fn clear(&mut self) {
+        self.inner.clear();
+    }
+
+    /// Iterate over the token and readiness of every event
+    pub fn iter(&self) -> EventsIter<'_> {
+        EventsIter { inner: self.inner.iter() }
+    }
+
+    /// Merge the events with the same token into the first of them, its
+    /// readiness has the flags of all. Returns the number of events left
+    pub fn coalesce(&mut self) -> usize {
+        self.coalesce_from(0)
+    }
+
+    /// Coalesce the events from index `start` on, the ones before are left
+    /// alone. Returns the number of events left after `start`
+    pub(crate) fn coalesce_from(&mut self, start: usize) -> usize {
+        let mut len = start;
 
-fn make_flags(interest: Interest, mode: Mode) -> epoll::EpollFlags {
-    let mut flags = epoll::EpollFlags::empty();
-    
-    match interest {
-        Interest::Readable => flags |= epoll::EpollFlags::EPOLLIN,  
-        Interest::Writable => flags |= epoll::EpollFlags::EPOLLOUT,
-        Interest::Both => {
-            flags |= epoll::EpollFlags::EPOLLIN;
-            flags |= epoll::EpollFlags::EPOLLOUT;
+        for i in start..self.inner.len() {
+            let event = &self.inner[i];
+            match self.inner[start..len].iter().position(|kept| kept.token == event.token) {
+                Some(kept) => {
+                    let readiness = self.inner[start + kept].readiness.merge(event.readiness);
+                    self.inner[start + kept].readiness = readiness;
+                }
+                None => {
+                    self.inner.swap(len, i);
+                    len += 1;
+                }
+            }
         }
+
+        self.inner.truncate(len);
+        len - start
     }
-    
-    match mode {
-        Mode::Level => { /* This is the default */ }
-        Mode::Edge => flags |= epoll::EpollFlags::EPOLLET,
-        Mode::OneShot => flags |= epoll::EpollFlags::EPOLLONESHOT,
+
+    pub(crate) fn push(&mut self, token: Token, readiness: Readiness) {
+        self.inner.push(Event { readiness, token });
     }
 
-    flags 
+    pub(crate) fn try_reserve(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        self.inner.try_reserve(additional)
+    }
+
+    pub(crate) fn as_slice(&self) -> &[Event] {
+        &self.inner
+    }
+}
+
+/// Fixed size event buffer owned by the caller, see [`Epoll::poll_fixed`].
+/// Holds up to `N` raw epoll events and is decoded while iterating, so
+/// polling into it never allocates
+pub struct FixedEvents<const N: usize> {
+    buffer: [MaybeUninit<libc::epoll_event>; N],
+    /// Number of initialized events at the start of the buffer
+    len: usize,
 }
 
-fn flags_to_readiness(flags: epoll::EpollFlags) -> Readiness {
-    Readiness {
-        readable: flags.contains(epoll::EpollFlags::EPOLLIN),
-        writable: flags.contains(epoll::EpollFlags::EPOLLOUT),
-        error: flags.contains(epoll::EpollFlags::EPOLLERR),
+impl<const N: usize> FixedEvents<N> {
+    pub const fn new() -> FixedEvents<N> {
+        FixedEvents { buffer: [MaybeUninit::uninit(); N], len: 0 }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Iterate over the token and readiness of the events of the last poll
+    pub fn iter(&self) -> FixedEventsIter<'_> {
+        FixedEventsIter { inner: self.initialized().iter() }
+    }
+
+    fn initialized(&self) -> &[libc::epoll_event] {
+        // The first len events were written by the last poll
+        unsafe { slice::from_raw_parts(self.buffer.as_ptr() as *const libc::epoll_event, self.len) }
     }
 }
 
+impl<const N: usize> Default for FixedEvents<N> {
+    fn default() -> FixedEvents<N> {
+        FixedEvents::new()
+    }
+}
 
-impl From<Token> for usize {
-    fn from(val: Token) -> usize {
-        val.0
+impl<const N: usize> std::fmt::Debug for FixedEvents<N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
     }
 }
 
-impl Epoll {
-    /// Create a new epoll instance
-    pub fn create() -> io::Result<Epoll> {
-        let epoll_fd = epoll::epoll_create()?;
-        Ok(Epoll { epoll_fd })
+impl<'a, const N: usize> IntoIterator for &'a FixedEvents<N> {
+    type Item = (Token, Readiness);
+    type IntoIter = FixedEventsIter<'a>;
+
+    fn into_iter(self) -> FixedEventsIter<'a> {
+        self.iter()
     }
+}
 
-    /// Poll the epoll instance for new events.
-    /// Call this one on each iteration of your event loop
-    pub fn poll(
-        &mut self, 
-        timeout: Option<Duration>
-    ) -> io::Result<Events> {
+/// Iterator over the token and readiness of the events in [`FixedEvents`]
+#[derive(Debug, Clone)]
+pub struct FixedEventsIter<'a> {
+    inner: slice::Iter<'a, libc::epoll_event>,
+}
 
-        let mut buffer = [epoll::EpollEvent::empty(); 32];
+impl Iterator for FixedEventsIter<'_> {
+    type Item = (Token, Readiness);
+
+    fn next(&mut self) -> Option<(Token, Readiness)> {
+        self.inner.next().map(decode)
+    }
 
-        let timeout = timeout.map(|d| d.as_millis() as isize).unwrap_or(-1);
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
 
-        let n_events = epoll::epoll_wait(
-            self.epoll_fd, 
-            &mut buffer,
-            timeout,
-        )?; 
+impl ExactSizeIterator for FixedEventsIter<'_> {}
 
-        let mut events = buffer.iter().take(n_events).map(|event| Event {
-            readiness: flags_to_readiness(event.events()),
-            token: Token(event.data() as usize)
-        }).collect(); 
+/// The token and readiness of a raw event
+fn decode(event: &libc::epoll_event) -> (Token, Readiness) {
+    // Copied out, the fields of the packed struct can not be borrowed
+    let (events, data) = (event.events, event.u64);
+    (Token(data as usize), Readiness::from_bits(events))
+}
 
-        Ok(events)
+impl<'a> IntoIterator for &'a Events {
+    type Item = (Token, Readiness);
+    type IntoIter = EventsIter<'a>;
+
+    fn into_iter(self) -> EventsIter<'a> {
+        self.iter()
     }
+}
 
-    /// Register a new file descriptor in the epoll instance
-    pub fn register(
-        &mut self,
-        fd: RawFd,
-        token: Token,
-        interest: Interest,
-        mode: Mode
-    ) -> io::Result<()> {
-         let mut event = epoll::EpollEvent::new(make_flags(interest, mode), usize::from(token) as u64); 
-         epoll::epoll_ctl(self.epoll_fd, epoll::EpollOp::EpollCtlAdd, fd, &mut event)
-            .map_err(Into::into)
+/// Iterator over the token and readiness of the events in [`Events`]
+#[derive(Debug)]
+pub struct EventsIter<'a> {
+    inner: std::slice::Iter<'a, Event>,
+}
+
+impl Iterator for EventsIter<'_> {
+    type Item = (Token, Readiness);
+
+    fn next(&mut self) -> Option<(Token, Readiness)> {
+        self.inner.next().map(|event| (event.token, event.readiness))
     }
 
-    /// Reregister a file descriptor in the epoll instance
-    /// often used when wanting to change say the mode or interest
-    pub fn reregister(
-        &mut self,
-        fd: RawFd,
-        token: Token,
-        interest: Interest,
-        mode: Mode
-    ) -> io::Result<()> {
-         let mut event = epoll::EpollEvent::new(make_flags(interest, mode), usize::from(token) as u64); 
-         epoll::epoll_ctl(self.epoll_fd, epoll::EpollOp::EpollCtlMod, fd, &mut event)
-            .map_err(Into::into)
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
     }
+}
 
-    /// Stop polling events a file descriptor
-    pub fn unregister(
-        &mut self,
-        fd: RawFd
-    ) -> io::Result<()> {
-        epoll::epoll_ctl(self.epoll_fd, epoll::EpollOp::EpollCtlDel, fd, None).map_err(Into::into)
+impl ExactSizeIterator for EventsIter<'_> {}
+
+/// Events decoded into parallel arrays, the token and readiness
+/// of one event share the same index in both vectors
+#[derive(Debug, Default)]
+pub struct EventColumns {
+    pub tokens: Vec<Token>,
+    pub readiness: Vec<Readiness>,
+}
+
+impl EventColumns {
+    /// Create empty columns
+    pub fn new() -> EventColumns {
+        EventColumns::default()
+    }
+
+    /// Create columns with room for `capacity` events
+    pub fn with_capacity(capacity: usize) -> EventColumns {
+        EventColumns {
+            tokens: Vec::with_capacity(capacity),
+            readiness: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Number of events in the columns
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    /// Returns true if there are no events
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// Remove all events while keeping the allocations
+    pub fn clear(&mut self) {
+        self.tokens.clear();
+        self.readiness.clear();
     }
 
+    /// Iterate over the events as token and readiness pairs
+    pub fn iter(&self) -> impl Iterator<Item = (Token, Readiness)> + '_ {
+        self.tokens.iter().copied().zip(self.readiness.iter().copied())
+    }
 }
 
-impl AsRawFd for Epoll {
-    fn as_raw_fd(&self) -> RawFd {
-       self.epoll_fd 
-    }    
+/// A registration as the instance remembers it, see [`Epoll::registrations`]
+#[derive(Debug, Copy, Clone)]
+pub struct RegistrationInfo<'a> {
+    pub fd: RawFd,
+    pub token: Token,
+    pub interest: Interest,
+    pub mode: Mode,
+    pub label: Option<&'a str>,
+}
+
+fn make_flags(interest: Interest, mode: Mode) -> u32 {
+    let mut flags = 0;
+
+    let interests = [
+        (Interest::READABLE, libc::EPOLLIN),
+        (Interest::WRITABLE, libc::EPOLLOUT),
+        (Interest::PRIORITY, libc::EPOLLPRI),
+        (Interest::READ_CLOSED, libc::EPOLLRDHUP),
+    ];
+
+    for (wanted, flag) in interests {
+        if interest.contains(wanted) {
+            flags |= flag as u32;
+        }
+    }
+
+    match mode {
+        Mode::Level => { /* This is the default */ }
+        Mode::Edge => flags |= libc::EPOLLET as u32,
+        Mode::OneShot => flags |= libc::EPOLLONESHOT as u32,
+    }
+
+    flags 
+}
+
+fn alloc_error(err: TryReserveError) -> io::Error {
+    io::Error::new(io::ErrorKind::OutOfMemory, err)
+}
+
+fn unknown_token() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "no registration with this token")
+}
+
+/// Error for an operation strict mode refused to hand to the kernel
+fn rejected(kind: io::ErrorKind, reason: &str) -> io::Error {
+    io::Error::new(kind, reason)
+}
+
+fn make_poll_flags(interest: Interest) -> libc::c_short {
+    let mut flags = 0;
+
+    let interests = [
+        (Interest::READABLE, libc::POLLIN),
+        (Interest::WRITABLE, libc::POLLOUT),
+        (Interest::PRIORITY, libc::POLLPRI),
+        (Interest::READ_CLOSED, libc::POLLRDHUP),
+    ];
+
+    for (wanted, flag) in interests {
+        if interest.contains(wanted) {
+            flags |= flag;
+        }
+    }
+
+    flags
 }
 
-impl Drop for Epoll {
-    fn drop(&mut self) {
-        let _ = nix::unistd::close(self.epoll_fd);
+/// The poll(2) flags share their values with the epoll ones, except POLLNVAL
+/// which has no epoll counterpart and is reported as an error
+fn poll_flags_to_events(flags: libc::c_short) -> u32 {
+    let mut bits = (flags & !libc::POLLNVAL) as u16 as u32;
+
+    if flags & libc::POLLNVAL != 0 {
+        bits |= libc::EPOLLERR as u32;
     }
+
+    bits
 }
 
+/// Set once epoll_pwait2 turned out to be missing, kernels before 5.11, or
+/// forbidden by seccomp
+static NO_PWAIT2: AtomicBool = AtomicBool::new(false);
+
+/// Size of the kernel's sigset, not of the larger glibc sigset_t
+const KERNEL_SIGSET_SIZE: usize = 8;
+
+/// Milliseconds for epoll_wait, rounded up so a sub-millisecond
+/// timeout does not turn into a busy loop of zero timeouts
+fn timeout_millis(timeout: Option<Duration>) -> libc::c_int {
+    match timeout {
+        Some(timeout) => {
+            let millis = timeout.as_nanos().div_ceil(1_000_000);
+            millis.min(libc::c_int::MAX as u128) as libc::c_int
+        }
+        None => -1,
+    }
+}
+
+/// Wait with the full precision of `timeout` through epoll_pwait2, falls back
+/// to epoll_wait or epoll_pwait (with the timeout rounded up to milliseconds)
+/// on kernels without it and where seccomp forbids it. libc has no wrapper
+/// for epoll_pwait2
+fn epoll_wait(
+    epoll_fd: RawFd,
+    buffer: &mut [MaybeUninit<libc::epoll_event>],
+    timeout: Option<Duration>,
+    sigmask: Option<&SigSet>
+) -> io::Result<usize> {
+    let events = buffer.as_mut_ptr() as *mut libc::epoll_event;
+    let max_events = buffer.len() as libc::c_int;
+    let sigmask = sigmask.map_or(ptr::null(), |sigmask| sigmask.as_raw() as *const libc::sigset_t);
+
+    if !NO_PWAIT2.load(Ordering::Relaxed) {
+        let timeout = timeout.map(sys::timespec);
+        let timeout = timeout.as_ref().map_or(ptr::null(), |timeout| timeout as *const libc::timespec);
+
+        let n = unsafe {
+            libc::syscall(
+                libc::SYS_epoll_pwait2,
+                epoll_fd,
+                events,
+                max_events,
+                timeout,
+                sigmask,
+                KERNEL_SIGSET_SIZE
+            )
+        };
+
+        if n >= 0 {
+            return Ok(n as usize);
+        }
+
+        // Seccomp profiles older than the syscall fail it with EPERM,
+        // epoll_pwait2 itself never does
+        let err = io::Error::last_os_error();
+        if !matches!(err.raw_os_error(), Some(libc::ENOSYS | libc::EPERM)) {
+            return Err(err);
+        }
+
+        NO_PWAIT2.store(true, Ordering::Relaxed);
+    }
+
+    let n = unsafe {
+        if sigmask.is_null() {
+            libc::epoll_wait(epoll_fd, events, max_events, timeout_millis(timeout))
+        } else {
+            libc::epoll_pwait(epoll_fd, events, max_events, timeout_millis(timeout), sigmask)
+        }
+    };
+
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(n as usize)
+}
+
+/// Block until `source` is ready for `interest` or `timeout` passes, without
+/// an epoll instance. Returns None on timeout. A signal interrupting the
+/// wait resumes it for the time left. Errors and hangups are always reported
+pub fn wait_for<S: AsFd + ?Sized>(
+    source: &S,
+    interest: Interest,
+    timeout: Option<Duration>
+) -> io::Result<Option<Readiness>> {
+    let fd = source.as_fd().as_raw_fd();
+    let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+    let mut remaining = timeout;
+
+    loop {
+        let mut fds = [libc::pollfd { fd, events: make_poll_flags(interest), revents: 0 }];
+        let timeout = remaining.map(sys::timespec);
+
+        let ready = unsafe {
+            libc::ppoll(
+                fds.as_mut_ptr(),
+                fds.len() as libc::nfds_t,
+                timeout.as_ref().map_or(ptr::null(), |timeout| timeout as *const libc::timespec),
+                ptr::null()
+            )
+        };
+
+        match sys::cvt(ready) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(Readiness::from_bits(poll_flags_to_events(fds[0].revents)))),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {
+                if let Some(deadline) = deadline {
+                    remaining = Some(deadline.saturating_duration_since(Instant::now()));
+                }
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Wait on a single file descriptor with ppoll(2) instead of epoll_wait,
+/// its readiness is stored in `event` like epoll would have
+fn poll_single(
+    fd: RawFd,
+    registration: &Registration,
+    timeout: Option<Duration>,
+    sigmask: Option<&SigSet>,
+    event: &mut MaybeUninit<libc::epoll_event>
+) -> io::Result<usize> {
+    let mut fds = [libc::pollfd { fd, events: make_poll_flags(registration.interest), revents: 0 }];
+    let timeout = timeout.map(sys::timespec);
+
+    let ready = unsafe {
+        libc::ppoll(
+            fds.as_mut_ptr(),
+            fds.len() as libc::nfds_t,
+            timeout.as_ref().map_or(ptr::null(), |timeout| timeout as *const libc::timespec),
+            sigmask.map_or(ptr::null(), |sigmask| sigmask.as_raw() as *const libc::sigset_t)
+        )
+    };
+
+    if ready < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    if ready == 0 {
+        return Ok(0);
+    }
+
+    event.write(libc::epoll_event {
+        events: poll_flags_to_events(fds[0].revents),
+        u64: registration.token.0 as u64,
+    });
+
+    Ok(1)
+}
+
+
+impl From<Token> for usize {
+    fn from(val: Token) -> usize {
+        val.0
+    }
+}
+
+impl Epoll {
+    /// Create a new epoll instance
+    pub fn create() -> io::Result<Epoll> {
+        EpollBuilder::new().build()
+    }
+
+    /// Configure the instance before creating it, see [`EpollBuilder`]
+    pub fn builder() -> EpollBuilder {
+        EpollBuilder::new()
+    }
+
+    /// Create a second instance waiting on the same interest list, the epoll
+    /// fd is duplicated with F_DUPFD_CLOEXEC. Both wait on the same kernel
+    /// instance: level triggered readiness is reported to both, an edge to
+    /// whichever waits first. The bookkeeping (labels, error policies,
+    /// settings) is copied as it is now, a wakeup profile and the stats start
+    /// empty. Registrations made through one instance later are unknown to
+    /// the other
+    pub fn try_clone(&self) -> io::Result<Epoll> {
+        self.cloned.store(true, Ordering::Relaxed);
+
+        Ok(Epoll {
+            epoll_fd: Arc::new(sys::dup(self.epoll_fd.as_raw_fd())?),
+            registry: self.registry.clone(),
+            fast_path: self.fast_path,
+            strict: self.strict,
+            profile: self.profile.as_ref().map(|_| WakeupProfile::new()),
+            fallible_alloc: self.fallible_alloc,
+            error_policies: self.error_policies.clone(),
+            disabled: self.disabled.clone(),
+            wakers: self.wakers.clone(),
+            retry_interrupted: self.retry_interrupted,
+            edge_nonblocking: self.edge_nonblocking,
+            stats: self.stats.as_ref().map(|_| PollStats::default()),
+            coalesce: self.coalesce,
+            user_data: Slab::new(),
+            events_capacity: self.events_capacity,
+            default_interest: self.default_interest,
+            default_mode: self.default_mode,
+            dups: HashMap::new(),
+            released: Released::default(),
+            leaked: Leaked::default(),
+            leak_hook: None,
+            namespaces: self.namespaces.clone(),
+            cloned: self.cloned.clone(),
+        })
+    }
+
+    /// Retry a wait interrupted by a signal for the rest of its timeout
+    /// instead of failing with Interrupted. Off by default, loops that check
+    /// a flag set by a signal handler need the interruption.
+    /// [`Epoll::poll_with_sigmask`] is never retried
+    pub fn set_retry_interrupted(&mut self, enabled: bool) {
+        self.retry_interrupted = enabled;
+    }
+
+    /// Switch every fd registered or reregistered edge triggered to
+    /// non-blocking mode with fcntl. A blocking fd read until WouldBlock
+    /// blocks instead once it is drained, and the loop stalls
+    pub fn set_edge_nonblocking(&mut self, enabled: bool) {
+        self.edge_nonblocking = enabled;
+    }
+
+    /// Merge the events one poll returns for the same token into one, see
+    /// [`Events::coalesce`]. Several fds registered with one token, like the
+    /// duplicates of a socket, otherwise each report their own event.
+    /// Applies to the polls filling [`Events`]
+    pub fn set_coalesce(&mut self, enabled: bool) {
+        self.coalesce = enabled;
+    }
+
+    /// Enable or disable the single source fast path. When enabled and exactly
+    /// one level triggered file descriptor is registered, poll waits on it with
+    /// poll(2) directly and skips the epoll bookkeeping in the kernel.
+    /// Edge, one-shot, exclusive and wakeup registrations always go through
+    /// epoll, and so does an instance that was cloned with [`Epoll::try_clone`].
+    /// Every fast poll costs an fstat making sure the fd was not closed and reused.
+    pub fn set_fast_path(&mut self, enabled: bool) {
+        self.fast_path = enabled;
+    }
+
+    /// Enable or disable strict mode. In strict mode sequences which are
+    /// known to be wrong fail up front with a descriptive error instead of
+    /// an errno from the kernel, or no error at all:
+    /// registering an fd twice, reregistering or unregistering an fd that is
+    /// not registered, reregistering an exclusive entry, rearming a
+    /// registration that is not one-shot, exclusive one-shot
+    /// registrations, edge triggered registrations of blocking fds and polling
+    /// an empty instance without a timeout, which would block forever
+    pub fn set_strict(&mut self, enabled: bool) {
+        self.strict = enabled;
+    }
+
+    /// Enable or disable detection of registrations sharing one open file
+    /// description, as created by dup, fork or fd passing. Epoll treats such
+    /// fds independently and reports every event once for each of them.
+    /// A duplicate is reported as a tracing warning with the `tracing`
+    /// feature, or rejected in strict mode.
+    /// Costs a kcmp for every registration of a file that is already registered
+    pub fn set_detect_duplicates(&mut self, enabled: bool) {
+        self.registry.set_index_files(enabled);
+    }
+
+    /// Enable or disable wakeup profiling. While enabled every blocking poll
+    /// is attributed to the tokens that woke it up, the timeout or a signal.
+    /// Enabling it while it is already enabled keeps the current profile
+    pub fn set_wakeup_profiling(&mut self, enabled: bool) {
+        match (enabled, self.profile.is_some()) {
+            (true, false) => self.profile = Some(WakeupProfile::new()),
+            (false, _) => self.profile = None,
+            (true, true) => {}
+        }
+    }
+
+    /// Enable or disable the poll stats, see [`PollStats`]. Enabling them
+    /// while they are already enabled keeps the current counts
+    pub fn set_stats(&mut self, enabled: bool) {
+        match (enabled, self.stats.is_some()) {
+            (true, false) => self.stats = Some(PollStats::default()),
+            (false, _) => self.stats = None,
+            (true, true) => {}
+        }
+    }
+
+    /// Enable or disable fallible allocation. When enabled, growing the events
+    /// buffer or the internal registry uses try_reserve and failure is returned
+    /// as an io::ErrorKind::OutOfMemory error instead of aborting the process.
+    /// A failed registration is never handed to the kernel
+    pub fn set_fallible_alloc(&mut self, enabled: bool) {
+        self.fallible_alloc = enabled;
+    }
+
+    /// The wakeup profile, if profiling is enabled
+    pub fn wakeup_profile(&self) -> Option<&WakeupProfile> {
+        self.profile.as_ref()
+    }
+
+    /// The wakeup profile, if profiling is enabled, e.g. to reset it
+    pub fn wakeup_profile_mut(&mut self) -> Option<&mut WakeupProfile> {
+        self.profile.as_mut()
+    }
+
+    /// The poll stats, if they are enabled
+    pub fn stats(&self) -> Option<&PollStats> {
+        self.stats.as_ref()
+    }
+
+    /// The poll stats, if they are enabled, e.g. to reset them
+    pub fn stats_mut(&mut self) -> Option<&mut PollStats> {
+        self.stats.as_mut()
+    }
+
+    /// Poll the epoll instance for new events.
+    /// Call this one on each iteration of your event loop
+    pub fn poll<T: Into<Timeout>>(
+        &mut self,
+        timeout: T
+    ) -> io::Result<Events> {
+        let mut events = Events::with_capacity(self.events_capacity);
+
+        self.poll_append(&mut events, timeout)?;
+
+        Ok(events)
+    }
+
+    /// Poll the epoll instance and store the new events in `events`.
+    /// The buffer is cleared first so its allocation can be reused.
+    /// Returns the number of events, 0 when the timeout expired
+    pub fn poll_into<T: Into<Timeout>>(
+        &mut self,
+        events: &mut Events,
+        timeout: T
+    ) -> io::Result<usize> {
+        events.clear();
+        self.poll_append(events, timeout)
+    }
+
+    /// Collect the events that are ready right now without waiting, the same
+    /// as a zero timeout. Timeouts below a millisecond do wait: they are
+    /// passed to epoll_pwait2 with full precision, or rounded up where the
+    /// kernel lacks it
+    pub fn poll_nonblocking(
+        &mut self,
+        events: &mut Events
+    ) -> io::Result<usize> {
+        self.poll_into(events, Some(Duration::ZERO))
+    }
+
+    /// Like [`Epoll::poll_into`] with a deadline instead of a timeout. A signal
+    /// interrupting the wait resumes it for the time left, a deadline that
+    /// passed polls once without waiting
+    pub fn poll_until(
+        &mut self,
+        events: &mut Events,
+        deadline: Instant
+    ) -> io::Result<usize> {
+        loop {
+            match self.poll_into(events, Timeout::At(deadline)) {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                result => return result,
+            }
+        }
+    }
+
+    /// Poll the epoll instance and append the new events to `events`
+    /// without clearing it. Useful for gathering the events of several
+    /// zero-timeout polls, possibly on different instances, into one batch.
+    /// Returns the number of events appended
+    pub fn poll_append<T: Into<Timeout>>(
+        &mut self,
+        events: &mut Events,
+        timeout: T
+    ) -> io::Result<usize> {
+        let timeout = timeout.into().remaining();
+
+        if self.fallible_alloc {
+            events.try_reserve(MAX_EVENTS).map_err(alloc_error)?;
+        }
+
+        let start = events.len();
+        let count = self.wait(timeout, None, |token, readiness| events.push(token, readiness))?;
+
+        Ok(if self.coalesce { events.coalesce_from(start) } else { count })
+    }
+
+    /// Poll the epoll instance with `sigmask` as the signal mask of the thread
+    /// for the duration of the wait, built on epoll_pwait. Block a signal, check
+    /// the state its handler updates, then wait with a mask that unblocks it:
+    /// the signal can only arrive during the wait, which returns Interrupted.
+    /// The buffer is cleared first, returns the number of events
+    pub fn poll_with_sigmask<T: Into<Timeout>>(
+        &mut self,
+        events: &mut Events,
+        timeout: T,
+        sigmask: &SigSet
+    ) -> io::Result<usize> {
+        let timeout = timeout.into().remaining();
+        events.clear();
+
+        if self.fallible_alloc {
+            events.try_reserve(MAX_EVENTS).map_err(alloc_error)?;
+        }
+
+        let count = self.wait(timeout, Some(sigmask), |token, readiness| events.push(token, readiness))?;
+
+        Ok(if self.coalesce { events.coalesce() } else { count })
+    }
+
+    /// Poll the epoll instance and decode the events into `columns`.
+    /// The columns are cleared first, letting the same allocation be
+    /// reused on every iteration. Returns the number of events
+    pub fn poll_columns<T: Into<Timeout>>(
+        &mut self,
+        columns: &mut EventColumns,
+        timeout: T
+    ) -> io::Result<usize> {
+        let timeout = timeout.into().remaining();
+        columns.clear();
+
+        if self.fallible_alloc {
+            columns.tokens.try_reserve(MAX_EVENTS).map_err(alloc_error)?;
+            columns.readiness.try_reserve(MAX_EVENTS).map_err(alloc_error)?;
+        }
+
+        self.wait(timeout, None, |token, readiness| {
+            columns.tokens.push(token);
+            columns.readiness.push(readiness);
+        })
+    }
+
+    /// Poll the epoll instance into a fixed buffer owned by the caller,
+    /// nothing is allocated. At most `N` events are taken per call, the rest
+    /// stay ready for the next one. Events are not coalesced. Returns an
+    /// iterator over the new events
+    pub fn poll_fixed<'a, const N: usize, T: Into<Timeout>>(
+        &mut self,
+        events: &'a mut FixedEvents<N>,
+        timeout: T
+    ) -> io::Result<FixedEventsIter<'a>> {
+        let timeout = timeout.into().remaining();
+        events.len = 0;
+        events.len = self.wait_into(&mut events.buffer, timeout, None, |_, _| {})?;
+
+        Ok(events.iter())
+    }
+
+    /// Wait for events and hand each decoded one to `f`
+    fn wait<F: FnMut(Token, Readiness)>(
+        &mut self,
+        timeout: Option<Duration>,
+        sigmask: Option<&SigSet>,
+        f: F
+    ) -> io::Result<usize> {
+        self.wait_into(&mut [MaybeUninit::uninit(); MAX_EVENTS], timeout, sigmask, f)
+    }
+
+    /// Wait for events in `buffer` and hand each decoded one to `f`. The
+    /// events that were delivered are moved to the start of the buffer,
+    /// returns their number
+    fn wait_into<F: FnMut(Token, Readiness)>(
+        &mut self,
+        buffer: &mut [MaybeUninit<libc::epoll_event>],
+        timeout: Option<Duration>,
+        sigmask: Option<&SigSet>,
+        mut f: F
+    ) -> io::Result<usize> {
+        self.forget_released();
+
+        // Only waits that may actually sleep are interesting for the profile
+        let mut profile = match timeout {
+            Some(timeout) if timeout.is_zero() => None,
+            _ => self.profile.take(),
+        };
+
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!("poll", timeout = ?timeout).entered();
+
+        let mut stats = self.stats.take();
+        let start = Instant::now();
+        if let Some(stats) = stats.as_mut() {
+            stats.record_start(start);
+        }
+
+        let hangup = libc::EPOLLHUP as u32;
+        let mut failed = Vec::new();
+
+        let mut delivered = 0;
+        let mut deliver = |token, readiness: Readiness| {
+            if self.disabled.contains(&token) {
+                return false;
+            }
+
+            if readiness.error || readiness.raw_bits() & hangup != 0 {
+                if let Some(fd) = self.error_policies.get(&token) {
+                    failed.push((*fd, token, readiness));
+                }
+            }
+
+            if let Some(waker) = self.wakers.get(&token) {
+                waker.drain();
+            }
+
+            if let Some(profile) = profile.as_mut() {
+                profile.record_token(token);
+            }
+
+            if let Some(stats) = stats.as_mut() {
+                stats.record_token(token);
+            }
+
+            delivered += 1;
+            f(token, readiness);
+            true
+        };
+
+        // A wait with a signal mask is there to be interrupted
+        let retry = self.retry_interrupted && sigmask.is_none();
+        let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+        let mut remaining = timeout;
+
+        let result = loop {
+            match self.wait_events(buffer, remaining, sigmask, &mut deliver) {
+                Err(err) if retry && err.kind() == io::ErrorKind::Interrupted => {
+                    if let Some(deadline) = deadline {
+                        remaining = Some(deadline.saturating_duration_since(Instant::now()));
+                    }
+                }
+                result => break result,
+            }
+        };
+
+        if let Some(mut profile) = profile {
+            match &result {
+                Ok(_) => profile.record_wakeup(delivered),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => profile.record_interrupt(),
+                Err(_) => {}
+            }
+
+            self.profile = Some(profile);
+        }
+
+        #[cfg(feature = "tracing")]
+        match &result {
+            Ok(_) => tracing::trace!(events = delivered, waited = ?start.elapsed(), "poll returned"),
+            Err(err) => tracing::trace!(error = %err, waited = ?start.elapsed(), "poll failed"),
+        }
+
+        if let Some(mut stats) = stats {
+            match &result {
+                Ok(_) => stats.record_poll(start, timeout, delivered, false),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => stats.record_poll(start, timeout, 0, true),
+                Err(_) => {}
+            }
+
+            self.stats = Some(stats);
+        }
+
+        // The failing events were delivered once, apply the policies afterwards
+        for (fd, token, readiness) in failed {
+            self.apply_on_error(fd, token, readiness);
+        }
+
+        result.map(|_| delivered)
+    }
+
+    /// Wait for events in `buffer`, then keep the ones `f` accepts at its start
+    fn wait_events<F: FnMut(Token, Readiness) -> bool>(
+        &self,
+        buffer: &mut [MaybeUninit<libc::epoll_event>],
+        timeout: Option<Duration>,
+        sigmask: Option<&SigSet>,
+        mut f: F
+    ) -> io::Result<usize> {
+        if self.strict && timeout.is_none() && self.registry.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "polling an empty epoll instance without a timeout blocks forever"
+            ));
+        }
+
+        let single = self.registry.single().filter(|(fd, registration)| {
+            // poll(2) can not be told to ignore a disabled fd's errors
+            self.fast_path
+                && matches!(registration.mode, Mode::Level)
+                && !registration.interest.contains(Interest::READ_CLOSED)
+                && !self.disabled.contains(&registration.token)
+                // Only epoll wakes one of the instances sharing an exclusive
+                // fd and holds a wakeup source while events are pending
+                && !registration.exclusive
+                && !registration.wakeup
+                // Clones and dropped scoped registrations leave entries in the
+                // kernel the registry does not reflect
+                && !self.cloned.load(Ordering::Relaxed)
+                && self.released.lock().is_ok_and(|released| released.is_empty())
+                && self.leaked.lock().is_ok_and(|leaked| leaked.is_empty())
+                // A closed or reused fd would be polled for the wrong file
+                && registration.file.is_some()
+                && duplicate::file_id(*fd) == registration.file
+        });
+
+        let n_events = match (single, buffer.first_mut()) {
+            (Some((fd, registration)), Some(event)) => poll_single(fd, registration, timeout, sigmask, event)?,
+            _ => epoll_wait(self.epoll_fd.as_raw_fd(), buffer, timeout, sigmask)?,
+        };
+
+        let mut kept = 0;
+        for i in 0..n_events {
+            // Written by the kernel or poll_single above
+            let event = unsafe { buffer[i].assume_init() };
+            let (token, readiness) = decode(&event);
+
+            if f(token, readiness) {
+                buffer[kept].write(event);
+                kept += 1;
+            }
+        }
+
+        Ok(kept)
+    }
+
+    /// Create a [`Waker`] registered readable with `token`, other threads
+    /// use it to make a blocked poll return
+    pub fn waker(&mut self, token: Token) -> io::Result<Waker> {
+        let waker = Waker::new()?;
+
+        self.register(&waker.as_fd(), token, Interest::READABLE, Mode::Level)?;
+        self.wakers.insert(token, waker.clone());
+
+        Ok(waker)
+    }
+
+    /// Register a new source in the epoll instance
+    pub fn register<S: Source + ?Sized>(
+        &mut self,
+        source: &S,
+        token: Token,
+        interest: Interest,
+        mode: Mode
+    ) -> io::Result<()> {
+        source.register(self, token, interest, mode)
+    }
+
+    /// Register a source with the default interest and mode chosen with
+    /// [`EpollBuilder`], readable and level triggered unless changed
+    pub fn register_default<S: Source + ?Sized>(&mut self, source: &S, token: Token) -> io::Result<()> {
+        source.register(self, token, self.default_interest, self.default_mode)
+    }
+
+    /// A token namespace nobody else got from this instance or its clones,
+    /// see [`namespace`]. Fails once all of them are handed out
+    pub fn token_namespace(&self) -> io::Result<TokenNamespace> {
+        namespace::allocate(&self.namespaces)
+    }
+
+    /// Reregister a source in the epoll instance
+    /// often used when wanting to change say the mode or interest
+    pub fn reregister<S: Source + ?Sized>(
+        &mut self,
+        source: &S,
+        token: Token,
+        interest: Interest,
+        mode: Mode
+    ) -> io::Result<()> {
+        source.reregister(self, token, interest, mode)
+    }
+
+    /// Arm a one-shot registration again with the interest and mode it was
+    /// registered with, after an event disabled it
+    pub fn rearm<S: AsFd + ?Sized>(
+        &mut self,
+        source: &S,
+        token: Token
+    ) -> io::Result<()> {
+        self.rearm_fd(source.as_fd().as_raw_fd(), token, None)
+    }
+
+    /// Like [`Epoll::rearm`], switching to `interest`, e.g. from reading a
+    /// request to writing its response
+    pub fn rearm_with_interest<S: AsFd + ?Sized>(
+        &mut self,
+        source: &S,
+        token: Token,
+        interest: Interest
+    ) -> io::Result<()> {
+        self.rearm_fd(source.as_fd().as_raw_fd(), token, Some(interest))
+    }
+
+    /// Rearm with `interest`, or the registered one for None
+    fn rearm_fd(&mut self, fd: RawFd, token: Token, interest: Option<Interest>) -> io::Result<()> {
+        let registration = match self.registry.get(fd) {
+            Some(registration) => registration,
+            None => return Err(self.ctl_error(CtlOp::Modify, fd, None, rejected(io::ErrorKind::NotFound, "not registered"))),
+        };
+
+        if self.strict && !matches!(registration.mode, Mode::OneShot) {
+            return Err(self.ctl_error(CtlOp::Modify, fd, Some(registration), rejected(
+                io::ErrorKind::InvalidInput,
+                "only one-shot registrations are rearmed"
+            )));
+        }
+
+        let interest = interest.unwrap_or(registration.interest);
+        self.reregister_fd(fd, token, interest, registration.mode)
+    }
+
+    /// Register `source` for as long as the returned registration lives. The
+    /// source stays borrowed until then, it can not be closed while it is in
+    /// the interest list. Dropping the registration unregisters it
+    pub fn register_scoped<'a, S: AsFd + ?Sized>(
+        &mut self,
+        source: &'a S,
+        token: Token,
+        interest: Interest,
+        mode: Mode
+    ) -> io::Result<ScopedRegistration<'a>> {
+        let fd = source.as_fd();
+        self.add(fd.as_raw_fd(), Registration::new(token, interest, mode))?;
+
+        Ok(ScopedRegistration::new(fd, token, self.epoll_fd.clone(), self.released.clone()))
+    }
+
+    /// Forget the scoped registrations and owned sources dropped since the
+    /// last call, the kernel side was removed by their drop already. The
+    /// owned sources are reported to the leak hook
+    fn forget_released(&mut self) {
+        let released = std::mem::take(&mut *self.released.lock().unwrap_or_else(|err| err.into_inner()));
+
+        for (fd, token) in released {
+            if self.registry.get(fd).is_some_and(|registration| registration.token == token) {
+                self.remove_registration(fd);
+            }
+        }
+
+        let leaked = std::mem::take(&mut *self.leaked.lock().unwrap_or_else(|err| err.into_inner()));
+
+        for (fd, token) in leaked {
+            let registration = match self.registry.get(fd) {
+                Some(registration) if registration.token == token => registration,
+                _ => continue,
+            };
+
+            warn!(fd, token = token.0, "source dropped while registered");
+            if let Some(hook) = &mut self.leak_hook {
+                (hook.0)(RegistrationInfo {
+                    fd,
+                    token,
+                    interest: registration.interest,
+                    mode: registration.mode,
+                    label: registration.label.as_deref(),
+                });
+            }
+
+            self.remove_registration(fd);
+        }
+    }
+
+    /// Call `hook` with every registration whose [`Timer`](timer::Timer),
+    /// [`Signals`](signal::Signals) or [`Channel`](channel::Channel) was
+    /// dropped without being unregistered. The drop removes the fd from the
+    /// interest list, the instance forgets and reports the registration
+    /// with its next registration or poll:
+    ///
+    /// ```ignore
+    /// epoll.set_leak_hook(|leak| eprintln!("{:?} was dropped while registered", leak.token));
+    /// ```
+    pub fn set_leak_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(RegistrationInfo<'_>) + Send + 'static,
+    {
+        self.leak_hook = Some(LeakHook(Box::new(hook)));
+    }
+
+    /// Where the owned sources registered with this instance report their drop
+    pub(crate) fn leak_list(&self) -> (&Arc<OwnedFd>, &Leaked) {
+        (&self.epoll_fd, &self.leaked)
+    }
+
+    /// Change only the interest of the registration with `token`, keeping
+    /// its mode and the rest of what the instance remembers about it. A
+    /// one-shot registration is armed again. The token has to be unique,
+    /// tokens are not indexed, this walks every registration
+    pub fn set_interest(&mut self, token: Token, interest: Interest) -> io::Result<()> {
+        let (fd, mode) = match self.by_token(token) {
+            Some((fd, registration)) => (fd, registration.mode),
+            None => return Err(unknown_token()),
+        };
+
+        self.reregister_fd(fd, token, interest, mode)
+    }
+
+    /// Change only the mode of the registration with `token`, keeping its
+    /// interest, see [`Epoll::set_interest`]
+    pub fn set_mode(&mut self, token: Token, mode: Mode) -> io::Result<()> {
+        let (fd, interest) = match self.by_token(token) {
+            Some((fd, registration)) => (fd, registration.interest),
+            None => return Err(unknown_token()),
+        };
+
+        self.reregister_fd(fd, token, interest, mode)
+    }
+
+    /// Stop polling events of a source
+    pub fn unregister<S: Source + ?Sized>(
+        &mut self,
+        source: &S
+    ) -> io::Result<()> {
+        source.unregister(self)
+    }
+
+    pub(crate) fn register_fd(
+        &mut self,
+        fd: RawFd,
+        token: Token,
+        interest: Interest,
+        mode: Mode
+    ) -> io::Result<()> {
+         self.add(fd, Registration::new(token, interest, mode))
+    }
+
+    /// Register a new file descriptor with a human readable label, e.g. "client:10.0.0.5".
+    /// The label is kept for as long as the fd is registered and is included in
+    /// the [`CtlError`] of every failed operation on it
+    pub fn register_named<S: AsFd + ?Sized, L: Into<String>>(
+        &mut self,
+        source: &S,
+        token: Token,
+        label: L,
+        interest: Interest,
+        mode: Mode
+    ) -> io::Result<()> {
+        let fd = source.as_fd().as_raw_fd();
+        let label = Some(label.into());
+        self.add(fd, Registration { label, ..Registration::new(token, interest, mode) })
+    }
+
+    /// Get the label a file descriptor was registered with
+    pub fn label<S: AsFd + ?Sized>(&self, source: &S) -> Option<&str> {
+        self.label_of(source.as_fd().as_raw_fd())
+    }
+
+    fn label_of(&self, fd: RawFd) -> Option<&str> {
+        self.registry.get(fd).and_then(|registration| registration.label.as_deref())
+    }
+
+    /// Register a new file descriptor with `data` attached, e.g. the state of
+    /// a connection. The token comes from an internal slab, look the data up
+    /// with it in [`Epoll::data`]. It is dropped when the fd is unregistered.
+    /// Do not mix these tokens with hand picked ones in one instance
+    pub fn register_with_data<S: AsFd + ?Sized, T: Any + Send>(
+        &mut self,
+        source: &S,
+        interest: Interest,
+        mode: Mode,
+        data: T
+    ) -> io::Result<Token> {
+        let fd = source.as_fd().as_raw_fd();
+        let token = self.user_data.vacant_token();
+
+        self.add(fd, Registration::new(token, interest, mode))?;
+        Ok(self.user_data.insert(UserData { fd, data: Box::new(data) }))
+    }
+
+    /// The data registered with `token`, None if there is none or it is not
+    /// a `T`
+    pub fn data<T: Any>(&self, token: Token) -> Option<&T> {
+        self.user_data.get(token).and_then(|entry| entry.data.downcast_ref())
+    }
+
+    pub fn data_mut<T: Any>(&mut self, token: Token) -> Option<&mut T> {
+        self.user_data.get_mut(token).and_then(|entry| entry.data.downcast_mut())
+    }
+
+    /// Choose what happens to a registered file descriptor once poll reports an
+    /// error or a hangup for it, so a dead connection whose handler forgot to
+    /// unregister does not keep waking up every poll. The event itself is still
+    /// delivered once. Registrations start out with OnError::Keep.
+    /// The policy finds the registration by its token, which has to be unique
+    pub fn set_on_error<S: AsFd + ?Sized>(
+        &mut self,
+        source: &S,
+        policy: OnError
+    ) -> io::Result<()> {
+        let fd = source.as_fd().as_raw_fd();
+        let registration = match self.registry.get_mut(fd) {
+            Some(registration) => registration,
+            None => return Err(self.ctl_error(
+                CtlOp::Modify,
+                fd,
+                None,
+                rejected(io::ErrorKind::NotFound, "not registered")
+            )),
+        };
+
+        registration.on_error = policy;
+        let token = registration.token;
+
+        match policy {
+            OnError::Keep => self.error_policies.remove(&token),
+            OnError::Disable | OnError::Remove | OnError::RemoveOnHangup => self.error_policies.insert(token, fd),
+        };
+
+        Ok(())
+    }
+
+    /// Returns true if the file descriptor was disabled by its OnError policy,
+    /// reregister it to receive its events again
+    pub fn is_disabled<S: AsFd + ?Sized>(&self, source: &S) -> bool {
+        self.registry.get(source.as_fd().as_raw_fd()).is_some_and(|registration| self.disabled.contains(&registration.token))
+    }
+
+    /// Returns true if the file descriptor is registered in this instance
+    pub fn is_registered<S: AsFd + ?Sized>(&self, source: &S) -> bool {
+        self.registry.get(source.as_fd().as_raw_fd()).is_some()
+    }
+
+    /// The token a file descriptor is registered with
+    pub fn token_for<S: AsFd + ?Sized>(&self, source: &S) -> Option<Token> {
+        self.registry.get(source.as_fd().as_raw_fd()).map(|registration| registration.token)
+    }
+
+    /// The interest of the registration with `token`. Tokens are not indexed,
+    /// this walks every registration
+    pub fn interest_of(&self, token: Token) -> Option<Interest> {
+        self.by_token(token).map(|(_, registration)| registration.interest)
+    }
+
+    /// The mode of the registration with `token`, see [`Epoll::interest_of`]
+    pub fn mode_of(&self, token: Token) -> Option<Mode> {
+        self.by_token(token).map(|(_, registration)| registration.mode)
+    }
+
+    fn by_token(&self, token: Token) -> Option<(RawFd, &Registration)> {
+        self.registry.iter().find(|(_, registration)| registration.token == token)
+    }
+
+    /// Number of registered file descriptors
+    pub fn registered(&self) -> usize {
+        self.registry.len()
+    }
+
+    /// Iterate over every registration, e.g. to find leaked ones or to
+    /// unregister everything on shutdown. Registrations are only known to
+    /// this instance if they went through it, a fd closed without being
+    /// unregistered is listed until its number is reused
+    pub fn registrations(&self) -> impl Iterator<Item = RegistrationInfo<'_>> {
+        self.registry.iter().map(|(fd, registration)| RegistrationInfo {
+            fd,
+            token: registration.token,
+            interest: registration.interest,
+            mode: registration.mode,
+            label: registration.label.as_deref(),
+        })
+    }
+
+    /// Register a file descriptor that is shared with other epoll instances,
+    /// usually a listener inherited by several worker processes or threads.
+    /// It is added with EPOLLEXCLUSIVE so an incoming event wakes up one (or a few)
+    /// of the instances instead of all of them.
+    /// The kernel refuses to reregister exclusive entries and to combine them with
+    /// Mode::OneShot, unregister and register again instead
+    pub fn register_exclusive<S: AsFd + ?Sized>(
+        &mut self,
+        source: &S,
+        token: Token,
+        interest: Interest,
+        mode: Mode
+    ) -> io::Result<()> {
+         self.add(source.as_fd().as_raw_fd(), Registration { exclusive: true, ..Registration::new(token, interest, mode) })
+    }
+
+    /// Register a duplicate of the file descriptor, so one file can be registered
+    /// several times with different tokens and interests, e.g. by two subsystems
+    /// sharing a socket. Epoll refuses to add the same fd twice but treats dups
+    /// independently. The duplicate is owned by the instance, remove it with
+    /// [`Epoll::unregister_dup`]. Every registration of the file reports its own
+    /// events, reading or accepting through one affects all of them
+    pub fn register_dup<S: AsFd + ?Sized>(
+        &mut self,
+        source: &S,
+        token: Token,
+        interest: Interest,
+        mode: Mode
+    ) -> io::Result<()> {
+        let registration = Registration { dup: true, ..Registration::new(token, interest, mode) };
+        let fd = source.as_fd().as_raw_fd();
+
+        let dup = sys::dup(fd).map_err(|err| self.ctl_error(CtlOp::Add, fd, Some(&registration), err))?;
+        self.add(dup.as_raw_fd(), registration)?;
+        self.dups.insert(dup.as_raw_fd(), dup);
+
+        Ok(())
+    }
+
+    /// Unregister the duplicate registered with `token` by [`Epoll::register_dup`]
+    /// and close it
+    pub fn unregister_dup(&mut self, token: Token) -> io::Result<()> {
+        let fd = self.dups.keys()
+            .copied()
+            .find(|fd| self.registry.get(*fd).is_some_and(|registration| registration.token == token));
+
+        match fd {
+            Some(fd) => self.unregister_fd(fd),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "no duplicate registered with this token")),
+        }
+    }
+
+    /// Register a file descriptor with EPOLLWAKEUP, for systems that autosleep.
+    /// From the moment one of its events is queued until the next poll the kernel
+    /// holds a wakeup source, so the system can not suspend before the event is
+    /// handled. Reregistering keeps the flag.
+    /// Needs CAP_BLOCK_SUSPEND, without it the kernel quietly registers the fd
+    /// without the flag, see [`Epoll::wakeup_allowed`]
+    pub fn register_wakeup<S: AsFd + ?Sized>(
+        &mut self,
+        source: &S,
+        token: Token,
+        interest: Interest,
+        mode: Mode
+    ) -> io::Result<()> {
+         self.add(source.as_fd().as_raw_fd(), Registration { wakeup: true, ..Registration::new(token, interest, mode) })
+    }
+
+    /// Returns true if EPOLLWAKEUP registrations actually hold off suspend: the
+    /// kernel supports system sleep and the process has CAP_BLOCK_SUSPEND
+    pub fn wakeup_allowed() -> bool {
+        const CAP_BLOCK_SUSPEND: u32 = 36;
+
+        // Only present with CONFIG_PM_SLEEP, without it the flag is always dropped
+        if !std::path::Path::new("/sys/power/wakeup_count").exists() {
+            return false;
+        }
+
+        let status = match std::fs::read_to_string("/proc/self/status") {
+            Ok(status) => status,
+            Err(_) => return false,
+        };
+
+        status.lines()
+            .find_map(|line| line.strip_prefix("CapEff:"))
+            .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+            .is_some_and(|caps| caps & (1 << CAP_BLOCK_SUSPEND) != 0)
+    }
+
+    pub(crate) fn reregister_fd(
+        &mut self,
+        fd: RawFd,
+        token: Token,
+        interest: Interest,
+        mode: Mode
+    ) -> io::Result<()> {
+         self.forget_released();
+         let attempted = Registration::new(token, interest, mode);
+
+         let existing = self.registry.get(fd);
+         let wakeup = existing.is_some_and(|registration| registration.wakeup);
+
+         if self.strict {
+             let reason = match existing {
+                 None => Some((io::ErrorKind::NotFound, "not registered")),
+                 Some(registration) if registration.exclusive => Some((
+                     io::ErrorKind::InvalidInput,
+                     "exclusive registrations can not be reregistered"
+                 )),
+                 Some(_) => None,
+             };
+
+             if let Some((kind, reason)) = reason {
+                 return Err(self.ctl_error(CtlOp::Modify, fd, Some(&attempted), rejected(kind, reason)));
+             }
+         }
+
+         self.prepare_edge(CtlOp::Modify, fd, &attempted)?;
+
+         if self.fallible_alloc && existing.is_none() {
+             if let Err(err) = self.registry.try_reserve(fd, None) {
+                 return Err(self.ctl_error(CtlOp::Modify, fd, Some(&attempted), alloc_error(err)));
+             }
+         }
+
+         let mut flags = make_flags(interest, mode);
+         // A MOD replaces every flag, the wakeup one has to be passed again
+         if wakeup {
+             flags |= libc::EPOLLWAKEUP as u32;
+         }
+
+         let result = sys::epoll_ctl(self.epoll_fd.as_raw_fd(), libc::EPOLL_CTL_MOD, fd, flags, usize::from(token) as u64);
+
+         if let Some(stale) = result.as_ref().err().and_then(|err| self.take_stale_after(fd, err)) {
+             return Err(self.reused_error(CtlOp::Modify, fd, Some(&attempted), stale));
+         }
+
+         let result = result.map_err(|err| self.ctl_error(CtlOp::Modify, fd, Some(&attempted), err));
+
+         trace!(fd, token = token.0, interest = %interest, mode = ?mode, ok = result.is_ok(), "reregister");
+         result?;
+
+         match self.registry.get_mut(fd) {
+             Some(registration) => {
+                 let previous = registration.token;
+                 registration.token = token;
+                 registration.interest = interest;
+                 registration.mode = mode;
+
+                 self.disabled.remove(&previous);
+                 if self.error_policies.remove(&previous).is_some() {
+                     self.error_policies.insert(token, fd);
+                 }
+             }
+             None => self.registry.insert(fd, attempted),
+         }
+
+         Ok(())
+    }
+
+    pub(crate) fn unregister_fd(
+        &mut self,
+        fd: RawFd
+    ) -> io::Result<()> {
+        self.forget_released();
+
+        if self.strict && self.registry.get(fd).is_none() {
+            return Err(self.ctl_error(CtlOp::Delete, fd, None, rejected(io::ErrorKind::NotFound, "not registered")));
+        }
+
+        let result = sys::epoll_ctl(self.epoll_fd.as_raw_fd(), libc::EPOLL_CTL_DEL, fd, 0, 0);
+
+        if let Some(stale) = result.as_ref().err().and_then(|err| self.take_stale_after(fd, err)) {
+            return Err(self.reused_error(CtlOp::Delete, fd, None, stale));
+        }
+
+        let existing = self.registry.get(fd);
+        let result = result.map_err(|err| self.ctl_error(CtlOp::Delete, fd, existing, err));
+
+        trace!(fd, token = existing.map(|registration| registration.token.0), ok = result.is_ok(), "unregister");
+        result?;
+
+        self.remove_registration(fd);
+        Ok(())
+    }
+
+    fn add(&mut self, fd: RawFd, mut registration: Registration) -> io::Result<()> {
+        self.forget_released();
+        registration.file = duplicate::file_id(fd);
+
+        if let Some(stale) = self.take_stale(fd, registration.file) {
+            return Err(self.reused_error(CtlOp::Add, fd, Some(&registration), stale));
+        }
+
+        // Duplicates made by register_dup are there on purpose
+        if self.registry.indexes_files() && !registration.dup {
+            self.check_duplicate(fd, &registration)?;
+        }
+
+        if self.strict {
+            if self.registry.get(fd).is_some() {
+                return Err(self.ctl_error(CtlOp::Add, fd, Some(&registration), rejected(
+                    io::ErrorKind::AlreadyExists,
+                    "already registered"
+                )));
+            }
+
+            if registration.exclusive && matches!(registration.mode, Mode::OneShot) {
+                return Err(self.ctl_error(CtlOp::Add, fd, Some(&registration), rejected(
+                    io::ErrorKind::InvalidInput,
+                    "exclusive registrations can not be one-shot"
+                )));
+            }
+        }
+
+        self.prepare_edge(CtlOp::Add, fd, &registration)?;
+
+        if self.fallible_alloc {
+            if let Err(err) = self.registry.try_reserve(fd, registration.file) {
+                return Err(self.ctl_error(CtlOp::Add, fd, Some(&registration), alloc_error(err)));
+            }
+        }
+
+        let mut flags = make_flags(registration.interest, registration.mode);
+        if registration.exclusive {
+            flags |= libc::EPOLLEXCLUSIVE as u32;
+        }
+        if registration.wakeup {
+            flags |= libc::EPOLLWAKEUP as u32;
+        }
+
+        let data = usize::from(registration.token) as u64;
+        let result = sys::epoll_ctl(self.epoll_fd.as_raw_fd(), libc::EPOLL_CTL_ADD, fd, flags, data)
+            .map_err(|err| self.ctl_error(CtlOp::Add, fd, Some(&registration), err));
+
+        trace!(
+            fd,
+            token = registration.token.0,
+            interest = %registration.interest,
+            mode = ?registration.mode,
+            ok = result.is_ok(),
+            "register"
+        );
+        result?;
+
+        self.registry.insert(fd, registration);
+        Ok(())
+    }
+
+    /// Switch an edge triggered fd to non-blocking mode, or reject a blocking
+    /// one in strict mode
+    fn prepare_edge(&self, op: CtlOp, fd: RawFd, registration: &Registration) -> io::Result<()> {
+        if !matches!(registration.mode, Mode::Edge) || !(self.edge_nonblocking || self.strict) {
+            return Ok(());
+        }
+
+        let flags = sys::get_flags(fd)
+            .map_err(|err| self.ctl_error(op, fd, Some(registration), err))?;
+
+        if flags & libc::O_NONBLOCK != 0 {
+            return Ok(());
+        }
+
+        if !self.edge_nonblocking {
+            return Err(self.ctl_error(op, fd, Some(registration), rejected(
+                io::ErrorKind::InvalidInput,
+                "edge triggered fds must be non-blocking"
+            )));
+        }
+
+        sys::set_flags(fd, flags | libc::O_NONBLOCK)
+            .map_err(|err| self.ctl_error(op, fd, Some(registration), err))
+    }
+
+    /// Attach the context of a failed ctl operation to its error
+    fn ctl_error(
+        &self,
+        op: CtlOp,
+        fd: RawFd,
+        registration: Option<&Registration>,
+        source: io::Error
+    ) -> io::Error {
+        self.ctl_context(op, fd, registration, source).into()
+    }
+
+    /// The label is taken from the current registration if the attempt has none
+    fn ctl_context(
+        &self,
+        op: CtlOp,
+        fd: RawFd,
+        registration: Option<&Registration>,
+        source: io::Error
+    ) -> CtlError {
+        let mut err = CtlError::new(op, fd, source);
+
+        if let Some(registration) = registration {
+            err.token = Some(registration.token);
+            err.interest = Some(registration.interest);
+            err.mode = Some(registration.mode);
+            err.label = registration.label.clone();
+        }
+
+        if err.label.is_none() {
+            err.label = self.label_of(fd).map(String::from);
+        }
+
+        err
+    }
+
+    /// Remove the registration of `fd` if it was made for another file than
+    /// the one `fd` refers to now, the fd was closed without being unregistered
+    /// and its number got reused
+    fn take_stale(&mut self, fd: RawFd, current: Option<FileId>) -> Option<Registration> {
+        let registered = self.registry.get(fd)?.file;
+
+        match (registered, current) {
+            (Some(registered), Some(current)) if registered != current => self.remove_registration(fd),
+            _ => None,
+        }
+    }
+
+    /// Check for a reused fd after a MOD or DEL failed. The kernel forgets
+    /// an entry once its file is closed, so a reused fd number fails with
+    /// ENOENT and only then costs an fstat
+    fn take_stale_after(&mut self, fd: RawFd, err: &io::Error) -> Option<Registration> {
+        if err.raw_os_error() != Some(libc::ENOENT) {
+            return None;
+        }
+
+        self.take_stale(fd, duplicate::file_id(fd))
+    }
+
+    /// Drop the registration of `fd` together with its OnError state
+    fn remove_registration(&mut self, fd: RawFd) -> Option<Registration> {
+        let registration = self.registry.remove(fd)?;
+
+        if registration.on_error != OnError::Keep {
+            self.error_policies.remove(&registration.token);
+        }
+        self.disabled.remove(&registration.token);
+        self.wakers.remove(&registration.token);
+
+        if self.user_data.get(registration.token).is_some_and(|entry| entry.fd == fd) {
+            self.user_data.remove(registration.token);
+        }
+
+        if registration.dup {
+            self.dups.remove(&fd);
+        }
+
+        Some(registration)
+    }
+
+    /// Disable or remove a registration that reported an error or a hangup.
+    /// Failures are ignored, e.g. the fd may already be closed, which removes
+    /// it from epoll anyway
+    fn apply_on_error(&mut self, fd: RawFd, token: Token, readiness: Readiness) {
+        let policy = match self.registry.get(fd) {
+            Some(registration) if registration.token == token => registration.on_error,
+            _ => return,
+        };
+
+        match policy {
+            OnError::Keep => {}
+            OnError::Disable => {
+                // The kernel always reports errors and hangups, a MOD can not mask
+                // them. A one-shot entry goes quiet after its next event though,
+                // which is swallowed since the token is disabled
+                let oneshot = libc::EPOLLONESHOT as u32;
+                let _ = sys::epoll_ctl(self.epoll_fd.as_raw_fd(), libc::EPOLL_CTL_MOD, fd, oneshot, usize::from(token) as u64);
+                self.disabled.insert(token);
+            }
+            OnError::RemoveOnHangup if !readiness.hangup || readiness.readable => {}
+            OnError::Remove | OnError::RemoveOnHangup => {
+                let _ = sys::epoll_ctl(self.epoll_fd.as_raw_fd(), libc::EPOLL_CTL_DEL, fd, 0, 0);
+                self.remove_registration(fd);
+            }
+        }
+    }
+
+    fn reused_error(
+        &self,
+        op: CtlOp,
+        fd: RawFd,
+        registration: Option<&Registration>,
+        stale: Registration
+    ) -> io::Error {
+        let reason = match &stale.label {
+            Some(label) => format!(
+                "fd was closed without being unregistered and reused, dropped the stale registration of token {} ({})",
+                stale.token.0, label
+            ),
+            None => format!(
+                "fd was closed without being unregistered and reused, dropped the stale registration of token {}",
+                stale.token.0
+            ),
+        };
+
+        let mut err = self.ctl_context(op, fd, registration, io::Error::other(reason))
+            .with_kind(CtlErrorKind::FdReused);
+        err.stale_token = Some(stale.token);
+
+        err.into()
+    }
+
+    fn check_duplicate(&self, fd: RawFd, registration: &Registration) -> io::Result<()> {
+        let file = match registration.file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+
+        let other = self.registry.same_file(file).iter()
+            .copied()
+            .find(|other| *other != fd && duplicate::same_description(fd, *other));
+
+        let other = match other.and_then(|other| self.registry.get(other).map(|r| (other, r))) {
+            Some(other) => other,
+            None => return Ok(()),
+        };
+
+        let what = match other.1.label.as_deref() {
+            Some(label) => format!("shares its open file description with fd {} ({})", other.0, label),
+            None => format!("shares its open file description with fd {} (token {})", other.0, other.1.token.0),
+        };
+
+        if self.strict {
+            let source = io::Error::new(io::ErrorKind::AlreadyExists, what);
+            return Err(self.ctl_error(CtlOp::Add, fd, Some(registration), source));
+        }
+
+        warn!(
+            fd,
+            token = registration.token.0,
+            label = registration.label.as_deref(),
+            "{}, events will be reported twice",
+            what
+        );
+
+        Ok(())
+    }
+
+}
+
+impl AsRawFd for Epoll {
+    fn as_raw_fd(&self) -> RawFd {
+       self.epoll_fd.as_raw_fd()
+    }    
+}
+
+impl AsFd for Epoll {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.epoll_fd.as_fd()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+
+    use super::*;
+
+    /// Make the fd number of `victim` refer to a new socket, the way a close
+    /// without unregistering followed by another open reuses it
+    fn reuse_fd(victim: &UnixStream) -> UnixStream {
+        let (replacement, peer) = UnixStream::pair().unwrap();
+        assert!(unsafe { libc::dup2(replacement.as_raw_fd(), victim.as_raw_fd()) } >= 0);
+        peer
+    }
+
+    fn ctl_kind(err: &io::Error) -> CtlErrorKind {
+        CtlError::of(err).expect("not a ctl error").kind()
+    }
+
+    #[test]
+    fn errnos_survive_the_conversion_to_io_error() {
+        fn register_twice(epoll: &mut Epoll, stream: &UnixStream) -> io::Result<()> {
+            epoll.register(stream, Token(1), Interest::READABLE, Mode::Level)?;
+            epoll.register(stream, Token(2), Interest::READABLE, Mode::Level)?;
+            Ok(())
+        }
+
+        let mut epoll = Epoll::create().unwrap();
+        let (stream, _peer) = UnixStream::pair().unwrap();
+
+        let err = register_twice(&mut epoll, &stream).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(CtlError::raw_os_error(&err), Some(libc::EEXIST));
+        assert_eq!(CtlError::raw_os_error(&io::Error::from_raw_os_error(libc::EBADF)), Some(libc::EBADF));
+        assert_eq!(CtlError::raw_os_error(&io::Error::from(io::ErrorKind::Other)), None);
+    }
+
+    #[test]
+    fn reused_fd_is_detected_on_register() {
+        let mut epoll = Epoll::create().unwrap();
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        epoll.register(&stream, Token(1), Interest::READABLE, Mode::Level).unwrap();
+
+        let _peer = reuse_fd(&stream);
+
+        let err = epoll.register(&stream, Token(2), Interest::READABLE, Mode::Level).unwrap_err();
+        assert_eq!(ctl_kind(&err), CtlErrorKind::FdReused);
+        assert_eq!(CtlError::of(&err).unwrap().stale_token, Some(Token(1)));
+
+        // The stale entry is gone, registering again succeeds
+        epoll.register(&stream, Token(2), Interest::READABLE, Mode::Level).unwrap();
+    }
+
+    #[test]
+    fn reused_fd_is_detected_on_reregister_and_unregister() {
+        let mut epoll = Epoll::create().unwrap();
+        let (first, _first_peer) = UnixStream::pair().unwrap();
+        let (second, _second_peer) = UnixStream::pair().unwrap();
+        epoll.register(&first, Token(1), Interest::READABLE, Mode::Level).unwrap();
+        epoll.register(&second, Token(2), Interest::READABLE, Mode::Level).unwrap();
+
+        let _first_peer = reuse_fd(&first);
+        let _second_peer = reuse_fd(&second);
+
+        let err = epoll.reregister(&first, Token(1), Interest::WRITABLE, Mode::Level).unwrap_err();
+        assert_eq!(ctl_kind(&err), CtlErrorKind::FdReused);
+
+        let err = epoll.unregister(&second).unwrap_err();
+        assert_eq!(ctl_kind(&err), CtlErrorKind::FdReused);
+        assert_eq!(CtlError::of(&err).unwrap().stale_token, Some(Token(2)));
+    }
+
+    #[test]
+    fn live_fds_are_not_reported_as_reused() {
+        let mut epoll = Epoll::create().unwrap();
+        let (stream, _peer) = UnixStream::pair().unwrap();
+
+        epoll.register(&stream, Token(1), Interest::READABLE, Mode::Level).unwrap();
+        epoll.reregister(&stream, Token(3), Interest::BOTH, Mode::Level).unwrap();
+        epoll.unregister(&stream).unwrap();
+
+        let err = epoll.unregister(&stream).unwrap_err();
+        assert_eq!(ctl_kind(&err), CtlErrorKind::NotRegistered);
+    }
+
+    #[test]
+    fn duplicates_are_accepted_or_rejected_in_strict_mode() {
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        let duplicate = stream.try_clone().unwrap();
+
+        let mut epoll = Epoll::builder().detect_duplicates(true).build().unwrap();
+        epoll.register(&stream, Token(1), Interest::READABLE, Mode::Level).unwrap();
+        epoll.register(&duplicate, Token(2), Interest::READABLE, Mode::Level).unwrap();
+
+        let mut strict = Epoll::builder().detect_duplicates(true).strict(true).build().unwrap();
+        strict.register(&stream, Token(1), Interest::READABLE, Mode::Level).unwrap();
+        let err = strict.register(&duplicate, Token(2), Interest::READABLE, Mode::Level).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn strict_mode_only_rearms_oneshot_registrations() {
+        let (level, _level_peer) = UnixStream::pair().unwrap();
+        let (oneshot, _oneshot_peer) = UnixStream::pair().unwrap();
+
+        let mut epoll = Epoll::builder().strict(true).build().unwrap();
+        epoll.register(&level, Token(1), Interest::READABLE, Mode::Level).unwrap();
+        epoll.register(&oneshot, Token(2), Interest::READABLE, Mode::OneShot).unwrap();
+
+        let err = epoll.rearm(&level, Token(1)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = epoll.rearm_with_interest(&level, Token(1), Interest::WRITABLE).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        epoll.rearm(&oneshot, Token(2)).unwrap();
+        epoll.rearm_with_interest(&oneshot, Token(2), Interest::WRITABLE).unwrap();
+
+        let err = epoll.register(&level, Token(3), Interest::READABLE, Mode::Level).unwrap_err();
+        assert_eq!(ctl_kind(&err), CtlErrorKind::AlreadyRegistered);
+
+        let mut lenient = Epoll::create().unwrap();
+        lenient.register(&level, Token(1), Interest::READABLE, Mode::Level).unwrap();
+        lenient.rearm(&level, Token(1)).unwrap();
+    }
+
+    #[test]
+    fn fast_path_sees_registrations_made_through_a_clone() {
+        let mut epoll = Epoll::builder().fast_path(true).build().unwrap();
+        let (first, _first_peer) = UnixStream::pair().unwrap();
+        let (second, second_peer) = UnixStream::pair().unwrap();
+        epoll.register(&first, Token(1), Interest::READABLE, Mode::Level).unwrap();
+
+        let mut clone = epoll.try_clone().unwrap();
+        clone.register(&second, Token(2), Interest::READABLE, Mode::Level).unwrap();
+        (&second_peer).write_all(b"x").unwrap();
+
+        let events = epoll.poll(Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(events.iter().map(|(token, _)| token).collect::<Vec<_>>(), [Token(2)]);
+    }
+
+    #[test]
+    fn fast_path_skips_a_reused_fd() {
+        let mut epoll = Epoll::builder().fast_path(true).build().unwrap();
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        epoll.register(&stream, Token(1), Interest::READABLE, Mode::Level).unwrap();
+
+        let replacement_peer = reuse_fd(&stream);
+        (&replacement_peer).write_all(b"x").unwrap();
+
+        // The kernel dropped the entry with the old file, the new one is unknown
+        assert!(epoll.poll(Some(Duration::from_millis(10))).unwrap().is_empty());
+    }
+
+    #[test]
+    fn fast_path_leaves_exclusive_registrations_to_epoll() {
+        let listener = Arc::new(std::net::TcpListener::bind("127.0.0.1:0").unwrap());
+        listener.set_nonblocking(true).unwrap();
+        let barrier = Arc::new(std::sync::Barrier::new(4));
+
+        let workers: Vec<_> = (0..3)
+            .map(|_| {
+                let listener = listener.clone();
+                let barrier = barrier.clone();
+
+                std::thread::spawn(move || {
+                    let mut epoll = Epoll::builder().fast_path(true).build().unwrap();
+                    epoll.register_exclusive(&*listener, Token(0), Interest::READABLE, Mode::Level).unwrap();
+
+                    barrier.wait();
+                    !epoll.poll(Some(Duration::from_millis(500))).unwrap().is_empty()
+                })
+            })
+            .collect();
+
+        // poll(2) would wake every instance blocked on the listener
+        barrier.wait();
+        std::thread::sleep(Duration::from_millis(100));
+        let _stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        let woken = workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap())
+            .filter(|woken| *woken)
+            .count();
+        assert!((1..3).contains(&woken), "{woken} of 3 instances woken");
+    }
+
+    /// Interrupt the blocking syscall the calling thread makes within
+    /// `delay` with SIGURG, which is ignored unless it has a handler
+    fn interrupt_after(delay: Duration) -> std::thread::JoinHandle<()> {
+        extern "C" fn ignore(_: libc::c_int) {}
+
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = ignore as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            assert_eq!(libc::sigaction(libc::SIGURG, &action, std::ptr::null_mut()), 0);
+        }
+
+        let thread = unsafe { libc::pthread_self() };
+        std::thread::spawn(move || {
+            std::thread::sleep(delay);
+            assert_eq!(unsafe { libc::pthread_kill(thread, libc::SIGURG) }, 0);
+        })
+    }
+
+    #[test]
+    fn poll_until_resumes_after_a_signal() {
+        let mut epoll = Epoll::create().unwrap();
+        let mut events = Events::new();
+        let deadline = Instant::now() + Duration::from_millis(100);
+
+        let interrupter = interrupt_after(Duration::from_millis(20));
+        assert_eq!(epoll.poll_until(&mut events, deadline).unwrap(), 0);
+        assert!(Instant::now() >= deadline);
+        interrupter.join().unwrap();
+    }
+
+    #[test]
+    fn interrupted_waits_fail_unless_retried() {
+        let mut epoll = Epoll::create().unwrap();
+        let mut events = Events::new();
+
+        let interrupter = interrupt_after(Duration::from_millis(20));
+        let err = epoll.poll_into(&mut events, Duration::from_secs(5)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        interrupter.join().unwrap();
+
+        epoll.set_retry_interrupted(true);
+        let start = Instant::now();
+        let interrupter = interrupt_after(Duration::from_millis(20));
+        assert_eq!(epoll.poll_into(&mut events, Duration::from_millis(100)).unwrap(), 0);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        interrupter.join().unwrap();
+    }
+
+    #[test]
+    fn wait_for_resumes_after_a_signal() {
+        let (stream, peer) = UnixStream::pair().unwrap();
+        let start = Instant::now();
+
+        let interrupter = interrupt_after(Duration::from_millis(20));
+        assert!(wait_for(&stream, Interest::READABLE, Some(Duration::from_millis(100))).unwrap().is_none());
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        interrupter.join().unwrap();
+
+        (&peer).write_all(b"x").unwrap();
+        let readiness = wait_for(&stream, Interest::READABLE, None).unwrap().unwrap();
+        assert!(readiness.readable);
+    }
+}
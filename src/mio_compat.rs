@@ -0,0 +1,163 @@
+//! Driving mio based components from a copoll loop, with the `mio-compat`
+//! feature.
+//!
+//! A [`MioBridge`] owns a `mio::Poll` and is a [`Source`](crate::Source) for
+//! its epoll fd, which is readable while any of its registrations has an
+//! event pending. Code written against `mio::Registry` keeps registering its
+//! `mio::event::Source`s with [`MioBridge::registry`], and the copoll loop
+//! hands the events to it through [`MioBridge::dispatch`]:
+//!
+//! ```ignore
+//! let mut bridge = MioBridge::new()?;
+//! bridge.register(&mut listener, Token(0), Interest::READABLE)?;
+//! epoll.register(&bridge, MIO, Interest::READABLE, Mode::Level)?;
+//!
+//! // in the dispatch of MIO
+//! bridge.dispatch(|event| component.handle(event))?;
+//! ```
+//!
+//! Tokens of the bridge are separate from the ones of the outer instance,
+//! and registrations keep the mio semantics, they are always edge triggered.
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::time::Duration;
+
+use mio::event::{Event, Source};
+use mio::{Events, Poll, Registry};
+
+use crate::{Interest, Token};
+
+/// Events collected per poll of the nested instance by [`MioBridge::new`]
+const DEFAULT_CAPACITY: usize = 256;
+
+impl From<mio::Token> for Token {
+    fn from(token: mio::Token) -> Token {
+        Token(token.0)
+    }
+}
+
+impl From<Token> for mio::Token {
+    fn from(token: Token) -> mio::Token {
+        mio::Token(token.0)
+    }
+}
+
+impl From<mio::Interest> for Interest {
+    fn from(interest: mio::Interest) -> Interest {
+        let mut converted = Interest::empty();
+
+        if interest.is_readable() {
+            converted |= Interest::READABLE;
+        }
+
+        if interest.is_writable() {
+            converted |= Interest::WRITABLE;
+        }
+
+        if interest.is_priority() {
+            converted |= Interest::PRIORITY;
+        }
+
+        converted
+    }
+}
+
+/// The mio interest of `interest`, mio always requests read closed events.
+/// Fails for an interest mio can not express
+fn to_mio_interest(interest: Interest) -> io::Result<mio::Interest> {
+    [
+        (Interest::READABLE, mio::Interest::READABLE),
+        (Interest::WRITABLE, mio::Interest::WRITABLE),
+        (Interest::PRIORITY, mio::Interest::PRIORITY),
+    ]
+    .into_iter()
+    .filter(|(copoll, _)| interest.contains(*copoll))
+    .map(|(_, mio)| mio)
+    .reduce(mio::Interest::add)
+    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "mio needs a readable, writable or priority interest"))
+}
+
+/// A `mio::Poll` nested in a copoll instance, see the [module docs](self)
+#[derive(Debug)]
+pub struct MioBridge {
+    poll: Poll,
+    events: Events,
+}
+
+impl MioBridge {
+    pub fn new() -> io::Result<MioBridge> {
+        MioBridge::with_capacity(DEFAULT_CAPACITY)
+    }
+
+    /// Collect up to `capacity` events per poll of the nested instance
+    pub fn with_capacity(capacity: usize) -> io::Result<MioBridge> {
+        Ok(MioBridge::from_poll(Poll::new()?, capacity))
+    }
+
+    /// Bridge a poll mio sources are already registered with
+    pub fn from_poll(poll: Poll, capacity: usize) -> MioBridge {
+        MioBridge { poll, events: Events::with_capacity(capacity.max(1)) }
+    }
+
+    /// The registry for code written against mio, it can be cloned with
+    /// `try_clone` and used from other threads
+    pub fn registry(&self) -> &Registry {
+        self.poll.registry()
+    }
+
+    /// Register a mio source with copoll's token and interest types
+    pub fn register<S: Source + ?Sized>(&self, source: &mut S, token: Token, interest: Interest) -> io::Result<()> {
+        self.registry().register(source, token.into(), to_mio_interest(interest)?)
+    }
+
+    pub fn reregister<S: Source + ?Sized>(&self, source: &mut S, token: Token, interest: Interest) -> io::Result<()> {
+        self.registry().reregister(source, token.into(), to_mio_interest(interest)?)
+    }
+
+    pub fn deregister<S: Source + ?Sized>(&self, source: &mut S) -> io::Result<()> {
+        self.registry().deregister(source)
+    }
+
+    /// Hand every pending mio event to `f` without blocking, call it when
+    /// the bridge was reported readable. Returns the number of events
+    pub fn dispatch<F: FnMut(&Event)>(&mut self, mut f: F) -> io::Result<usize> {
+        let mut dispatched = 0;
+
+        loop {
+            match self.poll.poll(&mut self.events, Some(Duration::ZERO)) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+
+            let mut count = 0;
+            for event in self.events.iter() {
+                count += 1;
+                f(event);
+            }
+
+            dispatched += count;
+
+            // A full buffer may have left events behind
+            if count < self.events.capacity() {
+                return Ok(dispatched);
+            }
+        }
+    }
+
+    pub fn into_poll(self) -> Poll {
+        self.poll
+    }
+}
+
+impl AsRawFd for MioBridge {
+    fn as_raw_fd(&self) -> RawFd {
+        self.poll.as_raw_fd()
+    }
+}
+
+impl AsFd for MioBridge {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.poll.registry().as_fd()
+    }
+}
@@ -0,0 +1,257 @@
+//! Types shaped like the ones of mio, so a mio based server can move to copoll
+//! by changing its imports.
+//!
+//! Like mio every registration is edge triggered. Sources are anything with a
+//! raw fd, [`SourceFd`] wraps a bare one the way `mio::unix::SourceFd` does.
+//! Unlike mio the [`Registry`] can not be cloned or shared between threads
+//! ([`shared::Registry`](crate::shared::Registry) can),
+//! and EPOLLRDHUP is not requested, so [`Event::is_read_closed`] only sees
+//! a peer closing its write side when the kernel reports it as a hangup.
+use std::cell::RefCell;
+use std::fmt;
+use std::io;
+use std::ops::{BitOr, BitOrAssign};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+
+use crate::{event, Epoll, Mode, Timeout};
+
+pub use crate::Token;
+
+/// Readiness interest of a registration, combine them with `|`
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Interest(u8);
+
+const READABLE: u8 = 0b01;
+const WRITABLE: u8 = 0b10;
+
+impl Interest {
+    pub const READABLE: Interest = Interest(READABLE);
+    pub const WRITABLE: Interest = Interest(WRITABLE);
+
+    /// Both interests of `self` and `other`
+    pub const fn add(self, other: Interest) -> Interest {
+        Interest(self.0 | other.0)
+    }
+
+    /// `self` without `other`, None if nothing is left
+    pub fn remove(self, other: Interest) -> Option<Interest> {
+        match self.0 & !other.0 {
+            0 => None,
+            bits => Some(Interest(bits)),
+        }
+    }
+
+    pub const fn is_readable(self) -> bool {
+        self.0 & READABLE != 0
+    }
+
+    pub const fn is_writable(self) -> bool {
+        self.0 & WRITABLE != 0
+    }
+
+    fn to_interest(self) -> crate::Interest {
+        match (self.is_readable(), self.is_writable()) {
+            (true, true) => crate::Interest::BOTH,
+            (false, true) => crate::Interest::WRITABLE,
+            _ => crate::Interest::READABLE,
+        }
+    }
+}
+
+impl BitOr for Interest {
+    type Output = Interest;
+
+    fn bitor(self, other: Interest) -> Interest {
+        self.add(other)
+    }
+}
+
+impl BitOrAssign for Interest {
+    fn bitor_assign(&mut self, other: Interest) {
+        *self = self.add(other);
+    }
+}
+
+impl fmt::Debug for Interest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.is_readable(), self.is_writable()) {
+            (true, true) => write!(f, "READABLE | WRITABLE"),
+            (false, true) => write!(f, "WRITABLE"),
+            _ => write!(f, "READABLE"),
+        }
+    }
+}
+
+/// Registers a bare file descriptor, like `mio::unix::SourceFd`
+#[derive(Debug)]
+pub struct SourceFd<'a>(pub &'a RawFd);
+
+impl AsRawFd for SourceFd<'_> {
+    fn as_raw_fd(&self) -> RawFd {
+        *self.0
+    }
+}
+
+/// Registers sources with the [`Poll`] it was taken from
+#[derive(Debug)]
+pub struct Registry {
+    epoll: RefCell<Epoll>,
+}
+
+impl Registry {
+    /// Register a source, edge triggered
+    pub fn register<S: AsRawFd + ?Sized>(
+        &self,
+        source: &mut S,
+        token: Token,
+        interests: Interest
+    ) -> io::Result<()> {
+        self.epoll.borrow_mut().register(&event::borrow_raw(source), token, interests.to_interest(), Mode::Edge)
+    }
+
+    /// Change the token or interests of a registered source
+    pub fn reregister<S: AsRawFd + ?Sized>(
+        &self,
+        source: &mut S,
+        token: Token,
+        interests: Interest
+    ) -> io::Result<()> {
+        self.epoll.borrow_mut().reregister(&event::borrow_raw(source), token, interests.to_interest(), Mode::Edge)
+    }
+
+    /// Stop receiving events for a source
+    pub fn deregister<S: AsRawFd + ?Sized>(&self, source: &mut S) -> io::Result<()> {
+        self.epoll.borrow_mut().unregister(&event::borrow_raw(source))
+    }
+}
+
+/// The mio::Poll counterpart, owns the epoll instance
+#[derive(Debug)]
+pub struct Poll {
+    registry: Registry,
+}
+
+impl Poll {
+    pub fn new() -> io::Result<Poll> {
+        Ok(Poll { registry: Registry { epoll: RefCell::new(Epoll::create()?) } })
+    }
+
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// Wait for events, `events` is cleared first
+    pub fn poll<T: Into<Timeout>>(&mut self, events: &mut Events, timeout: T) -> io::Result<()> {
+        self.registry.epoll.get_mut().poll_into(&mut events.inner, timeout)?;
+        Ok(())
+    }
+}
+
+impl AsRawFd for Poll {
+    fn as_raw_fd(&self) -> RawFd {
+        self.registry.epoll.borrow().as_raw_fd()
+    }
+}
+
+impl AsFd for Poll {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        event::borrow_raw(self)
+    }
+}
+
+/// Buffer filled by [`Poll::poll`]
+#[derive(Debug)]
+pub struct Events {
+    inner: crate::Events,
+}
+
+impl Events {
+    pub fn with_capacity(capacity: usize) -> Events {
+        Events { inner: crate::Events::with_capacity(capacity) }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.inner.capacity()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        Iter { inner: self.inner.as_slice().iter() }
+    }
+
+    pub fn clear(&mut self) {
+        self.inner.clear();
+    }
+}
+
+impl<'a> IntoIterator for &'a Events {
+    type Item = &'a Event;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+/// Iterator over the events of [`Events`]
+#[derive(Debug)]
+pub struct Iter<'a> {
+    inner: std::slice::Iter<'a, crate::Event>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a Event;
+
+    fn next(&mut self) -> Option<&'a Event> {
+        self.inner.next().map(Event::from_event)
+    }
+}
+
+/// A readiness event, with the accessors of mio::event::Event
+#[derive(Debug)]
+#[repr(transparent)]
+pub struct Event {
+    inner: crate::Event,
+}
+
+impl Event {
+    fn from_event(event: &crate::Event) -> &Event {
+        // Event is a transparent wrapper around crate::Event
+        unsafe { &*(event as *const crate::Event as *const Event) }
+    }
+
+    fn has(&self, flags: libc::c_int) -> bool {
+        self.inner.readiness.raw_bits() & flags as u32 != 0
+    }
+
+    pub fn token(&self) -> Token {
+        self.inner.token
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.inner.readiness.readable || self.has(libc::EPOLLPRI)
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.inner.readiness.writable
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.inner.readiness.error
+    }
+
+    pub fn is_read_closed(&self) -> bool {
+        self.inner.readiness.read_closed
+    }
+
+    pub fn is_write_closed(&self) -> bool {
+        self.inner.readiness.write_closed
+    }
+
+    pub fn is_priority(&self) -> bool {
+        self.has(libc::EPOLLPRI)
+    }
+}
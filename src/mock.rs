@@ -0,0 +1,208 @@
+//! A [`Poller`] without a kernel behind it, for testing code that consumes
+//! events.
+//!
+//! Tests register fds as usual and then inject readiness with
+//! [`MockPoller::ready`]; the next wait delivers it to the tokens that are
+//! registered. Time is a fake clock: a wait with nothing to deliver returns
+//! at once and advances the clock by its timeout.
+//!
+//! ```ignore
+//! let mut poller = MockPoller::new();
+//! app.register_with(&mut poller)?;
+//!
+//! poller.readable(Token(1));
+//! app.turn(&mut poller)?;
+//! ```
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::os::fd::{AsRawFd, BorrowedFd, RawFd};
+use std::time::{Duration, Instant};
+
+use crate::{Events, Interest, Mode, Poller, Readiness, Timeout, Token};
+
+#[derive(Debug, Copy, Clone)]
+struct Entry {
+    token: Token,
+    interest: Interest,
+    mode: Mode,
+    /// Cleared once a one-shot registration delivered an event
+    armed: bool,
+}
+
+/// Poller delivering injected readiness, see the [module docs](self)
+#[derive(Debug)]
+pub struct MockPoller {
+    registrations: HashMap<RawFd, Entry>,
+    pending: VecDeque<(Token, Readiness)>,
+    now: Instant,
+}
+
+impl Default for MockPoller {
+    fn default() -> MockPoller {
+        MockPoller::new()
+    }
+}
+
+impl MockPoller {
+    pub fn new() -> MockPoller {
+        MockPoller { registrations: HashMap::new(), pending: VecDeque::new(), now: Instant::now() }
+    }
+
+    /// Queue `readiness` for `token`. It is delivered by the first wait while
+    /// a registration with the token is armed
+    pub fn ready(&mut self, token: Token, readiness: Readiness) {
+        self.pending.push_back((token, readiness));
+    }
+
+    pub fn readable(&mut self, token: Token) {
+        self.ready(token, Readiness::from_bits(libc::EPOLLIN as u32));
+    }
+
+    pub fn writable(&mut self, token: Token) {
+        self.ready(token, Readiness::from_bits(libc::EPOLLOUT as u32));
+    }
+
+    /// Queue a hangup, reported as closed in both directions
+    pub fn hangup(&mut self, token: Token) {
+        self.ready(token, Readiness::from_bits(libc::EPOLLHUP as u32));
+    }
+
+    /// Number of injected events not delivered yet
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// The token, interest and mode `fd` is registered with
+    pub fn registration(&self, fd: BorrowedFd<'_>) -> Option<(Token, Interest, Mode)> {
+        self.registrations.get(&fd.as_raw_fd()).map(|entry| (entry.token, entry.interest, entry.mode))
+    }
+
+    /// Number of registered fds
+    pub fn len(&self) -> usize {
+        self.registrations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.registrations.is_empty()
+    }
+
+    /// The fake clock, starts at the time the poller was created
+    pub fn now(&self) -> Instant {
+        self.now
+    }
+
+    /// Move the fake clock forward
+    pub fn advance(&mut self, duration: Duration) {
+        self.now += duration;
+    }
+}
+
+impl Poller for MockPoller {
+    fn register(&mut self, fd: BorrowedFd<'_>, token: Token, interest: Interest, mode: Mode) -> io::Result<()> {
+        let fd = fd.as_raw_fd();
+        if self.registrations.contains_key(&fd) {
+            return Err(io::Error::from_raw_os_error(libc::EEXIST));
+        }
+
+        self.registrations.insert(fd, Entry { token, interest, mode, armed: true });
+        Ok(())
+    }
+
+    fn reregister(&mut self, fd: BorrowedFd<'_>, token: Token, interest: Interest, mode: Mode) -> io::Result<()> {
+        match self.registrations.get_mut(&fd.as_raw_fd()) {
+            Some(entry) => {
+                *entry = Entry { token, interest, mode, armed: true };
+                Ok(())
+            }
+            None => Err(io::Error::from_raw_os_error(libc::ENOENT)),
+        }
+    }
+
+    fn unregister(&mut self, fd: BorrowedFd<'_>) -> io::Result<()> {
+        match self.registrations.remove(&fd.as_raw_fd()) {
+            Some(_) => Ok(()),
+            None => Err(io::Error::from_raw_os_error(libc::ENOENT)),
+        }
+    }
+
+    /// Deliver the injected readiness of armed registrations, the rest stays
+    /// queued. With nothing to deliver the clock advances by `timeout`, up
+    /// to a deadline on the fake clock, a wait without one fails instead of
+    /// hanging the test
+    fn wait(&mut self, events: &mut Events, timeout: Timeout) -> io::Result<usize> {
+        events.clear();
+
+        let mut kept = VecDeque::new();
+        while let Some((token, readiness)) = self.pending.pop_front() {
+            let entry = self.registrations.values_mut().find(|entry| entry.token == token && entry.armed);
+
+            match entry {
+                Some(entry) => {
+                    events.push(token, readiness);
+
+                    if matches!(entry.mode, Mode::OneShot) {
+                        entry.armed = false;
+                    }
+                }
+                None => kept.push_back((token, readiness)),
+            }
+        }
+        self.pending = kept;
+
+        if events.is_empty() {
+            match timeout {
+                Timeout::Immediate => {}
+                Timeout::After(timeout) => self.advance(timeout),
+                Timeout::At(deadline) => self.advance(deadline.saturating_duration_since(self.now)),
+                Timeout::Infinite => return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "waiting without a timeout and nothing to deliver"
+                )),
+            }
+        }
+
+        Ok(events.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::AsFd;
+    use std::os::unix::net::UnixStream;
+
+    use super::*;
+
+    #[test]
+    fn injected_readiness_reaches_registered_tokens() {
+        let mut poller = MockPoller::new();
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        poller.register(stream.as_fd(), Token(1), Interest::READABLE, Mode::OneShot).unwrap();
+
+        poller.readable(Token(1));
+        poller.readable(Token(2));
+
+        let mut events = Events::new();
+        assert_eq!(poller.wait(&mut events, Timeout::Immediate).unwrap(), 1);
+        assert_eq!(events.iter().map(|(token, _)| token).collect::<Vec<_>>(), [Token(1)]);
+
+        // One-shot, the next readiness waits until it is rearmed
+        poller.readable(Token(1));
+        assert_eq!(poller.wait(&mut events, Timeout::Immediate).unwrap(), 0);
+    }
+
+    #[test]
+    fn timeouts_advance_the_fake_clock() {
+        let mut poller = MockPoller::new();
+        let mut events = Events::new();
+        let start = poller.now();
+
+        poller.wait(&mut events, Duration::from_secs(5).into()).unwrap();
+        assert_eq!(poller.now(), start + Duration::from_secs(5));
+
+        poller.wait(&mut events, Timeout::At(start + Duration::from_secs(60))).unwrap();
+        assert_eq!(poller.now(), start + Duration::from_secs(60));
+
+        let err = poller.wait(&mut events, Timeout::Infinite).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
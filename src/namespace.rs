@@ -0,0 +1,104 @@
+//! Splitting the token space between the components sharing an instance.
+//!
+//! [`Token::compose`] packs a namespace into the upper
+//! [`Token::NAMESPACE_BITS`] bits of a token and an index into the rest.
+//! Namespace 0 holds the small tokens written by hand, the highest one is
+//! kept for the tokens copoll reserves, like the one of
+//! [`EventLoop`](crate::EventLoop) signals.
+//!
+//! [`Epoll::token_namespace`](crate::Epoll::token_namespace) hands every
+//! component a namespace of its own, so the tokens it makes can not collide
+//! with the ones of another component:
+//!
+//! ```ignore
+//! let connections = epoll.token_namespace()?;
+//! let token = connections.token(slot).expect("index fits");
+//! epoll.register(&stream, token, Interest::READABLE, Mode::Level)?;
+//!
+//! // in the dispatch
+//! if let Some(slot) = connections.index_of(token) {
+//!     handle(slot);
+//! }
+//! ```
+//!
+//! [`Slab`](crate::Slab) tokens carry a generation in their upper half and
+//! can not be composed.
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::Token;
+
+/// The namespaces allocated of an instance, shared with its clones
+pub(crate) type Namespaces = Arc<AtomicUsize>;
+
+/// Allocate the next namespace, the first one is 1
+pub(crate) fn allocate(namespaces: &Namespaces) -> io::Result<TokenNamespace> {
+    let namespace = namespaces
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| {
+            (last < Token::MAX_NAMESPACE).then_some(last + 1)
+        })
+        .map_err(|_| io::Error::new(io::ErrorKind::OutOfMemory, "all token namespaces are allocated"))?;
+
+    Ok(TokenNamespace { namespace: namespace + 1 })
+}
+
+const INDEX_BITS: u32 = usize::BITS - Token::NAMESPACE_BITS;
+
+impl Token {
+    /// Bits of a composed token that hold the namespace
+    pub const NAMESPACE_BITS: u32 = 16;
+    /// The highest namespace [`Token::compose`] accepts, the one above is reserved
+    pub const MAX_NAMESPACE: usize = (1 << Token::NAMESPACE_BITS) - 2;
+    /// The highest index [`Token::compose`] accepts
+    pub const MAX_INDEX: usize = (1 << INDEX_BITS) - 1;
+
+    /// The token of `index` in `namespace`, None if either does not fit
+    pub const fn compose(namespace: usize, index: usize) -> Option<Token> {
+        if namespace > Token::MAX_NAMESPACE || index > Token::MAX_INDEX {
+            return None;
+        }
+
+        Some(Token(namespace << INDEX_BITS | index))
+    }
+
+    /// The namespace and index of the token, the reverse of [`Token::compose`]
+    pub const fn decompose(self) -> (usize, usize) {
+        (self.namespace(), self.index())
+    }
+
+    pub const fn namespace(self) -> usize {
+        self.0 >> INDEX_BITS
+    }
+
+    pub const fn index(self) -> usize {
+        self.0 & Token::MAX_INDEX
+    }
+}
+
+/// A namespace allocated by [`Epoll::token_namespace`](crate::Epoll::token_namespace)
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct TokenNamespace {
+    namespace: usize,
+}
+
+impl TokenNamespace {
+    pub fn namespace(&self) -> usize {
+        self.namespace
+    }
+
+    /// The token of `index`, None if it is above [`Token::MAX_INDEX`]
+    pub fn token(&self, index: usize) -> Option<Token> {
+        Token::compose(self.namespace, index)
+    }
+
+    /// Returns true if `token` was made by this namespace
+    pub fn contains(&self, token: Token) -> bool {
+        token.namespace() == self.namespace
+    }
+
+    /// The index of `token`, None if it belongs to another namespace
+    pub fn index_of(&self, token: Token) -> Option<usize> {
+        self.contains(token).then_some(token.index())
+    }
+}
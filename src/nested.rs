@@ -0,0 +1,78 @@
+//! Composing epoll instances.
+//!
+//! An epoll fd is readable while any of its registrations is ready, so one
+//! instance can be registered in another. A [`SubPoller`] wraps the inner
+//! instance of a subsystem and is a [`Source`](crate::Source) itself:
+//! register it in the top-level loop and call [`SubPoller::drain`] whenever
+//! it is reported readable, which collects the ready events of the inner
+//! instance without waiting.
+//!
+//! ```ignore
+//! let mut audio = SubPoller::new(Epoll::create()?);
+//! audio.get_mut().register(&device, Token(0), Interest::READABLE, Mode::Level)?;
+//! epoll.register(&audio, AUDIO, Interest::READABLE, Mode::Level)?;
+//!
+//! for (token, _) in &epoll.poll(None)? {
+//!     if token == AUDIO {
+//!         for (inner, readiness) in audio.drain()? {
+//!             // tokens of the inner instance
+//!         }
+//!     }
+//! }
+//! ```
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::time::Duration;
+
+use crate::{Epoll, Events};
+
+/// An epoll instance registered in another one, see the [module docs](self)
+#[derive(Debug)]
+pub struct SubPoller {
+    epoll: Epoll,
+    events: Events,
+}
+
+impl SubPoller {
+    pub fn new(epoll: Epoll) -> SubPoller {
+        SubPoller { epoll, events: Events::new() }
+    }
+
+    /// The inner instance, e.g. to register its sources
+    pub fn get_ref(&self) -> &Epoll {
+        &self.epoll
+    }
+
+    pub fn get_mut(&mut self) -> &mut Epoll {
+        &mut self.epoll
+    }
+
+    /// Collect the events that are ready on the inner instance without
+    /// waiting. Empty when it was reported readable spuriously
+    pub fn drain(&mut self) -> io::Result<&Events> {
+        self.epoll.poll_into(&mut self.events, Some(Duration::ZERO))?;
+        Ok(&self.events)
+    }
+
+    /// The events of the last drain
+    pub fn events(&self) -> &Events {
+        &self.events
+    }
+
+    /// The inner instance, unregister the sub poller first
+    pub fn into_inner(self) -> Epoll {
+        self.epoll
+    }
+}
+
+impl AsRawFd for SubPoller {
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll.as_raw_fd()
+    }
+}
+
+impl AsFd for SubPoller {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.epoll.as_fd()
+    }
+}
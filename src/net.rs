@@ -0,0 +1,291 @@
+//! Socket helpers that go together with readiness events
+use std::fs::File;
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::mem;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd};
+use std::os::unix::net::{self, UnixListener, UnixStream};
+
+use crate::{sys, Readiness};
+
+/// Outcome of a non-blocking connect, see [`connect_result`]
+#[derive(Debug)]
+pub enum ConnectResult {
+    /// The connection is established
+    Connected,
+    /// The connection failed, the error is the one reported by SO_ERROR
+    Failed(io::Error),
+    /// Not decided yet, keep the socket registered and wait for the next event
+    Pending,
+}
+
+/// Start a non-blocking TCP connect. The stream is returned right away,
+/// register it writable and pass its readiness to [`connect_result`]
+pub fn connect_tcp(addr: SocketAddr) -> io::Result<TcpStream> {
+    let (storage, len) = sockaddr(addr);
+
+    let fd = sys::cvt(unsafe {
+        libc::socket(
+            storage.ss_family as libc::c_int,
+            libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0
+        )
+    })?;
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+
+    let result = sys::cvt(unsafe {
+        libc::connect(fd, &storage as *const libc::sockaddr_storage as *const libc::sockaddr, len)
+    });
+
+    match result {
+        Ok(_) => Ok(stream),
+        Err(err) if err.raw_os_error() == Some(libc::EINPROGRESS) => Ok(stream),
+        Err(err) => Err(err),
+    }
+}
+
+/// `addr` as a sockaddr_in or sockaddr_in6 and its length
+fn sockaddr(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sin = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: addr.port().to_be(),
+                sin_addr: libc::in_addr { s_addr: u32::from_ne_bytes(addr.ip().octets()) },
+                sin_zero: [0; 8],
+            };
+            unsafe { (&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in).write(sin) };
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: addr.port().to_be(),
+                sin6_flowinfo: addr.flowinfo(),
+                sin6_addr: libc::in6_addr { s6_addr: addr.ip().octets() },
+                sin6_scope_id: addr.scope_id(),
+            };
+            unsafe { (&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6).write(sin6) };
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+
+    (storage, len as libc::socklen_t)
+}
+
+/// Take the pending error of `socket`, SO_ERROR, once readiness reported
+/// an error for it. The kernel clears it while reading, a second call
+/// returns None
+pub fn take_error<S: AsFd + ?Sized>(socket: &S) -> io::Result<Option<io::Error>> {
+    match sys::getsockopt_int(socket.as_fd().as_raw_fd(), libc::SOL_SOCKET, libc::SO_ERROR)? {
+        0 => Ok(None),
+        errno => Ok(Some(io::Error::from_raw_os_error(errno))),
+    }
+}
+
+/// Classify the readiness reported for a socket with a non-blocking connect in
+/// flight. Linux reports a failed connect as writable together with an error,
+/// so writability alone does not mean success: the pending error is always
+/// checked first, and whether the socket has a peer decides the rest
+pub fn connect_result(socket: BorrowedFd<'_>, readiness: Readiness) -> io::Result<ConnectResult> {
+    if !readiness.writable && !readiness.error {
+        return Ok(ConnectResult::Pending);
+    }
+
+    if let Some(err) = take_error(&socket)? {
+        return Ok(ConnectResult::Failed(err));
+    }
+
+    let mut peer: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let result = sys::cvt(unsafe {
+        libc::getpeername(socket.as_raw_fd(), &mut peer as *mut libc::sockaddr_storage as *mut libc::sockaddr, &mut len)
+    });
+
+    match result {
+        Ok(_) => Ok(ConnectResult::Connected),
+        // The error was already collected by someone else, the connect still failed
+        Err(err) if err.raw_os_error() == Some(libc::ENOTCONN) && readiness.error => {
+            Ok(ConnectResult::Failed(io::Error::from_raw_os_error(libc::ECONNREFUSED)))
+        }
+        Err(err) if err.raw_os_error() == Some(libc::ENOTCONN) => Ok(ConnectResult::Pending),
+        Err(err) => Err(err),
+    }
+}
+
+/// A listening socket [`Acceptor`] can drain. It has to be in non-blocking
+/// mode, otherwise accepting blocks once the queue is empty
+pub trait Listener: AsFd {
+    type Stream;
+    type Addr;
+
+    /// Accept one connection, the stream is made non-blocking. Fails with
+    /// WouldBlock once the queue of a non-blocking listener is empty
+    fn accept_nonblocking(&self) -> io::Result<(Self::Stream, Self::Addr)>;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+    type Addr = SocketAddr;
+
+    fn accept_nonblocking(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let (stream, addr) = self.accept()?;
+        stream.set_nonblocking(true)?;
+        Ok((stream, addr))
+    }
+}
+
+impl Listener for UnixListener {
+    type Stream = UnixStream;
+    type Addr = net::SocketAddr;
+
+    fn accept_nonblocking(&self) -> io::Result<(UnixStream, net::SocketAddr)> {
+        let (stream, addr) = self.accept()?;
+        stream.set_nonblocking(true)?;
+        Ok((stream, addr))
+    }
+}
+
+/// Accept queue pressure seen by an [`Acceptor`]
+#[derive(Debug, Clone, Default)]
+pub struct AcceptStats {
+    /// Calls to accept_batch
+    pub batches: u64,
+    /// Connections handed out
+    pub accepted: u64,
+    /// The largest batch so far
+    pub max_batch: usize,
+    /// Batches that stopped at the cap with connections possibly still queued
+    pub cap_hits: u64,
+    /// Batches that drained the queue
+    pub drained: u64,
+    /// Connections closed right away because the process ran out of fds
+    pub shed: u64,
+}
+
+/// Drains listeners in batches. Keeps one spare fd so running out of file
+/// descriptors does not leave the listener ready forever: on EMFILE the spare
+/// is closed to accept and close the pending connections, then reopened
+#[derive(Debug)]
+pub struct Acceptor {
+    cap: usize,
+    spare: Option<File>,
+    stats: AcceptStats,
+}
+
+impl Acceptor {
+    /// Accept at most `cap` connections per batch, so one busy listener
+    /// can not starve the rest of the loop
+    pub fn new(cap: usize) -> io::Result<Acceptor> {
+        Ok(Acceptor { cap: cap.max(1), spare: Some(File::open("/dev/null")?), stats: AcceptStats::default() })
+    }
+
+    pub fn stats(&self) -> &AcceptStats {
+        &self.stats
+    }
+
+    /// Accept until the listener would block or the cap is reached and
+    /// return every connection with its peer address. Errors are only
+    /// returned if nothing was accepted, they show up again on the next call.
+    /// A blocking listener is rejected with InvalidInput, it would block the
+    /// loop once its queue is drained
+    pub fn accept_batch<L: Listener>(&mut self, listener: &L) -> io::Result<Vec<(L::Stream, L::Addr)>> {
+        if sys::get_flags(listener.as_fd().as_raw_fd())? & libc::O_NONBLOCK == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the listener has to be non-blocking"));
+        }
+
+        let mut batch = Vec::new();
+        self.stats.batches += 1;
+
+        let result = loop {
+            if batch.len() == self.cap {
+                self.stats.cap_hits += 1;
+                break Ok(());
+            }
+
+            match listener.accept_nonblocking() {
+                Ok(accepted) => batch.push(accepted),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    self.stats.drained += 1;
+                    break Ok(());
+                }
+                // The connection was reset while queued, or a signal arrived
+                Err(err) if matches!(err.raw_os_error(), Some(libc::ECONNABORTED) | Some(libc::EINTR)) => {}
+                Err(err) if matches!(err.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE)) => {
+                    match self.shed(listener) {
+                        Some(true) => {}
+                        Some(false) => {
+                            self.stats.drained += 1;
+                            break Ok(());
+                        }
+                        None => break Err(err),
+                    }
+                }
+                Err(err) => break Err(err),
+            }
+        };
+
+        self.stats.accepted += batch.len() as u64;
+        self.stats.max_batch = self.stats.max_batch.max(batch.len());
+
+        match result {
+            Err(err) if batch.is_empty() => Err(err),
+            _ => Ok(batch),
+        }
+    }
+
+    /// Close the spare fd to accept a connection and close it again.
+    /// Some(false) if the queue turned out empty, None without a spare
+    fn shed<L: Listener>(&mut self, listener: &L) -> Option<bool> {
+        self.spare.take()?;
+
+        let shed = match listener.accept_nonblocking() {
+            Ok(_) => {
+                self.stats.shed += 1;
+                true
+            }
+            Err(err) => err.kind() != io::ErrorKind::WouldBlock,
+        };
+
+        self.spare = File::open("/dev/null").ok();
+        Some(shed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listener_with_clients(clients: usize) -> (TcpListener, Vec<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let clients = (0..clients).map(|_| TcpStream::connect(addr).unwrap()).collect();
+
+        (listener, clients)
+    }
+
+    #[test]
+    fn blocking_listener_is_rejected() {
+        let (listener, _clients) = listener_with_clients(1);
+        let mut acceptor = Acceptor::new(8).unwrap();
+
+        let err = acceptor.accept_batch(&listener).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn batches_are_capped_and_drain_the_queue() {
+        let (listener, _clients) = listener_with_clients(3);
+        listener.set_nonblocking(true).unwrap();
+        let mut acceptor = Acceptor::new(2).unwrap();
+
+        assert_eq!(acceptor.accept_batch(&listener).unwrap().len(), 2);
+        assert_eq!(acceptor.accept_batch(&listener).unwrap().len(), 1);
+        assert!(acceptor.accept_batch(&listener).unwrap().is_empty());
+
+        let stats = acceptor.stats();
+        assert_eq!((stats.batches, stats.accepted, stats.cap_hits, stats.drained), (3, 3, 1, 2));
+    }
+}
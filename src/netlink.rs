@@ -0,0 +1,204 @@
+//! Netlink sockets.
+//!
+//! A [`Netlink`] socket receives the notifications of the multicast groups
+//! it joined, e.g. link and address changes from rtnetlink with
+//! `RTMGRP_LINK | RTMGRP_IPV4_IFADDR`, and the replies to requests sent with
+//! [`Netlink::send`]. Register it readable and read until
+//! [`Netlink::recv`] returns None. Messages are split at their headers, the
+//! payload is left to the caller or a crate like netlink-packet-route.
+use std::io;
+use std::mem;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+
+use libc::{c_int, c_void};
+
+/// Size of struct nlmsghdr
+const HEADER_LEN: usize = 16;
+
+/// Large enough for any message of the kernel, it never sends more than a
+/// page or 8 KiB at once
+const BUFFER_LEN: usize = 32 * 1024;
+
+/// One message of a received datagram
+#[derive(Debug, Copy, Clone)]
+pub struct Message<'a> {
+    /// nlmsg_type, e.g. RTM_NEWLINK or NLMSG_ERROR
+    pub kind: u16,
+    pub flags: u16,
+    pub seq: u32,
+    /// Port id of the sender, 0 for the kernel
+    pub pid: u32,
+    /// Everything after the header
+    pub payload: &'a [u8],
+}
+
+/// Iterator over the messages of one datagram
+#[derive(Debug)]
+pub struct Messages<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Iterator for Messages<'a> {
+    type Item = Message<'a>;
+
+    fn next(&mut self) -> Option<Message<'a>> {
+        if self.buf.len() < HEADER_LEN {
+            return None;
+        }
+
+        let u32_at = |at: usize| u32::from_ne_bytes(self.buf[at..at + 4].try_into().unwrap());
+        let u16_at = |at: usize| u16::from_ne_bytes(self.buf[at..at + 2].try_into().unwrap());
+
+        let len = u32_at(0) as usize;
+        if len < HEADER_LEN || len > self.buf.len() {
+            // A malformed header, the rest can not be split
+            self.buf = &[];
+            return None;
+        }
+
+        let message = Message {
+            kind: u16_at(4),
+            flags: u16_at(6),
+            seq: u32_at(8),
+            pid: u32_at(12),
+            payload: &self.buf[HEADER_LEN..len],
+        };
+
+        // Messages start 4 byte aligned
+        let next = (len + 3) & !3;
+        self.buf = self.buf.get(next..).unwrap_or(&[]);
+
+        Some(message)
+    }
+}
+
+/// An AF_NETLINK socket, register it readable
+#[derive(Debug)]
+pub struct Netlink {
+    fd: OwnedFd,
+    buf: Vec<u8>,
+}
+
+impl Netlink {
+    /// Open a socket of `protocol`, e.g. NETLINK_ROUTE, joined to the
+    /// multicast `groups`, a bitmask of the RTMGRP_* constants and the like
+    pub fn open(protocol: c_int, groups: u32) -> io::Result<Netlink> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                protocol
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let netlink = Netlink { fd: unsafe { OwnedFd::from_raw_fd(fd) }, buf: vec![0; BUFFER_LEN] };
+
+        let addr = address(groups);
+        let result = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(netlink)
+    }
+
+    /// Open an rtnetlink socket joined to `groups`
+    pub fn route(groups: u32) -> io::Result<Netlink> {
+        Netlink::open(libc::NETLINK_ROUTE, groups)
+    }
+
+    /// Join the multicast group with the number `group`, for groups past
+    /// the 32 a bitmask reaches
+    pub fn join_group(&self, group: u32) -> io::Result<()> {
+        let result = unsafe {
+            libc::setsockopt(
+                self.fd.as_raw_fd(),
+                libc::SOL_NETLINK,
+                libc::NETLINK_ADD_MEMBERSHIP,
+                &group as *const u32 as *const c_void,
+                mem::size_of::<u32>() as libc::socklen_t
+            )
+        };
+
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// Send `message`, a complete netlink message with its header, to the
+    /// kernel
+    pub fn send(&self, message: &[u8]) -> io::Result<usize> {
+        let addr = address(0);
+        let n = unsafe {
+            libc::sendto(
+                self.fd.as_raw_fd(),
+                message.as_ptr() as *const c_void,
+                message.len(),
+                0,
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t
+            )
+        };
+
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(n as usize)
+    }
+
+    /// Receive one datagram, None if nothing is queued. A full receive
+    /// queue drops notifications and fails once with ENOBUFS, resynchronize
+    /// with a dump request then
+    pub fn recv(&mut self) -> io::Result<Option<Messages<'_>>> {
+        let n = unsafe {
+            libc::recv(self.fd.as_raw_fd(), self.buf.as_mut_ptr() as *mut c_void, self.buf.len(), libc::MSG_TRUNC)
+        };
+
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            return match err.kind() {
+                io::ErrorKind::WouldBlock => Ok(None),
+                _ => Err(err),
+            };
+        }
+
+        let n = n as usize;
+        if n > self.buf.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "netlink datagram truncated"));
+        }
+
+        Ok(Some(Messages { buf: &self.buf[..n] }))
+    }
+}
+
+impl AsRawFd for Netlink {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for Netlink {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+/// The address of the kernel, or of this socket bound to `groups`
+fn address(groups: u32) -> libc::sockaddr_nl {
+    let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as u16;
+    addr.nl_groups = groups;
+    addr
+}
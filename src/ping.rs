@@ -0,0 +1,58 @@
+//! Scheduling another loop iteration from inside the loop.
+//!
+//! [`ping`] returns a [`Ping`], cloned freely and triggered from callbacks
+//! or other threads, and the [`PingSource`] to register. Any number of pings
+//! before the source is drained are reported as a single readable event.
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::sync::Arc;
+
+use crate::sys;
+
+/// Triggers the [`PingSource`] it was created with
+#[derive(Debug, Clone)]
+pub struct Ping {
+    fd: Arc<OwnedFd>,
+}
+
+impl Ping {
+    /// Make the source readable
+    pub fn ping(&self) {
+        // A write to an eventfd only fails once the counter is saturated,
+        // the source is readable already then
+        let _ = sys::write_counter(self.fd.as_raw_fd(), 1);
+    }
+}
+
+/// The registrable end of a [`ping`] pair
+#[derive(Debug)]
+pub struct PingSource {
+    fd: Arc<OwnedFd>,
+}
+
+impl PingSource {
+    /// Reset the source after it was reported readable.
+    /// Returns whether it was pinged since the last drain
+    pub fn drain(&mut self) -> io::Result<bool> {
+        Ok(sys::read_counter(self.fd.as_raw_fd())? != 0)
+    }
+}
+
+impl AsRawFd for PingSource {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for PingSource {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+/// Create a connected [`Ping`] and [`PingSource`]
+pub fn ping() -> io::Result<(Ping, PingSource)> {
+    let fd = Arc::new(sys::eventfd(0)?);
+
+    Ok((Ping { fd: fd.clone() }, PingSource { fd }))
+}
@@ -0,0 +1,166 @@
+//! The operations every backend provides, to pick one at compile time or
+//! at runtime.
+//!
+//! [`Poller`] is implemented by [`Epoll`], [`PollFallback`] and, with the
+//! `io-uring` feature, [`IoUring`](crate::uring::IoUring). Write a loop
+//! against `P: Poller` or `Box<dyn Poller>`, [`default`] picks epoll and
+//! falls back to poll(2) where epoll is refused, and tests can swap in a
+//! [`MockPoller`](crate::mock::MockPoller).
+use std::io;
+use std::os::fd::BorrowedFd;
+use crate::fallback::PollFallback;
+use crate::{Epoll, Events, Interest, Mode, Timeout, Token};
+
+/// Register fds and wait for their readiness
+pub trait Poller {
+    fn register(&mut self, fd: BorrowedFd<'_>, token: Token, interest: Interest, mode: Mode) -> io::Result<()>;
+
+    fn reregister(&mut self, fd: BorrowedFd<'_>, token: Token, interest: Interest, mode: Mode) -> io::Result<()>;
+
+    fn unregister(&mut self, fd: BorrowedFd<'_>) -> io::Result<()>;
+
+    /// Wait up to `timeout` and store the events in `events`, which is
+    /// cleared first. Returns the number of events. Takes a [`Timeout`]
+    /// rather than `impl Into<Timeout>` so `dyn Poller` works, convert an
+    /// Option or Duration with `.into()`
+    fn wait(&mut self, events: &mut Events, timeout: Timeout) -> io::Result<usize>;
+}
+
+impl Poller for Epoll {
+    fn register(&mut self, fd: BorrowedFd<'_>, token: Token, interest: Interest, mode: Mode) -> io::Result<()> {
+        Epoll::register(self, &fd, token, interest, mode)
+    }
+
+    fn reregister(&mut self, fd: BorrowedFd<'_>, token: Token, interest: Interest, mode: Mode) -> io::Result<()> {
+        Epoll::reregister(self, &fd, token, interest, mode)
+    }
+
+    fn unregister(&mut self, fd: BorrowedFd<'_>) -> io::Result<()> {
+        Epoll::unregister(self, &fd)
+    }
+
+    fn wait(&mut self, events: &mut Events, timeout: Timeout) -> io::Result<usize> {
+        self.poll_into(events, timeout)
+    }
+}
+
+impl Poller for PollFallback {
+    fn register(&mut self, fd: BorrowedFd<'_>, token: Token, interest: Interest, mode: Mode) -> io::Result<()> {
+        PollFallback::register(self, &fd, token, interest, mode)
+    }
+
+    fn reregister(&mut self, fd: BorrowedFd<'_>, token: Token, interest: Interest, mode: Mode) -> io::Result<()> {
+        PollFallback::reregister(self, &fd, token, interest, mode)
+    }
+
+    fn unregister(&mut self, fd: BorrowedFd<'_>) -> io::Result<()> {
+        PollFallback::unregister(self, &fd)
+    }
+
+    fn wait(&mut self, events: &mut Events, timeout: Timeout) -> io::Result<usize> {
+        self.poll_into(events, timeout)
+    }
+}
+
+#[cfg(feature = "io-uring")]
+impl Poller for crate::uring::IoUring {
+    fn register(&mut self, fd: BorrowedFd<'_>, token: Token, interest: Interest, mode: Mode) -> io::Result<()> {
+        crate::uring::IoUring::register(self, &fd, token, interest, mode)
+    }
+
+    fn reregister(&mut self, fd: BorrowedFd<'_>, token: Token, interest: Interest, mode: Mode) -> io::Result<()> {
+        crate::uring::IoUring::reregister(self, &fd, token, interest, mode)
+    }
+
+    fn unregister(&mut self, fd: BorrowedFd<'_>) -> io::Result<()> {
+        crate::uring::IoUring::unregister(self, &fd)
+    }
+
+    fn wait(&mut self, events: &mut Events, timeout: Timeout) -> io::Result<usize> {
+        self.poll_into(events, timeout)
+    }
+}
+
+impl<P: Poller + ?Sized> Poller for Box<P> {
+    fn register(&mut self, fd: BorrowedFd<'_>, token: Token, interest: Interest, mode: Mode) -> io::Result<()> {
+        (**self).register(fd, token, interest, mode)
+    }
+
+    fn reregister(&mut self, fd: BorrowedFd<'_>, token: Token, interest: Interest, mode: Mode) -> io::Result<()> {
+        (**self).reregister(fd, token, interest, mode)
+    }
+
+    fn unregister(&mut self, fd: BorrowedFd<'_>) -> io::Result<()> {
+        (**self).unregister(fd)
+    }
+
+    fn wait(&mut self, events: &mut Events, timeout: Timeout) -> io::Result<usize> {
+        (**self).wait(events, timeout)
+    }
+}
+
+impl<P: Poller + ?Sized> Poller for &mut P {
+    fn register(&mut self, fd: BorrowedFd<'_>, token: Token, interest: Interest, mode: Mode) -> io::Result<()> {
+        (**self).register(fd, token, interest, mode)
+    }
+
+    fn reregister(&mut self, fd: BorrowedFd<'_>, token: Token, interest: Interest, mode: Mode) -> io::Result<()> {
+        (**self).reregister(fd, token, interest, mode)
+    }
+
+    fn unregister(&mut self, fd: BorrowedFd<'_>) -> io::Result<()> {
+        (**self).unregister(fd)
+    }
+
+    fn wait(&mut self, events: &mut Events, timeout: Timeout) -> io::Result<usize> {
+        (**self).wait(events, timeout)
+    }
+}
+
+/// An epoll instance, or a poll(2) backend if creating one fails with
+/// ENOSYS or a seccomp filter's EPERM
+pub fn default() -> io::Result<Box<dyn Poller + Send>> {
+    match Epoll::create() {
+        Ok(epoll) => Ok(Box::new(epoll)),
+        Err(err) if matches!(err.raw_os_error(), Some(libc::ENOSYS) | Some(libc::EPERM)) => {
+            Ok(Box::new(PollFallback::create()?))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::os::fd::AsFd;
+    use std::os::unix::net::UnixStream;
+
+    use super::*;
+
+    /// A loop written once against the trait
+    fn ready_tokens(poller: &mut dyn Poller, fd: BorrowedFd<'_>) -> Vec<Token> {
+        poller.register(fd, Token(7), Interest::READABLE, Mode::Level).unwrap();
+
+        let mut events = Events::new();
+        poller.wait(&mut events, Timeout::Immediate).unwrap();
+        poller.unregister(fd).unwrap();
+
+        events.iter().map(|(token, _)| token).collect()
+    }
+
+    #[test]
+    fn every_backend_reports_through_the_trait() {
+        let (stream, peer) = UnixStream::pair().unwrap();
+        (&peer).write_all(b"x").unwrap();
+
+        let mut backends: Vec<Box<dyn Poller + Send>> = vec![
+            default().unwrap(),
+            Box::new(Epoll::create().unwrap()),
+            Box::new(PollFallback::create().unwrap()),
+        ];
+
+        for backend in &mut backends {
+            assert_eq!(ready_tokens(backend, stream.as_fd()), [Token(7)]);
+        }
+    }
+}
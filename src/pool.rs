@@ -0,0 +1,116 @@
+//! Watching idle connections of a client pool.
+//!
+//! A pooled connection should not see any traffic while it is idle, so any
+//! readability means the server closed it or sent something unsolicited and
+//! the connection can not be handed out anymore. [`PoolWatcher`] keeps every
+//! idle connection registered one-shot readable in its own epoll instance:
+//! returning a connection costs one syscall, handing one out a zero timeout
+//! poll plus the unregister, and nothing is spent while connections sit idle.
+use std::collections::HashMap;
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::time::Duration;
+
+use crate::{Epoll, Events, Interest, Mode, Token};
+
+/// Idle connections, handed out most recently returned first
+#[derive(Debug)]
+pub struct PoolWatcher<C: AsFd> {
+    epoll: Epoll,
+    idle: HashMap<Token, C>,
+    /// Idle tokens in the order they were returned
+    order: Vec<Token>,
+    next: usize,
+    events: Events,
+}
+
+impl<C: AsFd> PoolWatcher<C> {
+    pub fn new() -> io::Result<PoolWatcher<C>> {
+        Ok(PoolWatcher {
+            epoll: Epoll::create()?,
+            idle: HashMap::new(),
+            order: Vec::new(),
+            next: 0,
+            events: Events::new(),
+        })
+    }
+
+    /// Number of idle connections
+    pub fn len(&self) -> usize {
+        self.idle.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.idle.is_empty()
+    }
+
+    /// Return a connection to the pool. A connection that can not be
+    /// watched is dropped and the error returned
+    pub fn checkin(&mut self, conn: C) -> io::Result<()> {
+        let token = Token(self.next);
+        self.next = self.next.wrapping_add(1);
+
+        self.epoll.register(&conn.as_fd(), token, Interest::READABLE, Mode::OneShot)?;
+
+        self.idle.insert(token, conn);
+        self.order.push(token);
+        Ok(())
+    }
+
+    /// Hand out an idle connection. Connections the server closed in the
+    /// meantime are dropped on the way, they are found with a zero timeout
+    /// poll first
+    pub fn checkout(&mut self) -> io::Result<Option<C>> {
+        self.reap(Some(Duration::ZERO))?;
+
+        while let Some(token) = self.order.pop() {
+            let conn = match self.idle.remove(&token) {
+                Some(conn) => conn,
+                None => continue,
+            };
+
+            if self.epoll.unregister(&conn.as_fd()).is_ok() {
+                return Ok(Some(conn));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Wait up to `timeout` for idle connections to become readable and
+    /// take them out of the pool. The caller decides whether to read the
+    /// unsolicited data or just drop them
+    pub fn reap(&mut self, timeout: Option<Duration>) -> io::Result<Vec<C>> {
+        self.epoll.poll_into(&mut self.events, timeout)?;
+
+        let mut dead = Vec::with_capacity(self.events.len());
+        for (token, _) in &self.events {
+            if let Some(conn) = self.idle.remove(&token) {
+                // The one-shot entry is disarmed, drop it before the fd goes away
+                let _ = self.epoll.unregister(&conn.as_fd());
+                dead.push(conn);
+            }
+        }
+
+        if !dead.is_empty() {
+            let idle = &self.idle;
+            self.order.retain(|token| idle.contains_key(token));
+        }
+
+        Ok(dead)
+    }
+}
+
+/// The epoll fd turns readable when an idle connection needs reaping,
+/// register it in the main loop to reap without polling periodically
+impl<C: AsFd> AsRawFd for PoolWatcher<C> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll.as_raw_fd()
+    }
+}
+
+impl<C: AsFd> AsFd for PoolWatcher<C> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.epoll.as_fd()
+    }
+}
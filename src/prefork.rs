@@ -0,0 +1,249 @@
+//! Helpers for the prefork server model.
+//!
+//! Listening sockets are bound in the parent before forking and inherited by
+//! every worker. Each worker has to create its own [`Epoll`](crate::Epoll)
+//! after the fork, an epoll fd inherited across fork shares one interest list
+//! with the parent and every other worker. The listener is then registered with
+//! [`Epoll::register_exclusive`](crate::Epoll::register_exclusive) so an incoming
+//! connection wakes one worker (or a few) instead of all of them. Another worker
+//! can still win the race for a connection, which is why the listeners returned
+//! here are non-blocking and `accept` failing with `WouldBlock` is expected.
+//!
+//! Shutdown is coordinated through an inherited pipe. The supervisor keeps the
+//! write end and every worker registers the read end as a [`ShutdownSignal`].
+//! Closing the write end, through [`Supervisor::shutdown`] or because the
+//! supervisor died, makes the read end readable in all workers at once.
+use std::io;
+use std::net::{TcpListener, ToSocketAddrs};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::os::unix::process::ExitStatusExt;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::process::ExitStatus;
+
+use crate::sys;
+
+/// Bind a non-blocking TCP listener meant to be shared by forked workers
+pub fn bind_tcp<A: ToSocketAddrs>(addr: A) -> io::Result<TcpListener> {
+    let listener = TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Bind a non-blocking unix listener meant to be shared by forked workers
+pub fn bind_unix<P: AsRef<Path>>(path: P) -> io::Result<UnixListener> {
+    let listener = UnixListener::bind(path)?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Worker side of the control pipe, register it readable in the worker's epoll
+#[derive(Debug)]
+pub struct ShutdownSignal {
+    fd: OwnedFd,
+}
+
+impl ShutdownSignal {
+    /// Returns true once the supervisor asked the workers to stop.
+    /// Call it when the signal is reported readable
+    pub fn is_shutdown(&self) -> io::Result<bool> {
+        let mut buffer = [0u8; 64];
+
+        loop {
+            match sys::read(self.fd.as_raw_fd(), &mut buffer) {
+                Ok(0) => return Ok(true),
+                Ok(_) => continue,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl AsRawFd for ShutdownSignal {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for ShutdownSignal {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+/// Parent side of a group of forked workers. Dropping it closes the control
+/// pipe, the workers see the pipe close and exit on their own
+#[derive(Debug)]
+pub struct Supervisor {
+    control: OwnedFd,
+    workers: Vec<libc::pid_t>,
+}
+
+impl Supervisor {
+    /// Number of workers that were forked
+    pub fn len(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Returns true if no workers were forked
+    pub fn is_empty(&self) -> bool {
+        self.workers.is_empty()
+    }
+
+    /// Close the control pipe, telling every worker to stop, and wait for all
+    /// of them to exit. The exit statuses are returned in fork order
+    pub fn shutdown(self) -> io::Result<Vec<ExitStatus>> {
+        let Supervisor { control, workers } = self;
+        drop(control);
+
+        workers.iter().map(|pid| wait_for(*pid)).collect()
+    }
+}
+
+fn wait_for(pid: libc::pid_t) -> io::Result<ExitStatus> {
+    let mut status = 0;
+
+    loop {
+        if unsafe { libc::waitpid(pid, &mut status, 0) } >= 0 {
+            return Ok(ExitStatus::from_raw(status));
+        }
+
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// Fork `count` workers sharing every fd open in the parent, listeners
+/// included. Each child runs `worker` with its index and its end of the
+/// control pipe, then exits with the returned code (101 if it panicked)
+///
+/// # Safety
+///
+/// Same requirements as fork(2): when the parent has other threads running,
+/// only async-signal-safe operations are allowed in the child. Call this before
+/// spawning any threads.
+pub unsafe fn fork_workers<F>(count: usize, mut worker: F) -> io::Result<Supervisor>
+where
+    F: FnMut(usize, ShutdownSignal) -> i32
+{
+    let (read, write) = sys::pipe(libc::O_CLOEXEC | libc::O_NONBLOCK)?;
+
+    let mut supervisor = Supervisor { control: write, workers: Vec::with_capacity(count) };
+
+    for index in 0..count {
+        match libc::fork() {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => {
+                // The worker must not hold a write end, or it never sees the pipe close
+                drop(supervisor);
+
+                let signal = ShutdownSignal { fd: read };
+                let code = panic::catch_unwind(AssertUnwindSafe(|| worker(index, signal)))
+                    .unwrap_or(101);
+
+                std::process::exit(code);
+            }
+            child => supervisor.workers.push(child),
+        }
+    }
+
+    Ok(supervisor)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpStream;
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{Epoll, Interest, Mode, Token};
+
+    #[test]
+    fn exclusive_listener_wakes_a_few_blocked_instances() {
+        let listener = Arc::new(bind_tcp("127.0.0.1:0").unwrap());
+        let barrier = Arc::new(Barrier::new(4));
+
+        let workers: Vec<_> = (0..3)
+            .map(|_| {
+                let listener = listener.clone();
+                let barrier = barrier.clone();
+
+                thread::spawn(move || {
+                    let mut epoll = Epoll::create().unwrap();
+                    epoll.register_exclusive(&*listener, Token(0), Interest::READABLE, Mode::Level).unwrap();
+
+                    barrier.wait();
+                    !epoll.poll(Some(Duration::from_millis(500))).unwrap().is_empty()
+                })
+            })
+            .collect();
+
+        // Let every worker block in epoll_wait before the connection arrives
+        barrier.wait();
+        thread::sleep(Duration::from_millis(100));
+        let _stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        let woken = workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap())
+            .filter(|woken| *woken)
+            .count();
+        assert!((1..3).contains(&woken), "{woken} of 3 instances woken");
+    }
+
+    #[test]
+    fn exclusive_registrations_can_not_be_reregistered_or_oneshot() {
+        let listener = bind_tcp("127.0.0.1:0").unwrap();
+        let mut epoll = Epoll::create().unwrap();
+
+        let err = epoll.register_exclusive(&listener, Token(0), Interest::READABLE, Mode::OneShot).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        epoll.register_exclusive(&listener, Token(0), Interest::READABLE, Mode::Level).unwrap();
+        assert!(epoll.reregister(&listener, Token(1), Interest::READABLE, Mode::Level).is_err());
+
+        epoll.unregister(&listener).unwrap();
+        epoll.register(&listener, Token(1), Interest::READABLE, Mode::Level).unwrap();
+    }
+
+    #[test]
+    fn shutdown_signal_sees_the_pipe_close() {
+        let (read, write) = sys::pipe(libc::O_CLOEXEC | libc::O_NONBLOCK).unwrap();
+        let signal = ShutdownSignal { fd: read };
+
+        sys::write(write.as_raw_fd(), b"x").unwrap();
+        assert!(!signal.is_shutdown().unwrap());
+
+        drop(write);
+        assert!(signal.is_shutdown().unwrap());
+    }
+
+    #[test]
+    fn forked_workers_stop_on_shutdown() {
+        let supervisor = unsafe {
+            fork_workers(2, |index, signal| {
+                let mut epoll = Epoll::create().unwrap();
+                epoll.register(&signal, Token(0), Interest::READABLE, Mode::Level).unwrap();
+
+                loop {
+                    epoll.poll(None::<Duration>).unwrap();
+                    if signal.is_shutdown().unwrap() {
+                        return 10 + index as i32;
+                    }
+                }
+            })
+        }
+        .unwrap();
+        assert_eq!(supervisor.len(), 2);
+
+        let codes: Vec<_> = supervisor.shutdown().unwrap().iter().map(|status| status.code()).collect();
+        assert_eq!(codes, [Some(10), Some(11)]);
+    }
+}
@@ -0,0 +1,223 @@
+//! Pressure stall information (PSI) triggers as poll sources.
+//!
+//! The files in /proc/pressure, and the `*.pressure` files of a cgroup v2
+//! directory, report how long tasks stalled waiting for CPU, memory or IO. A
+//! [`Pressure`] writes a trigger to one of them: once tasks stalled for
+//! `threshold` in total within a `window`, the fd signals EPOLLPRI, at most
+//! once per window. Register it with Interest::PRIORITY, or with
+//! [`Pressure::register`], and read the stall totals with
+//! [`Pressure::read_event`]:
+//!
+//! ```ignore
+//! let memory = Pressure::new(Resource::Memory, Stall::Some, Duration::from_millis(150), Duration::from_secs(2))?;
+//! memory.register(&mut epoll, MEMORY)?;
+//!
+//! // in the dispatch of MEMORY
+//! let event = memory.read_event()?;
+//! shed_load(event.some.avg10);
+//! ```
+//!
+//! Triggers need Linux 5.2. The window has to be between 500ms and 10s and
+//! the threshold must not be longer, without CAP_SYS_RESOURCE the window
+//! also has to be a multiple of 2s. The kernel rejects others with EINVAL.
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::path::Path;
+use std::str;
+use std::time::Duration;
+
+use crate::{sys, Epoll, Interest, Mode, Token};
+
+/// Size of the buffer the pressure file is read into, it has two short lines
+const READ_BUFFER: usize = 256;
+
+/// A resource tasks can stall on
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Resource {
+    Cpu,
+    Memory,
+    Io,
+}
+
+impl Resource {
+    /// Name of the file in /proc/pressure
+    fn name(self) -> &'static str {
+        match self {
+            Resource::Cpu => "cpu",
+            Resource::Memory => "memory",
+            Resource::Io => "io",
+        }
+    }
+}
+
+/// Which stalls a trigger counts
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Stall {
+    /// Time at least one task stalled
+    Some,
+    /// Time all non-idle tasks stalled at once
+    Full,
+}
+
+impl Stall {
+    fn name(self) -> &'static str {
+        match self {
+            Stall::Some => "some",
+            Stall::Full => "full",
+        }
+    }
+}
+
+/// One line of a pressure file
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct StallStats {
+    /// Share of the last 10 seconds tasks stalled, in percent
+    pub avg10: f32,
+    /// Share of the last 60 seconds tasks stalled, in percent
+    pub avg60: f32,
+    /// Share of the last 300 seconds tasks stalled, in percent
+    pub avg300: f32,
+    /// Time tasks stalled in total
+    pub total: Duration,
+}
+
+impl StallStats {
+    fn parse(fields: str::SplitWhitespace<'_>) -> io::Result<StallStats> {
+        let mut stats = StallStats::default();
+
+        for field in fields {
+            let (key, value) = field.split_once('=').ok_or_else(invalid_data)?;
+
+            match key {
+                "avg10" => stats.avg10 = value.parse().map_err(|_| invalid_data())?,
+                "avg60" => stats.avg60 = value.parse().map_err(|_| invalid_data())?,
+                "avg300" => stats.avg300 = value.parse().map_err(|_| invalid_data())?,
+                "total" => stats.total = Duration::from_micros(value.parse().map_err(|_| invalid_data())?),
+                _ => {}
+            }
+        }
+
+        Ok(stats)
+    }
+}
+
+fn invalid_data() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "unexpected pressure file contents")
+}
+
+/// The stall totals read after a trigger fired
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PressureEvent {
+    pub resource: Resource,
+    /// The stall the trigger counts
+    pub stall: Stall,
+    pub some: StallStats,
+    /// None where the kernel does not report full stalls, e.g. CPU before 5.13
+    pub full: Option<StallStats>,
+}
+
+/// A PSI trigger, see the [module docs](self)
+#[derive(Debug)]
+pub struct Pressure {
+    fd: OwnedFd,
+    resource: Resource,
+    stall: Stall,
+    threshold: Duration,
+    window: Duration,
+}
+
+impl Pressure {
+    /// Trigger once tasks of the whole system stalled on `resource` for
+    /// `threshold` within `window`
+    pub fn new(resource: Resource, stall: Stall, threshold: Duration, window: Duration) -> io::Result<Pressure> {
+        let path = format!("/proc/pressure/{}", resource.name());
+        Pressure::open(Path::new(&path), resource, stall, threshold, window)
+    }
+
+    /// Trigger once tasks of the cgroup v2 directory `cgroup` stalled on
+    /// `resource` for `threshold` within `window`
+    pub fn cgroup<P: AsRef<Path>>(
+        cgroup: P,
+        resource: Resource,
+        stall: Stall,
+        threshold: Duration,
+        window: Duration
+    ) -> io::Result<Pressure> {
+        let path = cgroup.as_ref().join(format!("{}.pressure", resource.name()));
+        Pressure::open(&path, resource, stall, threshold, window)
+    }
+
+    fn open(path: &Path, resource: Resource, stall: Stall, threshold: Duration, window: Duration) -> io::Result<Pressure> {
+        let fd = sys::open(path, libc::O_RDWR | libc::O_NONBLOCK | libc::O_CLOEXEC)?;
+
+        // The trigger lives as long as the fd it was written to, the kernel
+        // wants it NUL terminated
+        let trigger = format!("{} {} {}\0", stall.name(), threshold.as_micros(), window.as_micros());
+        sys::write(fd.as_raw_fd(), trigger.as_bytes())?;
+
+        Ok(Pressure { fd, resource, stall, threshold, window })
+    }
+
+    /// Register with priority interest and level triggered
+    pub fn register(&self, epoll: &mut Epoll, token: Token) -> io::Result<()> {
+        epoll.register(&self.fd, token, Interest::PRIORITY, Mode::Level)
+    }
+
+    pub fn resource(&self) -> Resource {
+        self.resource
+    }
+
+    pub fn stall(&self) -> Stall {
+        self.stall
+    }
+
+    pub fn threshold(&self) -> Duration {
+        self.threshold
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Read the current stall totals, after EPOLLPRI was reported or at any
+    /// other time
+    pub fn read_event(&self) -> io::Result<PressureEvent> {
+        let mut buffer = [0u8; READ_BUFFER];
+
+        sys::lseek(self.fd.as_raw_fd(), 0, libc::SEEK_SET)?;
+        let n = sys::read(self.fd.as_raw_fd(), &mut buffer)?;
+        let contents = str::from_utf8(&buffer[..n]).map_err(|_| invalid_data())?;
+
+        let mut some = None;
+        let mut full = None;
+
+        for line in contents.lines() {
+            let mut fields = line.split_whitespace();
+
+            match fields.next() {
+                Some("some") => some = Some(StallStats::parse(fields)?),
+                Some("full") => full = Some(StallStats::parse(fields)?),
+                _ => {}
+            }
+        }
+
+        Ok(PressureEvent {
+            resource: self.resource,
+            stall: self.stall,
+            some: some.ok_or_else(invalid_data)?,
+            full,
+        })
+    }
+}
+
+impl AsRawFd for Pressure {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for Pressure {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
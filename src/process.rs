@@ -0,0 +1,349 @@
+//! Watching processes exit through the poll loop.
+//!
+//! A [`PidFd`] becomes readable once its process exits, it needs Linux 5.3
+//! for pidfd_open and 5.4 to wait on it. [`ChildWatcher`] owns a
+//! `std::process::Child` together with its pidfd and reaps it through std,
+//! [`EventLoop::insert_child`](crate::EventLoop::insert_child) hands the exit
+//! status straight to a callback.
+//!
+//! [`ChildOutput`] reads the stdout or stderr pipe of a child without
+//! blocking and hands it out in chunks or lines.
+//! [`EventLoop::insert_process`](crate::EventLoop::insert_process) supervises
+//! a child spawned with piped output: every line it prints and its exit
+//! status go to callbacks.
+use std::io;
+use std::mem;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Child, ChildStderr, ChildStdout, ExitStatus};
+
+use crate::sys;
+
+/// Size of the stack buffer ChildOutput reads into
+const READ_BUFFER: usize = 4096;
+
+/// Default longest line ChildOutput::read_lines hands out in one piece
+pub const MAX_LINE: usize = 64 * 1024;
+
+/// File descriptor referring to a process
+#[derive(Debug)]
+pub struct PidFd {
+    fd: OwnedFd,
+    pid: u32,
+}
+
+impl PidFd {
+    /// Open a pidfd for `pid`
+    pub fn open(pid: u32) -> io::Result<PidFd> {
+        let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0 as libc::c_uint) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+        Ok(PidFd { fd, pid })
+    }
+
+    /// Open a pidfd for a child spawned with std::process
+    pub fn from_child(child: &Child) -> io::Result<PidFd> {
+        PidFd::open(child.id())
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Reap the process if it exited, None while it is still running.
+    /// Only works for children of this process, do not mix it with
+    /// `Child::wait`, whichever reaps first gets the status
+    pub fn try_wait(&self) -> io::Result<Option<ExitStatus>> {
+        let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+
+        let ret = unsafe {
+            libc::waitid(
+                libc::P_PIDFD,
+                self.fd.as_raw_fd() as libc::id_t,
+                &mut info,
+                libc::WEXITED | libc::WNOHANG
+            )
+        };
+
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // WNOHANG with nothing to reap leaves the siginfo zeroed
+        if unsafe { info.si_pid() } == 0 {
+            return Ok(None);
+        }
+
+        let status = unsafe { info.si_status() };
+
+        // Rebuild the wait status ExitStatus is made of
+        let raw = match info.si_code {
+            libc::CLD_EXITED => (status & 0xff) << 8,
+            libc::CLD_DUMPED => status | 0x80,
+            _ => status,
+        };
+
+        Ok(Some(ExitStatus::from_raw(raw)))
+    }
+}
+
+impl AsRawFd for PidFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for PidFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+/// A child process and the pidfd telling when it exited
+#[derive(Debug)]
+pub struct ChildWatcher {
+    child: Child,
+    pidfd: PidFd,
+}
+
+impl ChildWatcher {
+    pub fn new(child: Child) -> io::Result<ChildWatcher> {
+        let pidfd = PidFd::from_child(&child)?;
+        Ok(ChildWatcher { child, pidfd })
+    }
+
+    pub fn child(&self) -> &Child {
+        &self.child
+    }
+
+    /// The child, e.g. to take its stdio or kill it
+    pub fn child_mut(&mut self) -> &mut Child {
+        &mut self.child
+    }
+
+    /// The exit status once the watcher was reported readable, None after
+    /// a spurious wakeup. The child is reaped by std, so calling it again
+    /// returns the same status
+    pub fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        self.child.try_wait()
+    }
+
+    pub fn into_child(self) -> Child {
+        self.child
+    }
+}
+
+impl AsRawFd for ChildWatcher {
+    fn as_raw_fd(&self) -> RawFd {
+        self.pidfd.as_raw_fd()
+    }
+}
+
+impl AsFd for ChildWatcher {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.pidfd.as_fd()
+    }
+}
+
+/// Which pipe of a child a [`ChildOutput`] reads
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Pipe {
+    Stdout,
+    Stderr,
+}
+
+/// The stdout or stderr pipe of a child, switched to non-blocking mode.
+/// Register it readable, level triggered, and read it on every event until
+/// a read method reports it closed
+#[derive(Debug)]
+pub struct ChildOutput {
+    fd: OwnedFd,
+    pipe: Pipe,
+    /// The start of a line that was not terminated yet
+    partial: Vec<u8>,
+    max_line: usize,
+    closed: bool,
+}
+
+impl ChildOutput {
+    pub fn stdout(stdout: ChildStdout) -> io::Result<ChildOutput> {
+        ChildOutput::new(stdout.into(), Pipe::Stdout)
+    }
+
+    pub fn stderr(stderr: ChildStderr) -> io::Result<ChildOutput> {
+        ChildOutput::new(stderr.into(), Pipe::Stderr)
+    }
+
+    /// Take the stdout and stderr pipes out of a child, None for the ones it
+    /// was not spawned with Stdio::piped()
+    pub fn take(child: &mut Child) -> io::Result<(Option<ChildOutput>, Option<ChildOutput>)> {
+        let stdout = child.stdout.take().map(ChildOutput::stdout).transpose()?;
+        let stderr = child.stderr.take().map(ChildOutput::stderr).transpose()?;
+
+        Ok((stdout, stderr))
+    }
+
+    fn new(fd: OwnedFd, pipe: Pipe) -> io::Result<ChildOutput> {
+        sys::set_nonblocking(fd.as_raw_fd())?;
+        Ok(ChildOutput { fd, pipe, partial: Vec::new(), max_line: MAX_LINE, closed: false })
+    }
+
+    pub fn pipe(&self) -> Pipe {
+        self.pipe
+    }
+
+    pub fn max_line(&self) -> usize {
+        self.max_line
+    }
+
+    /// Set the longest line read_lines hands out in one piece, [`MAX_LINE`]
+    /// by default. Longer lines are handed out in pieces of this length
+    pub fn set_max_line(&mut self, max_line: usize) -> io::Result<()> {
+        if max_line == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "lines need at least one byte"));
+        }
+
+        self.max_line = max_line;
+        Ok(())
+    }
+
+    /// Returns true once the child closed its end, usually because it exited
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Read until the pipe is empty, handing every chunk to `f` as it was
+    /// read. Returns true once the pipe is closed
+    pub fn read_chunks<F: FnMut(&[u8])>(&mut self, mut f: F) -> io::Result<bool> {
+        let mut buf = [0; READ_BUFFER];
+
+        while !self.closed {
+            match sys::read(self.fd.as_raw_fd(), &mut buf) {
+                Ok(0) => self.closed = true,
+                Ok(n) => f(&buf[..n]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(self.closed)
+    }
+
+    /// Read until the pipe is empty, handing every complete line to `f`
+    /// without its newline. The rest is kept for the next call, a last line
+    /// without a newline is handed out once the pipe is closed. Lines longer
+    /// than [`max_line`](ChildOutput::max_line) are handed out in pieces, a
+    /// child printing without newlines cannot grow the buffer without limit.
+    /// Returns true once the pipe is closed
+    pub fn read_lines<F: FnMut(&[u8])>(&mut self, mut f: F) -> io::Result<bool> {
+        let mut partial = mem::take(&mut self.partial);
+        let max_line = self.max_line;
+
+        let result = self.read_chunks(|chunk| {
+            partial.extend_from_slice(chunk);
+
+            let mut start = 0;
+            loop {
+                let rest = &partial[start..];
+
+                // A newline right after max_line bytes still ends the line
+                match rest.iter().take(max_line + 1).position(|byte| *byte == b'\n') {
+                    Some(end) => {
+                        f(&rest[..end]);
+                        start += end + 1;
+                    }
+                    None if rest.len() > max_line => {
+                        f(&rest[..max_line]);
+                        start += max_line;
+                    }
+                    None => break,
+                }
+            }
+
+            partial.drain(..start);
+        });
+
+        if self.closed && !partial.is_empty() {
+            f(&partial);
+            partial.clear();
+        }
+
+        self.partial = partial;
+        result
+    }
+}
+
+impl AsRawFd for ChildOutput {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for ChildOutput {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Write;
+    use std::process::Command;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{wait_for, Interest};
+
+    #[test]
+    fn long_lines_are_handed_out_in_pieces() {
+        let (read, write) = sys::pipe(libc::O_CLOEXEC).unwrap();
+        let mut output = ChildOutput::new(read, Pipe::Stdout).unwrap();
+        assert!(output.set_max_line(0).is_err());
+        output.set_max_line(4).unwrap();
+
+        let mut lines = Vec::new();
+        let mut write = File::from(write);
+        write.write_all(b"abcdefghij\nabcd\nab").unwrap();
+        assert!(!output.read_lines(|line| lines.push(line.to_vec())).unwrap());
+        assert_eq!(lines, [&b"abcd"[..], b"efgh", b"ij", b"abcd"]);
+
+        lines.clear();
+        write.write_all(b"cdef").unwrap();
+        drop(write);
+        assert!(output.read_lines(|line| lines.push(line.to_vec())).unwrap());
+        assert_eq!(lines, [&b"abcd"[..], b"ef"]);
+    }
+
+    // The children are reaped through their pidfd
+    #[test]
+    #[allow(clippy::zombie_processes)]
+    fn pidfd_reaps_an_exited_child() {
+        let child = Command::new("sh").args(["-c", "exit 3"]).spawn().unwrap();
+        let pidfd = PidFd::from_child(&child).unwrap();
+        assert_eq!(pidfd.pid(), child.id());
+
+        assert!(wait_for(&pidfd, Interest::READABLE, Some(Duration::from_secs(5))).unwrap().is_some());
+        assert_eq!(pidfd.try_wait().unwrap().and_then(|status| status.code()), Some(3));
+
+        // Reaped, nothing is left to wait for
+        assert!(pidfd.try_wait().is_err());
+    }
+
+    #[test]
+    #[allow(clippy::zombie_processes)]
+    fn pidfd_reports_running_and_killed_children() {
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        let pidfd = PidFd::from_child(&child).unwrap();
+
+        assert_eq!(pidfd.try_wait().unwrap(), None);
+
+        child.kill().unwrap();
+        assert!(wait_for(&pidfd, Interest::READABLE, Some(Duration::from_secs(5))).unwrap().is_some());
+        assert_eq!(pidfd.try_wait().unwrap().and_then(|status| status.signal()), Some(libc::SIGKILL));
+    }
+}
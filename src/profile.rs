@@ -0,0 +1,94 @@
+//! Wakeup attribution for tracking down unnecessary periodic wakeups
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::Token;
+
+/// Counts of why blocking polls returned, since profiling was enabled or reset.
+/// Polls with a zero timeout never sleep and are not counted
+#[derive(Debug, Clone)]
+pub struct WakeupProfile {
+    since: Instant,
+    wakeups: u64,
+    timeouts: u64,
+    interrupts: u64,
+    tokens: HashMap<Token, u64>,
+}
+
+impl WakeupProfile {
+    pub(crate) fn new() -> WakeupProfile {
+        WakeupProfile {
+            since: Instant::now(),
+            wakeups: 0,
+            timeouts: 0,
+            interrupts: 0,
+            tokens: HashMap::new(),
+        }
+    }
+
+    /// Total number of wakeups
+    pub fn wakeups(&self) -> u64 {
+        self.wakeups
+    }
+
+    /// Wakeups caused by the timeout expiring without any events
+    pub fn timeouts(&self) -> u64 {
+        self.timeouts
+    }
+
+    /// Wakeups caused by a signal interrupting the wait
+    pub fn interrupts(&self) -> u64 {
+        self.interrupts
+    }
+
+    /// How many wakeups the token took part in. A wakeup reporting
+    /// several tokens is attributed to every one of them
+    pub fn token_wakeups(&self, token: Token) -> u64 {
+        self.tokens.get(&token).copied().unwrap_or(0)
+    }
+
+    /// Tokens and their wakeup counts, the most frequent first
+    pub fn by_token(&self) -> Vec<(Token, u64)> {
+        let mut tokens: Vec<_> = self.tokens.iter().map(|(token, count)| (*token, *count)).collect();
+        tokens.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        tokens
+    }
+
+    /// Time covered by the profile
+    pub fn elapsed(&self) -> Duration {
+        self.since.elapsed()
+    }
+
+    /// Average wakeups per second over the profiled period
+    pub fn wakeups_per_second(&self) -> f64 {
+        let elapsed = self.elapsed().as_secs_f64();
+
+        if elapsed > 0.0 {
+            self.wakeups as f64 / elapsed
+        } else {
+            0.0
+        }
+    }
+
+    /// Start over with empty counts
+    pub fn reset(&mut self) {
+        *self = WakeupProfile::new();
+    }
+
+    pub(crate) fn record_token(&mut self, token: Token) {
+        *self.tokens.entry(token).or_insert(0) += 1;
+    }
+
+    pub(crate) fn record_wakeup(&mut self, events: usize) {
+        self.wakeups += 1;
+
+        if events == 0 {
+            self.timeouts += 1;
+        }
+    }
+
+    pub(crate) fn record_interrupt(&mut self) {
+        self.wakeups += 1;
+        self.interrupts += 1;
+    }
+}
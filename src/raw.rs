@@ -0,0 +1,95 @@
+//! Thin wrappers around the epoll syscalls, for code that wants to bypass
+//! [`Epoll`](crate::Epoll) and its bookkeeping.
+//!
+//! Flags are the plain epoll bits, [`EventFlags`](crate::EventFlags) has the
+//! readiness ones and the consts below the rest. The data of every event is
+//! the u64 it was registered with. Nothing is remembered between calls.
+//!
+//! ```ignore
+//! let epoll = raw::epoll_create1(true)?;
+//! raw::epoll_ctl(epoll.as_fd(), CtlOp::Add, socket.as_fd(), EventFlags::IN.bits() | raw::EDGE, 7)?;
+//!
+//! let mut events = [RawEvent::default(); 64];
+//! for event in raw::epoll_wait(epoll.as_fd(), &mut events, None)? {
+//!     println!("{} {:?}", event.data(), event.flags());
+//! }
+//! ```
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::fd::{AsRawFd, BorrowedFd, OwnedFd};
+use std::time::Duration;
+
+use crate::{sys, CtlOp, EventFlags};
+
+/// EPOLLET
+pub const EDGE: u32 = libc::EPOLLET as u32;
+/// EPOLLONESHOT
+pub const ONESHOT: u32 = libc::EPOLLONESHOT as u32;
+/// EPOLLEXCLUSIVE, only valid when adding
+pub const EXCLUSIVE: u32 = libc::EPOLLEXCLUSIVE as u32;
+/// EPOLLWAKEUP
+pub const WAKEUP: u32 = libc::EPOLLWAKEUP as u32;
+
+/// One struct epoll_event
+#[derive(Debug, Copy, Clone)]
+#[repr(transparent)]
+pub struct RawEvent(libc::epoll_event);
+
+impl RawEvent {
+    pub const fn new(events: u32, data: u64) -> RawEvent {
+        RawEvent(libc::epoll_event { events, u64: data })
+    }
+
+    pub const fn events(&self) -> u32 {
+        self.0.events
+    }
+
+    /// The readiness bits of the event
+    pub const fn flags(&self) -> EventFlags {
+        EventFlags::from_bits(self.0.events)
+    }
+
+    pub const fn data(&self) -> u64 {
+        self.0.u64
+    }
+}
+
+impl Default for RawEvent {
+    fn default() -> RawEvent {
+        RawEvent::new(0, 0)
+    }
+}
+
+/// A new epoll instance, with EPOLL_CLOEXEC if `cloexec` is set
+pub fn epoll_create1(cloexec: bool) -> io::Result<OwnedFd> {
+    sys::epoll_create(if cloexec { libc::EPOLL_CLOEXEC } else { 0 })
+}
+
+/// Add, modify or delete `fd` in the interest list of `epoll`. The events
+/// and data are ignored for a delete
+pub fn epoll_ctl(epoll: BorrowedFd<'_>, op: CtlOp, fd: BorrowedFd<'_>, events: u32, data: u64) -> io::Result<()> {
+    let op = match op {
+        CtlOp::Add => libc::EPOLL_CTL_ADD,
+        CtlOp::Modify => libc::EPOLL_CTL_MOD,
+        CtlOp::Delete => libc::EPOLL_CTL_DEL,
+    };
+
+    sys::epoll_ctl(epoll.as_raw_fd(), op, fd.as_raw_fd(), events, data)
+}
+
+/// Wait up to `timeout` for events, None waits forever, and return the ones
+/// that were stored at the start of `events`. Uses epoll_pwait2 where the
+/// kernel has it. A signal interrupting the wait fails with Interrupted
+pub fn epoll_wait<'a>(
+    epoll: BorrowedFd<'_>,
+    events: &'a mut [RawEvent],
+    timeout: Option<Duration>
+) -> io::Result<&'a [RawEvent]> {
+    // RawEvent is a transparent epoll_event and every element is initialized
+    let buffer = unsafe {
+        std::slice::from_raw_parts_mut(events.as_mut_ptr() as *mut MaybeUninit<libc::epoll_event>, events.len())
+    };
+
+    let n = crate::epoll_wait(epoll.as_raw_fd(), buffer, timeout, None)?;
+    Ok(&events[..n])
+}
@@ -0,0 +1,437 @@
+//! Driving futures from the poll loop.
+//!
+//! A [`Reactor`] owns an epoll instance, [`Async`] registers an fd in it edge
+//! triggered and parks the waker of a future waiting on the fd until
+//! [`Reactor::poll`] sees the readiness arrive. Nothing here is an executor:
+//! run the futures with any executor and call `poll` on the same thread
+//! whenever they are all pending. With the `futures-io` feature [`Async`]
+//! implements `AsyncRead` and `AsyncWrite`.
+//!
+//! ```ignore
+//! let reactor = Reactor::new()?;
+//! let stream = Async::new(TcpStream::connect(addr)?, &reactor)?;
+//!
+//! let mut buf = [0; 1024];
+//! let read = stream.read_with(|mut stream| stream.read(&mut buf));
+//! // poll `read` with an executor, reactor.poll(None) while it is pending
+//! ```
+//!
+//! [`block_on`](crate::block_on) is such an executor, it drives the
+//! reactor of the current thread, see [`Reactor::current`].
+use std::cell::RefCell;
+use std::future;
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+use crate::{sys, Epoll, Events, Interest, Mode, Readiness, Slab, Timeout, Token};
+
+/// Token of the waker behind [`Reactor::notifier`], the slab never hands it out
+const NOTIFY: Token = Token(usize::MAX);
+
+thread_local! {
+    static CURRENT: RefCell<Option<Reactor>> = const { RefCell::new(None) };
+}
+
+/// Readiness seen since the last WouldBlock and the parked wakers of one fd
+#[derive(Debug, Default)]
+struct SourceState {
+    readable: bool,
+    writable: bool,
+    reader: Option<Waker>,
+    writer: Option<Waker>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    epoll: Epoll,
+    sources: Slab<SourceState>,
+    events: Events,
+    notifier: Option<crate::Waker>,
+}
+
+/// Epoll instance waking the futures waiting on its fds. Clones share the
+/// instance, it is bound to the thread it was created on
+#[derive(Debug, Clone)]
+pub struct Reactor {
+    inner: Rc<RefCell<Inner>>,
+}
+
+impl Reactor {
+    pub fn new() -> io::Result<Reactor> {
+        let inner = Inner {
+            epoll: Epoll::create()?,
+            sources: Slab::new(),
+            events: Events::new(),
+            notifier: None,
+        };
+
+        Ok(Reactor { inner: Rc::new(RefCell::new(inner)) })
+    }
+
+    /// The reactor of the calling thread, created on first use
+    pub fn current() -> io::Result<Reactor> {
+        CURRENT.with(|current| {
+            let mut current = current.borrow_mut();
+
+            if let Some(reactor) = current.as_ref() {
+                return Ok(reactor.clone());
+            }
+
+            let reactor = Reactor::new()?;
+            *current = Some(reactor.clone());
+            Ok(reactor)
+        })
+    }
+
+    /// A waker making a blocked [`Reactor::poll`] return, from any thread.
+    /// Created on first use, every call returns a handle to the same one
+    pub fn notifier(&self) -> io::Result<crate::Waker> {
+        let inner = &mut *self.inner.borrow_mut();
+
+        if let Some(notifier) = inner.notifier.as_ref() {
+            return Ok(notifier.clone());
+        }
+
+        let notifier = inner.epoll.waker(NOTIFY)?;
+        inner.notifier = Some(notifier.clone());
+        Ok(notifier)
+    }
+
+    /// Wait up to `timeout` for readiness and wake the futures waiting for
+    /// it. Returns the number of futures woken
+    pub fn poll<T: Into<Timeout>>(&self, timeout: T) -> io::Result<usize> {
+        let mut wakers = Vec::new();
+
+        {
+            let inner = &mut *self.inner.borrow_mut();
+            inner.epoll.poll_into(&mut inner.events, timeout)?;
+
+            for (token, readiness) in &inner.events {
+                if let Some(state) = inner.sources.get_mut(token) {
+                    if is_readable(readiness) {
+                        state.readable = true;
+                        wakers.extend(state.reader.take());
+                    }
+                    if is_writable(readiness) {
+                        state.writable = true;
+                        wakers.extend(state.writer.take());
+                    }
+                }
+            }
+        }
+
+        // Woken futures may be polled right away, the borrow must be gone
+        let woken = wakers.len();
+        for waker in wakers {
+            waker.wake();
+        }
+
+        Ok(woken)
+    }
+
+    /// Number of registered fds
+    pub fn len(&self) -> usize {
+        self.inner.borrow().sources.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.borrow().sources.is_empty()
+    }
+}
+
+impl AsRawFd for Reactor {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.borrow().epoll.as_raw_fd()
+    }
+}
+
+/// Errors and hangups wake both directions, the next operation reports them
+fn is_readable(readiness: Readiness) -> bool {
+    readiness.readable || readiness.read_closed || readiness.error
+}
+
+fn is_writable(readiness: Readiness) -> bool {
+    readiness.writable || readiness.write_closed || readiness.error
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Direction {
+    Read,
+    Write,
+}
+
+/// An fd registered in a [`Reactor`], waited on asynchronously.
+/// The fd is switched to non-blocking mode
+#[derive(Debug)]
+pub struct Async<T: AsFd> {
+    io: Option<T>,
+    token: Token,
+    reactor: Reactor,
+}
+
+impl<T: AsFd> Async<T> {
+    /// Register `io` readable and writable, edge triggered
+    pub fn new(io: T, reactor: &Reactor) -> io::Result<Async<T>> {
+        let fd = io.as_fd().as_raw_fd();
+        sys::set_nonblocking(fd)?;
+
+        let token = {
+            let inner = &mut *reactor.inner.borrow_mut();
+            inner.sources.register(
+                &mut inner.epoll,
+                &io.as_fd(),
+                Interest::READABLE | Interest::WRITABLE | Interest::READ_CLOSED,
+                Mode::Edge,
+                SourceState::default()
+            )?
+        };
+
+        Ok(Async { io: Some(io), token, reactor: reactor.clone() })
+    }
+
+    pub fn get_ref(&self) -> &T {
+        self.io.as_ref().expect("io is only taken on drop")
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.io.as_mut().expect("io is only taken on drop")
+    }
+
+    /// Unregister the fd and get it back, it stays non-blocking
+    pub fn into_inner(mut self) -> io::Result<T> {
+        let io = self.io.take().expect("io is only taken on drop");
+        self.deregister(&io)?;
+        Ok(io)
+    }
+
+    /// Ready once the fd was reported readable since the last WouldBlock
+    pub fn poll_readable(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_ready(Direction::Read, cx)
+    }
+
+    /// Ready once the fd was reported writable since the last WouldBlock
+    pub fn poll_writable(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_ready(Direction::Write, cx)
+    }
+
+    /// Wait until the fd is readable
+    pub async fn readable(&self) -> io::Result<()> {
+        future::poll_fn(|cx| self.poll_readable(cx)).await
+    }
+
+    /// Wait until the fd is writable
+    pub async fn writable(&self) -> io::Result<()> {
+        future::poll_fn(|cx| self.poll_writable(cx)).await
+    }
+
+    /// Retry the non-blocking `op` until it does not return WouldBlock,
+    /// waiting for readability in between
+    pub async fn read_with<R, F: FnMut(&T) -> io::Result<R>>(&self, mut op: F) -> io::Result<R> {
+        future::poll_fn(|cx| self.poll_op(Direction::Read, cx, |io| op(io))).await
+    }
+
+    /// Retry the non-blocking `op` until it does not return WouldBlock,
+    /// waiting for writability in between
+    pub async fn write_with<R, F: FnMut(&T) -> io::Result<R>>(&self, mut op: F) -> io::Result<R> {
+        future::poll_fn(|cx| self.poll_op(Direction::Write, cx, |io| op(io))).await
+    }
+
+    fn poll_ready(&self, direction: Direction, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut inner = self.reactor.inner.borrow_mut();
+        let state = match inner.sources.get_mut(self.token) {
+            Some(state) => state,
+            None => return Poll::Ready(Err(io::Error::new(io::ErrorKind::NotFound, "not registered"))),
+        };
+
+        let (ready, waker) = match direction {
+            Direction::Read => (state.readable, &mut state.reader),
+            Direction::Write => (state.writable, &mut state.writer),
+        };
+
+        if ready {
+            return Poll::Ready(Ok(()));
+        }
+
+        match waker {
+            Some(waker) if waker.will_wake(cx.waker()) => {}
+            _ => *waker = Some(cx.waker().clone()),
+        }
+
+        Poll::Pending
+    }
+
+    /// Forget the readiness of `direction` after an operation hit WouldBlock
+    fn clear(&self, direction: Direction) {
+        if let Some(state) = self.reactor.inner.borrow_mut().sources.get_mut(self.token) {
+            match direction {
+                Direction::Read => state.readable = false,
+                Direction::Write => state.writable = false,
+            }
+        }
+    }
+
+    fn poll_op<R, F: FnMut(&T) -> io::Result<R>>(
+        &self,
+        direction: Direction,
+        cx: &mut Context<'_>,
+        mut op: F
+    ) -> Poll<io::Result<R>> {
+        loop {
+            if let Poll::Ready(Err(err)) = self.poll_ready(direction, cx) {
+                return Poll::Ready(Err(err));
+            }
+
+            // Try even without readiness, the fd may be ready from before
+            // it was registered. Edge triggering only reports changes
+            match op(self.get_ref()) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                result => return Poll::Ready(result),
+            }
+
+            self.clear(direction);
+
+            if self.poll_ready(direction, cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+    }
+
+    fn deregister(&self, io: &T) -> io::Result<()> {
+        let inner = &mut *self.reactor.inner.borrow_mut();
+        inner.sources.unregister(&mut inner.epoll, &io.as_fd(), self.token).map(drop)
+    }
+}
+
+impl<T: AsFd> AsFd for Async<T> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.get_ref().as_fd()
+    }
+}
+
+impl<T: AsFd> Drop for Async<T> {
+    fn drop(&mut self) {
+        if let Some(io) = self.io.take() {
+            let _ = self.deregister(&io);
+        }
+    }
+}
+
+#[cfg(feature = "futures-io")]
+mod futures_io_impls {
+    use std::io::{self, Read, Write};
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use futures_io::{AsyncRead, AsyncWrite};
+
+    use super::{Async, Direction};
+
+    impl<T> AsyncRead for Async<T>
+    where
+        T: std::os::fd::AsFd + Unpin,
+        for<'a> &'a T: Read,
+    {
+        fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            self.poll_op(Direction::Read, cx, |mut io| io.read(buf))
+        }
+    }
+
+    impl<T> AsyncWrite for Async<T>
+    where
+        T: std::os::fd::AsFd + Unpin,
+        for<'a> &'a T: Write,
+    {
+        fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.poll_op(Direction::Write, cx, |mut io| io.write(buf))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.poll_op(Direction::Write, cx, |mut io| io.flush())
+        }
+
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.poll_flush(cx)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::pin::pin;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::task::Wake;
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Records whether it was woken
+    #[derive(Default)]
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn readiness_wakes_the_waiting_future() {
+        let reactor = Reactor::new().unwrap();
+        let (stream, peer) = UnixStream::pair().unwrap();
+        let stream = Async::new(stream, &reactor).unwrap();
+        assert_eq!(reactor.len(), 1);
+
+        let flag = Arc::new(Flag::default());
+        let waker = Waker::from(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut buf = [0; 16];
+        let mut read = pin!(stream.read_with(|mut stream| stream.read(&mut buf)));
+        assert!(read.as_mut().poll(&mut cx).is_pending());
+
+        (&peer).write_all(b"hello").unwrap();
+        reactor.poll(Duration::from_secs(1)).unwrap();
+        assert!(flag.0.load(Ordering::SeqCst));
+
+        match read.as_mut().poll(&mut cx) {
+            Poll::Ready(Ok(n)) => assert_eq!(n, 5),
+            other => panic!("read not done: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn dropping_unregisters() {
+        let reactor = Reactor::new().unwrap();
+        let (stream, _peer) = UnixStream::pair().unwrap();
+
+        let stream = Async::new(stream, &reactor).unwrap();
+        drop(stream);
+        assert!(reactor.is_empty());
+
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        let stream = Async::new(stream, &reactor).unwrap().into_inner().unwrap();
+        assert!(reactor.is_empty());
+        drop(stream);
+    }
+
+    #[test]
+    fn notifier_wakes_a_blocked_poll() {
+        let reactor = Reactor::new().unwrap();
+        let notifier = reactor.notifier().unwrap();
+
+        let waker = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            notifier.wake().unwrap();
+        });
+
+        // Nothing waited on the notifier itself
+        assert_eq!(reactor.poll(Duration::from_secs(5)).unwrap(), 0);
+        waker.join().unwrap();
+    }
+}
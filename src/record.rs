@@ -0,0 +1,204 @@
+//! Recording delivered events and replaying them later.
+//!
+//! A [`Recorder`] writes every batch returned by poll into a compact binary
+//! log, a [`Replay`] reads such a log back and hands out the same batches in
+//! the same order, so a production trace can drive a postmortem session or a
+//! regression test through the code that normally consumes `Epoll::poll`.
+//!
+//! The log starts with the magic `COPOLLTR` and a version byte. Each batch is
+//! the time since recording started in nanoseconds (u64) and the number of
+//! events (u32), followed by every event as its token (u64) and raw epoll
+//! readiness bits (u32). All integers are little endian.
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{Events, Readiness, Token};
+
+const MAGIC: &[u8; 8] = b"COPOLLTR";
+const VERSION: u8 = 2;
+
+/// Events allocated up front for a batch, larger ones grow while being read
+/// so a corrupt count can not allocate much before the read fails
+const MAX_PREALLOCATED: usize = 1024;
+
+/// Writes event batches into a binary log
+#[derive(Debug)]
+pub struct Recorder<W: Write> {
+    writer: W,
+    started: Instant,
+}
+
+impl<W: Write> Recorder<W> {
+    /// Start a new log, the header is written right away
+    pub fn new(mut writer: W) -> io::Result<Recorder<W>> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+
+        Ok(Recorder { writer, started: Instant::now() })
+    }
+
+    /// Append one batch, call it with the events of every poll.
+    /// Empty batches are recorded too, they mark timeouts
+    pub fn record(&mut self, events: &Events) -> io::Result<()> {
+        let offset = self.started.elapsed().as_nanos().min(u64::MAX as u128) as u64;
+
+        self.writer.write_all(&offset.to_le_bytes())?;
+        self.writer.write_all(&(events.len() as u32).to_le_bytes())?;
+
+        for (token, readiness) in events {
+            self.writer.write_all(&(token.0 as u64).to_le_bytes())?;
+            self.writer.write_all(&readiness.raw_bits().to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Flush the underlying writer
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Get back the underlying writer
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// One recorded batch
+#[derive(Debug)]
+pub struct Batch {
+    /// When the batch was delivered, relative to the start of the recording
+    pub offset: Duration,
+    pub events: Events,
+}
+
+/// Reads a log written by [`Recorder`] and hands out its batches
+#[derive(Debug)]
+pub struct Replay<R: Read> {
+    reader: R,
+    realtime: bool,
+    started: Option<Instant>,
+}
+
+impl<R: Read> Replay<R> {
+    /// Open a log, the header is checked right away
+    pub fn new(mut reader: R) -> io::Result<Replay<R>> {
+        let mut header = [0u8; 9];
+        reader.read_exact(&mut header)?;
+
+        if &header[..8] != MAGIC || header[8] != VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a copoll event trace"));
+        }
+
+        Ok(Replay { reader, realtime: false, started: None })
+    }
+
+    /// When enabled, [`Replay::poll`] sleeps until each batch is due,
+    /// reproducing the original timing. Disabled by default
+    pub fn set_realtime(&mut self, enabled: bool) {
+        self.realtime = enabled;
+    }
+
+    /// Read the next batch, None once the log is exhausted
+    pub fn next_batch(&mut self) -> io::Result<Option<Batch>> {
+        let mut offset = [0u8; 8];
+        match self.reader.read_exact(&mut offset) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+
+        let mut count = [0u8; 4];
+        self.reader.read_exact(&mut count)?;
+
+        let count = u32::from_le_bytes(count) as usize;
+        let mut events = Events::with_capacity(count.min(MAX_PREALLOCATED));
+
+        for _ in 0..count {
+            let mut token = [0u8; 8];
+            self.reader.read_exact(&mut token)?;
+
+            let mut bits = [0u8; 4];
+            self.reader.read_exact(&mut bits)?;
+
+            events.push(
+                Token(u64::from_le_bytes(token) as usize),
+                Readiness::from_bits(u32::from_le_bytes(bits))
+            );
+        }
+
+        Ok(Some(Batch { offset: Duration::from_nanos(u64::from_le_bytes(offset)), events }))
+    }
+
+    /// Drop-in for `Epoll::poll` returning the recorded batches one by one,
+    /// None once the log is exhausted
+    pub fn poll(&mut self) -> io::Result<Option<Events>> {
+        let batch = match self.next_batch()? {
+            Some(batch) => batch,
+            None => return Ok(None),
+        };
+
+        if self.realtime {
+            let started = *self.started.get_or_insert_with(Instant::now);
+            if let Some(remaining) = batch.offset.checked_sub(started.elapsed()) {
+                thread::sleep(remaining);
+            }
+        }
+
+        Ok(Some(batch.events))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch(events: &[(usize, u32)]) -> Events {
+        let mut batch = Events::new();
+        for (token, bits) in events {
+            batch.push(Token(*token), Readiness::from_bits(*bits));
+        }
+        batch
+    }
+
+    fn contents(events: &Events) -> Vec<(Token, u32)> {
+        events.iter().map(|(token, readiness)| (token, readiness.raw_bits())).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let readable = libc::EPOLLIN as u32;
+        let hangup = (libc::EPOLLIN | libc::EPOLLHUP) as u32;
+        let batches = [batch(&[(1, readable), (usize::MAX - 1, hangup)]), batch(&[]), batch(&[(7, readable)])];
+
+        let mut recorder = Recorder::new(Vec::new()).unwrap();
+        for events in &batches {
+            recorder.record(events).unwrap();
+        }
+
+        let log = recorder.into_inner();
+        let mut replay = Replay::new(&log[..]).unwrap();
+        for events in &batches {
+            assert_eq!(contents(&replay.poll().unwrap().unwrap()), contents(events));
+        }
+
+        assert!(replay.poll().unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_foreign_data() {
+        assert_eq!(Replay::new(&b"NOTATRACE"[..]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn truncated_batch_fails() {
+        let mut log = Recorder::new(Vec::new()).unwrap().into_inner();
+        log.extend_from_slice(&0u64.to_le_bytes());
+        log.extend_from_slice(&u32::MAX.to_le_bytes());
+        log.extend_from_slice(&1u64.to_le_bytes());
+
+        let mut replay = Replay::new(&log[..]).unwrap();
+        assert_eq!(replay.next_batch().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,222 @@
+use std::collections::{HashMap, TryReserveError};
+use std::os::unix::io::RawFd;
+
+use crate::duplicate::FileId;
+use crate::sys;
+use crate::{Interest, Mode, OnError, Token};
+
+/// Upper bound on the dense table when RLIMIT_NOFILE is unlimited or huge
+const MAX_DENSE_LIMIT: usize = 1 << 20;
+
+/// Used when RLIMIT_NOFILE can not be read
+const DEFAULT_DENSE_LIMIT: usize = 1024;
+
+/// What the epoll instance remembers about a registered file descriptor
+#[derive(Debug, Clone)]
+pub(crate) struct Registration {
+    pub token: Token,
+    pub interest: Interest,
+    pub mode: Mode,
+    pub label: Option<String>,
+    pub exclusive: bool,
+    pub wakeup: bool,
+    /// The file the fd referred to when it was registered
+    pub file: Option<FileId>,
+    pub on_error: OnError,
+    /// The fd is a duplicate made by register_dup and owned by the instance
+    pub dup: bool,
+}
+
+impl Registration {
+    pub fn new(token: Token, interest: Interest, mode: Mode) -> Registration {
+        Registration { token, interest, mode, label: None, exclusive: false, wakeup: false, file: None, on_error: OnError::Keep, dup: false }
+    }
+}
+
+/// Bookkeeping of every file descriptor currently registered.
+///
+/// File descriptors below the soft RLIMIT_NOFILE are stored in a vector
+/// indexed by the fd itself, which is grown on demand and never hashed.
+/// Anything above the limit (the limit can be raised at runtime) falls
+/// back to a sparse map.
+#[derive(Debug, Clone)]
+pub(crate) struct Registry {
+    dense: Vec<Option<Registration>>,
+    sparse: HashMap<RawFd, Registration>,
+    /// Registered fds by the file behind them, only kept for duplicate detection
+    files: HashMap<FileId, Vec<RawFd>>,
+    index_files: bool,
+    dense_limit: usize,
+    len: usize,
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        let dense_limit = match sys::nofile_limit() {
+            Ok((Some(soft), _)) => (soft as usize).min(MAX_DENSE_LIMIT),
+            Ok((None, _)) => MAX_DENSE_LIMIT,
+            Err(_) => DEFAULT_DENSE_LIMIT,
+        };
+
+        Registry {
+            dense: Vec::new(),
+            sparse: HashMap::new(),
+            files: HashMap::new(),
+            index_files: false,
+            dense_limit,
+            len: 0,
+        }
+    }
+}
+
+impl Registry {
+    /// Start or stop indexing registrations by their file. Registrations
+    /// made while indexing was off are not part of the index
+    pub fn set_index_files(&mut self, enabled: bool) {
+        self.index_files = enabled;
+
+        if !enabled {
+            self.files.clear();
+        }
+    }
+
+    pub fn indexes_files(&self) -> bool {
+        self.index_files
+    }
+
+    pub fn insert(&mut self, fd: RawFd, registration: Registration) {
+        if let Some(file) = registration.file.filter(|_| self.index_files) {
+            self.files.entry(file).or_default().push(fd);
+        }
+
+        let previous = match dense_index(fd, self.dense_limit) {
+            Some(index) => {
+                if index >= self.dense.len() {
+                    self.dense.resize(index + 1, None);
+                }
+
+                self.dense[index].replace(registration)
+            }
+            None => self.sparse.insert(fd, registration),
+        };
+
+        match previous {
+            Some(previous) => self.forget_file(fd, previous.file),
+            None => self.len += 1,
+        }
+    }
+
+    /// Reserve what inserting `fd` needs, so the insert itself does not allocate
+    pub fn try_reserve(&mut self, fd: RawFd, file: Option<FileId>) -> Result<(), TryReserveError> {
+        match dense_index(fd, self.dense_limit) {
+            Some(index) => self.dense.try_reserve((index + 1).saturating_sub(self.dense.len()))?,
+            None => self.sparse.try_reserve(1)?,
+        }
+
+        if let Some(file) = file.filter(|_| self.index_files) {
+            match self.files.get_mut(&file) {
+                Some(fds) => fds.try_reserve(1)?,
+                None => {
+                    let mut fds = Vec::new();
+                    fds.try_reserve(1)?;
+                    self.files.try_reserve(1)?;
+                    self.files.insert(file, fds);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn get(&self, fd: RawFd) -> Option<&Registration> {
+        match dense_index(fd, self.dense_limit) {
+            Some(index) => self.dense.get(index).and_then(Option::as_ref),
+            None => self.sparse.get(&fd),
+        }
+    }
+
+    pub fn get_mut(&mut self, fd: RawFd) -> Option<&mut Registration> {
+        match dense_index(fd, self.dense_limit) {
+            Some(index) => self.dense.get_mut(index).and_then(Option::as_mut),
+            None => self.sparse.get_mut(&fd),
+        }
+    }
+
+    pub fn remove(&mut self, fd: RawFd) -> Option<Registration> {
+        let removed = match dense_index(fd, self.dense_limit) {
+            Some(index) => {
+                let removed = self.dense.get_mut(index).and_then(Option::take);
+
+                // Keep the last slot occupied so the table only spans live fds
+                while let Some(None) = self.dense.last() {
+                    self.dense.pop();
+                }
+
+                removed
+            }
+            None => self.sparse.remove(&fd),
+        };
+
+        if let Some(registration) = &removed {
+            self.forget_file(fd, registration.file);
+            self.len -= 1;
+        }
+
+        removed
+    }
+
+    /// Registered fds backed by the given file
+    pub fn same_file(&self, file: FileId) -> &[RawFd] {
+        self.files.get(&file).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    fn forget_file(&mut self, fd: RawFd, file: Option<FileId>) {
+        let file = match file {
+            Some(file) => file,
+            None => return,
+        };
+
+        if let Some(fds) = self.files.get_mut(&file) {
+            if let Some(position) = fds.iter().position(|other| *other == fd) {
+                fds.swap_remove(position);
+            }
+
+            if fds.is_empty() {
+                self.files.remove(&file);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Iterate over all registrations, dense fds come first in ascending order
+    pub fn iter(&self) -> impl Iterator<Item = (RawFd, &Registration)> {
+        let dense = self.dense.iter().enumerate().filter_map(|(fd, registration)| {
+            registration.as_ref().map(|registration| (fd as RawFd, registration))
+        });
+
+        dense.chain(self.sparse.iter().map(|(fd, registration)| (*fd, registration)))
+    }
+
+    /// Returns the registration if it is the only one
+    pub fn single(&self) -> Option<(RawFd, &Registration)> {
+        if self.len != 1 {
+            return None;
+        }
+
+        match self.dense.last() {
+            Some(Some(registration)) => Some(((self.dense.len() - 1) as RawFd, registration)),
+            _ => self.sparse.iter().next().map(|(fd, registration)| (*fd, registration)),
+        }
+    }
+}
+
+fn dense_index(fd: RawFd, dense_limit: usize) -> Option<usize> {
+    usize::try_from(fd).ok().filter(|index| *index < dense_limit)
+}
@@ -0,0 +1,58 @@
+//! Registrations that borrow their source.
+//!
+//! [`Epoll::register_scoped`](crate::Epoll::register_scoped) returns a
+//! [`ScopedRegistration`] holding a borrow of the registered source, so the
+//! source can not be dropped, and its fd closed, while it is registered.
+//! Dropping the registration removes the fd from the interest list right
+//! away. The instance forgets about it with its next registration or poll.
+use std::marker::PhantomData;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::sync::{Arc, Mutex};
+
+use crate::{sys, Token};
+
+/// Scoped registrations dropped since the instance last looked
+pub(crate) type Released = Arc<Mutex<Vec<(RawFd, Token)>>>;
+
+/// A registration borrowing its source for `'a`, unregistered on drop
+#[derive(Debug)]
+pub struct ScopedRegistration<'a> {
+    fd: RawFd,
+    token: Token,
+    epoll_fd: Arc<OwnedFd>,
+    released: Released,
+    source: PhantomData<BorrowedFd<'a>>,
+}
+
+impl<'a> ScopedRegistration<'a> {
+    pub(crate) fn new(fd: BorrowedFd<'a>, token: Token, epoll_fd: Arc<OwnedFd>, released: Released) -> ScopedRegistration<'a> {
+        ScopedRegistration { fd: fd.as_raw_fd(), token, epoll_fd, released, source: PhantomData }
+    }
+
+    pub fn token(&self) -> Token {
+        self.token
+    }
+}
+
+impl AsRawFd for ScopedRegistration<'_> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl AsFd for ScopedRegistration<'_> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        // The source is borrowed for as long as the registration lives
+        unsafe { BorrowedFd::borrow_raw(self.fd) }
+    }
+}
+
+impl Drop for ScopedRegistration<'_> {
+    fn drop(&mut self) {
+        // Fails if the fd was unregistered explicitly, which is fine
+        let _ = sys::epoll_ctl(self.epoll_fd.as_raw_fd(), libc::EPOLL_CTL_DEL, self.fd, 0, 0);
+
+        let mut released = self.released.lock().unwrap_or_else(|err| err.into_inner());
+        released.push((self.fd, self.token));
+    }
+}
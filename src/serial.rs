@@ -0,0 +1,201 @@
+//! Serial ports configured through termios.
+//!
+//! A [`SerialPort`] is opened non-blocking, put into the configured mode and
+//! restored to its previous settings on drop. Register it like any other fd.
+//! With modem control enabled CLOCAL is cleared, so losing the carrier makes
+//! the tty hang up and poll reports EPOLLHUP, check it with [`is_hangup`].
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::path::Path;
+
+use crate::{sys, Readiness};
+
+/// Parity bit setting
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+/// Line settings of a serial port, the default is 9600 8N1 in raw mode
+#[derive(Debug, Clone)]
+pub struct SerialConfig {
+    pub baud: u32,
+    /// 5 to 8
+    pub data_bits: u8,
+    pub parity: Parity,
+    /// 1 or 2
+    pub stop_bits: u8,
+    /// RTS/CTS hardware flow control
+    pub flow_control: bool,
+    /// Honour the modem status lines, a lost carrier hangs up the port
+    pub modem_control: bool,
+    /// No line editing, echo or translation of any byte
+    pub raw: bool,
+}
+
+impl Default for SerialConfig {
+    fn default() -> SerialConfig {
+        SerialConfig {
+            baud: 9600,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: 1,
+            flow_control: false,
+            modem_control: false,
+            raw: true,
+        }
+    }
+}
+
+/// Returns true if the readiness reported for a serial port is a hangup,
+/// e.g. the carrier was lost. Reads return end of file from then on
+pub fn is_hangup(readiness: &Readiness) -> bool {
+    readiness.hangup
+}
+
+/// An open serial port
+#[derive(Debug)]
+pub struct SerialPort {
+    fd: OwnedFd,
+    original: libc::termios,
+}
+
+impl SerialPort {
+    /// Open a tty like /dev/ttyUSB0 and apply `config`
+    pub fn open<P: AsRef<Path>>(path: P, config: &SerialConfig) -> io::Result<SerialPort> {
+        let fd = sys::open(path.as_ref(), libc::O_RDWR | libc::O_NOCTTY | libc::O_NONBLOCK)?;
+
+        let mut original = mem::MaybeUninit::<libc::termios>::uninit();
+        sys::cvt(unsafe { libc::tcgetattr(fd.as_raw_fd(), original.as_mut_ptr()) })?;
+        let original = unsafe { original.assume_init() };
+
+        let port = SerialPort { fd, original };
+        port.configure(config)?;
+
+        Ok(port)
+    }
+
+    /// Apply new line settings, the ones found at open are still restored on drop
+    pub fn configure(&self, config: &SerialConfig) -> io::Result<()> {
+        let mut settings = self.original;
+
+        if config.raw {
+            unsafe { libc::cfmakeraw(&mut settings) };
+        }
+
+        sys::cvt(unsafe { libc::cfsetspeed(&mut settings, baud_rate(config.baud)?) })?;
+
+        let flags = &mut settings.c_cflag;
+        *flags &= !libc::CSIZE;
+        *flags |= match config.data_bits {
+            5 => libc::CS5,
+            6 => libc::CS6,
+            7 => libc::CS7,
+            8 => libc::CS8,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "data bits must be 5 to 8")),
+        };
+
+        match config.parity {
+            Parity::None => *flags &= !(libc::PARENB | libc::PARODD),
+            Parity::Even => {
+                *flags |= libc::PARENB;
+                *flags &= !libc::PARODD;
+            }
+            Parity::Odd => *flags |= libc::PARENB | libc::PARODD,
+        }
+
+        match config.stop_bits {
+            1 => *flags &= !libc::CSTOPB,
+            2 => *flags |= libc::CSTOPB,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "stop bits must be 1 or 2")),
+        }
+
+        set_flag(flags, libc::CRTSCTS, config.flow_control);
+        set_flag(flags, libc::CLOCAL, !config.modem_control);
+        *flags |= libc::CREAD;
+
+        sys::cvt(unsafe { libc::tcsetattr(self.fd.as_raw_fd(), libc::TCSANOW, &settings) }).map(drop)
+    }
+}
+
+fn set_flag(flags: &mut libc::tcflag_t, flag: libc::tcflag_t, enabled: bool) {
+    if enabled {
+        *flags |= flag;
+    } else {
+        *flags &= !flag;
+    }
+}
+
+fn baud_rate(baud: u32) -> io::Result<libc::speed_t> {
+    Ok(match baud {
+        50 => libc::B50,
+        75 => libc::B75,
+        110 => libc::B110,
+        134 => libc::B134,
+        150 => libc::B150,
+        200 => libc::B200,
+        300 => libc::B300,
+        600 => libc::B600,
+        1200 => libc::B1200,
+        1800 => libc::B1800,
+        2400 => libc::B2400,
+        4800 => libc::B4800,
+        9600 => libc::B9600,
+        19200 => libc::B19200,
+        38400 => libc::B38400,
+        57600 => libc::B57600,
+        115200 => libc::B115200,
+        230400 => libc::B230400,
+        460800 => libc::B460800,
+        500000 => libc::B500000,
+        576000 => libc::B576000,
+        921600 => libc::B921600,
+        1000000 => libc::B1000000,
+        1152000 => libc::B1152000,
+        1500000 => libc::B1500000,
+        2000000 => libc::B2000000,
+        2500000 => libc::B2500000,
+        3000000 => libc::B3000000,
+        3500000 => libc::B3500000,
+        4000000 => libc::B4000000,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "unsupported baud rate")),
+    })
+}
+
+impl Read for SerialPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        sys::read(self.fd.as_raw_fd(), buf)
+    }
+}
+
+impl Write for SerialPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        sys::write(self.fd.as_raw_fd(), buf)
+    }
+
+    /// Wait until everything written was transmitted
+    fn flush(&mut self) -> io::Result<()> {
+        sys::cvt(unsafe { libc::tcdrain(self.fd.as_raw_fd()) }).map(drop)
+    }
+}
+
+impl AsRawFd for SerialPort {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for SerialPort {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+impl Drop for SerialPort {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(self.fd.as_raw_fd(), libc::TCSANOW, &self.original) };
+    }
+}
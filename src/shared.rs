@@ -0,0 +1,268 @@
+//! An epoll instance split into a [`Poll`] that waits and a [`Registry`]
+//! that registers, for registering from other threads while one blocks.
+//!
+//! The kernel keeps the interest list and allows epoll_ctl concurrently with
+//! epoll_wait, so [`Registry`] is Send, Sync and cheap to clone, and its
+//! methods take `&self`. In exchange it keeps no bookkeeping of its own: no
+//! labels, error policies or strict mode, see [`Epoll`](crate::Epoll) for
+//! those. A fd registered while a poll is blocked is reported by that poll.
+//!
+//! ```ignore
+//! let mut poll = Poll::create()?;
+//! let registry = poll.registry().clone();
+//!
+//! thread::spawn(move || {
+//!     let stream = listener.accept()?.0;
+//!     registry.register(&stream, Token(1), Interest::READABLE, Mode::Edge)
+//! });
+//!
+//! poll.poll(&mut events, None)?;
+//! ```
+//!
+//! [`Registry::register_guard`] ties a registration to a [`Registration`]
+//! that unregisters when dropped. It owns the source, so dropping it closes
+//! an owned fd after unregistering, pass a reference to keep the fd open.
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::error::{CtlError, CtlOp};
+use crate::{epoll_wait, make_flags, sys, Events, Interest, Mode, Readiness, Timeout, Token, MAX_EVENTS};
+
+/// Handle registering sources in the epoll instance of a [`Poll`]. Clones
+/// refer to the same instance and keep it open
+#[derive(Debug, Clone)]
+pub struct Registry {
+    epoll_fd: Arc<OwnedFd>,
+}
+
+impl Registry {
+    /// Register a new source
+    pub fn register<S: AsFd + ?Sized>(
+        &self,
+        source: &S,
+        token: Token,
+        interest: Interest,
+        mode: Mode
+    ) -> io::Result<()> {
+        self.ctl(CtlOp::Add, source.as_fd().as_raw_fd(), Some((token, interest, mode)))
+    }
+
+    /// Change the token, interest or mode of a registered source
+    pub fn reregister<S: AsFd + ?Sized>(
+        &self,
+        source: &S,
+        token: Token,
+        interest: Interest,
+        mode: Mode
+    ) -> io::Result<()> {
+        self.ctl(CtlOp::Modify, source.as_fd().as_raw_fd(), Some((token, interest, mode)))
+    }
+
+    /// Stop polling events of a source
+    pub fn unregister<S: AsFd + ?Sized>(&self, source: &S) -> io::Result<()> {
+        self.ctl(CtlOp::Delete, source.as_fd().as_raw_fd(), None)
+    }
+
+    /// Register `source` and unregister it again when the returned guard is
+    /// dropped
+    pub fn register_guard<S: AsFd>(
+        &self,
+        source: S,
+        token: Token,
+        interest: Interest,
+        mode: Mode
+    ) -> io::Result<Registration<S>> {
+        self.register(&source, token, interest, mode)?;
+        Ok(Registration { source: Some(source), registry: self.clone() })
+    }
+
+    fn ctl(&self, op: CtlOp, fd: RawFd, registration: Option<(Token, Interest, Mode)>) -> io::Result<()> {
+        let (events, data) = registration.map_or((0, 0), |(token, interest, mode)| {
+            (make_flags(interest, mode), token.0 as u64)
+        });
+
+        let epoll_op = match op {
+            CtlOp::Add => libc::EPOLL_CTL_ADD,
+            CtlOp::Modify => libc::EPOLL_CTL_MOD,
+            CtlOp::Delete => libc::EPOLL_CTL_DEL,
+        };
+
+        sys::epoll_ctl(self.epoll_fd.as_raw_fd(), epoll_op, fd, events, data).map_err(|source| {
+            let mut err = CtlError::new(op, fd, source);
+
+            if let Some((token, interest, mode)) = registration {
+                err.token = Some(token);
+                err.interest = Some(interest);
+                err.mode = Some(mode);
+            }
+
+            err.into()
+        })
+    }
+}
+
+impl AsRawFd for Registry {
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll_fd.as_raw_fd()
+    }
+}
+
+impl AsFd for Registry {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.epoll_fd.as_fd()
+    }
+}
+
+/// A registered source, unregistered when dropped. See
+/// [`Registry::register_guard`]
+#[derive(Debug)]
+pub struct Registration<S: AsFd> {
+    source: Option<S>,
+    registry: Registry,
+}
+
+impl<S: AsFd> Registration<S> {
+    pub fn get_ref(&self) -> &S {
+        self.source.as_ref().expect("source is only taken on drop")
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        self.source.as_mut().expect("source is only taken on drop")
+    }
+
+    /// Change the token, interest or mode of the registration
+    pub fn reregister(&self, token: Token, interest: Interest, mode: Mode) -> io::Result<()> {
+        self.registry.reregister(self.get_ref(), token, interest, mode)
+    }
+
+    /// Unregister the source and get it back
+    pub fn into_inner(mut self) -> io::Result<S> {
+        let source = self.source.take().expect("source is only taken on drop");
+        self.registry.unregister(&source)?;
+        Ok(source)
+    }
+}
+
+impl<S: AsFd> AsFd for Registration<S> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.get_ref().as_fd()
+    }
+}
+
+impl<S: AsFd> Drop for Registration<S> {
+    fn drop(&mut self) {
+        if let Some(source) = self.source.take() {
+            let _ = self.registry.unregister(&source);
+        }
+    }
+}
+
+/// The waiting half of the instance, see the [module docs](self)
+#[derive(Debug)]
+pub struct Poll {
+    registry: Registry,
+}
+
+impl Poll {
+    /// Create a new epoll instance
+    pub fn create() -> io::Result<Poll> {
+        let epoll_fd = sys::epoll_create(libc::EPOLL_CLOEXEC)?;
+        Ok(Poll { registry: Registry { epoll_fd: Arc::new(epoll_fd) } })
+    }
+
+    /// Another waiter on the instance of `registry`
+    pub(crate) fn with_registry(registry: Registry) -> Poll {
+        Poll { registry }
+    }
+
+    /// The registry of the instance, clone it to register from other threads
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// Wait up to `timeout` for events and store them in `events`, which is
+    /// cleared first. Returns the number of events, 0 when the timeout expired
+    pub fn poll<T: Into<Timeout>>(&mut self, events: &mut Events, timeout: T) -> io::Result<usize> {
+        self.poll_max(events, timeout.into().remaining(), MAX_EVENTS)
+    }
+
+    /// Like poll, taking at most `max` events (up to MAX_EVENTS) so several
+    /// waiters on one instance share a burst
+    pub(crate) fn poll_max(&mut self, events: &mut Events, timeout: Option<Duration>, max: usize) -> io::Result<usize> {
+        let mut buffer = [MaybeUninit::<libc::epoll_event>::uninit(); MAX_EVENTS];
+        let max = max.clamp(1, MAX_EVENTS);
+
+        events.clear();
+
+        let n_events = epoll_wait(self.registry.as_raw_fd(), &mut buffer[..max], timeout, None)?;
+        for event in buffer.iter().take(n_events) {
+            let event = unsafe { event.assume_init() };
+            let (bits, data) = (event.events, event.u64);
+            events.push(Token(data as usize), Readiness::from_bits(bits));
+        }
+
+        Ok(n_events)
+    }
+}
+
+impl AsRawFd for Poll {
+    fn as_raw_fd(&self) -> RawFd {
+        self.registry.as_raw_fd()
+    }
+}
+
+impl AsFd for Poll {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.registry.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn registering_from_another_thread_reaches_a_blocked_poll() {
+        let mut poll = Poll::create().unwrap();
+        let registry = poll.registry().clone();
+        let (stream, peer) = UnixStream::pair().unwrap();
+        (&peer).write_all(b"x").unwrap();
+
+        let registrar = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            registry.register(&stream, Token(1), Interest::READABLE, Mode::Level).unwrap();
+            stream
+        });
+
+        let mut events = Events::new();
+        assert_eq!(poll.poll(&mut events, Duration::from_secs(5)).unwrap(), 1);
+        assert_eq!(events.iter().map(|(token, _)| token).collect::<Vec<_>>(), [Token(1)]);
+
+        let stream = registrar.join().unwrap();
+        poll.registry().unregister(&stream).unwrap();
+    }
+
+    #[test]
+    fn dropping_a_guard_unregisters() {
+        let mut poll = Poll::create().unwrap();
+        let (stream, peer) = UnixStream::pair().unwrap();
+        (&peer).write_all(b"x").unwrap();
+
+        let guard = poll.registry().register_guard(&stream, Token(1), Interest::READABLE, Mode::Level).unwrap();
+        let mut events = Events::new();
+        assert_eq!(poll.poll(&mut events, Timeout::Immediate).unwrap(), 1);
+
+        drop(guard);
+        assert_eq!(poll.poll(&mut events, Timeout::Immediate).unwrap(), 0);
+
+        // The reference was unregistered, the stream itself stays open
+        poll.registry().register(&stream, Token(2), Interest::READABLE, Mode::Level).unwrap();
+    }
+}
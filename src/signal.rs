@@ -0,0 +1,417 @@
+//! Receiving signals through the poll loop.
+//!
+//! [`Signals`] blocks its signals in the calling thread and reads them from
+//! a signalfd instead, so SIGTERM, SIGHUP or SIGCHLD are handled between other
+//! events instead of in an async signal handler. The mask is per thread:
+//! create it before spawning threads, they inherit the blocked mask, or a
+//! thread without it may take the signal through its default action.
+use std::fmt;
+use std::io;
+use std::mem;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::ptr;
+use std::str::FromStr;
+
+use crate::leak::LeakGuard;
+use crate::sys::SignalFd;
+
+/// A standard signal number, e.g. `Signal::SIGTERM`. Parsed from and printed
+/// as its name
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Signal(libc::c_int);
+
+/// Every standard signal with its name
+const SIGNAL_NAMES: [(Signal, &str); 31] = [
+    (Signal::SIGHUP, "SIGHUP"),
+    (Signal::SIGINT, "SIGINT"),
+    (Signal::SIGQUIT, "SIGQUIT"),
+    (Signal::SIGILL, "SIGILL"),
+    (Signal::SIGTRAP, "SIGTRAP"),
+    (Signal::SIGABRT, "SIGABRT"),
+    (Signal::SIGBUS, "SIGBUS"),
+    (Signal::SIGFPE, "SIGFPE"),
+    (Signal::SIGKILL, "SIGKILL"),
+    (Signal::SIGUSR1, "SIGUSR1"),
+    (Signal::SIGSEGV, "SIGSEGV"),
+    (Signal::SIGUSR2, "SIGUSR2"),
+    (Signal::SIGPIPE, "SIGPIPE"),
+    (Signal::SIGALRM, "SIGALRM"),
+    (Signal::SIGTERM, "SIGTERM"),
+    (Signal::SIGSTKFLT, "SIGSTKFLT"),
+    (Signal::SIGCHLD, "SIGCHLD"),
+    (Signal::SIGCONT, "SIGCONT"),
+    (Signal::SIGSTOP, "SIGSTOP"),
+    (Signal::SIGTSTP, "SIGTSTP"),
+    (Signal::SIGTTIN, "SIGTTIN"),
+    (Signal::SIGTTOU, "SIGTTOU"),
+    (Signal::SIGURG, "SIGURG"),
+    (Signal::SIGXCPU, "SIGXCPU"),
+    (Signal::SIGXFSZ, "SIGXFSZ"),
+    (Signal::SIGVTALRM, "SIGVTALRM"),
+    (Signal::SIGPROF, "SIGPROF"),
+    (Signal::SIGWINCH, "SIGWINCH"),
+    (Signal::SIGIO, "SIGIO"),
+    (Signal::SIGPWR, "SIGPWR"),
+    (Signal::SIGSYS, "SIGSYS"),
+];
+
+impl Signal {
+    pub const SIGHUP: Signal = Signal(libc::SIGHUP);
+    pub const SIGINT: Signal = Signal(libc::SIGINT);
+    pub const SIGQUIT: Signal = Signal(libc::SIGQUIT);
+    pub const SIGILL: Signal = Signal(libc::SIGILL);
+    pub const SIGTRAP: Signal = Signal(libc::SIGTRAP);
+    pub const SIGABRT: Signal = Signal(libc::SIGABRT);
+    pub const SIGBUS: Signal = Signal(libc::SIGBUS);
+    pub const SIGFPE: Signal = Signal(libc::SIGFPE);
+    pub const SIGKILL: Signal = Signal(libc::SIGKILL);
+    pub const SIGUSR1: Signal = Signal(libc::SIGUSR1);
+    pub const SIGSEGV: Signal = Signal(libc::SIGSEGV);
+    pub const SIGUSR2: Signal = Signal(libc::SIGUSR2);
+    pub const SIGPIPE: Signal = Signal(libc::SIGPIPE);
+    pub const SIGALRM: Signal = Signal(libc::SIGALRM);
+    pub const SIGTERM: Signal = Signal(libc::SIGTERM);
+    pub const SIGSTKFLT: Signal = Signal(libc::SIGSTKFLT);
+    pub const SIGCHLD: Signal = Signal(libc::SIGCHLD);
+    pub const SIGCONT: Signal = Signal(libc::SIGCONT);
+    pub const SIGSTOP: Signal = Signal(libc::SIGSTOP);
+    pub const SIGTSTP: Signal = Signal(libc::SIGTSTP);
+    pub const SIGTTIN: Signal = Signal(libc::SIGTTIN);
+    pub const SIGTTOU: Signal = Signal(libc::SIGTTOU);
+    pub const SIGURG: Signal = Signal(libc::SIGURG);
+    pub const SIGXCPU: Signal = Signal(libc::SIGXCPU);
+    pub const SIGXFSZ: Signal = Signal(libc::SIGXFSZ);
+    pub const SIGVTALRM: Signal = Signal(libc::SIGVTALRM);
+    pub const SIGPROF: Signal = Signal(libc::SIGPROF);
+    pub const SIGWINCH: Signal = Signal(libc::SIGWINCH);
+    pub const SIGIO: Signal = Signal(libc::SIGIO);
+    pub const SIGPWR: Signal = Signal(libc::SIGPWR);
+    pub const SIGSYS: Signal = Signal(libc::SIGSYS);
+
+    /// The signal number
+    pub const fn as_raw(self) -> libc::c_int {
+        self.0
+    }
+
+    /// Every standard signal, in numeric order
+    pub fn iterator() -> impl Iterator<Item = Signal> {
+        SIGNAL_NAMES.iter().map(|(signal, _)| *signal)
+    }
+
+    fn name(self) -> Option<&'static str> {
+        SIGNAL_NAMES.iter().find(|(signal, _)| *signal == self).map(|(_, name)| *name)
+    }
+}
+
+impl TryFrom<libc::c_int> for Signal {
+    type Error = io::Error;
+
+    fn try_from(signo: libc::c_int) -> io::Result<Signal> {
+        Signal::iterator()
+            .find(|signal| signal.0 == signo)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))
+    }
+}
+
+/// Accepts the name with or without the SIG prefix, e.g. "SIGTERM" or "TERM"
+impl FromStr for Signal {
+    type Err = io::Error;
+
+    fn from_str(name: &str) -> io::Result<Signal> {
+        SIGNAL_NAMES.iter()
+            .find(|(_, known)| *known == name || known.strip_prefix("SIG") == Some(name))
+            .map(|(signal, _)| *signal)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("unknown signal {}", name)))
+    }
+}
+
+impl fmt::Debug for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "{}", name),
+            None => write!(f, "Signal({})", self.0),
+        }
+    }
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// A set of signals, the sigset_t of the signal mask and signalfd
+#[derive(Copy, Clone)]
+pub struct SigSet(libc::sigset_t);
+
+impl SigSet {
+    pub fn empty() -> SigSet {
+        let mut set = mem::MaybeUninit::<libc::sigset_t>::uninit();
+        unsafe { libc::sigemptyset(set.as_mut_ptr()) };
+        SigSet(unsafe { set.assume_init() })
+    }
+
+    /// Every signal
+    pub fn all() -> SigSet {
+        let mut set = mem::MaybeUninit::<libc::sigset_t>::uninit();
+        unsafe { libc::sigfillset(set.as_mut_ptr()) };
+        SigSet(unsafe { set.assume_init() })
+    }
+
+    pub fn add(&mut self, signal: Signal) {
+        unsafe { libc::sigaddset(&mut self.0, signal.0) };
+    }
+
+    pub fn remove(&mut self, signal: Signal) {
+        unsafe { libc::sigdelset(&mut self.0, signal.0) };
+    }
+
+    pub fn contains(&self, signal: Signal) -> bool {
+        unsafe { libc::sigismember(&self.0, signal.0) == 1 }
+    }
+
+    /// The signals of the set that are standard signals
+    pub fn iter(&self) -> impl Iterator<Item = Signal> + '_ {
+        Signal::iterator().filter(move |signal| self.contains(*signal))
+    }
+
+    /// The signal mask of the calling thread
+    pub fn thread_get_mask() -> io::Result<SigSet> {
+        let mut mask = SigSet::empty();
+        sigmask(libc::SIG_BLOCK, None, Some(&mut mask))?;
+        Ok(mask)
+    }
+
+    /// Add the set to the signal mask of the calling thread
+    pub fn thread_block(&self) -> io::Result<()> {
+        sigmask(libc::SIG_BLOCK, Some(self), None)
+    }
+
+    /// Remove the set from the signal mask of the calling thread
+    pub fn thread_unblock(&self) -> io::Result<()> {
+        sigmask(libc::SIG_UNBLOCK, Some(self), None)
+    }
+
+    pub(crate) fn as_raw(&self) -> &libc::sigset_t {
+        &self.0
+    }
+}
+
+impl FromIterator<Signal> for SigSet {
+    fn from_iter<I: IntoIterator<Item = Signal>>(signals: I) -> SigSet {
+        let mut set = SigSet::empty();
+        for signal in signals {
+            set.add(signal);
+        }
+        set
+    }
+}
+
+impl fmt::Debug for SigSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+fn sigmask(how: libc::c_int, set: Option<&SigSet>, old: Option<&mut SigSet>) -> io::Result<()> {
+    let set = set.map_or(ptr::null(), |set| &set.0 as *const libc::sigset_t);
+    let old = old.map_or(ptr::null_mut(), |old| &mut old.0 as *mut libc::sigset_t);
+
+    // pthread_sigmask returns the error instead of setting errno
+    match unsafe { libc::pthread_sigmask(how, set, old) } {
+        0 => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(errno)),
+    }
+}
+
+/// A received signal and who sent it
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SignalInfo {
+    pub signal: Signal,
+    /// Pid of the sender, for SIGCHLD the child that changed state
+    pub pid: u32,
+    /// Real uid of the sender
+    pub uid: u32,
+    /// The si_code, e.g. CLD_EXITED for SIGCHLD
+    pub code: i32,
+    /// Exit status or signal of the child for SIGCHLD
+    pub status: i32,
+}
+
+/// signalfd for a set of signals, blocked while it is alive
+#[derive(Debug)]
+pub struct Signals {
+    /// Declared first to drop while the fd is still open
+    pub(crate) leaks: LeakGuard,
+    fd: SignalFd,
+    mask: SigSet,
+    /// Signals that were already blocked before, they stay blocked on drop
+    blocked: SigSet,
+}
+
+impl Signals {
+    /// Block `signals` in the calling thread and receive them on the fd
+    pub fn new(signals: &[Signal]) -> io::Result<Signals> {
+        let mut mask = SigSet::empty();
+        for signal in signals {
+            mask.add(*signal);
+        }
+
+        let blocked = SigSet::thread_get_mask()?;
+        mask.thread_block()?;
+
+        // Nothing would read the signals blocked above
+        let fd = SignalFd::new(&mask).inspect_err(|_| {
+            let _ = unblock_new(&mask, &blocked);
+        })?;
+
+        Ok(Signals { leaks: LeakGuard::default(), fd, mask, blocked })
+    }
+
+    /// Start receiving `signal` too
+    pub fn add(&mut self, signal: Signal) -> io::Result<()> {
+        let mut mask = self.mask;
+        mask.add(signal);
+
+        let mut single = SigSet::empty();
+        single.add(signal);
+        single.thread_block()?;
+
+        if let Err(err) = self.fd.set_mask(&mask) {
+            if !self.mask.contains(signal) {
+                let _ = unblock_new(&single, &self.blocked);
+            }
+            return Err(err);
+        }
+
+        self.mask = mask;
+        Ok(())
+    }
+
+    /// Stop receiving `signal`, it is unblocked unless it was blocked before
+    pub fn remove(&mut self, signal: Signal) -> io::Result<()> {
+        let mut mask = self.mask;
+        mask.remove(signal);
+
+        self.fd.set_mask(&mask)?;
+        self.mask = mask;
+
+        if !self.blocked.contains(signal) {
+            let mut single = SigSet::empty();
+            single.add(signal);
+            single.thread_unblock()?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether `signal` is received through this fd
+    pub fn contains(&self, signal: Signal) -> bool {
+        self.mask.contains(signal)
+    }
+
+    /// Read one pending signal, None once they are drained. Read until
+    /// None when the fd is reported, several signals may be pending
+    pub fn read(&mut self) -> io::Result<Option<SignalInfo>> {
+        let info = match self.fd.read_signal()? {
+            Some(info) => info,
+            None => return Ok(None),
+        };
+
+        let signal = Signal::try_from(info.ssi_signo as i32)?;
+
+        Ok(Some(SignalInfo {
+            signal,
+            pid: info.ssi_pid,
+            uid: info.ssi_uid,
+            code: info.ssi_code,
+            status: info.ssi_status,
+        }))
+    }
+}
+
+impl Drop for Signals {
+    fn drop(&mut self) {
+        // Pending signals are delivered the normal way once unblocked
+        let _ = unblock_new(&self.mask, &self.blocked);
+    }
+}
+
+/// Unblock the signals of `mask` that are not in `blocked`, the mask the
+/// thread had before
+fn unblock_new(mask: &SigSet, blocked: &SigSet) -> io::Result<()> {
+    let mut unblock = SigSet::empty();
+    for signal in Signal::iterator() {
+        if mask.contains(signal) && !blocked.contains(signal) {
+            unblock.add(signal);
+        }
+    }
+
+    unblock.thread_unblock()
+}
+
+impl AsRawFd for Signals {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for Signals {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Send `signal` to the calling thread, the one whose mask Signals changes
+    fn raise(signal: Signal) {
+        assert_eq!(unsafe { libc::pthread_kill(libc::pthread_self(), signal.0) }, 0);
+    }
+
+    #[test]
+    fn raised_signals_are_read_from_the_fd() {
+        let mut signals = Signals::new(&[Signal::SIGUSR1]).unwrap();
+        assert!(SigSet::thread_get_mask().unwrap().contains(Signal::SIGUSR1));
+
+        raise(Signal::SIGUSR1);
+
+        let info = signals.read().unwrap().expect("no signal pending");
+        assert_eq!(info.signal, Signal::SIGUSR1);
+        assert_eq!(info.pid, std::process::id());
+        assert_eq!(signals.read().unwrap(), None);
+    }
+
+    #[test]
+    fn drop_restores_the_mask() {
+        let mut before = SigSet::empty();
+        before.add(Signal::SIGUSR2);
+        before.thread_block().unwrap();
+
+        let mut signals = Signals::new(&[Signal::SIGUSR1, Signal::SIGUSR2]).unwrap();
+        signals.add(Signal::SIGWINCH).unwrap();
+        assert!(SigSet::thread_get_mask().unwrap().contains(Signal::SIGWINCH));
+        drop(signals);
+
+        // Blocked before, so it stays blocked
+        let mask = SigSet::thread_get_mask().unwrap();
+        assert!(!mask.contains(Signal::SIGUSR1));
+        assert!(!mask.contains(Signal::SIGWINCH));
+        assert!(mask.contains(Signal::SIGUSR2));
+
+        before.thread_unblock().unwrap();
+    }
+
+    #[test]
+    fn removed_signals_are_unblocked() {
+        let mut signals = Signals::new(&[Signal::SIGUSR1, Signal::SIGWINCH]).unwrap();
+        signals.remove(Signal::SIGWINCH).unwrap();
+
+        assert!(!signals.contains(Signal::SIGWINCH));
+        let mask = SigSet::thread_get_mask().unwrap();
+        assert!(mask.contains(Signal::SIGUSR1));
+        assert!(!mask.contains(Signal::SIGWINCH));
+    }
+}
@@ -0,0 +1,310 @@
+//! Handing out tokens and finding the state behind them.
+//!
+//! [`Slab`] stores one value per registration and uses its index as the
+//! token, so the token of an event leads straight back to the connection
+//! state. Freed slots are reused, most recently freed first, which keeps the
+//! indices small and dense.
+//!
+//! A reused slot would route the events still queued for the previous
+//! registration, e.g. later in the same batch, to the new value. Every slot
+//! counts how often it was freed and the upper half of the token carries that
+//! generation, a token of an earlier generation finds nothing. The first
+//! token of a slot is its bare index. Generations wrap around after 2^32
+//! reuses of a slot, 2^16 where usize is 32 bits.
+use std::io;
+use std::ops::{Index, IndexMut};
+
+use crate::{Epoll, Interest, Mode, Source, Token};
+
+/// The token bits above the index hold the generation
+const GENERATION_SHIFT: u32 = usize::BITS / 2;
+
+const INDEX_MASK: usize = (1 << GENERATION_SHIFT) - 1;
+
+#[derive(Debug)]
+enum Entry<T> {
+    Occupied(T),
+    /// Index of the next free slot, if any
+    Vacant(Option<usize>),
+}
+
+#[derive(Debug)]
+struct Slot<T> {
+    generation: usize,
+    entry: Entry<T>,
+}
+
+fn make_token(index: usize, generation: usize) -> Token {
+    Token(generation << GENERATION_SHIFT | index)
+}
+
+/// The index and generation of a token
+fn split_token(token: Token) -> (usize, usize) {
+    (token.0 & INDEX_MASK, token.0 >> GENERATION_SHIFT)
+}
+
+/// Per-registration state stored by token
+#[derive(Debug)]
+pub struct Slab<T> {
+    slots: Vec<Slot<T>>,
+    free: Option<usize>,
+    len: usize,
+}
+
+impl<T> Default for Slab<T> {
+    fn default() -> Slab<T> {
+        Slab::new()
+    }
+}
+
+impl<T> Slab<T> {
+    pub fn new() -> Slab<T> {
+        Slab { slots: Vec::new(), free: None, len: 0 }
+    }
+
+    pub fn with_capacity(capacity: usize) -> Slab<T> {
+        Slab { slots: Vec::with_capacity(capacity), free: None, len: 0 }
+    }
+
+    /// Number of stored values
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The token the next insert will hand out
+    pub fn vacant_token(&self) -> Token {
+        match self.free {
+            Some(index) => make_token(index, self.slots[index].generation),
+            None => make_token(self.slots.len(), 0),
+        }
+    }
+
+    /// Store `value` and return its token
+    pub fn insert(&mut self, value: T) -> Token {
+        let token = self.vacant_token();
+
+        match self.free {
+            Some(index) => {
+                let slot = &mut self.slots[index];
+                self.free = match slot.entry {
+                    Entry::Vacant(next) => next,
+                    Entry::Occupied(_) => unreachable!("free list points at an occupied slot"),
+                };
+                slot.entry = Entry::Occupied(value);
+            }
+            None => {
+                assert!(self.slots.len() <= INDEX_MASK, "slab is full");
+                self.slots.push(Slot { generation: 0, entry: Entry::Occupied(value) });
+            }
+        }
+
+        self.len += 1;
+        token
+    }
+
+    /// Register `source` with the next free token and store `value` under it.
+    /// Nothing is stored if the registration fails
+    pub fn register<S: Source + ?Sized>(
+        &mut self,
+        epoll: &mut Epoll,
+        source: &S,
+        interest: Interest,
+        mode: Mode,
+        value: T
+    ) -> io::Result<Token> {
+        epoll.register(source, self.vacant_token(), interest, mode)?;
+        Ok(self.insert(value))
+    }
+
+    /// Unregister `source` and take the value stored under `token`.
+    /// The value stays stored if the unregistration fails
+    pub fn unregister<S: Source + ?Sized>(
+        &mut self,
+        epoll: &mut Epoll,
+        source: &S,
+        token: Token
+    ) -> io::Result<Option<T>> {
+        epoll.unregister(source)?;
+        Ok(self.remove(token))
+    }
+
+    /// Take the value out and free its token, the slot moves on to the next
+    /// generation
+    pub fn remove(&mut self, token: Token) -> Option<T> {
+        self.get(token)?;
+
+        let (index, _) = split_token(token);
+        let slot = &mut self.slots[index];
+        let entry = std::mem::replace(&mut slot.entry, Entry::Vacant(self.free));
+        slot.generation = (slot.generation + 1) & (usize::MAX >> GENERATION_SHIFT);
+        self.free = Some(index);
+        self.len -= 1;
+
+        match entry {
+            Entry::Occupied(value) => Some(value),
+            Entry::Vacant(_) => None,
+        }
+    }
+
+    pub fn contains(&self, token: Token) -> bool {
+        self.get(token).is_some()
+    }
+
+    pub fn get(&self, token: Token) -> Option<&T> {
+        let (index, generation) = split_token(token);
+        match self.slots.get(index) {
+            Some(Slot { generation: current, entry: Entry::Occupied(value) }) if *current == generation => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, token: Token) -> Option<&mut T> {
+        let (index, generation) = split_token(token);
+        match self.slots.get_mut(index) {
+            Some(Slot { generation: current, entry: Entry::Occupied(value) }) if *current == generation => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Every stored value with its token
+    pub fn iter(&self) -> impl Iterator<Item = (Token, &T)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| match &slot.entry {
+            Entry::Occupied(value) => Some((make_token(index, slot.generation), value)),
+            Entry::Vacant(_) => None,
+        })
+    }
+
+    /// Every stored value with its token, mutably
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Token, &mut T)> {
+        self.slots.iter_mut().enumerate().filter_map(|(index, slot)| match &mut slot.entry {
+            Entry::Occupied(value) => Some((make_token(index, slot.generation), value)),
+            Entry::Vacant(_) => None,
+        })
+    }
+
+    /// Remove every value, all tokens become free. The generations are kept
+    /// so tokens handed out before stay stale
+    pub fn clear(&mut self) {
+        let tokens: Vec<Token> = self.iter().map(|(token, _)| token).collect();
+        for token in tokens {
+            self.remove(token);
+        }
+    }
+}
+
+impl<T> Index<Token> for Slab<T> {
+    type Output = T;
+
+    fn index(&self, token: Token) -> &T {
+        self.get(token).expect("no value stored for token")
+    }
+}
+
+impl<T> IndexMut<Token> for Slab<T> {
+    fn index_mut(&mut self, token: Token) -> &mut T {
+        self.get_mut(token).expect("no value stored for token")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+
+    use super::*;
+
+    #[test]
+    fn insert_get_remove() {
+        let mut slab = Slab::new();
+        let first = slab.insert("first");
+        let second = slab.insert("second");
+
+        assert_eq!((first, second), (Token(0), Token(1)));
+        assert_eq!(slab.len(), 2);
+        assert_eq!(slab[first], "first");
+
+        slab[second] = "changed";
+        assert_eq!(slab.get(second), Some(&"changed"));
+
+        assert_eq!(slab.remove(first), Some("first"));
+        assert_eq!(slab.remove(first), None);
+        assert_eq!(slab.len(), 1);
+        assert_eq!(slab.iter().collect::<Vec<_>>(), [(second, &"changed")]);
+    }
+
+    #[test]
+    fn freed_slots_are_reused_most_recent_first() {
+        let mut slab = Slab::new();
+        let tokens: Vec<_> = (0..3).map(|i| slab.insert(i)).collect();
+
+        slab.remove(tokens[0]);
+        slab.remove(tokens[2]);
+
+        let (index, _) = split_token(slab.insert(3));
+        assert_eq!(index, 2);
+        let (index, _) = split_token(slab.insert(4));
+        assert_eq!(index, 0);
+        let (index, _) = split_token(slab.insert(5));
+        assert_eq!(index, 3);
+    }
+
+    #[test]
+    fn failed_registration_stores_nothing() {
+        let mut epoll = Epoll::create().unwrap();
+        let mut slab = Slab::new();
+        let (stream, _peer) = UnixStream::pair().unwrap();
+
+        let token = slab.register(&mut epoll, &stream, Interest::READABLE, Mode::Level, "stream").unwrap();
+        assert!(slab.register(&mut epoll, &stream, Interest::READABLE, Mode::Level, "again").is_err());
+        assert_eq!(slab.len(), 1);
+
+        assert_eq!(slab.unregister(&mut epoll, &stream, token).unwrap(), Some("stream"));
+        assert!(slab.unregister(&mut epoll, &stream, token).is_err());
+        assert!(slab.is_empty());
+    }
+
+    #[test]
+    fn tokens_of_a_freed_slot_go_stale() {
+        let mut slab = Slab::new();
+        let old = slab.insert("old");
+        slab.remove(old);
+
+        let new = slab.insert("new");
+        assert_eq!(split_token(old).0, split_token(new).0);
+        assert_ne!(old, new);
+
+        assert_eq!(slab.get(old), None);
+        assert!(!slab.contains(old));
+        assert_eq!(slab.remove(old), None);
+        assert_eq!(slab[new], "new");
+    }
+
+    #[test]
+    fn clear_keeps_tokens_stale() {
+        let mut slab = Slab::new();
+        let tokens: Vec<_> = (0..3).map(|i| slab.insert(i)).collect();
+
+        slab.clear();
+        assert!(slab.is_empty());
+
+        let reused: Vec<_> = (3..6).map(|i| slab.insert(i)).collect();
+        assert!(tokens.iter().all(|token| !slab.contains(*token)));
+        assert!(reused.iter().all(|token| slab.contains(*token)));
+    }
+
+    #[test]
+    fn generations_wrap_around() {
+        let mut slab = Slab::new();
+        slab.insert(0);
+        slab.slots[0].generation = usize::MAX >> GENERATION_SHIFT;
+
+        let last = make_token(0, usize::MAX >> GENERATION_SHIFT);
+        assert!(slab.contains(last));
+        slab.remove(last);
+
+        assert_eq!(slab.insert(1), Token(0));
+    }
+}
@@ -0,0 +1,227 @@
+//! Ready-made sources for the std networking types.
+//!
+//! Each wrapper switches its socket to non-blocking mode and registers it
+//! edge triggered with [`register`](TcpListenerSource::register), so the
+//! dispatch code has to drain it on every event. [`accept_all`] and
+//! [`drain_readable`] do exactly that:
+//!
+//! ```ignore
+//! let listener = UnixListenerSource::bind("test.sock")?;
+//! listener.register(&mut epoll, LISTENER)?;
+//!
+//! // in the dispatch of LISTENER
+//! listener.accept_all(|stream, _addr| connections.push(stream))?;
+//! ```
+//!
+//! [`accept_all`]: TcpListenerSource::accept_all
+//! [`drain_readable`]: TcpStreamSource::drain_readable
+use std::io::{self, Read};
+use std::net::{self, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+use crate::net::Listener;
+use crate::{Epoll, Interest, Mode, Token};
+
+/// Size of the stack buffer the drain methods read into
+const DRAIN_BUFFER: usize = 8192;
+
+/// What [`TcpStreamSource::drain_readable`] found
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Drained {
+    /// Bytes read in total
+    pub bytes: usize,
+    /// The peer closed its side, nothing more will arrive
+    pub closed: bool,
+}
+
+/// Accept until the listener would block, connections reset while queued are skipped
+fn accept_all<L: Listener, F: FnMut(L::Stream, L::Addr)>(listener: &L, mut f: F) -> io::Result<usize> {
+    let mut accepted = 0;
+
+    loop {
+        match listener.accept_nonblocking() {
+            Ok((stream, addr)) => {
+                accepted += 1;
+                f(stream, addr);
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(accepted),
+            Err(err) if matches!(err.raw_os_error(), Some(libc::ECONNABORTED) | Some(libc::EINTR)) => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+macro_rules! source_common {
+    ($source:ident, $inner:ty, $interest:expr) => {
+        impl $source {
+            /// Register edge triggered, the socket has to be drained on every event
+            pub fn register(&self, epoll: &mut Epoll, token: Token) -> io::Result<()> {
+                epoll.register(&self.inner, token, $interest, Mode::Edge)
+            }
+
+            pub fn get_ref(&self) -> &$inner {
+                &self.inner
+            }
+
+            pub fn get_mut(&mut self) -> &mut $inner {
+                &mut self.inner
+            }
+
+            pub fn into_inner(self) -> $inner {
+                self.inner
+            }
+        }
+
+        impl AsRawFd for $source {
+            fn as_raw_fd(&self) -> RawFd {
+                self.inner.as_raw_fd()
+            }
+        }
+
+        impl AsFd for $source {
+            fn as_fd(&self) -> BorrowedFd<'_> {
+                self.inner.as_fd()
+            }
+        }
+    };
+}
+
+/// A non-blocking [`TcpListener`]
+#[derive(Debug)]
+pub struct TcpListenerSource {
+    inner: TcpListener,
+}
+
+impl TcpListenerSource {
+    pub fn new(listener: TcpListener) -> io::Result<TcpListenerSource> {
+        listener.set_nonblocking(true)?;
+        Ok(TcpListenerSource { inner: listener })
+    }
+
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<TcpListenerSource> {
+        TcpListenerSource::new(TcpListener::bind(addr)?)
+    }
+
+    /// Accept every pending connection, the streams are non-blocking.
+    /// Returns the number accepted
+    pub fn accept_all<F: FnMut(TcpStream, SocketAddr)>(&self, f: F) -> io::Result<usize> {
+        accept_all(&self.inner, f)
+    }
+}
+
+source_common!(TcpListenerSource, TcpListener, Interest::READABLE);
+
+/// A non-blocking [`UnixListener`]
+#[derive(Debug)]
+pub struct UnixListenerSource {
+    inner: UnixListener,
+}
+
+impl UnixListenerSource {
+    pub fn new(listener: UnixListener) -> io::Result<UnixListenerSource> {
+        listener.set_nonblocking(true)?;
+        Ok(UnixListenerSource { inner: listener })
+    }
+
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<UnixListenerSource> {
+        UnixListenerSource::new(UnixListener::bind(path)?)
+    }
+
+    /// Accept every pending connection, the streams are non-blocking.
+    /// Returns the number accepted
+    pub fn accept_all<F>(&self, f: F) -> io::Result<usize>
+    where
+        F: FnMut(UnixStream, std::os::unix::net::SocketAddr),
+    {
+        accept_all(&self.inner, f)
+    }
+}
+
+source_common!(UnixListenerSource, UnixListener, Interest::READABLE);
+
+/// A non-blocking [`TcpStream`], registered readable and writable
+#[derive(Debug)]
+pub struct TcpStreamSource {
+    inner: TcpStream,
+}
+
+impl TcpStreamSource {
+    pub fn new(stream: TcpStream) -> io::Result<TcpStreamSource> {
+        stream.set_nonblocking(true)?;
+        Ok(TcpStreamSource { inner: stream })
+    }
+
+    /// Start a non-blocking connect, see [`connect_tcp`](crate::net::connect_tcp)
+    pub fn connect(addr: SocketAddr) -> io::Result<TcpStreamSource> {
+        Ok(TcpStreamSource { inner: crate::net::connect_tcp(addr)? })
+    }
+
+    /// Read until the stream would block or is closed, handing every chunk to `f`
+    pub fn drain_readable<F: FnMut(&[u8])>(&self, mut f: F) -> io::Result<Drained> {
+        let mut buf = [0; DRAIN_BUFFER];
+        let mut drained = Drained::default();
+
+        loop {
+            match (&self.inner).read(&mut buf) {
+                Ok(0) => {
+                    drained.closed = true;
+                    return Ok(drained);
+                }
+                Ok(n) => {
+                    drained.bytes += n;
+                    f(&buf[..n]);
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(drained),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    pub fn shutdown(&self, how: net::Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+}
+
+source_common!(TcpStreamSource, TcpStream, Interest::READABLE | Interest::WRITABLE);
+
+/// A non-blocking [`UdpSocket`]
+#[derive(Debug)]
+pub struct UdpSocketSource {
+    inner: UdpSocket,
+}
+
+impl UdpSocketSource {
+    pub fn new(socket: UdpSocket) -> io::Result<UdpSocketSource> {
+        socket.set_nonblocking(true)?;
+        Ok(UdpSocketSource { inner: socket })
+    }
+
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<UdpSocketSource> {
+        UdpSocketSource::new(UdpSocket::bind(addr)?)
+    }
+
+    /// Receive until the socket would block, handing every datagram and its
+    /// sender to `f`. Datagrams longer than 8 KiB are truncated. Returns the
+    /// number of datagrams
+    pub fn drain_readable<F: FnMut(&[u8], SocketAddr)>(&self, mut f: F) -> io::Result<usize> {
+        let mut buf = [0; DRAIN_BUFFER];
+        let mut received = 0;
+
+        loop {
+            match self.inner.recv_from(&mut buf) {
+                Ok((n, addr)) => {
+                    received += 1;
+                    f(&buf[..n], addr);
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(received),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+source_common!(UdpSocketSource, UdpSocket, Interest::READABLE);
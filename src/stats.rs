@@ -0,0 +1,293 @@
+//! Counters of what the polls of an instance did, for exporting to a
+//! metrics system. Enable them with [`Epoll::set_stats`](crate::Epoll::set_stats).
+//!
+//! An [`EventLoop`](crate::EventLoop) also times its callbacks, see
+//! [`EventLoop::set_handler_stats`](crate::EventLoop::set_handler_stats).
+//! The tokens are sorted into classes by a closure and every class gets a
+//! [`Histogram`], which shows what kind of source uses up the time of the
+//! loop:
+//!
+//! ```ignore
+//! event_loop.set_handler_stats(DEFAULT_BUCKETS, |token| if token == listener { "accept" } else { "client" });
+//! ...
+//! let text = event_loop.handler_stats().unwrap().to_prometheus("copoll_handler_seconds");
+//! ```
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use crate::Token;
+
+/// Poll counters since the stats were enabled or reset. Unlike the
+/// [`WakeupProfile`](crate::profile::WakeupProfile) every poll counts,
+/// including those with a zero timeout
+#[derive(Debug, Clone, Default)]
+pub struct PollStats {
+    polls: u64,
+    events: u64,
+    spurious: u64,
+    interrupts: u64,
+    blocked: Duration,
+    dispatching: Duration,
+    tokens: HashMap<Token, u64>,
+    /// When the previous poll returned, what follows is dispatching
+    last_return: Option<Instant>,
+}
+
+impl PollStats {
+    /// Number of polls that returned, with events, without or interrupted
+    pub fn polls(&self) -> u64 {
+        self.polls
+    }
+
+    /// Events delivered by all polls
+    pub fn events(&self) -> u64 {
+        self.events
+    }
+
+    /// Average number of events a poll delivered
+    pub fn events_per_poll(&self) -> f64 {
+        if self.polls > 0 {
+            self.events as f64 / self.polls as f64
+        } else {
+            0.0
+        }
+    }
+
+    /// Polls that returned before their timeout without delivering an
+    /// event, e.g. because every event was for a disabled registration
+    pub fn spurious(&self) -> u64 {
+        self.spurious
+    }
+
+    /// Polls a signal interrupted
+    pub fn interrupts(&self) -> u64 {
+        self.interrupts
+    }
+
+    /// Time spent inside polls
+    pub fn blocked(&self) -> Duration {
+        self.blocked
+    }
+
+    /// Time spent between a poll returning and the next one starting,
+    /// usually handling the events
+    pub fn dispatching(&self) -> Duration {
+        self.dispatching
+    }
+
+    /// Events delivered for `token`
+    pub fn token_events(&self, token: Token) -> u64 {
+        self.tokens.get(&token).copied().unwrap_or(0)
+    }
+
+    /// Tokens and their event counts, the most frequent first
+    pub fn by_token(&self) -> Vec<(Token, u64)> {
+        let mut tokens: Vec<_> = self.tokens.iter().map(|(token, count)| (*token, *count)).collect();
+        tokens.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        tokens
+    }
+
+    /// Start over with empty counts
+    pub fn reset(&mut self) {
+        *self = PollStats::default();
+    }
+
+    /// A poll is about to wait, the time since the last one was dispatching
+    pub(crate) fn record_start(&mut self, start: Instant) {
+        if let Some(last_return) = self.last_return {
+            self.dispatching += start.saturating_duration_since(last_return);
+        }
+    }
+
+    pub(crate) fn record_token(&mut self, token: Token) {
+        *self.tokens.entry(token).or_insert(0) += 1;
+    }
+
+    pub(crate) fn record_poll(&mut self, start: Instant, timeout: Option<Duration>, events: usize, interrupted: bool) {
+        let now = Instant::now();
+        let blocked = now.saturating_duration_since(start);
+
+        self.polls += 1;
+        self.events += events as u64;
+        self.blocked += blocked;
+        self.last_return = Some(now);
+
+        if interrupted {
+            self.interrupts += 1;
+        } else if events == 0 && timeout.is_none_or(|timeout| blocked < timeout) {
+            self.spurious += 1;
+        }
+    }
+}
+
+/// Upper bounds of the buckets of a [`Histogram`], from 10µs to 1s
+pub const DEFAULT_BUCKETS: &[Duration] = &[
+    Duration::from_micros(10),
+    Duration::from_micros(50),
+    Duration::from_micros(100),
+    Duration::from_micros(500),
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+];
+
+/// Durations counted in buckets, Prometheus style: every bucket counts the
+/// observations up to its upper bound, the last one has none
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    bounds: Vec<Duration>,
+    /// Observations per bucket, not cumulative, one more than bounds
+    counts: Vec<u64>,
+    sum: Duration,
+    count: u64,
+}
+
+impl Histogram {
+    /// An empty histogram with buckets up to `bounds`, in any order
+    pub fn new(bounds: &[Duration]) -> Histogram {
+        let mut bounds = bounds.to_vec();
+        bounds.sort();
+        bounds.dedup();
+
+        Histogram { counts: vec![0; bounds.len() + 1], bounds, sum: Duration::ZERO, count: 0 }
+    }
+
+    pub fn observe(&mut self, duration: Duration) {
+        let bucket = self.bounds.partition_point(|bound| *bound < duration);
+        self.counts[bucket] += 1;
+        self.sum += duration;
+        self.count += 1;
+    }
+
+    /// Number of observations
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Sum of the observations
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    /// The upper bound of every bucket and the observations up to it, None
+    /// for the last bucket, which counts all of them
+    pub fn buckets(&self) -> Vec<(Option<Duration>, u64)> {
+        let bounds = self.bounds.iter().copied().map(Some).chain([None]);
+
+        bounds
+            .zip(&self.counts)
+            .scan(0, |total, (bound, count)| {
+                *total += count;
+                Some((bound, *total))
+            })
+            .collect()
+    }
+
+    pub fn reset(&mut self) {
+        self.counts.iter_mut().for_each(|count| *count = 0);
+        self.sum = Duration::ZERO;
+        self.count = 0;
+    }
+}
+
+/// How long the callbacks of an event loop ran, by class of their token
+#[derive(Debug, Clone)]
+pub struct HandlerStats {
+    bounds: Vec<Duration>,
+    classes: HashMap<&'static str, Histogram>,
+}
+
+impl HandlerStats {
+    pub(crate) fn new(bounds: &[Duration]) -> HandlerStats {
+        HandlerStats { bounds: bounds.to_vec(), classes: HashMap::new() }
+    }
+
+    /// The histogram of `class`, None until one of its callbacks ran
+    pub fn class(&self, class: &str) -> Option<&Histogram> {
+        self.classes.get(class)
+    }
+
+    /// Every class with its histogram, by name
+    pub fn classes(&self) -> Vec<(&'static str, &Histogram)> {
+        let mut classes: Vec<_> = self.classes.iter().map(|(class, histogram)| (*class, histogram)).collect();
+        classes.sort_by_key(|(class, _)| *class);
+        classes
+    }
+
+    /// Start over with empty histograms
+    pub fn reset(&mut self) {
+        self.classes.clear();
+    }
+
+    /// The histograms in the Prometheus text format, as the histogram
+    /// `name` with a `class` label. Durations are in seconds
+    pub fn to_prometheus(&self, name: &str) -> String {
+        let mut text = format!("# TYPE {} histogram\n", name);
+
+        for (class, histogram) in self.classes() {
+            for (bound, count) in histogram.buckets() {
+                let le = bound.map_or("+Inf".to_string(), |bound| bound.as_secs_f64().to_string());
+                let _ = writeln!(text, "{}_bucket{{class=\"{}\",le=\"{}\"}} {}", name, class, le, count);
+            }
+
+            let _ = writeln!(text, "{}_sum{{class=\"{}\"}} {}", name, class, histogram.sum().as_secs_f64());
+            let _ = writeln!(text, "{}_count{{class=\"{}\"}} {}", name, class, histogram.count());
+        }
+
+        text
+    }
+
+    pub(crate) fn record(&mut self, class: &'static str, duration: Duration) {
+        let bounds = &self.bounds;
+        self.classes.entry(class).or_insert_with(|| Histogram::new(bounds)).observe(duration);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_are_cumulative() {
+        let mut histogram = Histogram::new(&[Duration::from_millis(10), Duration::from_millis(1)]);
+        histogram.observe(Duration::from_micros(500));
+        histogram.observe(Duration::from_millis(1));
+        histogram.observe(Duration::from_millis(5));
+        histogram.observe(Duration::from_secs(1));
+
+        assert_eq!(histogram.buckets(), [
+            (Some(Duration::from_millis(1)), 2),
+            (Some(Duration::from_millis(10)), 3),
+            (None, 4),
+        ]);
+        assert_eq!(histogram.count(), 4);
+        assert_eq!(histogram.sum(), Duration::from_micros(1_006_500));
+
+        histogram.reset();
+        assert_eq!(histogram.buckets().last(), Some(&(None, 0)));
+    }
+
+    #[test]
+    fn classes_are_exported_with_a_label() {
+        let mut stats = HandlerStats::new(&[Duration::from_millis(1)]);
+        stats.record("timer", Duration::from_millis(2));
+        stats.record("accept", Duration::from_micros(250));
+
+        assert_eq!(stats.to_prometheus("handler_seconds"), "\
+# TYPE handler_seconds histogram
+handler_seconds_bucket{class=\"accept\",le=\"0.001\"} 1
+handler_seconds_bucket{class=\"accept\",le=\"+Inf\"} 1
+handler_seconds_sum{class=\"accept\"} 0.00025
+handler_seconds_count{class=\"accept\"} 1
+handler_seconds_bucket{class=\"timer\",le=\"0.001\"} 0
+handler_seconds_bucket{class=\"timer\",le=\"+Inf\"} 1
+handler_seconds_sum{class=\"timer\"} 0.002
+handler_seconds_count{class=\"timer\"} 1
+");
+    }
+}
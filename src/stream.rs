@@ -0,0 +1,258 @@
+//! Epoll events as a `futures_core::Stream`, enabled with the `futures-core`
+//! feature.
+//!
+//! [`Epoll::event_stream`] works with any executor: while the stream is
+//! pending a helper thread waits for the epoll fd to become readable with
+//! poll(2) and wakes the task, the events themselves are always collected
+//! with zero timeout polls on the task.
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, OwnedFd, RawFd};
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use futures_core::Stream;
+
+use crate::{sys, Epoll, Events, Readiness, Token};
+
+#[derive(Debug, Default)]
+struct Shared {
+    /// Set while the stream is pending, taken by the thread to wake it
+    waker: Option<Waker>,
+    stop: bool,
+}
+
+#[derive(Debug)]
+struct Watcher {
+    shared: Arc<(Mutex<Shared>, Condvar)>,
+    /// Interrupts the poll of the thread on drop
+    stop: OwnedFd,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Watcher {
+    fn spawn(epoll_fd: RawFd) -> io::Result<Watcher> {
+        let stop = sys::eventfd(0)?;
+        let shared = Arc::new((Mutex::new(Shared::default()), Condvar::new()));
+
+        let thread = {
+            let shared = shared.clone();
+            let stop_fd = stop.as_raw_fd();
+
+            thread::Builder::new()
+                .name("copoll-stream".into())
+                .spawn(move || watch(epoll_fd, stop_fd, &shared))?
+        };
+
+        Ok(Watcher { shared, stop, thread: Some(thread) })
+    }
+
+    fn park(&self, waker: &Waker) {
+        let (lock, condvar) = &*self.shared;
+        let mut shared = lock.lock().unwrap_or_else(|err| err.into_inner());
+
+        match &shared.waker {
+            Some(parked) if parked.will_wake(waker) => {}
+            _ => shared.waker = Some(waker.clone()),
+        }
+
+        condvar.notify_one();
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        let (lock, condvar) = &*self.shared;
+        lock.lock().unwrap_or_else(|err| err.into_inner()).stop = true;
+        condvar.notify_one();
+
+        let _ = sys::write_counter(self.stop.as_raw_fd(), 1);
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// The helper thread: wait for a parked waker, then for the epoll fd
+fn watch(epoll_fd: RawFd, stop_fd: RawFd, shared: &(Mutex<Shared>, Condvar)) {
+    let (lock, condvar) = shared;
+
+    loop {
+        {
+            let mut state = lock.lock().unwrap_or_else(|err| err.into_inner());
+            while state.waker.is_none() && !state.stop {
+                state = condvar.wait(state).unwrap_or_else(|err| err.into_inner());
+            }
+
+            if state.stop {
+                return;
+            }
+        }
+
+        let mut fds = [
+            libc::pollfd { fd: epoll_fd, events: libc::POLLIN, revents: 0 },
+            libc::pollfd { fd: stop_fd, events: libc::POLLIN, revents: 0 },
+        ];
+        if let Err(err) = sys::cvt(unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) }) {
+            if err.kind() != io::ErrorKind::Interrupted {
+                return;
+            }
+        }
+
+        if fds[1].revents != 0 {
+            return;
+        }
+
+        if fds[0].revents != 0 {
+            let waker = lock.lock().unwrap_or_else(|err| err.into_inner()).waker.take();
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Stream of the events of an [`Epoll`], see [`Epoll::event_stream`]
+#[derive(Debug)]
+pub struct EventStream<'a> {
+    epoll: &'a mut Epoll,
+    events: Events,
+    /// How many events of the current batch were yielded
+    taken: usize,
+    watcher: Option<Watcher>,
+    ended: bool,
+    error: Option<io::Error>,
+}
+
+impl Epoll {
+    /// Borrow the instance as a stream of events. The stream ends when a
+    /// poll fails, [`EventStream::take_error`] tells why
+    pub fn event_stream(&mut self) -> EventStream<'_> {
+        EventStream { epoll: self, events: Events::new(), taken: 0, watcher: None, ended: false, error: None }
+    }
+}
+
+impl EventStream<'_> {
+    /// The error that ended the stream
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    fn end(&mut self, err: io::Error) {
+        self.ended = true;
+        self.error = Some(err);
+        self.watcher = None;
+    }
+
+    fn next_buffered(&mut self) -> Option<(Token, Readiness)> {
+        let event = self.events.as_slice().get(self.taken)?;
+        self.taken += 1;
+        Some((event.token, event.readiness))
+    }
+
+    fn refill(&mut self) -> io::Result<usize> {
+        self.taken = 0;
+
+        loop {
+            match self.epoll.poll_into(&mut self.events, Some(Duration::ZERO)) {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Stream for EventStream<'_> {
+    type Item = (Token, Readiness);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<(Token, Readiness)>> {
+        let this = self.get_mut();
+
+        if this.ended {
+            return Poll::Ready(None);
+        }
+
+        if let Some(event) = this.next_buffered() {
+            return Poll::Ready(Some(event));
+        }
+
+        match this.refill() {
+            Ok(0) => {}
+            Ok(_) => return Poll::Ready(this.next_buffered()),
+            Err(err) => {
+                this.end(err);
+                return Poll::Ready(None);
+            }
+        }
+
+        if this.watcher.is_none() {
+            match Watcher::spawn(this.epoll.as_fd().as_raw_fd()) {
+                Ok(watcher) => this.watcher = Some(watcher),
+                Err(err) => {
+                    this.end(err);
+                    return Poll::Ready(None);
+                }
+            }
+        }
+
+        // poll(2) is level triggered, events that arrived since the
+        // refill wake the task right away
+        if let Some(watcher) = &this.watcher {
+            watcher.park(cx.waker());
+        }
+
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+
+    use super::*;
+    use crate::{Interest, Mode};
+
+    fn next(stream: &mut EventStream<'_>) -> Option<(Token, Readiness)> {
+        crate::block_on(std::future::poll_fn(|cx| Pin::new(&mut *stream).poll_next(cx))).unwrap()
+    }
+
+    #[test]
+    fn ready_events_are_yielded_one_by_one() {
+        let mut epoll = Epoll::create().unwrap();
+        let (a, a_peer) = UnixStream::pair().unwrap();
+        let (b, b_peer) = UnixStream::pair().unwrap();
+        epoll.register(&a, Token(1), Interest::READABLE, Mode::Edge).unwrap();
+        epoll.register(&b, Token(2), Interest::READABLE, Mode::Edge).unwrap();
+        (&a_peer).write_all(b"x").unwrap();
+        (&b_peer).write_all(b"x").unwrap();
+
+        let mut stream = epoll.event_stream();
+        let mut tokens = [next(&mut stream).unwrap().0, next(&mut stream).unwrap().0];
+        tokens.sort();
+        assert_eq!(tokens, [Token(1), Token(2)]);
+        assert!(stream.take_error().is_none());
+    }
+
+    #[test]
+    fn pending_streams_are_woken_by_new_events() {
+        let mut epoll = Epoll::create().unwrap();
+        let (stream, peer) = UnixStream::pair().unwrap();
+        epoll.register(&stream, Token(1), Interest::READABLE, Mode::Edge).unwrap();
+
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            (&peer).write_all(b"x").unwrap();
+            peer
+        });
+
+        let mut events = epoll.event_stream();
+        let (token, readiness) = next(&mut events).unwrap();
+        assert_eq!(token, Token(1));
+        assert!(readiness.readable);
+        writer.join().unwrap();
+    }
+}
@@ -0,0 +1,280 @@
+//! The libc calls the rest of the crate is built on, with errno turned
+//! into io::Error and new fds handed out as OwnedFd
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+use std::time::Duration;
+
+use crate::signal::SigSet;
+
+/// The error of a call returning -1 on failure
+pub(crate) fn cvt(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// The error of a call returning a negative ssize_t on failure
+pub(crate) fn cvt_size(ret: libc::ssize_t) -> io::Result<usize> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+/// Take ownership of an fd returned by a successful call
+pub(crate) fn owned(ret: libc::c_int) -> io::Result<OwnedFd> {
+    cvt(ret).map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+pub(crate) fn read(fd: RawFd, buf: &mut [u8]) -> io::Result<usize> {
+    cvt_size(unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) })
+}
+
+pub(crate) fn write(fd: RawFd, buf: &[u8]) -> io::Result<usize> {
+    cvt_size(unsafe { libc::write(fd, buf.as_ptr() as *const libc::c_void, buf.len()) })
+}
+
+/// Read the 8 byte counter of an eventfd or timerfd, 0 if it is not ready
+pub(crate) fn read_counter(fd: RawFd) -> io::Result<u64> {
+    let mut counter = [0u8; 8];
+
+    match read(fd, &mut counter) {
+        Ok(_) => Ok(u64::from_ne_bytes(counter)),
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(0),
+        Err(err) => Err(err),
+    }
+}
+
+/// Add `value` to the counter of an eventfd
+pub(crate) fn write_counter(fd: RawFd, value: u64) -> io::Result<()> {
+    write(fd, &value.to_ne_bytes()).map(drop)
+}
+
+/// A non-blocking, close-on-exec eventfd
+pub(crate) fn eventfd(initval: u32) -> io::Result<OwnedFd> {
+    owned(unsafe { libc::eventfd(initval, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) })
+}
+
+/// A pipe with `flags` on both ends, read end first
+pub(crate) fn pipe(flags: libc::c_int) -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    cvt(unsafe { libc::pipe2(fds.as_mut_ptr(), flags) })?;
+
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+
+/// Open `path` with `flags`, O_CLOEXEC is always added
+pub(crate) fn open(path: &Path, flags: libc::c_int) -> io::Result<OwnedFd> {
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+    owned(unsafe { libc::open(path.as_ptr(), flags | libc::O_CLOEXEC) })
+}
+
+pub(crate) fn get_flags(fd: RawFd) -> io::Result<libc::c_int> {
+    cvt(unsafe { libc::fcntl(fd, libc::F_GETFL) })
+}
+
+pub(crate) fn set_flags(fd: RawFd, flags: libc::c_int) -> io::Result<()> {
+    cvt(unsafe { libc::fcntl(fd, libc::F_SETFL, flags) }).map(drop)
+}
+
+/// Add O_NONBLOCK to the file status flags
+pub(crate) fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    let flags = get_flags(fd)?;
+
+    if flags & libc::O_NONBLOCK == 0 {
+        set_flags(fd, flags | libc::O_NONBLOCK)?;
+    }
+
+    Ok(())
+}
+
+pub(crate) fn set_cloexec(fd: RawFd) -> io::Result<()> {
+    cvt(unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) }).map(drop)
+}
+
+/// Duplicate `fd` with F_DUPFD_CLOEXEC
+pub(crate) fn dup(fd: RawFd) -> io::Result<OwnedFd> {
+    owned(unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) })
+}
+
+pub(crate) fn fstat(fd: RawFd) -> io::Result<libc::stat> {
+    let mut stat = mem::MaybeUninit::<libc::stat>::uninit();
+    cvt(unsafe { libc::fstat(fd, stat.as_mut_ptr()) })?;
+
+    Ok(unsafe { stat.assume_init() })
+}
+
+pub(crate) fn lseek(fd: RawFd, offset: libc::off_t, whence: libc::c_int) -> io::Result<libc::off_t> {
+    let offset = unsafe { libc::lseek(fd, offset, whence) };
+
+    if offset < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(offset)
+    }
+}
+
+/// An int socket option like SO_ERROR
+pub(crate) fn getsockopt_int(fd: RawFd, level: libc::c_int, name: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    cvt(unsafe { libc::getsockopt(fd, level, name, &mut value as *mut libc::c_int as *mut libc::c_void, &mut len) })?;
+
+    Ok(value)
+}
+
+/// The address family of the local address of a socket
+pub(crate) fn socket_family(fd: RawFd) -> io::Result<libc::c_int> {
+    let mut addr = mem::MaybeUninit::<libc::sockaddr_storage>::zeroed();
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    cvt(unsafe { libc::getsockname(fd, addr.as_mut_ptr() as *mut libc::sockaddr, &mut len) })?;
+
+    Ok(unsafe { addr.assume_init() }.ss_family as libc::c_int)
+}
+
+/// The soft and hard RLIMIT_NOFILE, None for unlimited
+pub(crate) fn nofile_limit() -> io::Result<(Option<u64>, Option<u64>)> {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    cvt(unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) })?;
+
+    let finite = |value: libc::rlim_t| (value != libc::RLIM_INFINITY).then_some(value);
+    Ok((finite(limit.rlim_cur), finite(limit.rlim_max)))
+}
+
+pub(crate) fn timespec(duration: Duration) -> libc::timespec {
+    libc::timespec {
+        tv_sec: duration.as_secs().min(libc::time_t::MAX as u64) as libc::time_t,
+        tv_nsec: duration.subsec_nanos() as _,
+    }
+}
+
+pub(crate) fn duration(timespec: libc::timespec) -> Duration {
+    Duration::new(timespec.tv_sec.max(0) as u64, timespec.tv_nsec.clamp(0, 999_999_999) as u32)
+}
+
+pub(crate) fn clock_gettime(clock: libc::clockid_t) -> io::Result<Duration> {
+    let mut now = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    cvt(unsafe { libc::clock_gettime(clock, &mut now) })?;
+
+    Ok(duration(now))
+}
+
+/// A new epoll instance, `flags` is 0 or EPOLL_CLOEXEC
+pub(crate) fn epoll_create(flags: libc::c_int) -> io::Result<OwnedFd> {
+    owned(unsafe { libc::epoll_create1(flags) })
+}
+
+/// EPOLL_CTL_ADD, EPOLL_CTL_MOD or EPOLL_CTL_DEL `fd` with the event flags
+/// `events` and `data` handed back with its events
+pub(crate) fn epoll_ctl(epoll_fd: RawFd, op: libc::c_int, fd: RawFd, events: u32, data: u64) -> io::Result<()> {
+    let mut event = libc::epoll_event { events, u64: data };
+    cvt(unsafe { libc::epoll_ctl(epoll_fd, op, fd, &mut event) }).map(drop)
+}
+
+/// A non-blocking, close-on-exec timerfd
+#[derive(Debug)]
+pub(crate) struct TimerFd {
+    fd: OwnedFd,
+}
+
+impl TimerFd {
+    pub fn new(clock: libc::clockid_t) -> io::Result<TimerFd> {
+        let fd = owned(unsafe { libc::timerfd_create(clock, libc::TFD_NONBLOCK | libc::TFD_CLOEXEC) })?;
+        Ok(TimerFd { fd })
+    }
+
+    /// Arm the timer to fire at `value`, relative unless TFD_TIMER_ABSTIME is
+    /// in `flags`, and then every `interval` unless it is zero. A zero value
+    /// disarms the timer
+    pub fn set(&self, value: Duration, interval: Duration, flags: libc::c_int) -> io::Result<()> {
+        let new = libc::itimerspec { it_value: timespec(value), it_interval: timespec(interval) };
+        cvt(unsafe { libc::timerfd_settime(self.as_raw_fd(), flags, &new, ptr::null_mut()) }).map(drop)
+    }
+
+    pub fn unset(&self) -> io::Result<()> {
+        self.set(Duration::ZERO, Duration::ZERO, 0)
+    }
+
+    /// Time until the next expiration and the interval, None if disarmed
+    pub fn get(&self) -> io::Result<Option<(Duration, Duration)>> {
+        let mut current = libc::itimerspec {
+            it_value: libc::timespec { tv_sec: 0, tv_nsec: 0 },
+            it_interval: libc::timespec { tv_sec: 0, tv_nsec: 0 },
+        };
+        cvt(unsafe { libc::timerfd_gettime(self.as_raw_fd(), &mut current) })?;
+
+        let value = duration(current.it_value);
+        Ok((!value.is_zero()).then(|| (value, duration(current.it_interval))))
+    }
+
+    /// Expirations since the last read, 0 if there were none
+    pub fn read(&self) -> io::Result<u64> {
+        read_counter(self.as_raw_fd())
+    }
+}
+
+impl AsRawFd for TimerFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for TimerFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+/// A non-blocking, close-on-exec signalfd
+#[derive(Debug)]
+pub(crate) struct SignalFd {
+    fd: OwnedFd,
+}
+
+impl SignalFd {
+    pub fn new(mask: &SigSet) -> io::Result<SignalFd> {
+        let fd = owned(unsafe { libc::signalfd(-1, mask.as_raw(), libc::SFD_NONBLOCK | libc::SFD_CLOEXEC) })?;
+        Ok(SignalFd { fd })
+    }
+
+    pub fn set_mask(&self, mask: &SigSet) -> io::Result<()> {
+        cvt(unsafe { libc::signalfd(self.as_raw_fd(), mask.as_raw(), 0) }).map(drop)
+    }
+
+    /// Read one pending signal, None once they are drained
+    pub fn read_signal(&self) -> io::Result<Option<libc::signalfd_siginfo>> {
+        let mut info = mem::MaybeUninit::<libc::signalfd_siginfo>::uninit();
+        let size = mem::size_of::<libc::signalfd_siginfo>();
+
+        let n = unsafe { libc::read(self.as_raw_fd(), info.as_mut_ptr() as *mut libc::c_void, size) };
+
+        match cvt_size(n) {
+            Ok(n) if n == size => Ok(Some(unsafe { info.assume_init() })),
+            Ok(_) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "short signalfd read")),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl AsRawFd for SignalFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for SignalFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
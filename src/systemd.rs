@@ -0,0 +1,239 @@
+//! systemd integration without linking libsystemd.
+//!
+//! [`listen_fds`] implements the socket activation protocol of
+//! sd_listen_fds(3): the sockets systemd passed to the service are picked up
+//! from fd 3 onwards, classified and made non-blocking, ready to be registered.
+//! [`notify`] and its helpers talk to the service manager over NOTIFY_SOCKET
+//! like sd_notify(3), and a [`Watchdog`] is a timer to register in the loop
+//! that keeps the WatchdogSec= supervision satisfied.
+use std::env;
+use std::fs::File;
+use std::io;
+use std::net::{TcpListener, TcpStream, UdpSocket};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{self, UnixDatagram, UnixListener, UnixStream};
+use std::time::Duration;
+
+use crate::sys::{self, TimerFd};
+use crate::{Epoll, Interest, Mode, Token};
+
+/// The first fd passed by systemd
+const LISTEN_FDS_START: RawFd = 3;
+
+/// An fd passed by systemd, by what it turned out to be
+#[derive(Debug)]
+pub enum Activated {
+    TcpListener(TcpListener),
+    /// A connection of an Accept=yes socket unit
+    TcpStream(TcpStream),
+    Udp(UdpSocket),
+    UnixListener(UnixListener),
+    UnixStream(UnixStream),
+    UnixDatagram(UnixDatagram),
+    /// FIFOs, special files and sockets of other families, e.g. netlink
+    Other(File),
+}
+
+impl AsRawFd for Activated {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Activated::TcpListener(socket) => socket.as_raw_fd(),
+            Activated::TcpStream(socket) => socket.as_raw_fd(),
+            Activated::Udp(socket) => socket.as_raw_fd(),
+            Activated::UnixListener(socket) => socket.as_raw_fd(),
+            Activated::UnixStream(socket) => socket.as_raw_fd(),
+            Activated::UnixDatagram(socket) => socket.as_raw_fd(),
+            Activated::Other(file) => file.as_raw_fd(),
+        }
+    }
+}
+
+impl AsFd for Activated {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            Activated::TcpListener(socket) => socket.as_fd(),
+            Activated::TcpStream(socket) => socket.as_fd(),
+            Activated::Udp(socket) => socket.as_fd(),
+            Activated::UnixListener(socket) => socket.as_fd(),
+            Activated::UnixStream(socket) => socket.as_fd(),
+            Activated::UnixDatagram(socket) => socket.as_fd(),
+            Activated::Other(file) => file.as_fd(),
+        }
+    }
+}
+
+/// One passed fd and the name given to it with FileDescriptorName=
+#[derive(Debug)]
+pub struct ListenFd {
+    pub name: Option<String>,
+    pub socket: Activated,
+}
+
+impl ListenFd {
+    /// Register the fd readable and level triggered, labeled with its name.
+    /// Listeners report pending connections, everything else incoming data
+    pub fn register(&self, epoll: &mut Epoll, token: Token) -> io::Result<()> {
+        match &self.name {
+            Some(name) => epoll.register_named(&self.socket, token, name.clone(), Interest::READABLE, Mode::Level),
+            None => epoll.register(&self.socket, token, Interest::READABLE, Mode::Level),
+        }
+    }
+}
+
+/// Take over the fds passed by systemd. Returns nothing if the service was
+/// not socket activated, or LISTEN_PID names another process. The fds are
+/// marked close-on-exec and non-blocking. With `unset_env` the LISTEN_*
+/// variables are removed so child processes do not pick them up again.
+/// Call it once, the fds are owned by the returned values
+pub fn listen_fds(unset_env: bool) -> io::Result<Vec<ListenFd>> {
+    let pid = env::var("LISTEN_PID").ok();
+    let count = env::var("LISTEN_FDS").ok();
+    let names = env::var("LISTEN_FDNAMES").ok();
+
+    if unset_env {
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+    }
+
+    let for_us = pid.and_then(|pid| pid.parse::<u32>().ok()) == Some(std::process::id());
+    let count = match count.and_then(|count| count.parse::<RawFd>().ok()) {
+        Some(count) if for_us && count > 0 => count,
+        _ => return Ok(Vec::new()),
+    };
+
+    let mut names = names.as_deref().unwrap_or("").split(':').map(String::from);
+    let mut fds = Vec::with_capacity(count as usize);
+
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        sys::set_cloexec(fd)?;
+        sys::set_nonblocking(fd)?;
+
+        // systemd passes "unknown" for sockets without a name
+        let name = names.next().filter(|name| !name.is_empty() && name != "unknown");
+
+        fds.push(ListenFd { name, socket: classify(fd)? });
+    }
+
+    Ok(fds)
+}
+
+fn classify(fd: RawFd) -> io::Result<Activated> {
+    if sys::fstat(fd)?.st_mode & libc::S_IFMT != libc::S_IFSOCK {
+        return Ok(Activated::Other(unsafe { File::from_raw_fd(fd) }));
+    }
+
+    let kind = sys::getsockopt_int(fd, libc::SOL_SOCKET, libc::SO_TYPE)?;
+    let listening = sys::getsockopt_int(fd, libc::SOL_SOCKET, libc::SO_ACCEPTCONN)? != 0;
+    let family = sys::socket_family(fd)?;
+    let inet = matches!(family, libc::AF_INET | libc::AF_INET6);
+    let unix = family == libc::AF_UNIX;
+
+    let activated = match (inet, unix, kind, listening) {
+        (true, _, libc::SOCK_STREAM, true) => Activated::TcpListener(unsafe { TcpListener::from_raw_fd(fd) }),
+        (true, _, libc::SOCK_STREAM, false) => Activated::TcpStream(unsafe { TcpStream::from_raw_fd(fd) }),
+        (true, _, libc::SOCK_DGRAM, _) => Activated::Udp(unsafe { UdpSocket::from_raw_fd(fd) }),
+        (_, true, libc::SOCK_STREAM, true) => Activated::UnixListener(unsafe { UnixListener::from_raw_fd(fd) }),
+        (_, true, libc::SOCK_STREAM, false) => Activated::UnixStream(unsafe { UnixStream::from_raw_fd(fd) }),
+        (_, true, libc::SOCK_DGRAM, _) => Activated::UnixDatagram(unsafe { UnixDatagram::from_raw_fd(fd) }),
+        _ => Activated::Other(unsafe { File::from_raw_fd(fd) }),
+    };
+
+    Ok(activated)
+}
+
+/// Send a state update like "READY=1" to the service manager. Returns false
+/// if the service is not supervised, i.e. NOTIFY_SOCKET is not set.
+/// Several assignments can be sent at once separated by newlines
+pub fn notify(state: &str) -> io::Result<bool> {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(false),
+    };
+
+    let addr = match path.as_bytes().split_first() {
+        Some((b'@', name)) => net::SocketAddr::from_abstract_name(name)?,
+        Some((b'/', _)) => net::SocketAddr::from_pathname(&path)?,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "NOTIFY_SOCKET is not a socket path")),
+    };
+
+    let socket = UnixDatagram::unbound()?;
+    socket.set_nonblocking(true)?;
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+
+    Ok(true)
+}
+
+/// Tell the service manager that startup is finished
+pub fn ready() -> io::Result<bool> {
+    notify("READY=1")
+}
+
+/// Tell the service manager that the service is shutting down
+pub fn stopping() -> io::Result<bool> {
+    notify("STOPPING=1")
+}
+
+/// The watchdog timeout configured with WatchdogSec=, None if the watchdog
+/// is disabled or WATCHDOG_PID names another process
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    Some(Duration::from_micros(usec)).filter(|interval| !interval.is_zero())
+}
+
+/// Periodic timer sending WATCHDOG=1 at half the watchdog timeout, as
+/// recommended by sd_watchdog_enabled(3). Register it readable and call
+/// [`Watchdog::keepalive`] when it is reported. A stalled loop stops the
+/// keepalives, which is the point of the watchdog
+#[derive(Debug)]
+pub struct Watchdog {
+    timer: TimerFd,
+    period: Duration,
+}
+
+impl Watchdog {
+    /// None if the watchdog is not enabled for this process
+    pub fn new() -> io::Result<Option<Watchdog>> {
+        let period = match watchdog_interval() {
+            Some(interval) => interval / 2,
+            None => return Ok(None),
+        };
+
+        let timer = TimerFd::new(libc::CLOCK_MONOTONIC)?;
+        timer.set(period, period, 0)?;
+
+        Ok(Some(Watchdog { timer, period }))
+    }
+
+    /// How often the keepalive is sent
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// Collect the timer expiration and send WATCHDOG=1
+    pub fn keepalive(&mut self) -> io::Result<()> {
+        self.timer.read()?;
+
+        notify("WATCHDOG=1").map(drop)
+    }
+}
+
+impl AsRawFd for Watchdog {
+    fn as_raw_fd(&self) -> RawFd {
+        self.timer.as_raw_fd()
+    }
+}
+
+impl AsFd for Watchdog {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.timer.as_fd()
+    }
+}
@@ -0,0 +1,114 @@
+//! Fixed rate ticking for game servers and simulations.
+//!
+//! A [`TickLoop`] polls until the deadline of the current tick, gathering
+//! every event that arrives in the meantime, then hands the whole batch to
+//! the tick callback together with the tick number.
+//!
+//! Register the sources with Mode::Edge or Mode::OneShot. A level triggered
+//! fd stays ready until the callback consumes it and would be reported again
+//! by every poll of the tick.
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::{Epoll, Events};
+
+/// Drives an [`Epoll`] at a fixed tick rate
+#[derive(Debug)]
+pub struct TickLoop {
+    period: Duration,
+    started: Instant,
+    tick: u64,
+    missed: u64,
+    events: Events,
+}
+
+impl TickLoop {
+    /// Tick every `period`, the first tick ends one period from now
+    pub fn new(period: Duration) -> TickLoop {
+        assert!(!period.is_zero(), "tick period must not be zero");
+
+        TickLoop {
+            period,
+            started: Instant::now(),
+            tick: 0,
+            missed: 0,
+            events: Events::new(),
+        }
+    }
+
+    /// Tick `hz` times per second
+    pub fn with_rate(hz: u32) -> TickLoop {
+        assert!(hz > 0, "tick rate must not be zero");
+        TickLoop::new(Duration::from_secs(1) / hz)
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// Number of the next tick, counting from 0
+    pub fn next_tick(&self) -> u64 {
+        self.tick
+    }
+
+    /// Ticks skipped because a tick, or its callback, overran by more than a period
+    pub fn missed(&self) -> u64 {
+        self.missed
+    }
+
+    /// When the current tick ends
+    pub fn deadline(&self) -> Instant {
+        self.started + duration_mul(self.period, self.tick + 1)
+    }
+
+    /// Run a single tick: gather events until its deadline and call `f`
+    /// with the tick number and the events. Returns the tick number
+    pub fn tick<F: FnOnce(u64, &Events)>(&mut self, epoll: &mut Epoll, f: F) -> io::Result<u64> {
+        self.events.clear();
+
+        let deadline = self.deadline();
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+
+            // Events end a poll early, keep gathering until the deadline
+            match epoll.poll_append(&mut self.events, Some(deadline - now)) {
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        let tick = self.tick;
+        f(tick, &self.events);
+
+        // Skip the deadlines that already passed instead of running them in a burst
+        let elapsed = self.started.elapsed().as_nanos();
+        let current = (elapsed / self.period.as_nanos()) as u64;
+        if current > tick + 1 {
+            self.missed += current - tick - 1;
+            self.tick = current;
+        } else {
+            self.tick = tick + 1;
+        }
+
+        Ok(tick)
+    }
+
+    /// Run ticks until `f` returns false or polling fails
+    pub fn run<F: FnMut(u64, &Events) -> bool>(&mut self, epoll: &mut Epoll, mut f: F) -> io::Result<()> {
+        let mut running = true;
+
+        while running {
+            self.tick(epoll, |tick, events| running = f(tick, events))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn duration_mul(period: Duration, ticks: u64) -> Duration {
+    Duration::from_nanos((period.as_nanos() * ticks as u128).min(u64::MAX as u128) as u64)
+}
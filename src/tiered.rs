@@ -0,0 +1,166 @@
+//! Soft prioritization using two epoll instances
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::time::Duration;
+
+use crate::{Epoll, Events, Interest, Mode, Source, Timeout, Token};
+
+/// Token the fast instance is registered with inside the bulk instance.
+/// Do not use it for your own registrations
+pub const FAST_TIER_TOKEN: Token = Token(usize::MAX);
+
+/// Which epoll instance a registration goes into
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Tier {
+    /// Latency critical sources, always handled first
+    Fast,
+    /// Everything else
+    Bulk,
+}
+
+/// A pair of epoll instances where the fast one is nested inside the bulk one.
+/// Every poll drains the fast instance before looking at the bulk events, so
+/// control traffic is delivered ahead of data traffic even when both are ready
+#[derive(Debug)]
+pub struct TieredEpoll {
+    fast: Epoll,
+    bulk: Epoll,
+}
+
+impl TieredEpoll {
+    /// Create the two epoll instances and nest the fast one in the bulk one
+    pub fn create() -> io::Result<TieredEpoll> {
+        let fast = Epoll::create()?;
+        let mut bulk = Epoll::create()?;
+
+        bulk.register(&fast.as_fd(), FAST_TIER_TOKEN, Interest::READABLE, Mode::Level)?;
+
+        Ok(TieredEpoll { fast, bulk })
+    }
+
+    /// Register a source in the given tier
+    pub fn register<S: Source + ?Sized>(
+        &mut self,
+        source: &S,
+        token: Token,
+        interest: Interest,
+        mode: Mode,
+        tier: Tier
+    ) -> io::Result<()> {
+        self.tier(tier).register(source, token, interest, mode)
+    }
+
+    /// Reregister a source in the tier it was registered in
+    pub fn reregister<S: Source + ?Sized>(
+        &mut self,
+        source: &S,
+        token: Token,
+        interest: Interest,
+        mode: Mode,
+        tier: Tier
+    ) -> io::Result<()> {
+        self.tier(tier).reregister(source, token, interest, mode)
+    }
+
+    /// Stop polling a source in the tier it was registered in
+    pub fn unregister<S: Source + ?Sized>(&mut self, source: &S, tier: Tier) -> io::Result<()> {
+        self.tier(tier).unregister(source)
+    }
+
+    /// Poll both tiers, the events of the fast tier come first
+    pub fn poll<T: Into<Timeout>>(&mut self, timeout: T) -> io::Result<Events> {
+        let mut events = Events::new();
+        self.poll_into(&mut events, timeout)?;
+        Ok(events)
+    }
+
+    /// Poll both tiers into `events`, the events of the fast tier come first.
+    /// The buffer is cleared first, returns the number of events
+    pub fn poll_into<T: Into<Timeout>>(&mut self, events: &mut Events, timeout: T) -> io::Result<usize> {
+        let timeout = timeout.into();
+        events.clear();
+
+        self.fast.poll_append(events, Some(Duration::ZERO))?;
+
+        // Fast events are already pending, only pick up what bulk has ready
+        let timeout = if events.is_empty() { timeout } else { Timeout::Immediate };
+        let bulk = self.bulk.poll(timeout)?;
+
+        // The fast instance became ready while we were blocked on bulk
+        if events.is_empty() && bulk.iter().any(|(token, _)| token == FAST_TIER_TOKEN) {
+            self.fast.poll_append(events, Some(Duration::ZERO))?;
+        }
+
+        for (token, readiness) in bulk.iter().filter(|(token, _)| *token != FAST_TIER_TOKEN) {
+            events.push(token, readiness);
+        }
+
+        Ok(events.len())
+    }
+
+    fn tier(&mut self, tier: Tier) -> &mut Epoll {
+        match tier {
+            Tier::Fast => &mut self.fast,
+            Tier::Bulk => &mut self.bulk,
+        }
+    }
+}
+
+impl AsRawFd for TieredEpoll {
+    fn as_raw_fd(&self) -> RawFd {
+        self.bulk.as_raw_fd()
+    }
+}
+
+impl AsFd for TieredEpoll {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.bulk.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    use super::*;
+
+    fn tokens(events: &Events) -> Vec<Token> {
+        events.iter().map(|(token, _)| token).collect()
+    }
+
+    #[test]
+    fn fast_events_come_first() {
+        let mut tiered = TieredEpoll::create().unwrap();
+        let (bulk, bulk_peer) = UnixStream::pair().unwrap();
+        let (fast, fast_peer) = UnixStream::pair().unwrap();
+
+        tiered.register(&bulk, Token(1), Interest::READABLE, Mode::Level, Tier::Bulk).unwrap();
+        tiered.register(&fast, Token(2), Interest::READABLE, Mode::Level, Tier::Fast).unwrap();
+        (&bulk_peer).write_all(b"x").unwrap();
+        (&fast_peer).write_all(b"x").unwrap();
+
+        assert_eq!(tokens(&tiered.poll(Duration::from_secs(1)).unwrap()), [Token(2), Token(1)]);
+
+        tiered.unregister(&fast, Tier::Fast).unwrap();
+        assert_eq!(tokens(&tiered.poll(Duration::from_secs(1)).unwrap()), [Token(1)]);
+    }
+
+    #[test]
+    fn fast_event_wakes_a_blocked_poll() {
+        let mut tiered = TieredEpoll::create().unwrap();
+        let (fast, fast_peer) = UnixStream::pair().unwrap();
+        tiered.register(&fast, Token(2), Interest::READABLE, Mode::Level, Tier::Fast).unwrap();
+
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            (&fast_peer).write_all(b"x").unwrap();
+            fast_peer
+        });
+
+        // The nested instance itself is never reported
+        assert_eq!(tokens(&tiered.poll(Duration::from_secs(5)).unwrap()), [Token(2)]);
+        writer.join().unwrap();
+    }
+}
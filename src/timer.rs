@@ -0,0 +1,768 @@
+//! One-shot and repeating timers.
+//!
+//! A [`Timer`] is a timerfd, CLOCK_MONOTONIC unless another [`Clock`] is
+//! chosen. Register it readable and call [`Timer::expirations`] when it is
+//! reported, or insert it into an [`EventLoop`](crate::EventLoop) with
+//! [`insert_timer`](crate::EventLoop::insert_timer) which does that for you.
+//!
+//! Timers fire after a delay or at an absolute time, [`Timer::set_at`] for an
+//! `Instant` and [`Timer::set_at_system_time`] for a wall clock time on a
+//! realtime clock. A boottime clock keeps counting while the system is
+//! suspended, the alarm clocks also wake it up. A realtime timer with
+//! [`Timer::set_cancel_on_set`] is canceled when the clock is set, e.g. by
+//! NTP or the administrator, so cron-like deadlines can be recomputed.
+//!
+//! [`TimerQueue`] keeps any number of deadlines, e.g. one idle timeout per
+//! connection, behind a single timerfd that is always armed for the earliest
+//! of them. They are kept in a binary heap by default, a queue created with
+//! [`TimerStrategy::Wheel`] keeps them in a hierarchical timing wheel, which
+//! suits millions of coarse timeouts better.
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::leak::LeakGuard;
+use crate::sys::{self, TimerFd};
+use crate::Token;
+
+/// The clock a [`Timer`] counts on
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Clock {
+    /// Does not jump and stops while the system is suspended
+    Monotonic,
+    /// Wall clock time, jumps when the clock is set
+    Realtime,
+    /// Like Monotonic, but keeps counting while the system is suspended
+    Boottime,
+    /// Like Realtime, wakes the system up from suspend. Needs CAP_WAKE_ALARM
+    RealtimeAlarm,
+    /// Like Boottime, wakes the system up from suspend. Needs CAP_WAKE_ALARM
+    BoottimeAlarm,
+}
+
+impl Clock {
+    fn raw(self) -> libc::clockid_t {
+        match self {
+            Clock::Monotonic => libc::CLOCK_MONOTONIC,
+            Clock::Realtime => libc::CLOCK_REALTIME,
+            Clock::Boottime => libc::CLOCK_BOOTTIME,
+            Clock::RealtimeAlarm => libc::CLOCK_REALTIME_ALARM,
+            Clock::BoottimeAlarm => libc::CLOCK_BOOTTIME_ALARM,
+        }
+    }
+
+    fn is_realtime(self) -> bool {
+        matches!(self, Clock::Realtime | Clock::RealtimeAlarm)
+    }
+}
+
+/// Returns true if `err` is the error [`Timer::expirations`] fails with once
+/// the clock of a timer with cancel-on-set was set
+pub fn is_clock_set(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::ECANCELED)
+}
+
+/// Timer firing once after a delay, or repeatedly at an interval
+#[derive(Debug)]
+pub struct Timer {
+    /// Declared first to drop while the fd is still open
+    pub(crate) leaks: LeakGuard,
+    timer: TimerFd,
+    clock: Clock,
+    cancel_on_set: bool,
+}
+
+impl Timer {
+    /// A disarmed timer
+    pub fn new() -> io::Result<Timer> {
+        Timer::with_clock(Clock::Monotonic)
+    }
+
+    /// A disarmed timer counting on `clock`
+    pub fn with_clock(clock: Clock) -> io::Result<Timer> {
+        Ok(Timer { leaks: LeakGuard::default(), timer: TimerFd::new(clock.raw())?, clock, cancel_on_set: false })
+    }
+
+    pub fn clock(&self) -> Clock {
+        self.clock
+    }
+
+    /// A timer firing once, `delay` from now
+    pub fn oneshot(delay: Duration) -> io::Result<Timer> {
+        let mut timer = Timer::new()?;
+        timer.set(delay, None)?;
+        Ok(timer)
+    }
+
+    /// A timer firing every `interval`, starting `interval` from now
+    pub fn repeating(interval: Duration) -> io::Result<Timer> {
+        let mut timer = Timer::new()?;
+        timer.set(interval, Some(interval))?;
+        Ok(timer)
+    }
+
+    /// Fire `delay` from now and then every `interval`, if any, replacing
+    /// the previous setting. Expirations not collected yet are dropped
+    pub fn set(&mut self, delay: Duration, interval: Option<Duration>) -> io::Result<()> {
+        // An all zero expiration would disarm the timer instead
+        let delay = delay.max(Duration::from_nanos(1));
+
+        self.timer.set(delay, interval.unwrap_or(Duration::ZERO), 0)
+    }
+
+    /// Fire at `deadline` and then every `interval`, if any. The deadline is
+    /// taken over to the clock of the timer as it is offset from now, and
+    /// fires right away if it passed
+    pub fn set_at(&mut self, deadline: Instant, interval: Option<Duration>) -> io::Result<()> {
+        let now = sys::clock_gettime(self.clock.raw())?;
+        let deadline = now + deadline.saturating_duration_since(Instant::now());
+
+        self.set_absolute(deadline, interval)
+    }
+
+    /// Fire at the wall clock time `deadline` and then every `interval`, if
+    /// any. Only realtime clocks count in wall clock time, other timers fail
+    /// with InvalidInput. A deadline that passed fires right away
+    pub fn set_at_system_time(&mut self, deadline: SystemTime, interval: Option<Duration>) -> io::Result<()> {
+        if !self.clock.is_realtime() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "wall clock deadlines need a realtime clock"));
+        }
+
+        let deadline = deadline.duration_since(SystemTime::UNIX_EPOCH).unwrap_or(Duration::ZERO);
+        self.set_absolute(deadline, interval)
+    }
+
+    /// Cancel the timer when its clock is set, the next
+    /// [`Timer::expirations`] fails with ECANCELED, see [`is_clock_set`].
+    /// Applies to the absolute deadlines of realtime timers set afterwards
+    pub fn set_cancel_on_set(&mut self, enabled: bool) {
+        self.cancel_on_set = enabled;
+    }
+
+    fn set_absolute(&mut self, deadline: Duration, interval: Option<Duration>) -> io::Result<()> {
+        let mut flags = libc::TFD_TIMER_ABSTIME;
+        if self.cancel_on_set && self.clock.is_realtime() {
+            flags |= libc::TFD_TIMER_CANCEL_ON_SET;
+        }
+
+        // Zero would disarm the timer, the epoch passed long ago either way
+        let deadline = deadline.max(Duration::from_nanos(1));
+
+        self.timer.set(deadline, interval.unwrap_or(Duration::ZERO), flags)
+    }
+
+    /// Disarm the timer, expirations not collected yet are dropped
+    pub fn cancel(&mut self) -> io::Result<()> {
+        self.timer.unset()
+    }
+
+    /// Time left until the next expiration, None if the timer is disarmed
+    pub fn remaining(&self) -> io::Result<Option<Duration>> {
+        Ok(self.timer.get()?.map(|(remaining, _)| remaining))
+    }
+
+    /// Drain the timer after it was reported readable and return how many
+    /// times it expired since the last call, 0 after a spurious wakeup.
+    /// A level triggered registration keeps firing until this is called.
+    /// Fails with ECANCELED once a cancel-on-set timer's clock was set
+    pub fn expirations(&mut self) -> io::Result<u64> {
+        self.timer.read()
+    }
+}
+
+impl AsRawFd for Timer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.timer.as_raw_fd()
+    }
+}
+
+impl AsFd for Timer {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.timer.as_fd()
+    }
+}
+
+/// Bits of the slot index of a wheel level, 64 slots per level
+const WHEEL_BITS: u32 = 6;
+
+const WHEEL_SLOTS: u64 = 1 << WHEEL_BITS;
+
+/// A tick of 1ms makes the top level span 4.6 hours, later deadlines go
+/// around it more than once
+const WHEEL_LEVELS: usize = 4;
+
+/// How a [`TimerQueue`] keeps its deadlines
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TimerStrategy {
+    /// A binary heap, deadlines expire exactly and inserting one is
+    /// O(log n). Suits a few precise timers
+    Heap,
+    /// A hashed hierarchical timing wheel of `tick` wide slots, inserting
+    /// and cancelling is O(1) and deadlines expire up to a tick late. Suits
+    /// many coarse timeouts, like one idle timeout per connection
+    Wheel { tick: Duration },
+}
+
+/// A deadline, its generation and its token
+type Entry = (Instant, u64, Token);
+
+/// Where the deadlines of a queue are ordered. Replaced and cancelled
+/// deadlines stay until they are reached, their generation no longer
+/// matches the live one
+#[derive(Debug)]
+enum Store {
+    /// Earliest deadline first
+    Heap(BinaryHeap<Reverse<Entry>>),
+    Wheel(Wheel),
+}
+
+impl Store {
+    /// Number of entries, stale ones included
+    fn len(&self) -> usize {
+        match self {
+            Store::Heap(heap) => heap.len(),
+            Store::Wheel(wheel) => wheel.len(),
+        }
+    }
+
+    fn push(&mut self, entry: Entry) {
+        match self {
+            Store::Heap(heap) => heap.push(Reverse(entry)),
+            Store::Wheel(wheel) => wheel.insert(entry),
+        }
+    }
+
+    /// Drop every stale entry
+    fn rebuild(&mut self, deadlines: &HashMap<Token, (Instant, u64)>) {
+        let live = deadlines.iter().map(|(token, (deadline, generation))| (*deadline, *generation, *token));
+
+        match self {
+            Store::Heap(heap) => *heap = live.map(Reverse).collect(),
+            Store::Wheel(wheel) => {
+                wheel.clear();
+                live.for_each(|entry| wheel.insert(entry));
+            }
+        }
+    }
+
+    /// The earliest live deadline
+    fn next(&mut self, deadlines: &HashMap<Token, (Instant, u64)>) -> Option<Instant> {
+        match self {
+            Store::Heap(heap) => {
+                discard_stale(heap, deadlines);
+                heap.peek().map(|Reverse((deadline, _, _))| *deadline)
+            }
+            Store::Wheel(wheel) => wheel.next(deadlines),
+        }
+    }
+
+    /// Take the live entries expired at `now` out, earliest first
+    fn expire(&mut self, now: Instant, deadlines: &HashMap<Token, (Instant, u64)>) -> Vec<Entry> {
+        let mut expired = Vec::new();
+
+        match self {
+            Store::Heap(heap) => loop {
+                discard_stale(heap, deadlines);
+
+                match heap.peek() {
+                    Some(Reverse((deadline, _, _))) if *deadline <= now => {}
+                    _ => break,
+                }
+
+                expired.extend(heap.pop().map(|Reverse(entry)| entry));
+            },
+            Store::Wheel(wheel) => {
+                wheel.advance(now, deadlines, &mut expired);
+                expired.sort();
+            }
+        }
+
+        expired
+    }
+
+    /// When the timer has to fire for `deadline` to be expired
+    fn wake_at(&self, deadline: Instant) -> Instant {
+        match self {
+            Store::Heap(_) => deadline,
+            Store::Wheel(wheel) => wheel.tick_start(wheel.tick_of(deadline)),
+        }
+    }
+}
+
+/// Pop replaced and cancelled deadlines off the top of the heap
+fn discard_stale(heap: &mut BinaryHeap<Reverse<Entry>>, deadlines: &HashMap<Token, (Instant, u64)>) {
+    while let Some(Reverse(entry)) = heap.peek() {
+        if is_live(entry, deadlines) {
+            break;
+        }
+
+        heap.pop();
+    }
+}
+
+fn is_live((deadline, generation, token): &Entry, deadlines: &HashMap<Token, (Instant, u64)>) -> bool {
+    deadlines.get(token) == Some(&(*deadline, *generation))
+}
+
+/// Hashed hierarchical timing wheel. A deadline expires at the first tick
+/// not before it. It goes into the level whose slots are as wide as its
+/// distance in ticks allows, 64^n ticks wide on level n, and moves down a
+/// level whenever the wheel reaches the start of its slot
+#[derive(Debug)]
+struct Wheel {
+    tick: Duration,
+    start: Instant,
+    /// The tick, counted from start, the wheel was advanced to
+    now: u64,
+    /// Entries by level and slot
+    levels: Vec<Vec<Vec<Entry>>>,
+    /// Entries per level
+    counts: [usize; WHEEL_LEVELS],
+    /// Entries whose tick was reached when they were inserted
+    due: Vec<Entry>,
+}
+
+impl Wheel {
+    fn new(tick: Duration) -> Wheel {
+        Wheel {
+            tick,
+            start: Instant::now(),
+            now: 0,
+            levels: (0..WHEEL_LEVELS).map(|_| (0..WHEEL_SLOTS).map(|_| Vec::new()).collect()).collect(),
+            counts: [0; WHEEL_LEVELS],
+            due: Vec::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.counts.iter().sum::<usize>() + self.due.len()
+    }
+
+    fn clear(&mut self) {
+        self.levels.iter_mut().flatten().for_each(Vec::clear);
+        self.counts = [0; WHEEL_LEVELS];
+        self.due.clear();
+    }
+
+    /// The first tick not before `deadline`
+    fn tick_of(&self, deadline: Instant) -> u64 {
+        let nanos = deadline.saturating_duration_since(self.start).as_nanos();
+        u64::try_from(nanos.div_ceil(self.tick.as_nanos())).unwrap_or(u64::MAX)
+    }
+
+    fn tick_start(&self, tick: u64) -> Instant {
+        let offset = u64::try_from(self.tick.as_nanos() * tick as u128).unwrap_or(u64::MAX);
+        self.start.checked_add(Duration::from_nanos(offset)).unwrap_or(self.start)
+    }
+
+    fn insert(&mut self, entry: Entry) {
+        let tick = self.tick_of(entry.0);
+        if tick <= self.now {
+            self.due.push(entry);
+            return;
+        }
+
+        // The level whose slots span the distance, 64^level <= distance
+        let distance = tick - self.now;
+        let level = ((distance.ilog2() / WHEEL_BITS) as usize).min(WHEEL_LEVELS - 1);
+
+        self.levels[level][slot(tick, level)].push(entry);
+        self.counts[level] += 1;
+    }
+
+    /// Advance to `now`, moving the live entries expired by then to `expired`
+    fn advance(&mut self, now: Instant, deadlines: &HashMap<Token, (Instant, u64)>, expired: &mut Vec<Entry>) {
+        // The last tick that started, tick_of rounds the other way
+        let elapsed = now.saturating_duration_since(self.start).as_nanos() / self.tick.as_nanos();
+        let target = u64::try_from(elapsed).unwrap_or(u64::MAX).max(self.now);
+
+        while self.now < target {
+            // Nothing happens before the next slot of the lowest level in use
+            let lowest = match self.counts.iter().position(|count| *count > 0) {
+                Some(lowest) => lowest,
+                None => {
+                    self.now = target;
+                    break;
+                }
+            };
+
+            let width = 1 << (WHEEL_BITS * lowest as u32);
+            let next = (self.now / width + 1) * width;
+            if next > target {
+                self.now = target;
+                break;
+            }
+
+            self.now = next;
+
+            // The slots starting at this tick move down, highest level first
+            for level in (1..WHEEL_LEVELS).rev() {
+                if next.is_multiple_of(1 << (WHEEL_BITS * level as u32)) {
+                    self.cascade(level, deadlines);
+                }
+            }
+
+            self.cascade(0, deadlines);
+        }
+
+        expired.extend(self.due.drain(..).filter(|entry| is_live(entry, deadlines)));
+    }
+
+    /// Insert the live entries of the current slot of `level` again, on the
+    /// level below or as due
+    fn cascade(&mut self, level: usize, deadlines: &HashMap<Token, (Instant, u64)>) {
+        let entries = std::mem::take(&mut self.levels[level][slot(self.now, level)]);
+        self.counts[level] -= entries.len();
+
+        for entry in entries.into_iter().filter(|entry| is_live(entry, deadlines)) {
+            self.insert(entry);
+        }
+    }
+
+    /// The earliest live deadline. The first slot holding any on every level
+    /// has the earliest of that level, unless a deadline too far away for
+    /// the top level went around it
+    fn next(&self, deadlines: &HashMap<Token, (Instant, u64)>) -> Option<Instant> {
+        let live = |entry: &&Entry| is_live(entry, deadlines);
+        let mut earliest = self.due.iter().filter(live).map(|(deadline, _, _)| *deadline).min();
+
+        for (level, slots) in self.levels.iter().enumerate() {
+            if self.counts[level] == 0 {
+                continue;
+            }
+
+            let shift = WHEEL_BITS * level as u32;
+            let base = self.now >> shift;
+
+            for distance in 1..=WHEEL_SLOTS {
+                let first = slots[slot((base + distance) << shift, level)]
+                    .iter()
+                    .filter(live)
+                    .filter(|(deadline, _, _)| self.tick_of(*deadline) >> shift == base + distance)
+                    .map(|(deadline, _, _)| *deadline)
+                    .min();
+
+                if let Some(first) = first {
+                    earliest = Some(earliest.map_or(first, |earliest| earliest.min(first)));
+                    break;
+                }
+            }
+        }
+
+        earliest.or_else(|| {
+            self.levels[WHEEL_LEVELS - 1].iter().flatten().filter(live).map(|(deadline, _, _)| *deadline).min()
+        })
+    }
+}
+
+/// The slot of `tick` on `level`
+fn slot(tick: u64, level: usize) -> usize {
+    ((tick >> (WHEEL_BITS * level as u32)) % WHEEL_SLOTS) as usize
+}
+
+/// Deadlines by token multiplexed onto one [`Timer`]. Register it readable
+/// and call [`TimerQueue::expired`] when it is reported
+#[derive(Debug)]
+pub struct TimerQueue {
+    timer: Timer,
+    store: Store,
+    /// The live deadline and its generation for every token
+    deadlines: HashMap<Token, (Instant, u64)>,
+    /// When the timer is armed to fire
+    armed: Option<Instant>,
+    generation: u64,
+}
+
+impl TimerQueue {
+    /// A queue keeping its deadlines in a binary heap
+    pub fn new() -> io::Result<TimerQueue> {
+        TimerQueue::with_strategy(TimerStrategy::Heap)
+    }
+
+    /// A queue keeping its deadlines as `strategy` says. A wheel needs a
+    /// tick of at least a nanosecond
+    pub fn with_strategy(strategy: TimerStrategy) -> io::Result<TimerQueue> {
+        let store = match strategy {
+            TimerStrategy::Heap => Store::Heap(BinaryHeap::new()),
+            TimerStrategy::Wheel { tick } if tick.is_zero() => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "the tick of a wheel can not be zero"));
+            }
+            TimerStrategy::Wheel { tick } => Store::Wheel(Wheel::new(tick)),
+        };
+
+        Ok(TimerQueue {
+            timer: Timer::new()?,
+            store,
+            deadlines: HashMap::new(),
+            armed: None,
+            generation: 0,
+        })
+    }
+
+    /// Number of pending deadlines
+    pub fn len(&self) -> usize {
+        self.deadlines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deadlines.is_empty()
+    }
+
+    /// Expire `token` at `deadline`, replacing its previous deadline
+    pub fn insert(&mut self, token: Token, deadline: Instant) -> io::Result<()> {
+        self.generation += 1;
+        self.deadlines.insert(token, (deadline, self.generation));
+        self.store.push((deadline, self.generation, token));
+
+        // Resetting idle timeouts leaves a stale entry behind every time
+        if self.store.len() > 2 * self.deadlines.len() + 64 {
+            self.store.rebuild(&self.deadlines);
+        }
+
+        if self.armed.is_none_or(|armed| self.store.wake_at(deadline) < armed) {
+            self.arm(Some(deadline))?;
+        }
+
+        Ok(())
+    }
+
+    /// Expire `token` `delay` from now, replacing its previous deadline
+    pub fn insert_after(&mut self, token: Token, delay: Duration) -> io::Result<()> {
+        self.insert(token, Instant::now() + delay)
+    }
+
+    /// Drop the deadline of `token`. Returns whether it had one
+    pub fn cancel(&mut self, token: Token) -> bool {
+        // The timer stays armed, an early wakeup just finds nothing expired
+        self.deadlines.remove(&token).is_some()
+    }
+
+    /// The pending deadline of `token`
+    pub fn deadline(&self, token: Token) -> Option<Instant> {
+        self.deadlines.get(&token).map(|(deadline, _)| *deadline)
+    }
+
+    /// The earliest pending deadline
+    pub fn next_deadline(&mut self) -> Option<Instant> {
+        self.store.next(&self.deadlines)
+    }
+
+    /// Call `f` with the token and deadline of every deadline that passed,
+    /// earliest first, and arm the timer for the next one. Reschedule the
+    /// tokens that need it afterwards. Returns how many deadlines expired
+    pub fn expired<F: FnMut(Token, Instant)>(&mut self, mut f: F) -> io::Result<usize> {
+        self.timer.expirations()?;
+
+        let expired = self.store.expire(Instant::now(), &self.deadlines);
+        for (deadline, _, token) in &expired {
+            self.deadlines.remove(token);
+            f(*token, *deadline);
+        }
+
+        let next = self.store.next(&self.deadlines);
+        self.arm(next)?;
+
+        Ok(expired.len())
+    }
+
+    fn arm(&mut self, deadline: Option<Instant>) -> io::Result<()> {
+        let wake_at = deadline.map(|deadline| self.store.wake_at(deadline));
+
+        match wake_at {
+            Some(wake_at) => self.timer.set(wake_at.saturating_duration_since(Instant::now()), None)?,
+            None => self.timer.cancel()?,
+        }
+
+        self.armed = wake_at;
+        Ok(())
+    }
+}
+
+impl AsRawFd for TimerQueue {
+    fn as_raw_fd(&self) -> RawFd {
+        self.timer.as_raw_fd()
+    }
+}
+
+impl AsFd for TimerQueue {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.timer.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::{Epoll, Interest, Mode};
+
+    fn expired(queue: &mut TimerQueue) -> Vec<Token> {
+        let mut tokens = Vec::new();
+        queue.expired(|token, _| tokens.push(token)).unwrap();
+        tokens
+    }
+
+    #[test]
+    fn oneshot_timers_fire_once() {
+        let mut epoll = Epoll::create().unwrap();
+        let mut timer = Timer::oneshot(Duration::from_millis(10)).unwrap();
+        epoll.register(&timer, Token(0), Interest::READABLE, Mode::Level).unwrap();
+
+        assert!(timer.remaining().unwrap().is_some());
+        assert_eq!(epoll.poll(Some(Duration::from_secs(1))).unwrap().len(), 1);
+        assert_eq!(timer.expirations().unwrap(), 1);
+
+        // Drained and disarmed
+        assert!(epoll.poll(Some(Duration::from_millis(30))).unwrap().is_empty());
+        assert_eq!(timer.remaining().unwrap(), None);
+    }
+
+    #[test]
+    fn repeating_timers_count_missed_expirations() {
+        let mut timer = Timer::repeating(Duration::from_millis(5)).unwrap();
+        thread::sleep(Duration::from_millis(30));
+
+        assert!(timer.expirations().unwrap() >= 2);
+        assert!(timer.remaining().unwrap().is_some());
+    }
+
+    #[test]
+    fn cancelled_timers_do_not_fire() {
+        let mut timer = Timer::oneshot(Duration::from_millis(10)).unwrap();
+        timer.cancel().unwrap();
+        assert_eq!(timer.remaining().unwrap(), None);
+
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(timer.expirations().unwrap(), 0);
+    }
+
+    #[test]
+    fn deadlines_expire_earliest_first() {
+        let mut queue = TimerQueue::new().unwrap();
+        let now = Instant::now();
+
+        queue.insert(Token(1), now + Duration::from_millis(30)).unwrap();
+        queue.insert(Token(2), now + Duration::from_millis(10)).unwrap();
+        queue.insert(Token(3), now + Duration::from_millis(20)).unwrap();
+        queue.insert(Token(4), now + Duration::from_secs(10)).unwrap();
+
+        thread::sleep(Duration::from_millis(50));
+
+        assert_eq!(expired(&mut queue), [Token(2), Token(3), Token(1)]);
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.next_deadline(), Some(now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn cancelled_and_replaced_deadlines_do_not_expire() {
+        let mut queue = TimerQueue::new().unwrap();
+        let later = Instant::now() + Duration::from_secs(10);
+
+        queue.insert_after(Token(1), Duration::from_millis(10)).unwrap();
+        queue.insert_after(Token(2), Duration::from_millis(10)).unwrap();
+        queue.insert_after(Token(3), Duration::from_millis(10)).unwrap();
+
+        assert!(queue.cancel(Token(1)));
+        assert!(!queue.cancel(Token(1)));
+        queue.insert(Token(2), later).unwrap();
+
+        thread::sleep(Duration::from_millis(30));
+
+        assert_eq!(expired(&mut queue), [Token(3)]);
+        assert_eq!(queue.deadline(Token(1)), None);
+        assert_eq!(queue.deadline(Token(2)), Some(later));
+        assert_eq!(queue.next_deadline(), Some(later));
+    }
+
+    #[test]
+    fn timer_fires_for_the_earliest_deadline() {
+        let mut epoll = Epoll::create().unwrap();
+        let mut queue = TimerQueue::new().unwrap();
+        epoll.register(&queue, Token(0), Interest::READABLE, Mode::Level).unwrap();
+
+        queue.insert_after(Token(1), Duration::from_secs(10)).unwrap();
+        queue.insert_after(Token(2), Duration::from_millis(20)).unwrap();
+
+        let events = epoll.poll(Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(events.iter().map(|(token, _)| token).collect::<Vec<_>>(), [Token(0)]);
+        assert_eq!(expired(&mut queue), [Token(2)]);
+
+        // Armed for the remaining deadline, disarmed once none is left
+        assert!(queue.timer.remaining().unwrap().is_some());
+        queue.cancel(Token(1));
+        assert!(expired(&mut queue).is_empty());
+        assert_eq!(queue.timer.remaining().unwrap(), None);
+    }
+
+    #[test]
+    fn replaced_deadlines_are_compacted() {
+        let mut queue = TimerQueue::new().unwrap();
+
+        for _ in 0..1000 {
+            queue.insert_after(Token(1), Duration::from_secs(10)).unwrap();
+        }
+
+        assert_eq!(queue.len(), 1);
+        assert!(queue.store.len() <= 2 + 64 + 1);
+    }
+
+    #[test]
+    fn wheel_expires_deadlines_of_every_level_earliest_first() {
+        let mut queue = TimerQueue::with_strategy(TimerStrategy::Wheel { tick: Duration::from_micros(1) }).unwrap();
+        let now = Instant::now();
+
+        // 64^n microseconds apart, one deadline per level
+        queue.insert(Token(1), now + Duration::from_millis(300)).unwrap();
+        queue.insert(Token(2), now + Duration::from_millis(20)).unwrap();
+        queue.insert(Token(3), now + Duration::from_millis(1)).unwrap();
+        queue.insert(Token(4), now + Duration::from_micros(40)).unwrap();
+        queue.insert(Token(5), now + Duration::from_secs(10)).unwrap();
+
+        thread::sleep(Duration::from_millis(320));
+
+        assert_eq!(expired(&mut queue), [Token(4), Token(3), Token(2), Token(1)]);
+        assert_eq!(queue.next_deadline(), Some(now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn wheel_finds_the_next_deadline() {
+        let mut queue = TimerQueue::with_strategy(TimerStrategy::Wheel { tick: Duration::from_millis(1) }).unwrap();
+        let now = Instant::now();
+        let minute = now + Duration::from_secs(60);
+        // Too far away for the top level, it goes around
+        let day = now + Duration::from_secs(24 * 60 * 60);
+
+        queue.insert(Token(1), day).unwrap();
+        queue.insert(Token(2), minute).unwrap();
+        queue.insert(Token(3), now + Duration::from_secs(5)).unwrap();
+        queue.cancel(Token(3));
+        assert_eq!(queue.next_deadline(), Some(minute));
+
+        queue.cancel(Token(2));
+        assert_eq!(queue.next_deadline(), Some(day));
+    }
+
+    #[test]
+    fn wheel_timer_fires_once_the_deadline_passed() {
+        let mut epoll = Epoll::create().unwrap();
+        let mut queue = TimerQueue::with_strategy(TimerStrategy::Wheel { tick: Duration::from_millis(5) }).unwrap();
+        epoll.register(&queue, Token(0), Interest::READABLE, Mode::Level).unwrap();
+
+        let deadline = Instant::now() + Duration::from_millis(12);
+        queue.insert(Token(1), deadline).unwrap();
+
+        assert_eq!(epoll.poll(Some(Duration::from_secs(1))).unwrap().len(), 1);
+        assert!(Instant::now() >= deadline);
+        assert_eq!(expired(&mut queue), [Token(1)]);
+        assert_eq!(queue.timer.remaining().unwrap(), None);
+    }
+
+    #[test]
+    fn wheels_need_a_tick() {
+        let err = TimerQueue::with_strategy(TimerStrategy::Wheel { tick: Duration::ZERO }).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
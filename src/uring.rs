@@ -0,0 +1,316 @@
+//! Readiness through io_uring poll requests, enabled with the `io-uring`
+//! feature.
+//!
+//! [`IoUring`] has the register and poll surface of [`Epoll`](crate::Epoll)
+//! with the same tokens, interests, modes and events, so a loop can switch
+//! between the two. Registering, reregistering and unregistering only queue
+//! requests, they are submitted together with the next poll, which saves a
+//! syscall per change under load. Edge registrations use multishot polls
+//! (Linux 5.13), level ones a single-shot poll armed again after every
+//! completion.
+//!
+//! Because the requests are submitted later, an fd the kernel refuses, e.g.
+//! one that was closed, is not an error of `register`: its token is reported
+//! once with the error readiness set and the registration is dropped.
+use std::collections::HashMap;
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, RawFd};
+
+use io_uring::{cqueue, opcode, squeue, types};
+
+use crate::{make_flags, Events, Interest, Mode, Readiness, Timeout, Token};
+
+/// How many requests fit in the submission queue by default
+const ENTRIES: u32 = 256;
+
+/// user_data of the poll remove requests, their completions are ignored
+const REMOVE: u64 = u64::MAX;
+
+#[derive(Debug)]
+struct Armed {
+    token: Token,
+    interest: Interest,
+    mode: Mode,
+    /// Distinguishes completions of this registration from earlier ones of
+    /// the same fd
+    generation: u32,
+}
+
+impl Armed {
+    fn user_data(&self, fd: RawFd) -> u64 {
+        (self.generation as u64) << 32 | fd as u32 as u64
+    }
+}
+
+/// io_uring instance polling registered fds for readiness
+pub struct IoUring {
+    ring: io_uring::IoUring,
+    registrations: HashMap<RawFd, Armed>,
+    generation: u32,
+}
+
+impl IoUring {
+    /// Create a ring with room for 256 queued requests
+    pub fn create() -> io::Result<IoUring> {
+        IoUring::with_entries(ENTRIES)
+    }
+
+    /// Create a ring with room for `entries` queued requests, more are
+    /// submitted early instead of failing
+    pub fn with_entries(entries: u32) -> io::Result<IoUring> {
+        Ok(IoUring {
+            ring: io_uring::IoUring::new(entries)?,
+            registrations: HashMap::new(),
+            generation: 0,
+        })
+    }
+
+    /// Register a new source
+    pub fn register<S: AsFd + ?Sized>(
+        &mut self,
+        source: &S,
+        token: Token,
+        interest: Interest,
+        mode: Mode
+    ) -> io::Result<()> {
+        let fd = source.as_fd().as_raw_fd();
+        if self.registrations.contains_key(&fd) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "already registered"));
+        }
+
+        self.arm(fd, token, interest, mode)
+    }
+
+    /// Change the token, interest or mode of a registered source, a one-shot
+    /// registration is armed again
+    pub fn reregister<S: AsFd + ?Sized>(
+        &mut self,
+        source: &S,
+        token: Token,
+        interest: Interest,
+        mode: Mode
+    ) -> io::Result<()> {
+        let fd = source.as_fd().as_raw_fd();
+        let previous = match self.registrations.remove(&fd) {
+            Some(previous) => previous,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "not registered")),
+        };
+
+        self.push(&opcode::PollRemove::new(previous.user_data(fd)).build().user_data(REMOVE))?;
+        self.arm(fd, token, interest, mode)
+    }
+
+    /// Stop polling a source
+    pub fn unregister<S: AsFd + ?Sized>(&mut self, source: &S) -> io::Result<()> {
+        let fd = source.as_fd().as_raw_fd();
+        let previous = match self.registrations.remove(&fd) {
+            Some(previous) => previous,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "not registered")),
+        };
+
+        self.push(&opcode::PollRemove::new(previous.user_data(fd)).build().user_data(REMOVE))
+    }
+
+    /// Start a [`Batch`](crate::batch::Batch) of registration changes
+    pub fn batch<'f>(&mut self) -> crate::batch::Batch<'_, 'f, IoUring> {
+        crate::batch::Batch::new(self)
+    }
+
+    /// Poll for new events
+    pub fn poll<T: Into<Timeout>>(&mut self, timeout: T) -> io::Result<Events> {
+        let mut events = Events::new();
+        self.poll_into(&mut events, timeout)?;
+        Ok(events)
+    }
+
+    /// Submit the queued requests, wait up to `timeout` for events and store
+    /// them in `events`, which is cleared first. Returns the number of events
+    pub fn poll_into<T: Into<Timeout>>(&mut self, events: &mut Events, timeout: T) -> io::Result<usize> {
+        let timeout = timeout.into().remaining();
+        events.clear();
+
+        let submitted = if self.ring.completion().is_empty() {
+            match timeout {
+                Some(timeout) if timeout.is_zero() => self.ring.submit(),
+                Some(timeout) => {
+                    let timespec = types::Timespec::from(timeout);
+                    let args = types::SubmitArgs::new().timespec(&timespec);
+                    self.ring.submitter().submit_with_args(1, &args)
+                }
+                None => self.ring.submit_and_wait(1),
+            }
+        } else {
+            self.ring.submit()
+        };
+
+        match submitted {
+            Ok(_) => {}
+            Err(err) if err.raw_os_error() == Some(libc::ETIME) => {}
+            Err(err) => return Err(err),
+        }
+
+        let completions: Vec<cqueue::Entry> = self.ring.completion().collect();
+        for completion in completions {
+            self.complete(&completion, events)?;
+        }
+
+        Ok(events.len())
+    }
+
+    /// Deliver one completion and arm the poll again where needed
+    fn complete(&mut self, completion: &cqueue::Entry, events: &mut Events) -> io::Result<()> {
+        let user_data = completion.user_data();
+        if user_data == REMOVE {
+            return Ok(());
+        }
+
+        let fd = user_data as u32 as RawFd;
+        let armed = match self.registrations.get(&fd) {
+            Some(armed) if armed.user_data(fd) == user_data => armed,
+            // Completion of a poll that was removed or replaced since
+            _ => return Ok(()),
+        };
+
+        let result = completion.result();
+        if result == -libc::ECANCELED {
+            return Ok(());
+        }
+
+        if result < 0 {
+            events.push(armed.token, Readiness::from_bits(libc::EPOLLERR as u32));
+            self.registrations.remove(&fd);
+            return Ok(());
+        }
+
+        events.push(armed.token, Readiness::from_bits(result as u32));
+
+        let rearm = match armed.mode {
+            Mode::Level => true,
+            // The kernel ends a multishot poll e.g. when it runs out of
+            // completion queue space
+            Mode::Edge => !cqueue::more(completion.flags()),
+            Mode::OneShot => false,
+        };
+
+        if rearm {
+            let entry = poll_entry(fd, armed);
+            self.push(&entry)?;
+        }
+
+        Ok(())
+    }
+
+    fn arm(&mut self, fd: RawFd, token: Token, interest: Interest, mode: Mode) -> io::Result<()> {
+        self.generation = self.generation.wrapping_add(1);
+        let armed = Armed { token, interest, mode, generation: self.generation };
+
+        self.push(&poll_entry(fd, &armed))?;
+        self.registrations.insert(fd, armed);
+        Ok(())
+    }
+
+    /// Queue a request, submitting the queue first if it is full
+    fn push(&mut self, entry: &squeue::Entry) -> io::Result<()> {
+        if self.ring.submission().is_full() {
+            self.ring.submit()?;
+        }
+
+        unsafe { self.ring.submission().push(entry) }
+            .map_err(|_| io::Error::new(io::ErrorKind::WouldBlock, "submission queue is full"))
+    }
+}
+
+fn poll_entry(fd: RawFd, armed: &Armed) -> squeue::Entry {
+    // The epoll bits of the interests are the poll bits
+    let flags = make_flags(armed.interest, Mode::Level);
+
+    opcode::PollAdd::new(types::Fd(fd), flags)
+        .multi(matches!(armed.mode, Mode::Edge))
+        .build()
+        .user_data(armed.user_data(fd))
+}
+
+impl std::fmt::Debug for IoUring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IoUring")
+            .field("fd", &self.ring.as_raw_fd())
+            .field("registrations", &self.registrations)
+            .finish()
+    }
+}
+
+impl AsRawFd for IoUring {
+    fn as_raw_fd(&self) -> RawFd {
+        self.ring.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+
+    use super::*;
+
+    /// A ring, None where io_uring is disabled or filtered by seccomp
+    fn ring() -> Option<IoUring> {
+        match IoUring::create() {
+            Ok(ring) => Some(ring),
+            Err(err) if matches!(err.raw_os_error(), Some(libc::ENOSYS | libc::EPERM)) => None,
+            Err(err) => panic!("creating a ring failed: {}", err),
+        }
+    }
+
+    fn tokens(ring: &mut IoUring, timeout: Duration) -> Vec<Token> {
+        ring.poll(timeout).unwrap().iter().map(|(token, _)| token).collect()
+    }
+
+    #[test]
+    fn level_registrations_report_until_drained() {
+        let Some(mut ring) = ring() else { return };
+        let (mut stream, peer) = UnixStream::pair().unwrap();
+        ring.register(&stream, Token(1), Interest::READABLE, Mode::Level).unwrap();
+
+        assert!(tokens(&mut ring, Duration::ZERO).is_empty());
+        (&peer).write_all(b"x").unwrap();
+
+        let events = ring.poll(Duration::from_secs(1)).unwrap();
+        let (token, readiness) = events.iter().next().unwrap();
+        assert_eq!(token, Token(1));
+        assert!(readiness.readable);
+
+        // Armed again while the byte is unread
+        assert_eq!(tokens(&mut ring, Duration::from_secs(1)), [Token(1)]);
+
+        stream.read_exact(&mut [0; 1]).unwrap();
+        assert!(tokens(&mut ring, Duration::from_millis(20)).is_empty());
+    }
+
+    #[test]
+    fn oneshot_registrations_wait_for_a_reregister() {
+        let Some(mut ring) = ring() else { return };
+        let (stream, peer) = UnixStream::pair().unwrap();
+        ring.register(&stream, Token(1), Interest::READABLE, Mode::OneShot).unwrap();
+        (&peer).write_all(b"x").unwrap();
+
+        assert_eq!(tokens(&mut ring, Duration::from_secs(1)), [Token(1)]);
+        assert!(tokens(&mut ring, Duration::from_millis(20)).is_empty());
+
+        ring.reregister(&stream, Token(2), Interest::READABLE, Mode::OneShot).unwrap();
+        assert_eq!(tokens(&mut ring, Duration::from_secs(1)), [Token(2)]);
+    }
+
+    #[test]
+    fn unregistered_sources_are_not_reported() {
+        let Some(mut ring) = ring() else { return };
+        let (stream, peer) = UnixStream::pair().unwrap();
+        ring.register(&stream, Token(1), Interest::READABLE, Mode::Edge).unwrap();
+        ring.unregister(&stream).unwrap();
+        (&peer).write_all(b"x").unwrap();
+
+        assert!(tokens(&mut ring, Duration::from_millis(20)).is_empty());
+        assert_eq!(ring.unregister(&stream).unwrap_err().kind(), io::ErrorKind::NotFound);
+        ring.register(&stream, Token(1), Interest::READABLE, Mode::Edge).unwrap();
+    }
+}
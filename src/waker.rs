@@ -0,0 +1,156 @@
+//! Waking a blocked poll from another thread.
+//!
+//! Wakes are coalesced: only the first [`Waker::wake`] after the poll
+//! reported the waker writes the eventfd, the rest see it is signaled
+//! already and return without a syscall. A [`SignalSafeWaker`] does the same
+//! from a signal handler, without the coalescing.
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::sys;
+
+/// Handle to an eventfd registered with [`Epoll::waker`](crate::Epoll::waker).
+/// Clone it and send it to other threads, [`Waker::wake`] makes the poll
+/// return an event with the waker's token. The eventfd is drained by the
+/// poll that reports it, several wakes before that are reported once
+#[derive(Debug, Clone)]
+pub struct Waker {
+    fd: Arc<OwnedFd>,
+    /// Written and not drained yet, shared by all clones
+    signaled: Arc<AtomicBool>,
+}
+
+impl Waker {
+    pub(crate) fn new() -> io::Result<Waker> {
+        Ok(Waker { fd: Arc::new(sys::eventfd(0)?), signaled: Arc::new(AtomicBool::new(false)) })
+    }
+
+    /// Wake the poll. Only the first wake since the poll last reported the
+    /// waker writes the eventfd, the others cost an atomic swap
+    pub fn wake(&self) -> io::Result<()> {
+        if self.signaled.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+
+        match sys::write_counter(self.fd.as_raw_fd(), 1) {
+            // The counter is saturated, the poll is woken up already
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            Err(err) => {
+                self.signaled.store(false, Ordering::Release);
+                Err(err)
+            }
+            result => result,
+        }
+    }
+
+    /// A handle for waking the poll from a signal handler, see
+    /// [`SignalSafeWaker`]. Keep this waker alive while the handler may run,
+    /// the handle does not keep the eventfd open
+    pub fn signal_safe(&self) -> SignalSafeWaker {
+        SignalSafeWaker { fd: self.fd.as_raw_fd() }
+    }
+
+    /// Reset the counter so the level triggered registration settles.
+    /// The flag is cleared after the read: a wake skipped in between happened
+    /// before the caller handles this event, so it is not lost
+    pub(crate) fn drain(&self) {
+        let _ = sys::read_counter(self.fd.as_raw_fd());
+        self.signaled.store(false, Ordering::Release);
+    }
+}
+
+impl AsRawFd for Waker {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for Waker {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+/// The eventfd of a [`Waker`] as a plain fd, to be stored in a static and
+/// woken from a signal handler. It is Copy and does not own the fd, the
+/// waker it came from has to outlive every handler that may use it
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SignalSafeWaker {
+    fd: RawFd,
+}
+
+impl SignalSafeWaker {
+    /// The handle of an eventfd owned elsewhere, e.g. one stored in an
+    /// AtomicI32 for a signal handler to pick up
+    ///
+    /// # Safety
+    /// `fd` has to be an eventfd registered readable, which stays open for
+    /// as long as the handle is used
+    pub const unsafe fn from_raw_fd(fd: RawFd) -> SignalSafeWaker {
+        SignalSafeWaker { fd }
+    }
+
+    /// Wake the poll. Async-signal-safe: a single write(2) of the eventfd
+    /// counter, nothing is allocated or locked and errno is left as it was.
+    /// A counter that is full already means the poll is woken up anyway
+    pub fn wake(&self) {
+        let errno = unsafe { *libc::__errno_location() };
+
+        let one = 1u64;
+        unsafe { libc::write(self.fd, &one as *const u64 as *const libc::c_void, 8) };
+
+        unsafe { *libc::__errno_location() = errno };
+    }
+}
+
+impl AsRawFd for SignalSafeWaker {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{Epoll, Token};
+
+    #[test]
+    fn wakes_are_coalesced_until_the_poll_reports_them() {
+        let mut epoll = Epoll::create().unwrap();
+        let waker = epoll.waker(Token(9)).unwrap();
+
+        let producers: Vec<_> = (0..4)
+            .map(|_| {
+                let waker = waker.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        waker.wake().unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        for producer in producers {
+            producer.join().unwrap();
+        }
+
+        // Every wake after the first one skipped the write
+        let mut counter = [0u8; 8];
+        assert_eq!(sys::read(waker.as_raw_fd(), &mut counter).unwrap(), 8);
+        assert_eq!(u64::from_ne_bytes(counter), 1);
+        sys::write_counter(waker.as_raw_fd(), 1).unwrap();
+
+        let events = epoll.poll(Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(events.iter().map(|(token, _)| token).collect::<Vec<_>>(), [Token(9)]);
+        assert!(epoll.poll(Some(Duration::ZERO)).unwrap().is_empty());
+
+        // Reported and drained, the next wake writes again
+        waker.wake().unwrap();
+        assert_eq!(epoll.poll(Some(Duration::from_secs(1))).unwrap().len(), 1);
+    }
+}
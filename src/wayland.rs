@@ -0,0 +1,136 @@
+//! Wayland clients on a copoll loop, with the `wayland-client` feature.
+//!
+//! A [`WaylandSource`] owns the connection and an event queue and is a
+//! [`Source`](crate::Source) for the connection fd. libwayland only lets a
+//! thread block on the fd between preparing a read and either reading or
+//! cancelling it, otherwise events another queue or thread read into this
+//! queue go unnoticed until the next unrelated wakeup. The source follows
+//! that protocol in two calls around every poll:
+//!
+//! ```ignore
+//! let mut wayland = WaylandSource::new(connection, queue);
+//! epoll.register(&wayland, WAYLAND, Interest::READABLE, Mode::Level)?;
+//!
+//! loop {
+//!     wayland.prepare(&mut app)?;
+//!     let events = epoll.poll(None)?;
+//!     let readable = events.iter().any(|(token, _)| token == WAYLAND);
+//!     wayland.dispatch(readable, &mut app)?;
+//! }
+//! ```
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+
+use wayland_client::backend::{Backend, ReadEventsGuard, WaylandError};
+use wayland_client::{Connection, DispatchError, EventQueue};
+
+/// A wayland connection and event queue, see the [module docs](self)
+pub struct WaylandSource<D> {
+    connection: Connection,
+    backend: Backend,
+    queue: EventQueue<D>,
+    /// A prepared read, taken by the dispatch after the poll
+    guard: Option<ReadEventsGuard>,
+}
+
+impl<D> WaylandSource<D> {
+    pub fn new(connection: Connection, queue: EventQueue<D>) -> WaylandSource<D> {
+        let backend = connection.backend();
+        WaylandSource { connection, backend, queue, guard: None }
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    pub fn queue(&self) -> &EventQueue<D> {
+        &self.queue
+    }
+
+    pub fn queue_mut(&mut self) -> &mut EventQueue<D> {
+        &mut self.queue
+    }
+
+    /// Call right before every poll that may block. Dispatches the events
+    /// already queued, flushes the requests and prepares the read. Returns
+    /// the number of events dispatched
+    pub fn prepare(&mut self, state: &mut D) -> io::Result<usize> {
+        let mut dispatched = 0;
+
+        // A previous prepare without a dispatch, cancel it
+        self.guard = None;
+
+        loop {
+            dispatched += self.queue.dispatch_pending(state).map_err(dispatch_error)?;
+
+            // A full socket sends the rest with the next flush
+            match self.connection.flush() {
+                Err(WaylandError::Io(err)) if err.kind() == io::ErrorKind::WouldBlock => {}
+                result => result.map_err(wayland_error)?,
+            }
+
+            // Only fails while events are queued, dispatch them first
+            if let Some(guard) = self.queue.prepare_read() {
+                self.guard = Some(guard);
+                return Ok(dispatched);
+            }
+        }
+    }
+
+    /// Call after every poll, with whether the fd was reported readable.
+    /// Reads the new events or cancels the prepared read, then dispatches
+    /// the queue. Returns the number of events dispatched
+    pub fn dispatch(&mut self, readable: bool, state: &mut D) -> io::Result<usize> {
+        if let Some(guard) = self.guard.take() {
+            if readable {
+                match guard.read() {
+                    Ok(_) => {}
+                    Err(WaylandError::Io(err)) if err.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(err) => return Err(wayland_error(err)),
+                }
+            }
+        }
+
+        self.queue.dispatch_pending(state).map_err(dispatch_error)
+    }
+
+    /// The connection and the event queue, cancels a prepared read
+    pub fn into_inner(self) -> (Connection, EventQueue<D>) {
+        (self.connection, self.queue)
+    }
+}
+
+impl<D> std::fmt::Debug for WaylandSource<D> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WaylandSource")
+            .field("fd", &self.as_raw_fd())
+            .field("prepared", &self.guard.is_some())
+            .finish()
+    }
+}
+
+impl<D> AsRawFd for WaylandSource<D> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.backend.poll_fd().as_raw_fd()
+    }
+}
+
+impl<D> AsFd for WaylandSource<D> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.backend.poll_fd()
+    }
+}
+
+fn wayland_error(err: WaylandError) -> io::Error {
+    match err {
+        WaylandError::Io(err) => err,
+        WaylandError::Protocol(err) => io::Error::other(err),
+    }
+}
+
+fn dispatch_error(err: DispatchError) -> io::Error {
+    match err {
+        DispatchError::Backend(err) => wayland_error(err),
+        err => io::Error::new(io::ErrorKind::InvalidData, err),
+    }
+}
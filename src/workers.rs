@@ -0,0 +1,294 @@
+//! Dispatching ready sources on a pool of threads.
+//!
+//! Every thread of a [`WorkerPool`] waits on the same epoll instance and
+//! every source is registered one-shot, so an event reaches exactly one
+//! thread and the source stays disarmed while its callback runs. Returning
+//! Continue arms it again, the next event may go to any thread. A source is
+//! never handled by two threads at once, different sources run in parallel.
+//!
+//! ```ignore
+//! let pool = WorkerPool::new(4)?;
+//! pool.insert(stream, Interest::READABLE, |_, stream| {
+//!     handle(stream);
+//!     PostAction::Continue
+//! })?;
+//! ```
+use std::io;
+use std::os::fd::AsFd;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+
+use crate::shared::{Poll, Registry};
+use crate::{Events, Interest, Mode, PostAction, Readiness, Slab, Token, Waker};
+
+/// Token of the waker that stops the threads, registered level triggered so
+/// every waiting thread sees it
+const STOP: Token = Token(usize::MAX);
+
+/// A source and its callback, type erased over both
+trait Handle: Send {
+    fn handle(&mut self, readiness: Readiness) -> PostAction;
+
+    fn reregister(&self, registry: &Registry, token: Token) -> io::Result<()>;
+
+    fn unregister(&self, registry: &Registry) -> io::Result<()>;
+}
+
+struct Handler<T, F> {
+    source: T,
+    interest: Interest,
+    callback: F,
+}
+
+impl<T, F> Handle for Handler<T, F>
+where
+    T: AsFd + Send,
+    F: FnMut(Readiness, &mut T) -> PostAction + Send,
+{
+    fn handle(&mut self, readiness: Readiness) -> PostAction {
+        (self.callback)(readiness, &mut self.source)
+    }
+
+    fn reregister(&self, registry: &Registry, token: Token) -> io::Result<()> {
+        registry.reregister(&self.source, token, self.interest, Mode::OneShot)
+    }
+
+    fn unregister(&self, registry: &Registry) -> io::Result<()> {
+        registry.unregister(&self.source)
+    }
+}
+
+struct Entry {
+    handler: Box<dyn Handle>,
+    /// Set by remove, a thread that got the event before must not run it
+    removed: bool,
+}
+
+type Entries = Mutex<Slab<Arc<Mutex<Entry>>>>;
+
+/// Threads waiting on one epoll instance, see the [module docs](self)
+pub struct WorkerPool {
+    registry: Registry,
+    entries: Arc<Entries>,
+    stop: Waker,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    /// Start `threads` threads, at least one
+    pub fn new(threads: usize) -> io::Result<WorkerPool> {
+        let poll = Poll::create()?;
+        let registry = poll.registry().clone();
+        let stop = Waker::new()?;
+        registry.register(&stop, STOP, Interest::READABLE, Mode::Level)?;
+
+        let mut pool = WorkerPool {
+            registry,
+            entries: Arc::new(Mutex::new(Slab::new())),
+            stop,
+            threads: Vec::new(),
+        };
+
+        for i in 0..threads.max(1) {
+            let poll = Poll::with_registry(pool.registry.clone());
+            let entries = pool.entries.clone();
+
+            let thread = thread::Builder::new()
+                .name(format!("copoll-worker-{}", i))
+                .spawn(move || work(poll, &entries))?;
+            pool.threads.push(thread);
+        }
+
+        Ok(pool)
+    }
+
+    /// Take ownership of `source` and call `callback` on one of the threads
+    /// whenever it is ready. Returns the token of the registration
+    pub fn insert<T, F>(&self, source: T, interest: Interest, callback: F) -> io::Result<Token>
+    where
+        T: AsFd + Send + 'static,
+        F: FnMut(Readiness, &mut T) -> PostAction + Send + 'static,
+    {
+        let mut entries = lock(&self.entries);
+        let token = entries.vacant_token();
+
+        self.registry.register(&source, token, interest, Mode::OneShot)?;
+
+        let handler = Box::new(Handler { source, interest, callback });
+        Ok(entries.insert(Arc::new(Mutex::new(Entry { handler, removed: false }))))
+    }
+
+    /// Arm a source again after its callback returned Disable
+    pub fn rearm(&self, token: Token) -> io::Result<()> {
+        let entry = self.entry(token)?;
+        let entry = lock(&entry);
+        entry.handler.reregister(&self.registry, token)
+    }
+
+    /// Unregister and drop the source with `token`. Waits for its callback
+    /// if it is running, it is not called again afterwards
+    pub fn remove(&self, token: Token) -> io::Result<()> {
+        let entry = match lock(&self.entries).remove(token) {
+            Some(entry) => entry,
+            None => return Err(not_found()),
+        };
+
+        let mut entry = lock(&entry);
+        entry.removed = true;
+        entry.handler.unregister(&self.registry)
+    }
+
+    /// Number of sources in the pool
+    pub fn len(&self) -> usize {
+        lock(&self.entries).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        lock(&self.entries).is_empty()
+    }
+
+    fn entry(&self, token: Token) -> io::Result<Arc<Mutex<Entry>>> {
+        lock(&self.entries).get(token).cloned().ok_or_else(not_found)
+    }
+}
+
+impl std::fmt::Debug for WorkerPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WorkerPool")
+            .field("registry", &self.registry)
+            .field("sources", &self.len())
+            .field("threads", &self.threads.len())
+            .finish()
+    }
+}
+
+/// Stops the threads and waits for the running callbacks. The sources are
+/// dropped with the pool
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        let _ = self.stop.wake();
+
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn lock<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+fn not_found() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "no source with this token")
+}
+
+/// A pool thread, runs until the stop waker fires
+fn work(mut poll: Poll, entries: &Entries) {
+    let mut events = Events::new();
+
+    loop {
+        // One event per wait, a burst is spread over the threads instead of
+        // queueing behind the thread that woke first
+        match poll.poll_max(&mut events, None, 1) {
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => return,
+        }
+
+        for (token, readiness) in &events {
+            if token == STOP {
+                return;
+            }
+
+            let entry = match lock(entries).get(token) {
+                Some(entry) => entry.clone(),
+                None => continue,
+            };
+
+            let mut entry = lock(&entry);
+            if entry.removed {
+                continue;
+            }
+
+            match entry.handler.handle(readiness) {
+                // A failed rearm leaves the source disarmed, like Disable
+                PostAction::Continue => {
+                    let _ = entry.handler.reregister(poll.registry(), token);
+                }
+                PostAction::Disable => {}
+                PostAction::Remove => {
+                    entry.removed = true;
+                    let _ = entry.handler.unregister(poll.registry());
+                    lock(entries).remove(token);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn remove_waits_for_the_running_callback() {
+        let pool = WorkerPool::new(2).unwrap();
+        let (stream, peer) = UnixStream::pair().unwrap();
+        let (started, started_rx) = mpsc::channel();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let finished = Arc::new(AtomicUsize::new(0));
+
+        let token = {
+            let calls = calls.clone();
+            let finished = finished.clone();
+
+            pool.insert(stream, Interest::READABLE, move |_, _| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                let _ = started.send(());
+                thread::sleep(Duration::from_millis(100));
+                finished.fetch_add(1, Ordering::SeqCst);
+                PostAction::Continue
+            })
+            .unwrap()
+        };
+
+        (&peer).write_all(b"x").unwrap();
+        started_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        pool.remove(token).unwrap();
+        assert_eq!(finished.load(Ordering::SeqCst), 1);
+        assert!(pool.is_empty());
+
+        // Continue would have armed it again, the byte is still unread
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        assert_eq!(pool.remove(token).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn callbacks_returning_remove_drop_their_source() {
+        let pool = WorkerPool::new(1).unwrap();
+        let (stream, peer) = UnixStream::pair().unwrap();
+        let (done, done_rx) = mpsc::channel();
+
+        pool.insert(stream, Interest::READABLE, move |_, _| {
+            let _ = done.send(());
+            PostAction::Remove
+        })
+        .unwrap();
+
+        (&peer).write_all(b"x").unwrap();
+        done_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // Removed after the callback returned
+        thread::sleep(Duration::from_millis(50));
+        assert!(pool.is_empty());
+    }
+}
@@ -0,0 +1,153 @@
+//! Zero-copy forwarding with splice(2) and sendfile(2).
+//!
+//! Every call moves as much as it can without blocking and returns how far it
+//! got together with the readiness to wait for before calling it again, so a
+//! proxy or file server only has to register the fds for what [`Wait`] says.
+//! All fds have to be non-blocking.
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::ptr;
+
+use crate::sys;
+
+/// How much a single splice or sendfile call asks for, the default pipe capacity
+const CHUNK: usize = 64 * 1024;
+
+/// What a transfer is waiting for
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Wait {
+    /// The source has nothing left for now, wait until it is readable
+    Readable,
+    /// The destination is full, wait until it is writable
+    Writable,
+    /// Everything was transferred, or the source reached end of file
+    Done,
+}
+
+/// Result of one call: the bytes written to the destination by it and
+/// what to wait for next
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Progress {
+    pub bytes: usize,
+    pub wait: Wait,
+}
+
+/// Forwards a socket into another one through a pipe, the data never
+/// reaches userspace. Use one per direction of a proxied connection
+#[derive(Debug)]
+pub struct Splicer {
+    reader: OwnedFd,
+    writer: OwnedFd,
+    buffered: usize,
+    eof: bool,
+}
+
+impl Splicer {
+    pub fn new() -> io::Result<Splicer> {
+        let (reader, writer) = sys::pipe(libc::O_NONBLOCK | libc::O_CLOEXEC)?;
+
+        Ok(Splicer { reader, writer, buffered: 0, eof: false })
+    }
+
+    /// Bytes read from the source that the destination did not take yet
+    pub fn buffered(&self) -> usize {
+        self.buffered
+    }
+
+    /// Move data from `from` to `to` until one of them would block.
+    /// Once `from` reported end of file the remaining buffered data is
+    /// still written and Wait::Done is returned when it is gone
+    pub fn forward(&mut self, from: BorrowedFd<'_>, to: BorrowedFd<'_>) -> io::Result<Progress> {
+        let mut bytes = 0;
+
+        loop {
+            if !self.eof && self.buffered < CHUNK {
+                match splice(from.as_raw_fd(), self.writer.as_raw_fd(), CHUNK - self.buffered) {
+                    Ok(0) => self.eof = true,
+                    Ok(n) => self.buffered += n,
+                    // Drain what is buffered first, if anything
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock && self.buffered == 0 => {
+                        return Ok(Progress { bytes, wait: Wait::Readable });
+                    }
+                    Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted) => {}
+                    Err(err) => return Err(err),
+                }
+            }
+
+            // Only reached with nothing buffered once the source is done
+            if self.buffered == 0 {
+                return Ok(Progress { bytes, wait: Wait::Done });
+            }
+
+            match splice(self.reader.as_raw_fd(), to.as_raw_fd(), self.buffered) {
+                Ok(n) => {
+                    self.buffered -= n;
+                    bytes += n;
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(Progress { bytes, wait: Wait::Writable }),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+/// Sends a range of a file to a socket with sendfile(2), borrowing the file
+#[derive(Debug)]
+pub struct FileSender<'a> {
+    file: BorrowedFd<'a>,
+    offset: libc::off_t,
+    end: libc::off_t,
+}
+
+impl<'a> FileSender<'a> {
+    /// Send the whole file, from its start up to its current size
+    pub fn new<F: AsFd>(file: &'a F) -> io::Result<FileSender<'a>> {
+        let size = sys::fstat(file.as_fd().as_raw_fd())?.st_size;
+        Ok(FileSender::range(file, 0, size as u64))
+    }
+
+    /// Send `len` bytes starting at `offset`
+    pub fn range<F: AsFd>(file: &'a F, offset: u64, len: u64) -> FileSender<'a> {
+        let offset = offset.min(libc::off_t::MAX as u64) as libc::off_t;
+        let end = offset.saturating_add(len.min(libc::off_t::MAX as u64) as libc::off_t);
+
+        FileSender { file: file.as_fd(), offset, end }
+    }
+
+    /// Bytes not sent yet
+    pub fn remaining(&self) -> u64 {
+        (self.end - self.offset) as u64
+    }
+
+    /// Send until the socket would block or the range is done. A file that
+    /// shrank in the meantime ends the transfer early
+    pub fn send(&mut self, socket: BorrowedFd<'_>) -> io::Result<Progress> {
+        let mut bytes = 0;
+
+        while self.offset < self.end {
+            let count = ((self.end - self.offset) as u64).min(CHUNK as u64) as usize;
+
+            let sent = unsafe { libc::sendfile(socket.as_raw_fd(), self.file.as_raw_fd(), &mut self.offset, count) };
+
+            match sys::cvt_size(sent) {
+                Ok(0) => {
+                    self.end = self.offset;
+                    break;
+                }
+                Ok(n) => bytes += n,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(Progress { bytes, wait: Wait::Writable }),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(Progress { bytes, wait: Wait::Done })
+    }
+}
+
+/// splice(2) up to `len` bytes without blocking, one of the fds is a pipe
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+    sys::cvt_size(unsafe { libc::splice(from, ptr::null_mut(), to, ptr::null_mut(), len, flags) })
+}